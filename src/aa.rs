@@ -0,0 +1,488 @@
+use vec::{Vec2, Vec3};
+use image::{Image, Color};
+use post::{FloatImage, luma, sample_bilinear, sample_clamped, to_float, to_image};
+
+// Post-process anti-aliasing: both passes find edges from luma alone, so
+// they only need the final colors, not depth or coverage
+
+#[derive(Clone, Copy)]
+pub struct FxaaOptions {
+    // How much to soften aliasing inside single pixels, in [0, 1]
+    pub subpixel: f32,
+    // Minimum local contrast, relative to the brightest neighbor, for a
+    // pixel to count as an edge
+    pub edge_threshold: f32,
+    // Keeps dark areas, where relative contrast is noisy, from being blurred
+    pub edge_threshold_min: f32,
+}
+
+impl FxaaOptions {
+    // The "quality 12" preset
+    pub fn new() -> FxaaOptions {
+        FxaaOptions {
+            subpixel: 0.75,
+            edge_threshold: 0.166,
+            edge_threshold_min: 0.0833,
+        }
+    }
+}
+
+// Step sizes of the search for the ends of an edge, in pixels
+const FXAA_STEPS: [f32; 12] = [1.0, 1.0, 1.0, 1.0, 1.0, 1.5, 2.0, 2.0, 2.0, 2.0, 4.0, 8.0];
+
+pub fn fxaa(image: &Image, options: &FxaaOptions) -> Image {
+    to_image(&fxaa_float(&to_float(image), options))
+}
+
+// FXAA 3.11, quality version
+pub fn fxaa_float(image: &FloatImage, options: &FxaaOptions) -> FloatImage {
+    let lumas: Vec<f32> = image.data.iter().map(|&c| luma(c)).collect();
+    let luma_at = |x: isize, y: isize| {
+        let x = x.clamp(0, image.width as isize - 1) as usize;
+        let y = y.clamp(0, image.height as isize - 1) as usize;
+        lumas[(image.height - y - 1) * image.width + x]
+    };
+    let luma_bilinear = |p: Vec2<f32>| luma(sample_bilinear(image, p.x, p.y));
+
+    let mut out = image.clone();
+
+    for y in 0..image.height {
+        for x in 0..image.width {
+            let (xi, yi) = (x as isize, y as isize);
+            let center = luma_at(xi, yi);
+            let (n, s, e, w) = (luma_at(xi, yi + 1), luma_at(xi, yi - 1), luma_at(xi + 1, yi), luma_at(xi - 1, yi));
+
+            let max = center.max(n).max(s).max(e).max(w);
+            let min = center.min(n).min(s).min(e).min(w);
+            let range = max - min;
+            if range < options.edge_threshold_min.max(max * options.edge_threshold) {
+                continue;
+            }
+
+            let (ne, nw, se, sw) = (luma_at(xi + 1, yi + 1), luma_at(xi - 1, yi + 1), luma_at(xi + 1, yi - 1), luma_at(xi - 1, yi - 1));
+
+            // Whether the edge runs along x (luma changes going up or down)
+            let edge_horizontal = (-2.0 * w + nw + sw).abs() + 2.0 * (-2.0 * center + n + s).abs() + (-2.0 * e + ne + se).abs();
+            let edge_vertical = (-2.0 * n + nw + ne).abs() + 2.0 * (-2.0 * center + w + e).abs() + (-2.0 * s + sw + se).abs();
+            let horizontal = edge_horizontal >= edge_vertical;
+
+            // Which side of the pixel the edge is on
+            let (luma_neg, luma_pos) = if horizontal { (s, n) } else { (w, e) };
+            let (gradient_neg, gradient_pos) = (luma_neg - center, luma_pos - center);
+            let neg_steepest = gradient_neg.abs() >= gradient_pos.abs();
+            let gradient_scaled = 0.25 * gradient_neg.abs().max(gradient_pos.abs());
+
+            let (step, local_average) = if neg_steepest {
+                (-1.0, 0.5 * (luma_neg + center))
+            } else {
+                (1.0, 0.5 * (luma_pos + center))
+            };
+
+            // Across the edge, and along it
+            let (normal, along) = if horizontal {
+                (Vec2 { x: 0.0, y: 1.0 }, Vec2 { x: 1.0, y: 0.0 })
+            } else {
+                (Vec2 { x: 1.0, y: 0.0 }, Vec2 { x: 0.0, y: 1.0 })
+            };
+
+            // Walk both ways along the edge, half a pixel over, until the
+            // luma stops matching the edge's average
+            let start = Vec2 { x: x as f32, y: y as f32 } + normal * (step * 0.5);
+            let (mut p1, mut p2) = (start + along * -FXAA_STEPS[0], start + along * FXAA_STEPS[0]);
+            let mut end1 = luma_bilinear(p1) - local_average;
+            let mut end2 = luma_bilinear(p2) - local_average;
+            let (mut done1, mut done2) = (end1.abs() >= gradient_scaled, end2.abs() >= gradient_scaled);
+
+            for &quality in FXAA_STEPS[1..].iter() {
+                if done1 && done2 {
+                    break;
+                }
+                if !done1 {
+                    p1 = p1 + along * -quality;
+                    end1 = luma_bilinear(p1) - local_average;
+                    done1 = end1.abs() >= gradient_scaled;
+                }
+                if !done2 {
+                    p2 = p2 + along * quality;
+                    end2 = luma_bilinear(p2) - local_average;
+                    done2 = end2.abs() >= gradient_scaled;
+                }
+            }
+
+            let (distance1, distance2) = if horizontal {
+                (x as f32 - p1.x, p2.x - x as f32)
+            } else {
+                (y as f32 - p1.y, p2.y - y as f32)
+            };
+            let nearer1 = distance1 < distance2;
+            let distance = distance1.min(distance2);
+            let thickness = distance1 + distance2;
+
+            // Only blend if the nearer end of the edge goes the same way as
+            // this pixel is from the edge's average
+            let end_luma = if nearer1 { end1 } else { end2 };
+            let edge_offset = if (end_luma < 0.0) != (center < local_average) {
+                0.5 - distance / thickness
+            } else {
+                0.0
+            };
+
+            // Subpixel aliasing: a pixel unlike all of its neighbors
+            let average = (2.0 * (n + s + e + w) + ne + nw + se + sw) / 12.0;
+            let sub1 = ((average - center).abs() / range).clamp(0.0, 1.0);
+            let sub2 = (-2.0 * sub1 + 3.0) * sub1 * sub1;
+            let sub_offset = sub2 * sub2 * options.subpixel;
+
+            let offset = edge_offset.max(sub_offset);
+            let p = Vec2 { x: x as f32, y: y as f32 } + normal * (offset * step);
+            out.set(x, y, sample_bilinear(image, p.x, p.y));
+        }
+    }
+
+    out
+}
+
+#[derive(Clone, Copy)]
+pub struct SmaaOptions {
+    // Minimum luma difference for an edge
+    pub threshold: f32,
+    // Edges weaker than the strongest edge next to them by more than this
+    // factor are dropped, so the pass doesn't blend across both sides of
+    // thin features
+    pub contrast_adaptation: f32,
+}
+
+impl SmaaOptions {
+    pub fn new() -> SmaaOptions {
+        SmaaOptions {
+            threshold: 0.1,
+            contrast_adaptation: 2.0,
+        }
+    }
+}
+
+pub fn smaa(image: &Image, options: &SmaaOptions) -> Image {
+    to_image(&smaa_float(&to_float(image), options))
+}
+
+// SMAA 1x: edge detection, then blending weights from the shape of each
+// edge, then blending every pixel with its neighbors by those weights. The
+// areas the reference implementation precomputes into a texture are worked
+// out directly from the edge shapes instead, and diagonal patterns are left
+// out.
+pub fn smaa_float(image: &FloatImage, options: &SmaaOptions) -> FloatImage {
+    let (width, height) = (image.width, image.height);
+    let edges = Edges::detect(image, options);
+
+    // Per pixel, how much to take from the neighbor at +x, -x, +y and -y
+    let mut weights = vec![[0.0f32; 4]; width * height];
+
+    // Edges between vertically adjacent pixels, as runs along x
+    blend_weights(width, height,
+        |a, b| edges.between_rows(a, b),
+        |a, b| edges.between_columns(a - 1, b),
+        |a, b, w, up| weights[b * width + a][if up { 2 } else { 3 }] += w);
+
+    // And the same along y, with x and y swapped
+    blend_weights(height, width,
+        |a, b| edges.between_columns(b, a),
+        |a, b| edges.between_rows(b, a - 1),
+        |a, b, w, up| weights[a * width + b][if up { 0 } else { 1 }] += w);
+
+    let mut out = image.clone();
+    for y in 0..height {
+        for x in 0..width {
+            let [px, nx, py, ny] = weights[y * width + x];
+            let (xi, yi) = (x as isize, y as isize);
+
+            // Blend along one axis only, the one with the stronger weight
+            let (w1, c1, w2, c2) = if px.max(nx) > py.max(ny) {
+                (px, sample_clamped(image, xi + 1, yi), nx, sample_clamped(image, xi - 1, yi))
+            } else {
+                (py, sample_clamped(image, xi, yi + 1), ny, sample_clamped(image, xi, yi - 1))
+            };
+
+            if w1 + w2 > 0.0 {
+                let scale = 1.0 / (w1 + w2).max(1.0);
+                let (w1, w2) = (w1 * scale, w2 * scale);
+                let c = *image.get(x, y);
+
+                out.set(x, y, c * (1.0 - w1 - w2) + c1 * w1 + c2 * w2);
+            }
+        }
+    }
+
+    out
+}
+
+struct Edges {
+    width: usize,
+    height: usize,
+    // Between (x, y) and (x, y + 1)
+    rows: Vec<bool>,
+    // Between (x, y) and (x + 1, y)
+    columns: Vec<bool>,
+}
+
+impl Edges {
+    fn detect(image: &FloatImage, options: &SmaaOptions) -> Edges {
+        let (width, height) = (image.width, image.height);
+        let lumas: Vec<f32> = (0..width * height).map(|i| luma(*image.get(i % width, i / width))).collect();
+
+        // 0 off the edges of the image, so they never count as edges
+        let delta_up = |x: isize, y: isize| {
+            if x < 0 || y < 0 || x >= width as isize || y + 1 >= height as isize {
+                0.0
+            } else {
+                let (x, y) = (x as usize, y as usize);
+                (lumas[y * width + x] - lumas[(y + 1) * width + x]).abs()
+            }
+        };
+        let delta_right = |x: isize, y: isize| {
+            if x < 0 || y < 0 || x + 1 >= width as isize || y >= height as isize {
+                0.0
+            } else {
+                let (x, y) = (x as usize, y as usize);
+                (lumas[y * width + x] - lumas[y * width + x + 1]).abs()
+            }
+        };
+
+        let mut edges = Edges {
+            width,
+            height,
+            rows: vec![false; width * height],
+            columns: vec![false; width * height],
+        };
+
+        for y in 0..height as isize {
+            for x in 0..width as isize {
+                let i = y as usize * width + x as usize;
+
+                // Compared with the edges around this one, along and across
+                let up = delta_up(x, y);
+                if up > options.threshold {
+                    let strongest = up
+                        .max(delta_up(x, y - 1)).max(delta_up(x, y + 1))
+                        .max(delta_right(x - 1, y)).max(delta_right(x, y))
+                        .max(delta_right(x - 1, y + 1)).max(delta_right(x, y + 1));
+                    edges.rows[i] = up * options.contrast_adaptation >= strongest;
+                }
+
+                let right = delta_right(x, y);
+                if right > options.threshold {
+                    let strongest = right
+                        .max(delta_right(x - 1, y)).max(delta_right(x + 1, y))
+                        .max(delta_up(x, y - 1)).max(delta_up(x, y))
+                        .max(delta_up(x + 1, y - 1)).max(delta_up(x + 1, y));
+                    edges.columns[i] = right * options.contrast_adaptation >= strongest;
+                }
+            }
+        }
+
+        edges
+    }
+
+    fn between_rows(&self, x: usize, y: usize) -> bool {
+        x < self.width && y < self.height && self.rows[y * self.width + x]
+    }
+
+    fn between_columns(&self, x: usize, y: usize) -> bool {
+        x < self.width && y < self.height && self.columns[y * self.width + x]
+    }
+}
+
+// Finds runs of edges along `a` between cells (a, b) and (a, b + 1), and
+// works out how much each cell beside a run should blend with the cell
+// across it.
+//
+// `edge(a, b)` is whether there's an edge between (a, b) and (a, b + 1);
+// `crossing(a, b)` whether there's one between (a - 1, b) and (a, b), for
+// a >= 1. `blend(a, b, weight, up)` adds to cell (a, b)'s weight towards
+// b + 1 if `up`, else towards b - 1.
+fn blend_weights<E, C, B>(along: usize, across: usize, edge: E, crossing: C, mut blend: B)
+        where E: Fn(usize, usize) -> bool, C: Fn(usize, usize) -> bool, B: FnMut(usize, usize, f32, bool) {
+    for b in 0..across.saturating_sub(1) {
+        let mut a = 0;
+
+        while a < along {
+            if !edge(a, b) {
+                a += 1;
+                continue;
+            }
+
+            let start = a;
+            while a < along && edge(a, b) {
+                a += 1;
+            }
+            let end = a;
+
+            // Which way the silhouette turns at each end of the run: up
+            // (towards b + 1), down, or not at all
+            let turn = |at: usize| -> f32 {
+                if at == 0 || at >= along {
+                    return 0.0;
+                }
+
+                match (crossing(at, b), crossing(at, b + 1)) {
+                    (false, true) => 0.5,
+                    (true, false) => -0.5,
+                    _ => 0.0,
+                }
+            };
+
+            let line = Line::new(start as f32, turn(start), end as f32, turn(end));
+
+            for cell in start..end {
+                let (above, below) = line.areas(cell as f32, cell as f32 + 1.0);
+
+                // The part of the cell the line cuts off belongs to the
+                // other side of the edge
+                if above > 0.0 {
+                    blend(cell, b + 1, above, false);
+                }
+                if below > 0.0 {
+                    blend(cell, b, below, true);
+                }
+            }
+        }
+    }
+}
+
+// The edge as it probably was before rasterization, as heights above the
+// run of pixel edges it was detected from
+struct Line {
+    points: Vec<Vec2<f32>>,
+}
+
+impl Line {
+    fn new(start: f32, start_height: f32, end: f32, end_height: f32) -> Line {
+        let points = if start_height != 0.0 && start_height == end_height {
+            // A bump: up to the middle and back
+            let middle = (start + end) / 2.0;
+            vec![
+                Vec2 { x: start, y: start_height },
+                Vec2 { x: middle, y: 0.0 },
+                Vec2 { x: end, y: end_height },
+            ]
+        } else {
+            vec![Vec2 { x: start, y: start_height }, Vec2 { x: end, y: end_height }]
+        };
+
+        Line { points }
+    }
+
+    // Area between the line and zero over [from, to], split into the parts
+    // above and below
+    fn areas(&self, from: f32, to: f32) -> (f32, f32) {
+        let (mut above, mut below) = (0.0, 0.0);
+
+        for pair in self.points.windows(2) {
+            let (p, q) = (pair[0], pair[1]);
+            let (u, v) = (from.max(p.x), to.min(q.x));
+            if v <= u {
+                continue;
+            }
+
+            let height = |x: f32| p.y + (q.y - p.y) * (x - p.x) / (q.x - p.x);
+            let (hu, hv) = (height(u), height(v));
+
+            if hu * hv >= 0.0 {
+                let area = (hu + hv) / 2.0 * (v - u);
+                if area > 0.0 { above += area } else { below -= area }
+            } else {
+                // Crosses zero inside: two triangles
+                let zero = u + (v - u) * hu / (hu - hv);
+                let (first, second) = (hu * (zero - u) / 2.0, hv * (v - zero) / 2.0);
+                for area in [first, second] {
+                    if area > 0.0 { above += area } else { below -= area }
+                }
+            }
+        }
+
+        (above, below)
+    }
+}
+
+// Red where there's an edge between columns, green between rows, for
+// debugging the edge pass
+pub fn smaa_edges(image: &Image, options: &SmaaOptions) -> Image {
+    let float = to_float(image);
+    let edges = Edges::detect(&float, options);
+    let mut out = Image::new(image.width, image.height);
+
+    for y in 0..image.height {
+        for x in 0..image.width {
+            let color = Vec3 {
+                x: if edges.between_columns(x, y) { 1.0 } else { 0.0 },
+                y: if edges.between_rows(x, y) { 1.0 } else { 0.0 },
+                z: 0.0,
+            };
+            out.set_pixel(x, y, &Color::from_vec(color));
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image<F: Fn(usize, usize) -> Color>(color: F) -> Image {
+        let mut image = Image::new(16, 16);
+
+        for y in 0..16 {
+            for x in 0..16 {
+                image.set_pixel(x, y, &color(x, y));
+            }
+        }
+
+        image
+    }
+
+    fn close(a: (f32, f32), b: (f32, f32)) -> bool {
+        (a.0 - b.0).abs() < 1e-6 && (a.1 - b.1).abs() < 1e-6
+    }
+
+    #[test]
+    fn flat_images_are_unchanged() {
+        let flat = image(|_, _| Color(90, 160, 30));
+
+        assert!(fxaa(&flat, &FxaaOptions::new()).data == flat.data);
+        assert!(smaa(&flat, &SmaaOptions::new()).data == flat.data);
+    }
+
+    #[test]
+    fn diagonal_edges_are_smoothed() {
+        // A staircase two pixels per step, so both passes see runs of edges
+        let edge = image(|x, y| if x / 2 > y / 2 { Color(255, 255, 255) } else { Color(0, 0, 0) });
+        let blended = |out: &Image| out.data.iter().filter(|&&v| v > 0 && v < 255).count();
+
+        assert_eq!(blended(&edge), 0);
+        assert!(blended(&fxaa(&edge, &FxaaOptions::new())) > 0);
+        assert!(blended(&smaa(&edge, &SmaaOptions::new())) > 0);
+
+        // Far from the edge nothing changes
+        let out = smaa(&edge, &SmaaOptions::new());
+        assert_eq!(out.get_pixel(15, 0).0, 255);
+        assert_eq!(out.get_pixel(0, 15).0, 0);
+    }
+
+    #[test]
+    fn splits_areas_around_the_line() {
+        let flat = Line::new(0.0, 0.0, 4.0, 0.0);
+        assert!(close(flat.areas(0.0, 4.0), (0.0, 0.0)));
+
+        // Down from 0.5 to the middle and back up
+        let bump = Line::new(0.0, 0.5, 4.0, 0.5);
+        assert!(close(bump.areas(0.0, 1.0), (0.375, 0.0)));
+        assert!(close(bump.areas(1.0, 2.0), (0.125, 0.0)));
+        assert!(close(bump.areas(0.0, 4.0), (1.0, 0.0)));
+
+        // From 0.5 to -0.5, crossing zero at 2
+        let step = Line::new(0.0, 0.5, 4.0, -0.5);
+        assert!(close(step.areas(0.0, 1.0), (0.375, 0.0)));
+        assert!(close(step.areas(1.0, 3.0), (0.125, 0.125)));
+        assert!(close(step.areas(0.0, 4.0), (0.5, 0.5)));
+    }
+}
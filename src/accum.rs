@@ -0,0 +1,233 @@
+use std::f32::consts::PI;
+use vec::{Vec2, Vec3};
+use image::Image;
+use matrix::Matrix4x4;
+use post::{FloatImage, to_float, to_image};
+
+// Sums whole renders in floating point, so averaging many of them doesn't
+// lose precision to rounding
+pub struct Accumulator {
+    sum: FloatImage,
+    count: usize,
+}
+
+impl Accumulator {
+    pub fn new(width: usize, height: usize) -> Accumulator {
+        Accumulator {
+            sum: FloatImage::new(width, height, Vec3 { x: 0.0, y: 0.0, z: 0.0 }),
+            count: 0,
+        }
+    }
+
+    // Must be the size the accumulator was created with
+    pub fn add(&mut self, image: &Image) {
+        self.add_float(&to_float(image));
+    }
+
+    pub fn add_float(&mut self, image: &FloatImage) {
+        assert!(image.width == self.sum.width && image.height == self.sum.height,
+                "accumulating a {}x{} image into {}x{}", image.width, image.height, self.sum.width, self.sum.height);
+
+        for (s, c) in self.sum.data.iter_mut().zip(image.data.iter()) {
+            *s = *s + *c;
+        }
+        self.count += 1;
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    pub fn average(&self) -> FloatImage {
+        let scale = 1.0 / self.count.max(1) as f32;
+        let mut average = self.sum.clone();
+
+        for c in average.data.iter_mut() {
+            *c = *c * scale;
+        }
+
+        average
+    }
+
+    pub fn image(&self) -> Image {
+        to_image(&self.average())
+    }
+}
+
+// The `index`th point of the Halton sequence in `base`, in [0, 1). Pairs of
+// coprime bases fill the unit square evenly however many points are used,
+// without the clumps of random samples. Panics if `base` is below 2.
+pub fn halton(index: usize, base: usize) -> f32 {
+    assert!(base >= 2, "the Halton sequence needs a base of at least 2, not {}", base);
+
+    let mut index = index;
+    let mut f = 1.0;
+    let mut result = 0.0;
+
+    while index > 0 {
+        f /= base as f32;
+        result += f * (index % base) as f32;
+        index /= base;
+    }
+
+    result
+}
+
+// How one render of an accumulated frame is offset from the others
+#[derive(Clone, Copy)]
+pub struct Jitter {
+    // In pixels, within [-0.5, 0.5]
+    pub pixel: Vec2<f32>,
+    // A point in the unit disk, for spreading a light over an area
+    pub light: Vec2<f32>,
+}
+
+impl Jitter {
+    // Renders exactly as without accumulation
+    pub fn none() -> Jitter {
+        Jitter {
+            pixel: Vec2 { x: 0.0, y: 0.0 },
+            light: Vec2 { x: 0.0, y: 0.0 },
+        }
+    }
+
+    // `samples` offsets spread evenly over the pixel and the light. A single
+    // sample isn't jittered at all.
+    pub fn sequence(samples: usize) -> Vec<Jitter> {
+        if samples <= 1 {
+            return vec![Jitter::none()];
+        }
+
+        // Index 0 is the origin in every base, so start at 1. The light
+        // uses different bases, or the pixel and light offsets would be
+        // correlated.
+        (1..samples + 1).map(|i| Jitter {
+            pixel: Vec2 { x: halton(i, 2) - 0.5, y: halton(i, 3) - 0.5 },
+            light: concentric_disk(halton(i, 5), halton(i, 7)),
+        }).collect()
+    }
+
+    // Shifts everything drawn with `screen` (viewport * projection * view)
+    // by the sub-pixel offset. Translating after the projection moves x and
+    // y by the offset times w, which is exactly the offset once divided by w.
+    pub fn screen(&self, screen: &Matrix4x4<f32>) -> Matrix4x4<f32> {
+        Matrix4x4::translation(Vec3 { x: self.pixel.x, y: self.pixel.y, z: 0.0 }) * screen.clone()
+    }
+
+    // A point on a disk-shaped light of `radius` around `position`, facing
+    // `towards` (usually the middle of the scene)
+    pub fn light_position(&self, position: Vec3<f32>, towards: Vec3<f32>, radius: f32) -> Vec3<f32> {
+        let (u, v) = perpendicular(towards - position);
+        position + (u * self.light.x + v * self.light.y) * radius
+    }
+
+    // A direction within `angle` radians of `direction`, for lights like the
+    // sun that are far away but not points
+    pub fn light_direction(&self, direction: Vec3<f32>, angle: f32) -> Vec3<f32> {
+        let direction = direction.norm();
+        let (u, v) = perpendicular(direction);
+        (direction + (u * self.light.x + v * self.light.y) * angle.tan()).norm()
+    }
+}
+
+// Maps the unit square onto the unit disk without bunching points in the
+// middle (Shirley and Chiu)
+fn concentric_disk(u: f32, v: f32) -> Vec2<f32> {
+    let (a, b) = (u * 2.0 - 1.0, v * 2.0 - 1.0);
+
+    if a == 0.0 && b == 0.0 {
+        return Vec2 { x: 0.0, y: 0.0 };
+    }
+
+    let (r, theta) = if a.abs() > b.abs() {
+        (a, PI / 4.0 * (b / a))
+    } else {
+        (b, PI / 2.0 - PI / 4.0 * (a / b))
+    };
+
+    Vec2 { x: r * theta.cos(), y: r * theta.sin() }
+}
+
+// Two unit vectors at right angles to `n` and each other
+fn perpendicular(n: Vec3<f32>) -> (Vec3<f32>, Vec3<f32>) {
+    let n = n.norm();
+    let axis = if n.x.abs() < 0.9 { Vec3 { x: 1.0, y: 0.0, z: 0.0 } } else { Vec3 { x: 0.0, y: 1.0, z: 0.0 } };
+    let u = n.cross(axis).norm();
+
+    (u, n.cross(u))
+}
+
+// Renders the frame once per jitter and averages the results: antialiased
+// edges and textures from the sub-pixel offsets, and soft shadows if
+// `render` moves its lights by the light offsets. The shaders themselves
+// don't change.
+pub fn accumulate<F: FnMut(&Jitter) -> Image>(width: usize, height: usize, samples: usize, mut render: F) -> Image {
+    let mut accumulator = Accumulator::new(width, height);
+
+    for jitter in Jitter::sequence(samples).iter() {
+        accumulator.add(&render(jitter));
+    }
+
+    accumulator.image()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn halton_reverses_digits() {
+        let base2: Vec<f32> = (0..8).map(|i| halton(i, 2)).collect();
+        assert_eq!(base2, vec![0.0, 0.5, 0.25, 0.75, 0.125, 0.625, 0.375, 0.875]);
+
+        let base3: Vec<f32> = (1..5).map(|i| halton(i, 3)).collect();
+        let expected = [1.0 / 3.0, 2.0 / 3.0, 1.0 / 9.0, 4.0 / 9.0];
+        for (a, b) in base3.iter().zip(expected.iter()) {
+            assert!((a - b).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn halton_rejects_base_1() {
+        halton(3, 1);
+    }
+
+    #[test]
+    fn concentric_disk_maps_the_square_onto_the_disk() {
+        let close = |p: Vec2<f32>, x: f32, y: f32| (p.x - x).abs() < 1e-6 && (p.y - y).abs() < 1e-6;
+
+        assert!(close(concentric_disk(0.5, 0.5), 0.0, 0.0));
+        assert!(close(concentric_disk(1.0, 0.5), 1.0, 0.0));
+        assert!(close(concentric_disk(0.5, 1.0), 0.0, 1.0));
+        assert!(close(concentric_disk(0.0, 0.5), -1.0, 0.0));
+        assert!(close(concentric_disk(0.75, 0.5), 0.5, 0.0));
+
+        // Corners end up on the diagonals, at the rim
+        let corner = concentric_disk(1.0, 1.0);
+        assert!(close(corner, 0.5f32.sqrt(), 0.5f32.sqrt()));
+
+        for i in 0..64 {
+            let p = concentric_disk(halton(i, 2), halton(i, 3));
+            assert!(p.x * p.x + p.y * p.y <= 1.0 + 1e-6);
+        }
+    }
+
+    #[test]
+    fn averages_what_it_accumulates() {
+        let mut accumulator = Accumulator::new(2, 1);
+        accumulator.add_float(&FloatImage::new(2, 1, Vec3 { x: 1.0, y: 0.0, z: 0.5 }));
+        accumulator.add_float(&FloatImage::new(2, 1, Vec3 { x: 0.0, y: 0.0, z: 0.5 }));
+
+        assert_eq!(accumulator.count(), 2);
+        let average = accumulator.average();
+        assert!(average.data.iter().all(|c| c.x == 0.5 && c.y == 0.0 && c.z == 0.5));
+    }
+
+    #[test]
+    #[should_panic]
+    fn rejects_images_of_another_size() {
+        let mut accumulator = Accumulator::new(4, 4);
+        accumulator.add_float(&FloatImage::new(4, 3, Vec3 { x: 0.0, y: 0.0, z: 0.0 }));
+    }
+}
@@ -0,0 +1,294 @@
+use vec::{Vec3, Quaternion};
+use matrix::{Matrix4x4, Transform};
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Interpolation {
+    Step,
+    Linear,
+    // Catmull-Rom through the neighbouring keys
+    Cubic,
+}
+
+pub trait Keyable: Copy {
+    fn add(self, other: Self) -> Self;
+    fn scale(self, by: f32) -> Self;
+
+    fn lerp(self, other: Self, t: f32) -> Self {
+        self.scale(1.0 - t).add(other.scale(t))
+    }
+
+    // Applied to the result of cubic interpolation
+    fn fixup(self) -> Self {
+        self
+    }
+
+    // An equivalent value as close as possible to `to`, for types with more
+    // than one way to say the same thing
+    fn align(self, _to: Self) -> Self {
+        self
+    }
+}
+
+impl Keyable for f32 {
+    fn add(self, other: f32) -> f32 {
+        self + other
+    }
+
+    fn scale(self, by: f32) -> f32 {
+        self * by
+    }
+}
+
+impl Keyable for Vec3<f32> {
+    fn add(self, other: Vec3<f32>) -> Vec3<f32> {
+        self + other
+    }
+
+    fn scale(self, by: f32) -> Vec3<f32> {
+        self * by
+    }
+}
+
+impl Keyable for Quaternion {
+    fn add(self, other: Quaternion) -> Quaternion {
+        self + other
+    }
+
+    fn scale(self, by: f32) -> Quaternion {
+        self * by
+    }
+
+    fn lerp(self, other: Quaternion, t: f32) -> Quaternion {
+        self.slerp(other, t)
+    }
+
+    fn fixup(self) -> Quaternion {
+        self.norm()
+    }
+
+    // q and -q are the same rotation, but blending between keys in opposite
+    // hemispheres goes the long way round
+    fn align(self, to: Quaternion) -> Quaternion {
+        if to.dot(self) < 0.0 { self * -1.0 } else { self }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Keyframe<T> {
+    pub time: f32,
+    pub value: T,
+}
+
+#[derive(Clone)]
+pub struct Track<T> {
+    pub interpolation: Interpolation,
+    keys: Vec<Keyframe<T>>,
+}
+
+fn hermite<T: Keyable>(p0: T, m0: T, p1: T, m1: T, t: f32) -> T {
+    let t2 = t * t;
+    let t3 = t2 * t;
+
+    p0.scale(2.0 * t3 - 3.0 * t2 + 1.0)
+        .add(m0.scale(t3 - 2.0 * t2 + t))
+        .add(p1.scale(-2.0 * t3 + 3.0 * t2))
+        .add(m1.scale(t3 - t2))
+}
+
+impl<T: Keyable> Track<T> {
+    pub fn new(interpolation: Interpolation) -> Track<T> {
+        Track {
+            interpolation,
+            keys: Vec::new(),
+        }
+    }
+
+    // Keys are kept sorted by time, so they can be added in any order
+    pub fn add_key(&mut self, time: f32, value: T) {
+        let index = self.keys.iter().position(|k| k.time > time).unwrap_or(self.keys.len());
+        self.keys.insert(index, Keyframe { time, value });
+    }
+
+    pub fn keys(&self) -> &[Keyframe<T>] {
+        &self.keys
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    pub fn duration(&self) -> f32 {
+        self.keys.last().map_or(0.0, |k| k.time)
+    }
+
+    // Times outside the keyed range hold the first or last value, and NaN
+    // holds the first
+    pub fn sample(&self, time: f32) -> Option<T> {
+        let first = self.keys.first()?;
+        let last = self.keys[self.keys.len() - 1];
+
+        if time.is_nan() || time <= first.time {
+            return Some(first.value);
+        }
+        if time >= last.time {
+            return Some(last.value);
+        }
+
+        let i = self.keys.iter().position(|k| k.time > time).unwrap() - 1;
+        let k0 = self.keys[i];
+        let k1 = self.keys[i + 1];
+        let dt = k1.time - k0.time;
+        let t = (time - k0.time) / dt;
+
+        Some(match self.interpolation {
+            Interpolation::Step => k0.value,
+            Interpolation::Linear => k0.value.lerp(k1.value, t),
+            Interpolation::Cubic => {
+                let before = if i > 0 { i - 1 } else { i };
+                let after = if i + 2 < self.keys.len() { i + 2 } else { i + 1 };
+
+                // Every key is brought next to the one before it, so the
+                // tangents don't see a sign flip as a huge change
+                let v0 = k0.value;
+                let v1 = k1.value.align(v0);
+                let prev = self.keys[before].value.align(v0);
+                let next = self.keys[after].value.align(v1);
+
+                let tangent = |a: T, b: T, span: f32| b.add(a.scale(-1.0)).scale(dt / span);
+
+                let m0 = tangent(prev, v1, k1.time - self.keys[before].time);
+                let m1 = tangent(v0, next, self.keys[after].time - k0.time);
+
+                hermite(v0, m0, v1, m1, t).fixup()
+            }
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct TransformAnimation {
+    pub translation: Track<Vec3<f32>>,
+    pub rotation: Track<Quaternion>,
+    pub scale: Track<Vec3<f32>>,
+}
+
+impl TransformAnimation {
+    pub fn new(interpolation: Interpolation) -> TransformAnimation {
+        TransformAnimation {
+            translation: Track::new(interpolation),
+            rotation: Track::new(interpolation),
+            scale: Track::new(interpolation),
+        }
+    }
+
+    pub fn duration(&self) -> f32 {
+        self.translation.duration()
+            .max(self.rotation.duration())
+            .max(self.scale.duration())
+    }
+
+    // Channels without any keys keep the value from `rest`
+    pub fn sample(&self, time: f32, rest: &Transform) -> Transform {
+        Transform {
+            translation: self.translation.sample(time).unwrap_or(rest.translation),
+            rotation: self.rotation.sample(time).unwrap_or(rest.rotation),
+            scale: self.scale.sample(time).unwrap_or(rest.scale),
+        }
+    }
+
+    pub fn sample_frame(&self, frame: usize, fps: f32, rest: &Transform) -> Matrix4x4<f32> {
+        self.sample(frame as f32 / fps, rest).to_matrix()
+    }
+
+    pub fn frame_count(&self, fps: f32) -> usize {
+        (self.duration() * fps).floor() as usize + 1
+    }
+}
+
+// A set of transform animations keyed by joint (or node) index
+#[derive(Clone)]
+pub struct Clip {
+    pub name: String,
+    pub channels: Vec<(usize, TransformAnimation)>,
+}
+
+impl Clip {
+    pub fn new(name: &str) -> Clip {
+        Clip {
+            name: name.to_string(),
+            channels: Vec::new(),
+        }
+    }
+
+    pub fn duration(&self) -> f32 {
+        self.channels.iter().fold(0.0, |d, (_, anim)| d.max(anim.duration()))
+    }
+
+    // Poses every target at `time`; targets without a channel stay at rest
+    pub fn sample(&self, time: f32, rest: &[Transform]) -> Vec<Transform> {
+        let mut pose = rest.to_vec();
+
+        for &(target, ref anim) in &self.channels {
+            if target < pose.len() {
+                pose[target] = anim.sample(time, &rest[target]);
+            }
+        }
+
+        pose
+    }
+
+    // Wraps time around so the clip loops, backwards too
+    pub fn sample_looped(&self, time: f32, rest: &[Transform]) -> Vec<Transform> {
+        let duration = self.duration();
+
+        if duration > 0.0 {
+            self.sample(time.rem_euclid(duration), rest)
+        } else {
+            self.sample(0.0, rest)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cubic_rotation_takes_the_short_way() {
+        let z = Vec3 { x: 0.0, y: 0.0, z: 1.0 };
+        let mut track = Track::new(Interpolation::Cubic);
+        track.add_key(0.0, Quaternion::identity());
+        // The same rotation as +90 degrees, from the other hemisphere
+        track.add_key(1.0, Quaternion::from_axis_angle(std::f32::consts::FRAC_PI_2, z) * -1.0);
+        track.add_key(2.0, Quaternion::from_axis_angle(std::f32::consts::PI, z));
+
+        let v = track.sample(0.5).unwrap().rotate(Vec3 { x: 1.0, y: 0.0, z: 0.0 });
+        let expected = std::f32::consts::FRAC_1_SQRT_2;
+
+        assert!((v.x - expected).abs() < 0.05 && (v.y - expected).abs() < 0.05, "{} {}", v.x, v.y);
+    }
+
+    #[test]
+    fn looping_wraps_negative_time() {
+        let mut anim = TransformAnimation::new(Interpolation::Linear);
+        anim.translation.add_key(0.0, Vec3 { x: 0.0, y: 0.0, z: 0.0 });
+        anim.translation.add_key(1.0, Vec3 { x: 1.0, y: 0.0, z: 0.0 });
+
+        let mut clip = Clip::new("test");
+        clip.channels.push((0, anim));
+
+        let pose = clip.sample_looped(-0.25, &[Transform::identity()]);
+        assert!((pose[0].translation.x - 0.75).abs() < 1e-5);
+    }
+
+    #[test]
+    fn nan_time_holds_the_first_key() {
+        for interpolation in [Interpolation::Step, Interpolation::Linear, Interpolation::Cubic] {
+            let mut track = Track::new(interpolation);
+            track.add_key(0.0, 1.0f32);
+            track.add_key(1.0, 2.0f32);
+
+            assert_eq!(track.sample(f32::NAN), Some(1.0));
+        }
+    }
+}
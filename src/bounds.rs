@@ -0,0 +1,208 @@
+use std::f32;
+use vec::Vec3;
+use matrix::Matrix4x4;
+use obj::Obj;
+
+#[derive(Clone, Copy)]
+pub struct Aabb {
+    pub min: Vec3<f32>,
+    pub max: Vec3<f32>,
+}
+
+impl Aabb {
+    // Contains nothing; growing it by any point gives that point
+    pub fn empty() -> Aabb {
+        Aabb {
+            min: Vec3 { x: f32::INFINITY, y: f32::INFINITY, z: f32::INFINITY },
+            max: Vec3 { x: f32::NEG_INFINITY, y: f32::NEG_INFINITY, z: f32::NEG_INFINITY },
+        }
+    }
+
+    pub fn from_points<I: IntoIterator<Item = Vec3<f32>>>(points: I) -> Aabb {
+        points.into_iter().fold(Aabb::empty(), |b, p| b.grow(p))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.min.x > self.max.x || self.min.y > self.max.y || self.min.z > self.max.z
+    }
+
+    pub fn grow(self, p: Vec3<f32>) -> Aabb {
+        Aabb {
+            min: Vec3 { x: self.min.x.min(p.x), y: self.min.y.min(p.y), z: self.min.z.min(p.z) },
+            max: Vec3 { x: self.max.x.max(p.x), y: self.max.y.max(p.y), z: self.max.z.max(p.z) },
+        }
+    }
+
+    pub fn union(self, other: &Aabb) -> Aabb {
+        self.grow(other.min).grow(other.max)
+    }
+
+    pub fn center(&self) -> Vec3<f32> {
+        (self.min + self.max) * 0.5
+    }
+
+    pub fn size(&self) -> Vec3<f32> {
+        self.max - self.min
+    }
+
+    pub fn contains(&self, p: Vec3<f32>) -> bool {
+        p.x >= self.min.x && p.x <= self.max.x &&
+            p.y >= self.min.y && p.y <= self.max.y &&
+            p.z >= self.min.z && p.z <= self.max.z
+    }
+
+    // Measured from the nearest point in the box
+    pub fn intersects_sphere(&self, sphere: &Sphere) -> bool {
+        if self.is_empty() {
+            return false;
+        }
+
+        let c = sphere.center;
+        let nearest = Vec3 {
+            x: c.x.clamp(self.min.x, self.max.x),
+            y: c.y.clamp(self.min.y, self.max.y),
+            z: c.z.clamp(self.min.z, self.max.z),
+        };
+
+        (c - nearest).length() <= sphere.radius
+    }
+
+    pub fn corners(&self) -> [Vec3<f32>; 8] {
+        let (a, b) = (self.min, self.max);
+
+        [
+            Vec3 { x: a.x, y: a.y, z: a.z },
+            Vec3 { x: b.x, y: a.y, z: a.z },
+            Vec3 { x: a.x, y: b.y, z: a.z },
+            Vec3 { x: b.x, y: b.y, z: a.z },
+            Vec3 { x: a.x, y: a.y, z: b.z },
+            Vec3 { x: b.x, y: a.y, z: b.z },
+            Vec3 { x: a.x, y: b.y, z: b.z },
+            Vec3 { x: b.x, y: b.y, z: b.z },
+        ]
+    }
+
+    // The box around the transformed corners, which may be looser than the
+    // box around the transformed geometry
+    pub fn transform(&self, mat: &Matrix4x4<f32>) -> Aabb {
+        Aabb::from_points(self.corners().iter().map(|&c| mat.transform_point(c)))
+    }
+
+    pub fn bounding_sphere(&self) -> Sphere {
+        Sphere {
+            center: self.center(),
+            radius: self.size().length() / 2.0,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Sphere {
+    pub center: Vec3<f32>,
+    pub radius: f32,
+}
+
+impl Sphere {
+    // Ritter's approximation: start from two far apart points, then grow
+    // to swallow anything left outside. Within a few percent of optimal.
+    // Points with NaN or infinite coordinates are ignored.
+    pub fn from_points(points: &[Vec3<f32>]) -> Sphere {
+        let points: Vec<Vec3<f32>> = points.iter().cloned()
+            .filter(|p| p.x.is_finite() && p.y.is_finite() && p.z.is_finite())
+            .collect();
+        if points.is_empty() {
+            return Sphere { center: Vec3 { x: 0.0, y: 0.0, z: 0.0 }, radius: 0.0 };
+        }
+
+        let farthest = |from: Vec3<f32>| {
+            *points.iter().max_by(|a, b| {
+                (**a - from).length().total_cmp(&(**b - from).length())
+            }).unwrap()
+        };
+
+        let a = farthest(points[0]);
+        let b = farthest(a);
+
+        let mut center = (a + b) * 0.5;
+        let mut radius = (b - a).length() / 2.0;
+
+        for &p in points.iter() {
+            let dist = (p - center).length();
+
+            if dist > radius {
+                let new_radius = (radius + dist) / 2.0;
+                center = center + (p - center) * ((new_radius - radius) / dist);
+                radius = new_radius;
+            }
+        }
+
+        Sphere { center, radius }
+    }
+
+    // Assumes `mat` doesn't shear; non-uniform scale grows the radius by
+    // the largest axis
+    pub fn transform(&self, mat: &Matrix4x4<f32>) -> Sphere {
+        let scale = mat.decompose().scale;
+        let max_scale = scale.x.abs().max(scale.y.abs()).max(scale.z.abs());
+
+        Sphere {
+            center: mat.transform_point(self.center),
+            radius: self.radius * max_scale,
+        }
+    }
+}
+
+impl Obj {
+    pub fn positions(&self) -> Vec<Vec3<f32>> {
+        (1..self.vert_count() + 1).map(|i| self.vert(i)).collect()
+    }
+
+    pub fn bounding_box(&self) -> Aabb {
+        Aabb::from_points(self.positions())
+    }
+
+    pub fn bounding_sphere(&self) -> Sphere {
+        Sphere::from_points(&self.positions())
+    }
+
+    // Centers the mesh on the origin and scales it uniformly so it fits in
+    // [-1, 1] on every axis
+    pub fn unit_box_matrix(&self) -> Matrix4x4<f32> {
+        let bounds = self.bounding_box();
+        if bounds.is_empty() {
+            return Matrix4x4::identity();
+        }
+
+        let size = bounds.size();
+        let largest = size.x.max(size.y).max(size.z);
+        let scale = if largest > 0.0 { 2.0 / largest } else { 1.0 };
+
+        Matrix4x4::scale(Vec3 { x: scale, y: scale, z: scale }) *
+            Matrix4x4::translation(bounds.center() * -1.0)
+    }
+
+    pub fn normalize_to_unit_box(&mut self) {
+        let mat = self.unit_box_matrix();
+        self.transform(&mat);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sphere_tolerates_nan_points() {
+        let points = [
+            Vec3 { x: 0.0, y: 0.0, z: 0.0 },
+            Vec3 { x: f32::NAN, y: 0.0, z: 0.0 },
+            Vec3 { x: 2.0, y: 0.0, z: 0.0 },
+        ];
+
+        let sphere = Sphere::from_points(&points);
+        assert!(sphere.center.x.is_finite() && sphere.radius.is_finite());
+        for p in [points[0], points[2]] {
+            assert!((p - sphere.center).length() <= sphere.radius + 1e-5);
+        }
+    }
+}
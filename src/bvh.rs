@@ -0,0 +1,432 @@
+use std::f32;
+use vec::Vec3;
+use matrix::Matrix4x4;
+use bounds::Aabb;
+use culling::{Frustum, Visibility};
+use obj::Obj;
+
+#[derive(Clone, Copy)]
+pub struct Ray {
+    pub origin: Vec3<f32>,
+    pub dir: Vec3<f32>,
+}
+
+impl Ray {
+    pub fn at(&self, t: f32) -> Vec3<f32> {
+        self.origin + self.dir * t
+    }
+
+    // The ray from `eye` through pixel (x, y), given the full
+    // viewport * projection * view matrix used to draw
+    pub fn through_pixel(eye: Vec3<f32>, x: f32, y: f32, mat: &Matrix4x4<f32>) -> Option<Ray> {
+        let inverse = mat.inverse()?;
+        let p = inverse.transform_point(Vec3 { x, y, z: 0.0 });
+
+        Some(Ray {
+            origin: eye,
+            dir: (p - eye).norm(),
+        })
+    }
+
+    // Slab test; returns the entry distance if the box is hit before `max_t`.
+    // Empty boxes and boxes with NaN corners are never hit.
+    pub fn hits_aabb(&self, aabb: &Aabb, max_t: f32) -> Option<f32> {
+        let valid = aabb.min.x <= aabb.max.x && aabb.min.y <= aabb.max.y && aabb.min.z <= aabb.max.z;
+        if !valid {
+            return None;
+        }
+
+        let mut t0: f32 = 0.0;
+        let mut t1 = max_t;
+
+        let axes = [
+            (self.origin.x, self.dir.x, aabb.min.x, aabb.max.x),
+            (self.origin.y, self.dir.y, aabb.min.y, aabb.max.y),
+            (self.origin.z, self.dir.z, aabb.min.z, aabb.max.z),
+        ];
+
+        for &(o, d, min, max) in axes.iter() {
+            let inv = 1.0 / d;
+            let (near, far) = if inv >= 0.0 {
+                ((min - o) * inv, (max - o) * inv)
+            } else {
+                ((max - o) * inv, (min - o) * inv)
+            };
+
+            t0 = t0.max(near);
+            t1 = t1.min(far);
+            if t0 > t1 {
+                return None;
+            }
+        }
+
+        Some(t0)
+    }
+
+    // Möller-Trumbore; returns (t, barycentric) on a hit
+    pub fn hits_triangle(&self, v0: Vec3<f32>, v1: Vec3<f32>, v2: Vec3<f32>) -> Option<(f32, Vec3<f32>)> {
+        let e1 = v1 - v0;
+        let e2 = v2 - v0;
+        let p = self.dir.cross(e2);
+        let det = e1.dot(p);
+
+        if det.abs() < 1e-8 {
+            return None;
+        }
+
+        let inv_det = 1.0 / det;
+        let s = self.origin - v0;
+        let u = s.dot(p) * inv_det;
+        if !(0.0..=1.0).contains(&u) {
+            return None;
+        }
+
+        let q = s.cross(e1);
+        let v = self.dir.dot(q) * inv_det;
+        if v < 0.0 || u + v > 1.0 {
+            return None;
+        }
+
+        let t = e2.dot(q) * inv_det;
+        if t <= 0.0 {
+            return None;
+        }
+
+        Some((t, Vec3 { x: 1.0 - u - v, y: u, z: v }))
+    }
+}
+
+enum BvhNode {
+    Leaf { bounds: Aabb, first: usize, count: usize },
+    Interior { bounds: Aabb, left: usize, right: usize },
+}
+
+impl BvhNode {
+    fn bounds(&self) -> &Aabb {
+        match *self {
+            BvhNode::Leaf { ref bounds, .. } | BvhNode::Interior { ref bounds, .. } => bounds,
+        }
+    }
+}
+
+const LEAF_SIZE: usize = 4;
+
+// A hierarchy over anything with a bounding box; queries hand back indices
+// into the slice it was built from
+pub struct Bvh {
+    nodes: Vec<BvhNode>,
+    // Primitive indices, reordered so every leaf covers a contiguous run
+    order: Vec<usize>,
+    prim_bounds: Vec<Aabb>,
+}
+
+impl Bvh {
+    pub fn build(bounds: &[Aabb]) -> Bvh {
+        let mut bvh = Bvh {
+            nodes: Vec::new(),
+            order: (0..bounds.len()).collect(),
+            prim_bounds: bounds.to_vec(),
+        };
+
+        if !bounds.is_empty() {
+            bvh.build_node(bounds, 0, bounds.len());
+        }
+
+        bvh
+    }
+
+    // Median split along the longest axis of the primitive centers
+    fn build_node(&mut self, bounds: &[Aabb], first: usize, count: usize) -> usize {
+        let node_bounds = self.order[first..first + count].iter()
+            .fold(Aabb::empty(), |b, &i| b.union(&bounds[i]));
+        let index = self.nodes.len();
+
+        if count <= LEAF_SIZE {
+            self.nodes.push(BvhNode::Leaf { bounds: node_bounds, first, count });
+            return index;
+        }
+
+        let centers = Aabb::from_points(self.order[first..first + count].iter().map(|&i| bounds[i].center()));
+        let size = centers.size();
+        let axis = |b: &Aabb| {
+            let c = b.center();
+            if size.x >= size.y && size.x >= size.z { c.x } else if size.y >= size.z { c.y } else { c.z }
+        };
+
+        self.order[first..first + count].sort_by(|&a, &b| axis(&bounds[a]).total_cmp(&axis(&bounds[b])));
+
+        // Reserve our slot before the children take theirs
+        self.nodes.push(BvhNode::Leaf { bounds: node_bounds, first, count });
+
+        let half = count / 2;
+        let left = self.build_node(bounds, first, half);
+        let right = self.build_node(bounds, first + half, count - half);
+
+        self.nodes[index] = BvhNode::Interior { bounds: node_bounds, left, right };
+        index
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        self.nodes.first().map_or(Aabb::empty(), |n| *n.bounds())
+    }
+
+    // Everything whose bounds might be inside the frustum. Subtrees that are
+    // entirely inside are taken without testing their children.
+    pub fn query_frustum(&self, frustum: &Frustum) -> Vec<usize> {
+        let mut out = Vec::new();
+        let mut stack = Vec::new();
+
+        if !self.nodes.is_empty() {
+            stack.push((0, false));
+        }
+
+        while let Some((index, inside)) = stack.pop() {
+            let node = &self.nodes[index];
+            let visibility = if inside { Visibility::Inside } else { frustum.test_aabb(node.bounds()) };
+
+            if visibility == Visibility::Outside {
+                continue;
+            }
+
+            match *node {
+                BvhNode::Leaf { first, count, .. } => {
+                    out.extend(self.order[first..first + count].iter()
+                               .filter(|&&i| inside || frustum.is_aabb_visible(&self.prim_bounds[i])));
+                }
+                BvhNode::Interior { left, right, .. } => {
+                    let inside = visibility == Visibility::Inside;
+                    stack.push((left, inside));
+                    stack.push((right, inside));
+                }
+            }
+        }
+
+        out
+    }
+
+    // Finds the closest hit. `intersect` tests one primitive and returns
+    // its hit distance along the ray.
+    pub fn query_ray<F>(&self, ray: &Ray, mut intersect: F) -> Option<(usize, f32)>
+            where F: FnMut(usize) -> Option<f32> {
+        let mut best: Option<(usize, f32)> = None;
+        let mut stack = Vec::new();
+
+        if !self.nodes.is_empty() {
+            stack.push(0);
+        }
+
+        while let Some(index) = stack.pop() {
+            let max_t = best.map_or(f32::INFINITY, |(_, t)| t);
+            let node = &self.nodes[index];
+
+            if ray.hits_aabb(node.bounds(), max_t).is_none() {
+                continue;
+            }
+
+            match *node {
+                BvhNode::Leaf { first, count, .. } => {
+                    for &prim in self.order[first..first + count].iter() {
+                        if let Some(t) = intersect(prim) {
+                            if t < best.map_or(f32::INFINITY, |(_, t)| t) {
+                                best = Some((prim, t));
+                            }
+                        }
+                    }
+                }
+                BvhNode::Interior { left, right, .. } => {
+                    stack.push(left);
+                    stack.push(right);
+                }
+            }
+        }
+
+        best
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Hit {
+    pub mesh: usize,
+    pub face: usize,
+    pub t: f32,
+    pub point: Vec3<f32>,
+    pub bary: Vec3<f32>,
+}
+
+struct SceneTriangle {
+    mesh: usize,
+    face: usize,
+    verts: [Vec3<f32>; 3],
+}
+
+// Two levels over a set of placed meshes: one over whole objects for
+// culling, one over every triangle in world space for ray casts and picking
+pub struct SceneBvh {
+    pub objects: Bvh,
+    pub object_bounds: Vec<Aabb>,
+    triangles: Vec<SceneTriangle>,
+    triangle_bvh: Bvh,
+}
+
+impl SceneBvh {
+    pub fn build(meshes: &[(&Obj, Matrix4x4<f32>)]) -> SceneBvh {
+        let mut triangles = Vec::new();
+        let mut object_bounds = Vec::new();
+
+        for (m, &(obj, ref model)) in meshes.iter().enumerate() {
+            let world: Vec<Vec3<f32>> = obj.positions().iter().map(|&p| model.transform_point(p)).collect();
+            object_bounds.push(Aabb::from_points(world.iter().cloned()));
+
+            for (f, face) in obj.faces().iter().enumerate() {
+                triangles.push(SceneTriangle {
+                    mesh: m,
+                    face: f,
+                    verts: [world[face.0.vindex - 1], world[face.1.vindex - 1], world[face.2.vindex - 1]],
+                });
+            }
+        }
+
+        let triangle_bounds: Vec<Aabb> = triangles.iter()
+            .map(|t| Aabb::from_points(t.verts.iter().cloned()))
+            .collect();
+
+        SceneBvh {
+            objects: Bvh::build(&object_bounds),
+            object_bounds,
+            triangle_bvh: Bvh::build(&triangle_bounds),
+            triangles,
+        }
+    }
+
+    // Indices of the meshes that might be visible; `frustum` should be
+    // built from projection * view, without any model matrix
+    pub fn visible_meshes(&self, frustum: &Frustum) -> Vec<usize> {
+        let mut visible = self.objects.query_frustum(frustum);
+        visible.sort();
+        visible
+    }
+
+    pub fn raycast(&self, ray: &Ray) -> Option<Hit> {
+        let triangles = &self.triangles;
+
+        self.triangle_bvh.query_ray(ray, |i| {
+            let v = &triangles[i].verts;
+            ray.hits_triangle(v[0], v[1], v[2]).map(|(t, _)| t)
+        }).map(|(i, t)| {
+            let tri = &triangles[i];
+            let bary = ray.hits_triangle(tri.verts[0], tri.verts[1], tri.verts[2]).unwrap().1;
+
+            Hit {
+                mesh: tri.mesh,
+                face: tri.face,
+                t,
+                point: ray.at(t),
+                bary,
+            }
+        })
+    }
+
+    // What's under pixel (x, y) when drawn with `mat` from `eye`
+    pub fn pick(&self, eye: Vec3<f32>, x: f32, y: f32, mat: &Matrix4x4<f32>) -> Option<Hit> {
+        Ray::through_pixel(eye, x, y, mat).and_then(|ray| self.raycast(&ray))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use bounds::Sphere;
+    use camera::Camera;
+
+    #[test]
+    fn builds_with_nan_bounds() {
+        // NaN on every axis, so the split hits it whichever axis it picks
+        let point = |x: f32| Vec3 { x, y: x, z: x };
+        let bounds: Vec<Aabb> = [0.0, f32::NAN, 2.0, 1.0, 5.0].iter()
+            .map(|&x| Aabb { min: point(x), max: point(x + 0.5) })
+            .collect();
+
+        let bvh = Bvh::build(&bounds);
+
+        // Every primitive ends up in exactly one leaf
+        let mut reachable = Vec::new();
+        let mut stack = vec![0];
+        while let Some(index) = stack.pop() {
+            match bvh.nodes[index] {
+                BvhNode::Leaf { first, count, .. } => reachable.extend_from_slice(&bvh.order[first..first + count]),
+                BvhNode::Interior { left, right, .. } => stack.extend_from_slice(&[left, right]),
+            }
+        }
+        reachable.sort();
+        assert_eq!(reachable, vec![0, 1, 2, 3, 4]);
+
+        // Along the diagonal, through every finite box
+        let ray = Ray { origin: point(-10.0), dir: point(1.0).norm() };
+        let hit = bvh.query_ray(&ray, |i| ray.hits_aabb(&bounds[i], f32::INFINITY));
+        assert_eq!(hit.map(|(i, _)| i), Some(0));
+
+        let ray = Ray { origin: Vec3 { x: 5.25, y: 5.25, z: -10.0 }, dir: Vec3 { x: 0.0, y: 0.0, z: 1.0 } };
+        let hit = bvh.query_ray(&ray, |i| ray.hits_aabb(&bounds[i], f32::INFINITY));
+        assert_eq!(hit.map(|(i, _)| i), Some(4));
+    }
+
+    fn camera() -> Camera {
+        let sphere = Sphere { center: Vec3 { x: 0.0, y: 0.0, z: 0.0 }, radius: 1.0 };
+        Camera::framing(&sphere, 0.8, Vec3 { x: 0.0, y: 0.0, z: 1.0 }, Vec3 { x: 0.0, y: 1.0, z: 0.0 })
+    }
+
+    // A square facing the camera, two units wide
+    fn quad() -> Obj {
+        let text = "v -1 -1 0\nv 1 -1 0\nv 1 1 0\nv -1 1 0\nf 1 2 3\nf 1 3 4\n";
+        Obj::from_reader(Cursor::new(text)).unwrap()
+    }
+
+    #[test]
+    fn rays_go_through_pixels() {
+        let camera = camera();
+        let mat = Matrix4x4::viewport(0.0, 0.0, 100.0, 100.0, 255.0) * camera.projection() * camera.view();
+
+        let ray = Ray::through_pixel(camera.eye, 50.0, 50.0, &mat).unwrap();
+        assert!((ray.dir.z + 1.0).abs() < 1e-5);
+
+        // Right of center on screen is +x in the world
+        let ray = Ray::through_pixel(camera.eye, 90.0, 50.0, &mat).unwrap();
+        assert!(ray.dir.x > 0.0 && ray.dir.y.abs() < 1e-5);
+
+        assert!(Ray::through_pixel(camera.eye, 0.0, 0.0, &Matrix4x4::new()).is_none());
+    }
+
+    #[test]
+    fn picks_what_is_under_a_pixel() {
+        let camera = camera();
+        let mat = Matrix4x4::viewport(0.0, 0.0, 100.0, 100.0, 255.0) * camera.projection() * camera.view();
+        let obj = quad();
+        let moved = Matrix4x4::translation(Vec3 { x: 0.0, y: 0.0, z: -3.0 });
+        let scene = SceneBvh::build(&[(&obj, moved), (&obj, Matrix4x4::identity())]);
+
+        // The nearer copy hides the one behind it
+        let hit = scene.pick(camera.eye, 60.0, 55.0, &mat).unwrap();
+        assert_eq!(hit.mesh, 1);
+        assert!(hit.point.z.abs() < 1e-4);
+        assert!((hit.t - camera.distance()).abs() < 0.1);
+        assert!((hit.bary.x + hit.bary.y + hit.bary.z - 1.0).abs() < 1e-5);
+
+        // Lower right is the first triangle, upper left the second
+        assert_eq!(scene.pick(camera.eye, 60.0, 45.0, &mat).unwrap().face, 0);
+        assert_eq!(scene.pick(camera.eye, 40.0, 55.0, &mat).unwrap().face, 1);
+
+        assert!(scene.pick(camera.eye, 1.0, 1.0, &mat).is_none());
+    }
+
+    #[test]
+    fn culls_meshes_outside_the_frustum() {
+        let camera = camera();
+        let frustum = Frustum::from_matrix(&(camera.projection() * camera.view()));
+        let obj = quad();
+        let at = |x: f32, z: f32| Matrix4x4::translation(Vec3 { x, y: 0.0, z });
+
+        let scene = SceneBvh::build(&[(&obj, at(0.0, 0.0)), (&obj, at(100.0, 0.0)), (&obj, at(0.0, 50.0)), (&obj, at(0.5, -1.0))]);
+        assert_eq!(scene.visible_meshes(&frustum), vec![0, 3]);
+    }
+}
@@ -0,0 +1,78 @@
+use vec::Vec3;
+use matrix::Matrix4x4;
+use bounds::Sphere;
+
+pub struct Camera {
+    pub eye: Vec3<f32>,
+    pub center: Vec3<f32>,
+    pub up: Vec3<f32>,
+    // Vertical field of view, in radians
+    pub fov: f32,
+}
+
+impl Camera {
+    // Backs the camera away from the sphere along `direction` until the
+    // whole sphere fits in the field of view
+    pub fn framing(sphere: &Sphere, fov: f32, direction: Vec3<f32>, up: Vec3<f32>) -> Camera {
+        let distance = sphere.radius / (fov / 2.0).sin();
+
+        Camera {
+            eye: sphere.center + direction.norm() * distance,
+            center: sphere.center,
+            up,
+            fov,
+        }
+    }
+
+    pub fn distance(&self) -> f32 {
+        (self.eye - self.center).length()
+    }
+
+    pub fn view(&self) -> Matrix4x4<f32> {
+        Matrix4x4::lookat(self.eye, self.center, self.up)
+    }
+
+    // Matrix4x4::perspective only knows about the eye distance, and always
+    // maps [-1, 1] on the center plane to the edges of the viewport. Scaling
+    // first makes the edges line up with the field of view instead.
+    pub fn projection(&self) -> Matrix4x4<f32> {
+        let distance = self.distance();
+        let scale = 1.0 / (distance * (self.fov / 2.0).tan());
+
+        Matrix4x4::perspective(distance * scale) * Matrix4x4::scale(Vec3 { x: scale, y: scale, z: scale })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vec::Vec4;
+
+    #[test]
+    fn framed_sphere_projects_inside_the_viewport() {
+        let sphere = Sphere { center: Vec3 { x: 3.0, y: -2.0, z: 5.0 }, radius: 4.0 };
+        let up = Vec3 { x: 0.0, y: 1.0, z: 0.0 };
+        let camera = Camera::framing(&sphere, 0.6, Vec3 { x: 1.0, y: 1.0, z: 3.0 }, up);
+        assert!((camera.distance() - 4.0 / 0.3f32.sin()).abs() < 1e-3);
+
+        let mat = Matrix4x4::viewport(0.0, 0.0, 800.0, 600.0, 255.0) * camera.projection() * camera.view();
+        let project = |p: Vec3<f32>| {
+            let clip = &mat * &Vec4 { x: p.x, y: p.y, z: p.z, w: 1.0 };
+            (clip.x / clip.w, clip.y / clip.w)
+        };
+
+        let (cx, cy) = project(sphere.center);
+        assert!((cx - 400.0).abs() < 1e-2 && (cy - 300.0).abs() < 1e-2);
+
+        // Points all over the sphere's surface
+        for i in 0..64 {
+            let theta = i as f32 * 0.7;
+            let z = i as f32 / 32.0 - 1.0;
+            let r = (1.0 - z * z).sqrt();
+            let dir = Vec3 { x: r * theta.cos(), y: r * theta.sin(), z };
+
+            let (x, y) = project(sphere.center + dir * sphere.radius);
+            assert!((0.0..=800.0).contains(&x) && (0.0..=600.0).contains(&y), "{} {}", x, y);
+        }
+    }
+}
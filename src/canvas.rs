@@ -0,0 +1,409 @@
+use std::cmp;
+use vec::Vec2;
+use image::{Image, Color};
+use lines::thick_line;
+
+// 2D drawing for annotating renders. Coordinates are the same y-up pixel
+// coordinates as set_pixel, but signed so shapes can hang off the edges.
+
+pub const GLYPH_WIDTH: usize = 5;
+pub const GLYPH_HEIGHT: usize = 8;
+// Distance between the starts of characters and of lines, before scaling
+pub const GLYPH_ADVANCE: usize = 6;
+pub const LINE_ADVANCE: usize = 10;
+
+// The classic 5x7 ASCII font from ' ' to '~'. Each byte is a column with
+// bit 0 at the top; bit 7 is only used by descenders.
+const FONT: [[u8; GLYPH_WIDTH]; 95] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00], // space
+    [0x00, 0x00, 0x5F, 0x00, 0x00], // !
+    [0x00, 0x07, 0x00, 0x07, 0x00], // "
+    [0x14, 0x7F, 0x14, 0x7F, 0x14], // #
+    [0x24, 0x2A, 0x7F, 0x2A, 0x12], // $
+    [0x23, 0x13, 0x08, 0x64, 0x62], // %
+    [0x36, 0x49, 0x56, 0x20, 0x50], // &
+    [0x00, 0x00, 0x07, 0x00, 0x00], // '
+    [0x00, 0x1C, 0x22, 0x41, 0x00], // (
+    [0x00, 0x41, 0x22, 0x1C, 0x00], // )
+    [0x2A, 0x1C, 0x7F, 0x1C, 0x2A], // *
+    [0x08, 0x08, 0x3E, 0x08, 0x08], // +
+    [0x00, 0x80, 0x70, 0x30, 0x00], // ,
+    [0x08, 0x08, 0x08, 0x08, 0x08], // -
+    [0x00, 0x00, 0x60, 0x60, 0x00], // .
+    [0x20, 0x10, 0x08, 0x04, 0x02], // /
+    [0x3E, 0x51, 0x49, 0x45, 0x3E], // 0
+    [0x00, 0x42, 0x7F, 0x40, 0x00], // 1
+    [0x72, 0x49, 0x49, 0x49, 0x46], // 2
+    [0x21, 0x41, 0x49, 0x4D, 0x33], // 3
+    [0x18, 0x14, 0x12, 0x7F, 0x10], // 4
+    [0x27, 0x45, 0x45, 0x45, 0x39], // 5
+    [0x3C, 0x4A, 0x49, 0x49, 0x31], // 6
+    [0x41, 0x21, 0x11, 0x09, 0x07], // 7
+    [0x36, 0x49, 0x49, 0x49, 0x36], // 8
+    [0x46, 0x49, 0x49, 0x29, 0x1E], // 9
+    [0x00, 0x00, 0x14, 0x00, 0x00], // :
+    [0x00, 0x40, 0x34, 0x00, 0x00], // ;
+    [0x00, 0x08, 0x14, 0x22, 0x41], // <
+    [0x14, 0x14, 0x14, 0x14, 0x14], // =
+    [0x00, 0x41, 0x22, 0x14, 0x08], // >
+    [0x02, 0x01, 0x59, 0x09, 0x06], // ?
+    [0x3E, 0x41, 0x5D, 0x59, 0x4E], // @
+    [0x7C, 0x12, 0x11, 0x12, 0x7C], // A
+    [0x7F, 0x49, 0x49, 0x49, 0x36], // B
+    [0x3E, 0x41, 0x41, 0x41, 0x22], // C
+    [0x7F, 0x41, 0x41, 0x41, 0x3E], // D
+    [0x7F, 0x49, 0x49, 0x49, 0x41], // E
+    [0x7F, 0x09, 0x09, 0x09, 0x01], // F
+    [0x3E, 0x41, 0x41, 0x51, 0x73], // G
+    [0x7F, 0x08, 0x08, 0x08, 0x7F], // H
+    [0x00, 0x41, 0x7F, 0x41, 0x00], // I
+    [0x20, 0x40, 0x41, 0x3F, 0x01], // J
+    [0x7F, 0x08, 0x14, 0x22, 0x41], // K
+    [0x7F, 0x40, 0x40, 0x40, 0x40], // L
+    [0x7F, 0x02, 0x1C, 0x02, 0x7F], // M
+    [0x7F, 0x04, 0x08, 0x10, 0x7F], // N
+    [0x3E, 0x41, 0x41, 0x41, 0x3E], // O
+    [0x7F, 0x09, 0x09, 0x09, 0x06], // P
+    [0x3E, 0x41, 0x51, 0x21, 0x5E], // Q
+    [0x7F, 0x09, 0x19, 0x29, 0x46], // R
+    [0x26, 0x49, 0x49, 0x49, 0x32], // S
+    [0x03, 0x01, 0x7F, 0x01, 0x03], // T
+    [0x3F, 0x40, 0x40, 0x40, 0x3F], // U
+    [0x1F, 0x20, 0x40, 0x20, 0x1F], // V
+    [0x3F, 0x40, 0x38, 0x40, 0x3F], // W
+    [0x63, 0x14, 0x08, 0x14, 0x63], // X
+    [0x03, 0x04, 0x78, 0x04, 0x03], // Y
+    [0x61, 0x59, 0x49, 0x4D, 0x43], // Z
+    [0x00, 0x7F, 0x41, 0x41, 0x41], // [
+    [0x02, 0x04, 0x08, 0x10, 0x20], // \
+    [0x00, 0x41, 0x41, 0x41, 0x7F], // ]
+    [0x04, 0x02, 0x01, 0x02, 0x04], // ^
+    [0x40, 0x40, 0x40, 0x40, 0x40], // _
+    [0x00, 0x01, 0x02, 0x00, 0x00], // `
+    [0x20, 0x54, 0x54, 0x78, 0x40], // a
+    [0x7F, 0x28, 0x44, 0x44, 0x38], // b
+    [0x38, 0x44, 0x44, 0x44, 0x28], // c
+    [0x38, 0x44, 0x44, 0x28, 0x7F], // d
+    [0x38, 0x54, 0x54, 0x54, 0x18], // e
+    [0x00, 0x08, 0x7E, 0x09, 0x02], // f
+    [0x18, 0xA4, 0xA4, 0xA4, 0x7C], // g
+    [0x7F, 0x08, 0x04, 0x04, 0x78], // h
+    [0x00, 0x44, 0x7D, 0x40, 0x00], // i
+    [0x20, 0x40, 0x40, 0x3D, 0x00], // j
+    [0x7F, 0x10, 0x28, 0x44, 0x00], // k
+    [0x00, 0x41, 0x7F, 0x40, 0x00], // l
+    [0x7C, 0x04, 0x78, 0x04, 0x78], // m
+    [0x7C, 0x08, 0x04, 0x04, 0x78], // n
+    [0x38, 0x44, 0x44, 0x44, 0x38], // o
+    [0xFC, 0x24, 0x24, 0x24, 0x18], // p
+    [0x18, 0x24, 0x24, 0x24, 0xFC], // q
+    [0x7C, 0x08, 0x04, 0x04, 0x08], // r
+    [0x48, 0x54, 0x54, 0x54, 0x24], // s
+    [0x04, 0x04, 0x3F, 0x44, 0x24], // t
+    [0x3C, 0x40, 0x40, 0x20, 0x7C], // u
+    [0x1C, 0x20, 0x40, 0x20, 0x1C], // v
+    [0x3C, 0x40, 0x30, 0x40, 0x3C], // w
+    [0x44, 0x28, 0x10, 0x28, 0x44], // x
+    [0x1C, 0xA0, 0xA0, 0xA0, 0x7C], // y
+    [0x44, 0x64, 0x54, 0x4C, 0x44], // z
+    [0x00, 0x08, 0x36, 0x41, 0x00], // {
+    [0x00, 0x00, 0x77, 0x00, 0x00], // |
+    [0x00, 0x41, 0x36, 0x08, 0x00], // }
+    [0x02, 0x01, 0x02, 0x04, 0x02], // ~
+];
+
+fn glyph(c: char) -> &'static [u8; GLYPH_WIDTH] {
+    let code = c as usize;
+
+    if (32..127).contains(&code) {
+        &FONT[code - 32]
+    } else {
+        &FONT['?' as usize - 32]
+    }
+}
+
+impl Image {
+    fn plot(&mut self, x: isize, y: isize, color: &Color, alpha: f32) {
+        if x >= 0 && y >= 0 {
+            self.blend_pixel(x as usize, y as usize, color, alpha);
+        }
+    }
+
+    // Clamps a span of pixels to the image
+    fn clip(start: isize, len: isize, size: usize) -> (usize, usize) {
+        let from = cmp::max(start, 0) as usize;
+        let to = cmp::min(start + len, size as isize);
+
+        (from, cmp::max(to, 0) as usize)
+    }
+
+    // (x, y) is the bottom left corner
+    pub fn fill_rect(&mut self, x: isize, y: isize, width: isize, height: isize, color: &Color) {
+        let (x0, x1) = Image::clip(x, width, self.width);
+        let (y0, y1) = Image::clip(y, height, self.height);
+
+        for py in y0..y1 {
+            for px in x0..x1 {
+                self.set_pixel(px, py, color);
+            }
+        }
+    }
+
+    // The outline is drawn inside the rectangle
+    pub fn stroke_rect(&mut self, x: isize, y: isize, width: isize, height: isize, thickness: isize, color: &Color) {
+        let t = cmp::min(thickness, cmp::min(width, height) / 2 + 1);
+
+        self.fill_rect(x, y, width, t, color);
+        self.fill_rect(x, y + height - t, width, t, color);
+        self.fill_rect(x, y + t, t, height - 2 * t, color);
+        self.fill_rect(x + width - t, y + t, t, height - 2 * t, color);
+    }
+
+    // Runs `coverage` over every pixel in the box and blends in the ones it
+    // says are (partly) inside the shape
+    fn fill_coverage<F: Fn(f32, f32) -> f32>(&mut self, min: Vec2<f32>, max: Vec2<f32>, color: &Color, coverage: F) {
+        let (min_x, max_x) = self.clamp_x(min.x.floor() as isize, max.x.ceil() as isize);
+        let (min_y, max_y) = self.clamp_y(min.y.floor() as isize, max.y.ceil() as isize);
+
+        for y in min_y..max_y + 1 {
+            for x in min_x..max_x + 1 {
+                let c = coverage(x as f32, y as f32);
+
+                if c > 0.0 {
+                    self.plot(x, y, color, c);
+                }
+            }
+        }
+    }
+
+    // Clamps an inclusive range of columns or rows to the image, empty if
+    // it's entirely off it
+    fn clamp_x(&self, min: isize, max: isize) -> (isize, isize) {
+        (cmp::max(min, 0), cmp::min(max, self.width as isize - 1))
+    }
+
+    fn clamp_y(&self, min: isize, max: isize) -> (isize, isize) {
+        (cmp::max(min, 0), cmp::min(max, self.height as isize - 1))
+    }
+
+    // Circles are anti-aliased
+    pub fn fill_circle(&mut self, center: Vec2<f32>, radius: f32, color: &Color) {
+        let pad = radius + 1.0;
+        let min = Vec2 { x: center.x - pad, y: center.y - pad };
+        let max = Vec2 { x: center.x + pad, y: center.y + pad };
+
+        self.fill_coverage(min, max, color, |x, y| {
+            let d = ((x - center.x).powi(2) + (y - center.y).powi(2)).sqrt();
+            (radius + 0.5 - d).clamp(0.0, 1.0)
+        });
+    }
+
+    pub fn stroke_circle(&mut self, center: Vec2<f32>, radius: f32, width: f32, color: &Color) {
+        let pad = radius + width / 2.0 + 1.0;
+        let min = Vec2 { x: center.x - pad, y: center.y - pad };
+        let max = Vec2 { x: center.x + pad, y: center.y + pad };
+
+        self.fill_coverage(min, max, color, |x, y| {
+            let d = ((x - center.x).powi(2) + (y - center.y).powi(2)).sqrt();
+            (width / 2.0 + 0.5 - (d - radius).abs()).clamp(0.0, 1.0)
+        });
+    }
+
+    // Scanline fill with the even-odd rule, so self-intersecting polygons
+    // get holes
+    pub fn fill_polygon(&mut self, points: &[Vec2<f32>], color: &Color) {
+        if points.len() < 3 {
+            return;
+        }
+
+        let min_y = points.iter().fold(f32::INFINITY, |m, p| m.min(p.y)).ceil() as isize;
+        let max_y = points.iter().fold(f32::NEG_INFINITY, |m, p| m.max(p.y)).floor() as isize;
+        let mut crossings = Vec::new();
+
+        for y in cmp::max(min_y, 0)..cmp::min(max_y + 1, self.height as isize) {
+            let fy = y as f32;
+            crossings.clear();
+
+            for (i, a) in points.iter().enumerate() {
+                let b = points[(i + 1) % points.len()];
+
+                // Half-open so a vertex exactly on the scanline counts once
+                if (a.y <= fy && fy < b.y) || (b.y <= fy && fy < a.y) {
+                    crossings.push(a.x + (fy - a.y) / (b.y - a.y) * (b.x - a.x));
+                }
+            }
+
+            crossings.sort_by(|a, b| a.total_cmp(b));
+            for span in crossings.chunks(2) {
+                if let [from, to] = *span {
+                    let x0 = from.ceil() as isize;
+                    self.fill_rect(x0, y, to.ceil() as isize - x0, 1, color);
+                }
+            }
+        }
+    }
+
+    // Anti-aliased, with round joins and caps. Each pixel takes its best
+    // coverage from any segment, so joins don't get blended twice.
+    pub fn stroke_path(&mut self, points: &[Vec2<f32>], closed: bool, width: f32, color: &Color) {
+        if points.is_empty() {
+            return;
+        }
+
+        let pad = width / 2.0 + 2.0;
+        let min_x = (points.iter().fold(f32::INFINITY, |m, p| m.min(p.x)) - pad).floor() as isize;
+        let min_y = (points.iter().fold(f32::INFINITY, |m, p| m.min(p.y)) - pad).floor() as isize;
+        let max_x = (points.iter().fold(f32::NEG_INFINITY, |m, p| m.max(p.x)) + pad).ceil() as isize;
+        let max_y = (points.iter().fold(f32::NEG_INFINITY, |m, p| m.max(p.y)) + pad).ceil() as isize;
+
+        // Only what lands on the image needs coverage
+        let (min_x, max_x) = self.clamp_x(min_x, max_x);
+        let (min_y, max_y) = self.clamp_y(min_y, max_y);
+        if min_x > max_x || min_y > max_y {
+            return;
+        }
+
+        let stride = (max_x - min_x + 1) as usize;
+        let mut coverage = vec![0.0f32; stride * (max_y - min_y + 1) as usize];
+
+        let segments = if closed { points.len() } else { points.len().saturating_sub(1) };
+        for i in 0..cmp::max(segments, 1) {
+            let (a, b) = (points[i], points[(i + 1) % points.len()]);
+
+            thick_line(a, b, width, true, (self.width, self.height), |x, y, c, _| {
+                let index = (y - min_y) as usize * stride + (x - min_x) as usize;
+                if c > coverage[index] {
+                    coverage[index] = c;
+                }
+            });
+        }
+
+        for (i, &c) in coverage.iter().enumerate() {
+            if c > 0.0 {
+                self.plot(min_x + (i % stride) as isize, min_y + (i / stride) as isize, color, c);
+            }
+        }
+    }
+
+    pub fn stroke_polygon(&mut self, points: &[Vec2<f32>], width: f32, color: &Color) {
+        self.stroke_path(points, true, width, color);
+    }
+
+    pub fn draw_thick_line(&mut self, from: Vec2<f32>, to: Vec2<f32>, width: f32, color: &Color) {
+        self.stroke_path(&[from, to], false, width, color);
+    }
+
+    // Draws `src` with its bottom left corner at (x, y), mixed in by
+    // `opacity`
+    pub fn blit(&mut self, src: &Image, x: isize, y: isize, opacity: f32) {
+        for sy in 0..src.height {
+            for sx in 0..src.width {
+                self.plot(x + sx as isize, y + sy as isize, &src.get_pixel(sx, sy), opacity);
+            }
+        }
+    }
+
+    // Like blit, with one alpha byte per source pixel laid out like `data`
+    // (top row first)
+    pub fn blit_with_alpha(&mut self, src: &Image, alpha: &[u8], x: isize, y: isize) {
+        for sy in 0..src.height {
+            for sx in 0..src.width {
+                let a = alpha[(src.height - sy - 1) * src.width + sx];
+
+                if a > 0 {
+                    self.plot(x + sx as isize, y + sy as isize, &src.get_pixel(sx, sy), a as f32 / 255.0);
+                }
+            }
+        }
+    }
+
+    // Size in pixels of `text` drawn at `scale`; lines are split on '\n'
+    pub fn text_size(text: &str, scale: usize) -> (usize, usize) {
+        let lines = text.split('\n');
+        let longest = lines.clone().map(|line| line.chars().count()).max().unwrap_or(0);
+        let count = lines.count();
+
+        let width = if longest == 0 { 0 } else { (longest * GLYPH_ADVANCE - (GLYPH_ADVANCE - GLYPH_WIDTH)) * scale };
+        let height = ((count - 1) * LINE_ADVANCE + GLYPH_HEIGHT) * scale;
+
+        (width, height)
+    }
+
+    // Draws `text` in the built-in font with its top left corner at (x, y),
+    // each font pixel becoming a `scale` by `scale` block. Characters
+    // outside printable ASCII show up as '?'.
+    pub fn draw_text(&mut self, x: isize, y: isize, text: &str, color: &Color, scale: usize) {
+        let s = scale as isize;
+
+        for (row, line) in text.split('\n').enumerate() {
+            let top = y - (row * LINE_ADVANCE * scale) as isize;
+
+            for (i, c) in line.chars().enumerate() {
+                let left = x + (i * GLYPH_ADVANCE * scale) as isize;
+
+                for (col, bits) in glyph(c).iter().enumerate() {
+                    for bit in 0..GLYPH_HEIGHT {
+                        if bits & (1 << bit) != 0 {
+                            let px = left + col as isize * s;
+                            let py = top - (bit as isize + 1) * s + 1;
+                            self.fill_rect(px, py, s, s, color);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fill_polygon_tolerates_nan_points() {
+        let mut image = Image::new(8, 8);
+        let points = [
+            Vec2 { x: 1.0, y: 1.0 },
+            Vec2 { x: 6.0, y: 1.0 },
+            Vec2 { x: f32::NAN, y: 4.0 },
+            Vec2 { x: 1.0, y: 6.0 },
+        ];
+
+        image.fill_polygon(&points, &Color(255, 255, 255));
+
+        for y in 0..8 {
+            for x in 0..8 {
+                let inside = (1..7).contains(&x) && (1..7).contains(&y);
+                assert!(inside || image.get_pixel(x, y).0 == 0, "{} {}", x, y);
+            }
+        }
+
+        // A point with no usable y drops out of every scanline, leaving the
+        // rest of the polygon
+        let points = [
+            Vec2 { x: 1.0, y: 1.0 },
+            Vec2 { x: 6.0, y: 1.0 },
+            Vec2 { x: 6.0, y: 6.0 },
+            Vec2 { x: 3.0, y: f32::NAN },
+            Vec2 { x: 1.0, y: 6.0 },
+        ];
+        let mut image = Image::new(8, 8);
+        image.fill_polygon(&points, &Color(255, 255, 255));
+
+        assert_eq!(image.get_pixel(3, 3).0, 255);
+        assert_eq!(image.get_pixel(7, 3).0, 0);
+    }
+
+    #[test]
+    fn strokes_far_off_the_image_are_clipped() {
+        let mut image = Image::new(8, 8);
+        image.draw_thick_line(Vec2 { x: 0.0, y: 0.0 }, Vec2 { x: 1e5, y: 1e5 }, 2.0, &Color(255, 255, 255));
+
+        assert_eq!(image.get_pixel(4, 4).0, 255);
+        assert_eq!(image.get_pixel(7, 0).0, 0);
+
+        image.stroke_circle(Vec2 { x: 1e9, y: -1e9 }, 1e9, 2.0, &Color(255, 0, 0));
+        image.fill_circle(Vec2 { x: 4.0, y: 4.0 }, 1e6, &Color(0, 0, 255));
+        assert_eq!(image.get_pixel(0, 7).2, 255);
+    }
+}
@@ -0,0 +1,203 @@
+use vec::{Vec3, Vec4};
+use matrix::Matrix4x4;
+use bounds::{Aabb, Sphere};
+use mesh::IndexedMesh;
+
+// Points with normal.dot(p) + d >= 0 are on the inside
+#[derive(Clone, Copy)]
+pub struct Plane {
+    pub normal: Vec3<f32>,
+    pub d: f32,
+}
+
+impl Plane {
+    fn from_vec4(v: Vec4<f32>) -> Plane {
+        let length = v.xyz().length();
+
+        Plane {
+            normal: v.xyz() * (1.0 / length),
+            d: v.w / length,
+        }
+    }
+
+    pub fn distance(&self, p: Vec3<f32>) -> f32 {
+        self.normal.dot(p) + self.d
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Visibility {
+    Outside,
+    Intersecting,
+    Inside,
+}
+
+pub struct Frustum {
+    pub planes: Vec<Plane>,
+}
+
+impl Frustum {
+    // Extracts the planes (Gribb & Hartmann) from a projection * view (*
+    // model) matrix, i.e. everything before the viewport. The perspective
+    // here has no far plane, so the frustum is open-ended: the four sides
+    // plus the plane through the eye.
+    pub fn from_matrix(mat: &Matrix4x4<f32>) -> Frustum {
+        let (r0, r1, r3) = (mat.row(0), mat.row(1), mat.row(3));
+
+        Frustum {
+            planes: vec![
+                Plane::from_vec4(r3 + r0),
+                Plane::from_vec4(r3 + r0 * -1.0),
+                Plane::from_vec4(r3 + r1),
+                Plane::from_vec4(r3 + r1 * -1.0),
+                Plane::from_vec4(r3),
+            ],
+        }
+    }
+
+    pub fn test_sphere(&self, sphere: &Sphere) -> Visibility {
+        let mut result = Visibility::Inside;
+
+        for plane in self.planes.iter() {
+            let dist = plane.distance(sphere.center);
+
+            if dist < -sphere.radius {
+                return Visibility::Outside;
+            } else if dist < sphere.radius {
+                result = Visibility::Intersecting;
+            }
+        }
+
+        result
+    }
+
+    pub fn test_aabb(&self, aabb: &Aabb) -> Visibility {
+        let mut result = Visibility::Inside;
+
+        for plane in self.planes.iter() {
+            let n = plane.normal;
+
+            // The corners furthest along and against the plane normal
+            let positive = Vec3 {
+                x: if n.x >= 0.0 { aabb.max.x } else { aabb.min.x },
+                y: if n.y >= 0.0 { aabb.max.y } else { aabb.min.y },
+                z: if n.z >= 0.0 { aabb.max.z } else { aabb.min.z },
+            };
+            let negative = Vec3 {
+                x: if n.x >= 0.0 { aabb.min.x } else { aabb.max.x },
+                y: if n.y >= 0.0 { aabb.min.y } else { aabb.max.y },
+                z: if n.z >= 0.0 { aabb.min.z } else { aabb.max.z },
+            };
+
+            if plane.distance(positive) < 0.0 {
+                return Visibility::Outside;
+            } else if plane.distance(negative) < 0.0 {
+                result = Visibility::Intersecting;
+            }
+        }
+
+        result
+    }
+
+    pub fn is_sphere_visible(&self, sphere: &Sphere) -> bool {
+        self.test_sphere(sphere) != Visibility::Outside
+    }
+
+    pub fn is_aabb_visible(&self, aabb: &Aabb) -> bool {
+        self.test_aabb(aabb) != Visibility::Outside
+    }
+}
+
+// A run of consecutive triangles of an IndexedMesh, culled as a unit
+pub struct Cluster {
+    pub first_triangle: usize,
+    pub triangle_count: usize,
+    pub bounds: Aabb,
+}
+
+// Meshes exported from modelling tools keep neighbouring triangles close
+// together in the index buffer, so fixed-size runs make reasonably tight
+// clusters
+pub fn build_clusters<V>(mesh: &IndexedMesh<V>, triangles_per_cluster: usize) -> Vec<Cluster> {
+    let size = triangles_per_cluster.max(1);
+
+    (0..mesh.indices.len()).step_by(size).map(|first| {
+        let count = size.min(mesh.indices.len() - first);
+        let bounds = (first..first + count)
+            .flat_map(|t| mesh.indices[t].to_vec())
+            .fold(Aabb::empty(), |b, i| b.grow(mesh.vertices[i].0));
+
+        Cluster {
+            first_triangle: first,
+            triangle_count: count,
+            bounds,
+        }
+    }).collect()
+}
+
+// Triangles of every cluster that might be on screen, ready for
+// draw_indexed_triangles. `frustum` has to be in the mesh's model space.
+pub fn visible_triangles(clusters: &[Cluster], frustum: &Frustum) -> Vec<usize> {
+    clusters.iter()
+        .filter(|c| frustum.is_aabb_visible(&c.bounds))
+        .flat_map(|c| c.first_triangle..c.first_triangle + c.triangle_count)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use camera::Camera;
+
+    // Looking down -z at the origin from five units away
+    fn frustum() -> Frustum {
+        let camera = Camera {
+            eye: Vec3 { x: 0.0, y: 0.0, z: 5.0 },
+            center: Vec3 { x: 0.0, y: 0.0, z: 0.0 },
+            up: Vec3 { x: 0.0, y: 1.0, z: 0.0 },
+            fov: 1.0,
+        };
+
+        Frustum::from_matrix(&(camera.projection() * camera.view()))
+    }
+
+    fn sphere(x: f32, y: f32, z: f32, radius: f32) -> Sphere {
+        Sphere { center: Vec3 { x, y, z }, radius }
+    }
+
+    fn cube(x: f32, y: f32, z: f32, half: f32) -> Aabb {
+        Aabb {
+            min: Vec3 { x: x - half, y: y - half, z: z - half },
+            max: Vec3 { x: x + half, y: y + half, z: z + half },
+        }
+    }
+
+    #[test]
+    fn classifies_spheres() {
+        let frustum = frustum();
+
+        assert!(frustum.test_sphere(&sphere(0.0, 0.0, 0.0, 0.5)) == Visibility::Inside);
+        // Half of the view is about 2.7 units across at the origin
+        assert!(frustum.test_sphere(&sphere(2.7, 0.0, 0.0, 0.5)) == Visibility::Intersecting);
+        assert!(frustum.test_sphere(&sphere(0.0, -10.0, 0.0, 0.5)) == Visibility::Outside);
+        assert!(frustum.test_sphere(&sphere(0.0, 0.0, 8.0, 0.5)) == Visibility::Outside);
+        assert!(frustum.test_sphere(&sphere(0.0, 0.0, 5.0, 0.5)) == Visibility::Intersecting);
+
+        assert!(frustum.is_sphere_visible(&sphere(0.0, 0.0, -100.0, 1.0)));
+        assert!(!frustum.is_sphere_visible(&sphere(0.0, 100.0, -100.0, 1.0)));
+    }
+
+    #[test]
+    fn classifies_boxes() {
+        let frustum = frustum();
+
+        assert!(frustum.test_aabb(&cube(0.0, 0.0, 0.0, 0.5)) == Visibility::Inside);
+        assert!(frustum.test_aabb(&cube(0.0, 2.7, 0.0, 0.5)) == Visibility::Intersecting);
+        assert!(frustum.test_aabb(&cube(10.0, 0.0, 0.0, 0.5)) == Visibility::Outside);
+        assert!(frustum.test_aabb(&cube(0.0, 0.0, 8.0, 0.5)) == Visibility::Outside);
+        assert!(frustum.test_aabb(&cube(0.0, 0.0, 0.0, 100.0)) == Visibility::Intersecting);
+
+        assert!(frustum.is_aabb_visible(&cube(-2.7, -2.7, 0.0, 0.5)));
+        assert!(!frustum.is_aabb_visible(&cube(-4.0, -4.0, 0.0, 0.5)));
+    }
+}
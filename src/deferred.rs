@@ -0,0 +1,228 @@
+use std::cmp;
+use vec::{Vec2, Vec3, Vec4};
+use image::{Image, Color};
+use matrix::Matrix4x4;
+use obj::{Obj, FacePoint};
+use bounds::{Aabb, Sphere};
+use shader::Shader;
+use shaders::sample;
+use target::{GBuffer, GBufferFragment, Material};
+
+// Deferred shading in two passes: draw every mesh into a GBuffer with
+// GeometryShader, then light the G-buffer with Lighting::shade. The
+// lighting pass costs the same however many triangles were drawn, and each
+// light is only evaluated for the screen tiles it can reach.
+
+#[derive(Clone, Copy, Vary)]
+pub struct GeometryVars {
+    // Model space going into the vertex shader, world space coming out
+    pub position: Vec3<f32>,
+    pub normal: Vec3<f32>,
+    pub tex: Vec2<f32>,
+    pub color: Vec3<f32>,
+}
+
+impl GeometryVars {
+    // Missing texcoords and normals are left at zero
+    pub fn from_obj(obj: &Obj, fp: &FacePoint) -> GeometryVars {
+        let zero = Vec3 { x: 0.0, y: 0.0, z: 0.0 };
+
+        GeometryVars {
+            position: obj.vert(fp.vindex),
+            normal: if fp.nindex == 0 { zero } else { obj.norm_vert(fp.nindex) },
+            tex: if fp.tindex == 0 { Vec2 { x: 0.0, y: 0.0 } } else { obj.tex_vert(fp.tindex) },
+            color: obj.vert_color(fp.vindex),
+        }
+    }
+}
+
+// The geometry pass. Albedo is the vertex color, times the texture if
+// there is one.
+pub struct GeometryShader<'a> {
+    model: Matrix4x4<f32>,
+    normal_mat: Matrix4x4<f32>,
+    // viewport * projection * view
+    screen: Matrix4x4<f32>,
+    pub texture: Option<&'a Image>,
+    pub material: Material,
+    pub object_id: u32,
+}
+
+impl<'a> GeometryShader<'a> {
+    pub fn new(model: &Matrix4x4<f32>, screen: &Matrix4x4<f32>, object_id: u32) -> GeometryShader<'a> {
+        GeometryShader {
+            model: model.clone(),
+            normal_mat: model.inverse().unwrap_or_else(Matrix4x4::identity).transpose(),
+            screen: screen * model,
+            texture: None,
+            material: Material::new(),
+            object_id,
+        }
+    }
+}
+
+impl<'a> Shader<GeometryVars, GBufferFragment> for GeometryShader<'a> {
+    fn vertex(&self, pt: Vec3<f32>, vars: &GeometryVars) -> (Vec4<f32>, GeometryVars) {
+        let pt4 = Vec4 { x: pt.x, y: pt.y, z: pt.z, w: 1.0 };
+
+        (
+            &self.screen * &pt4,
+            GeometryVars {
+                position: self.model.transform_point(pt),
+                normal: self.normal_mat.transform_vector(vars.normal),
+                ..*vars
+            },
+        )
+    }
+
+    fn fragment(&self, _: Vec2<isize>, vars: GeometryVars) -> Option<GBufferFragment> {
+        let mut albedo = Color::from_vec(vars.color);
+
+        if let Some(tex) = self.texture {
+            albedo = sample(tex, vars.tex).multiply(&albedo);
+        }
+
+        Some(GBufferFragment {
+            albedo,
+            position: vars.position,
+            normal: if vars.normal.length() > 0.0 { vars.normal.norm() } else { vars.normal },
+            material: self.material,
+            object_id: self.object_id,
+        })
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct DirectionalLight {
+    // Towards the light
+    pub direction: Vec3<f32>,
+    pub color: Vec3<f32>,
+}
+
+// Falls off smoothly to nothing at `radius`, so lights can be culled
+// exactly at that distance
+#[derive(Clone, Copy)]
+pub struct PointLight {
+    pub position: Vec3<f32>,
+    pub color: Vec3<f32>,
+    pub radius: f32,
+}
+
+impl PointLight {
+    fn attenuation(&self, distance: f32) -> f32 {
+        let x = (1.0 - (distance / self.radius).powi(2)).max(0.0);
+        x * x
+    }
+}
+
+// Lights are shaded in screen tiles this many pixels across
+const TILE_SIZE: usize = 16;
+
+pub struct Lighting {
+    pub eye: Vec3<f32>,
+    pub ambient: Vec3<f32>,
+    pub directional: Vec<DirectionalLight>,
+    pub points: Vec<PointLight>,
+    // For pixels no geometry covered
+    pub background: Color,
+}
+
+impl Lighting {
+    pub fn new(eye: Vec3<f32>) -> Lighting {
+        Lighting {
+            eye,
+            ambient: Vec3 { x: 0.1, y: 0.1, z: 0.1 },
+            directional: Vec::new(),
+            points: Vec::new(),
+            background: Color(0, 0, 0),
+        }
+    }
+
+    // Blinn-Phong. The result keeps the G-buffer's depth, so forward passes
+    // (lines, transparent objects) can be drawn over it afterwards.
+    pub fn shade(&self, gbuffer: &GBuffer) -> Image {
+        let (width, height) = (gbuffer.albedo.width, gbuffer.albedo.height);
+        let mut image = Image::new(width, height);
+        let mut lights = Vec::with_capacity(self.points.len());
+
+        for tile_y in (0..height).step_by(TILE_SIZE) {
+            for tile_x in (0..width).step_by(TILE_SIZE) {
+                let xs = tile_x..cmp::min(tile_x + TILE_SIZE, width);
+                let ys = tile_y..cmp::min(tile_y + TILE_SIZE, height);
+
+                let mut bounds = Aabb::empty();
+                for y in ys.clone() {
+                    for x in xs.clone() {
+                        if gbuffer.covered(x, y) {
+                            bounds = bounds.grow(*gbuffer.position.get(x, y));
+                        }
+                    }
+                }
+
+                lights.clear();
+                lights.extend(self.points.iter().filter(|l| {
+                    bounds.intersects_sphere(&Sphere { center: l.position, radius: l.radius })
+                }));
+
+                for y in ys.clone() {
+                    for x in xs.clone() {
+                        if gbuffer.covered(x, y) {
+                            let color = self.shade_pixel(gbuffer, x, y, &lights);
+                            image.set_pixel_with_depth(x, y, &color, *gbuffer.depth.get(x, y) as isize);
+                        } else {
+                            image.set_pixel(x, y, &self.background);
+                        }
+                    }
+                }
+            }
+        }
+
+        image
+    }
+
+    fn shade_pixel(&self, gbuffer: &GBuffer, x: usize, y: usize, points: &[&PointLight]) -> Color {
+        let albedo = gbuffer.albedo.get(x, y).to_vec();
+        let position = *gbuffer.position.get(x, y);
+        let normal = *gbuffer.normal.get(x, y);
+        let material = gbuffer.material.get(x, y);
+        let view = (self.eye - position).norm();
+
+        // Surfaces without normals are lit as if they face every light
+        let lit = |to_light: Vec3<f32>, radiance: Vec3<f32>| -> Vec3<f32> {
+            if normal.length() == 0.0 {
+                return mul(albedo, radiance);
+            }
+
+            let diffuse = normal.dot(to_light);
+            if diffuse <= 0.0 {
+                return Vec3 { x: 0.0, y: 0.0, z: 0.0 };
+            }
+
+            let half = (to_light + view).norm();
+            let specular = material.specular * normal.dot(half).max(0.0).powf(material.shininess);
+
+            (mul(albedo, radiance) * diffuse) + radiance * specular
+        };
+
+        let mut color = mul(albedo, self.ambient);
+
+        for light in self.directional.iter() {
+            color = color + lit(light.direction.norm(), light.color);
+        }
+
+        for light in points.iter() {
+            let offset = light.position - position;
+            let distance = offset.length();
+
+            if distance < light.radius && distance > 0.0 {
+                color = color + lit(offset * (1.0 / distance), light.color * light.attenuation(distance));
+            }
+        }
+
+        Color::from_vec(color)
+    }
+}
+
+fn mul(a: Vec3<f32>, b: Vec3<f32>) -> Vec3<f32> {
+    Vec3 { x: a.x * b.x, y: a.y * b.y, z: a.z * b.z }
+}
@@ -0,0 +1,204 @@
+use std::f32;
+use vec::Vec3;
+use image::{Image, Color};
+use matrix::Matrix4x4;
+use camera::Camera;
+use target::Buffer;
+use post::FloatImage;
+
+// Angle between consecutive samples of the gather spiral, so no two line up
+const GOLDEN_ANGLE: f32 = 2.399_963;
+// The spiral's radius grows by roughly this many pixels per turn. Smaller
+// is smoother and slower.
+const RADIUS_STEP: f32 = 0.5;
+
+// A thin lens. Points at the focus distance are sharp; everything nearer
+// or farther is spread over a circle of confusion that grows with the
+// aperture.
+#[derive(Clone, Copy)]
+pub struct Lens {
+    // Diameter, in world units. 0 is a pinhole camera with everything in
+    // focus.
+    pub aperture: f32,
+    // From the eye, in world units
+    pub focus_distance: f32,
+    // In pixels. Bounds both the blur and the cost of gathering it.
+    pub max_radius: f32,
+}
+
+impl Lens {
+    // Focused on the camera's center
+    pub fn new(camera: &Camera, aperture: f32) -> Lens {
+        Lens {
+            aperture,
+            focus_distance: camera.distance(),
+            max_radius: 12.0,
+        }
+    }
+
+    // Blur radius in pixels for every pixel of `depth` (GBuffer::depth's
+    // convention), drawn with `camera` and `screen` = viewport * projection
+    // * view. Assumes the viewport fills the buffer. Pixels nothing covered
+    // are treated as infinitely far away.
+    pub fn circle_of_confusion(&self, depth: &Buffer<f32>, camera: &Camera, screen: &Matrix4x4<f32>) -> Buffer<f32> {
+        let (scale, offset) = distance_from_depth(camera, screen);
+        let focus = self.focus_distance.max(1e-6);
+
+        // Converts a diameter on the focus plane to a radius in pixels
+        let pixels = depth.height as f32 / (2.0 * focus * (camera.fov / 2.0).tan()) / 2.0;
+
+        let mut coc = Buffer::new(depth.width, depth.height, 0.0);
+        for (c, &d) in coc.data.iter_mut().zip(depth.data.iter()) {
+            let diameter = if d.is_finite() {
+                let distance = (d * scale + offset).max(1e-6);
+                self.aperture * (distance - focus).abs() / distance
+            } else {
+                self.aperture
+            };
+
+            *c = (diameter * pixels).min(self.max_radius);
+        }
+
+        coc
+    }
+
+    pub fn apply(&self, image: &FloatImage, depth: &Buffer<f32>, camera: &Camera, screen: &Matrix4x4<f32>) -> FloatImage {
+        let coc = self.circle_of_confusion(depth, camera, screen);
+        gather(image, depth, &coc)
+    }
+}
+
+// Depth isn't divided by w, so it's an affine function of distance along
+// the view axis. Returns (scale, offset) such that distance = depth *
+// scale + offset.
+fn distance_from_depth(camera: &Camera, screen: &Matrix4x4<f32>) -> (f32, f32) {
+    let axis = (camera.center - camera.eye).norm();
+    let row = screen.row(2);
+    let depth_at = |distance: f32| {
+        let p = camera.eye + axis * distance;
+        row.x * p.x + row.y * p.y + row.z * p.z + row.w
+    };
+
+    let (d1, d2) = (depth_at(1.0), depth_at(2.0));
+    let scale = 1.0 / (d2 - d1);
+
+    (scale, 1.0 - d1 * scale)
+}
+
+// Scatter-as-gather along a golden angle spiral (after Gustafsson, "Bokeh
+// depth of field in a single pass"). A sample counts towards a pixel when
+// its own circle of confusion reaches that far, so blurry foreground
+// spreads over sharp background. Samples behind the pixel are limited to
+// its blur, which keeps blurry background from leaking over sharp edges.
+pub fn gather(image: &FloatImage, depth: &Buffer<f32>, coc: &Buffer<f32>) -> FloatImage {
+    let max_radius = coc.data.iter().cloned().fold(0.0, f32::max);
+    let mut out = image.clone();
+
+    if max_radius < RADIUS_STEP {
+        return out;
+    }
+
+    // The spiral is the same for every pixel
+    let mut spiral = Vec::new();
+    let (mut radius, mut angle) = (RADIUS_STEP, 0.0f32);
+    while radius < max_radius {
+        let dx = (angle.cos() * radius).round() as isize;
+        let dy = (angle.sin() * radius).round() as isize;
+        spiral.push((dx, dy, radius));
+
+        radius += RADIUS_STEP / radius;
+        angle += GOLDEN_ANGLE;
+    }
+
+    for y in 0..image.height {
+        for x in 0..image.width {
+            let center_depth = *depth.get(x, y);
+            let center_size = *coc.get(x, y);
+
+            let mut color = *image.get(x, y);
+            let mut total = 1.0;
+
+            for &(dx, dy, radius) in spiral.iter() {
+                let sx = (x as isize + dx).clamp(0, image.width as isize - 1) as usize;
+                let sy = (y as isize + dy).clamp(0, image.height as isize - 1) as usize;
+
+                let mut size = *coc.get(sx, sy);
+                if *depth.get(sx, sy) < center_depth {
+                    size = size.min(center_size * 2.0);
+                }
+
+                let sample = *image.get(sx, sy);
+                let m = smoothstep(radius - 0.5, radius + 0.5, size);
+                let average = color * (1.0 / total);
+
+                color = color + average + (sample - average) * m;
+                total += 1.0;
+            }
+
+            out.set(x, y, color * (1.0 / total));
+        }
+    }
+
+    out
+}
+
+fn smoothstep(edge0: f32, edge1: f32, x: f32) -> f32 {
+    let t = ((x - edge0) / (edge1 - edge0)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+// Sharp pixels are black, the largest blur white
+pub fn coc_image(coc: &Buffer<f32>) -> Image {
+    let max = coc.data.iter().cloned().fold(1e-6, f32::max);
+
+    coc.to_image(|&c| Color::from_vec(Vec3 { x: c, y: c, z: c } * (1.0 / max)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn circles_of_confusion_grow_away_from_focus() {
+        let camera = Camera {
+            eye: Vec3 { x: 0.0, y: 0.0, z: 5.0 },
+            center: Vec3 { x: 0.0, y: 0.0, z: 0.0 },
+            up: Vec3 { x: 0.0, y: 1.0, z: 0.0 },
+            fov: 1.0,
+        };
+        let screen = Matrix4x4::viewport(0.0, 0.0, 5.0, 1.0, 255.0) * camera.projection() * camera.view();
+        let lens = Lens { max_radius: 100.0, ..Lens::new(&camera, 0.5) };
+
+        // Points straight ahead at these distances from the eye
+        let distances = [5.0, 4.0, 3.0, 7.0, 10.0];
+        let mut depth = Buffer::new(5, 1, 0.0);
+        for (x, &d) in distances.iter().enumerate() {
+            let row = screen.row(2);
+            depth.set(x, 0, row.z * (5.0 - d) + row.w);
+        }
+
+        let coc = lens.circle_of_confusion(&depth, &camera, &screen);
+        let at = |x: usize| *coc.get(x, 0);
+
+        assert!(at(0).abs() < 1e-3);
+        assert!(at(0) < at(1) && at(1) < at(2));
+        assert!(at(0) < at(3) && at(3) < at(4));
+    }
+
+    #[test]
+    fn zero_blur_leaves_the_image_alone() {
+        let mut image = FloatImage::new(6, 6, Vec3 { x: 0.0, y: 0.0, z: 0.0 });
+        let mut depth = Buffer::new(6, 6, f32::NEG_INFINITY);
+        for y in 0..6 {
+            for x in 0..6 {
+                image.set(x, y, Vec3 { x: x as f32 / 5.0, y: y as f32 / 5.0, z: ((x + y) % 2) as f32 });
+                depth.set(x, y, (x * y) as f32);
+            }
+        }
+
+        let out = gather(&image, &depth, &Buffer::new(6, 6, 0.0));
+        for (a, b) in out.data.iter().zip(image.data.iter()) {
+            assert!(a.x == b.x && a.y == b.y && a.z == b.z);
+        }
+    }
+}
@@ -0,0 +1,862 @@
+use std;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Cursor, Read, Write};
+use std::path::Path;
+use imagefmt;
+use imagefmt::{ColFmt, ColType};
+use png;
+use exr::prelude::{AnyChannel, AnyChannels, FlatSamples, SmallVec, WritableImage};
+use exr::prelude::traits::{ReadChannels, ReadLayers};
+use image::Image;
+
+#[derive(Debug)]
+pub enum ImageError {
+    Io(io::Error),
+    // Neither the file's contents nor its extension say what it is
+    UnknownFormat(String),
+    Unsupported(String),
+    Invalid(String),
+    Png(png::DecodingError),
+    PngEncode(png::EncodingError),
+    Exr(exr::error::Error),
+    // TGA, BMP and JPEG go through imagefmt
+    Codec(imagefmt::Error),
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ImageError::Io(ref e) => write!(f, "{}", e),
+            ImageError::UnknownFormat(ref name) => write!(f, "unknown image format: {}", name),
+            ImageError::Unsupported(ref msg) => write!(f, "unsupported image: {}", msg),
+            ImageError::Invalid(ref msg) => write!(f, "invalid image: {}", msg),
+            ImageError::Png(ref e) => write!(f, "PNG: {}", e),
+            ImageError::PngEncode(ref e) => write!(f, "PNG: {}", e),
+            ImageError::Exr(ref e) => write!(f, "OpenEXR: {}", e),
+            ImageError::Codec(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for ImageError {}
+
+impl From<io::Error> for ImageError {
+    fn from(e: io::Error) -> ImageError {
+        ImageError::Io(e)
+    }
+}
+
+impl From<png::DecodingError> for ImageError {
+    fn from(e: png::DecodingError) -> ImageError {
+        ImageError::Png(e)
+    }
+}
+
+impl From<png::EncodingError> for ImageError {
+    fn from(e: png::EncodingError) -> ImageError {
+        ImageError::PngEncode(e)
+    }
+}
+
+impl From<exr::error::Error> for ImageError {
+    fn from(e: exr::error::Error) -> ImageError {
+        ImageError::Exr(e)
+    }
+}
+
+impl From<imagefmt::Error> for ImageError {
+    fn from(e: imagefmt::Error) -> ImageError {
+        ImageError::Codec(e)
+    }
+}
+
+fn invalid<T>(msg: &str) -> Result<T, ImageError> {
+    Err(ImageError::Invalid(msg.to_string()))
+}
+
+// The product of image dimensions read from a header, which may be anything
+fn checked_size(dimensions: &[usize]) -> Result<usize, ImageError> {
+    match dimensions.iter().try_fold(1usize, |size, &d| size.checked_mul(d)) {
+        Some(size) => Ok(size),
+        None => invalid("image is too large"),
+    }
+}
+
+// `len` bytes from `offset`, where both come from the file
+fn pixel_data(bytes: &[u8], offset: usize, len: usize) -> Result<&[u8], ImageError> {
+    match offset.checked_add(len).and_then(|end| bytes.get(offset..end)) {
+        Some(data) => Ok(data),
+        None => invalid("truncated pixel data"),
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ImageFormat {
+    Png,
+    Tga,
+    Bmp,
+    // Read only
+    Jpeg,
+    // Also covers PGM
+    Ppm,
+    Pfm,
+    Hdr,
+    Exr,
+}
+
+impl ImageFormat {
+    pub fn from_path(path: &str) -> Option<ImageFormat> {
+        let ext = Path::new(path).extension()?.to_str()?.to_lowercase();
+
+        match &ext[..] {
+            "png" => Some(ImageFormat::Png),
+            "tga" => Some(ImageFormat::Tga),
+            "bmp" => Some(ImageFormat::Bmp),
+            "jpg" | "jpeg" => Some(ImageFormat::Jpeg),
+            "ppm" | "pgm" | "pnm" => Some(ImageFormat::Ppm),
+            "pfm" => Some(ImageFormat::Pfm),
+            "hdr" | "pic" => Some(ImageFormat::Hdr),
+            "exr" => Some(ImageFormat::Exr),
+            _ => None,
+        }
+    }
+
+    // TGA has no magic number, so it can only be recognized by extension
+    pub fn detect(bytes: &[u8]) -> Option<ImageFormat> {
+        if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+            Some(ImageFormat::Png)
+        } else if bytes.starts_with(b"\x76\x2f\x31\x01") {
+            Some(ImageFormat::Exr)
+        } else if bytes.starts_with(b"#?RADIANCE") || bytes.starts_with(b"#?RGBE") {
+            Some(ImageFormat::Hdr)
+        } else if bytes.starts_with(b"PF") || bytes.starts_with(b"Pf") {
+            Some(ImageFormat::Pfm)
+        } else if bytes.len() > 2 && bytes[0] == b'P' && b"1234567".contains(&bytes[1]) && (bytes[2] as char).is_whitespace() {
+            Some(ImageFormat::Ppm)
+        } else if bytes.starts_with(b"BM") {
+            Some(ImageFormat::Bmp)
+        } else if bytes.starts_with(b"\xff\xd8\xff") {
+            Some(ImageFormat::Jpeg)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Clone)]
+pub enum Samples {
+    U8(Vec<u8>),
+    U16(Vec<u16>),
+    // Linear, not limited to [0, 1]
+    F32(Vec<f32>),
+}
+
+impl Samples {
+    pub fn len(&self) -> usize {
+        match *self {
+            Samples::U8(ref v) => v.len(),
+            Samples::U16(ref v) => v.len(),
+            Samples::F32(ref v) => v.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Integer samples map to [0, 1]
+    pub fn to_f32(&self) -> Vec<f32> {
+        match *self {
+            Samples::U8(ref v) => v.iter().map(|&s| s as f32 / 255.0).collect(),
+            Samples::U16(ref v) => v.iter().map(|&s| s as f32 / 65535.0).collect(),
+            Samples::F32(ref v) => v.clone(),
+        }
+    }
+
+    // Floats are clamped to [0, 1]
+    pub fn to_u8(&self) -> Vec<u8> {
+        match *self {
+            Samples::U8(ref v) => v.clone(),
+            Samples::U16(ref v) => v.iter().map(|&s| ((s as u32 * 255 + 32767) / 65535) as u8).collect(),
+            Samples::F32(ref v) => v.iter().map(|&s| (s.clamp(0.0, 1.0) * 255.0).round() as u8).collect(),
+        }
+    }
+
+    pub fn to_u16(&self) -> Vec<u16> {
+        match *self {
+            Samples::U8(ref v) => v.iter().map(|&s| s as u16 * 257).collect(),
+            Samples::U16(ref v) => v.clone(),
+            Samples::F32(ref v) => v.iter().map(|&s| (s.clamp(0.0, 1.0) * 65535.0).round() as u16).collect(),
+        }
+    }
+
+    // Same sample type as `self`, converting from floats in [0, 1]
+    fn like(&self, values: &[f32]) -> Samples {
+        let values = Samples::F32(values.to_vec());
+
+        match *self {
+            Samples::U8(_) => Samples::U8(values.to_u8()),
+            Samples::U16(_) => Samples::U16(values.to_u16()),
+            Samples::F32(_) => values,
+        }
+    }
+}
+
+// Pixels as loaded from or written to a file: 1 (gray), 2 (gray + alpha),
+// 3 (RGB) or 4 (RGBA) interleaved channels, top row first
+#[derive(Clone)]
+pub struct PixelBuffer {
+    pub width: usize,
+    pub height: usize,
+    pub channels: usize,
+    pub samples: Samples,
+}
+
+impl PixelBuffer {
+    pub fn new(width: usize, height: usize, channels: usize, samples: Samples) -> Result<PixelBuffer, ImageError> {
+        if channels == 0 || channels > 4 {
+            return Err(ImageError::Unsupported(format!("{} channels", channels)));
+        }
+        if checked_size(&[width, height, channels]).ok() != Some(samples.len()) {
+            return invalid("pixel data doesn't match the image size");
+        }
+
+        Ok(PixelBuffer { width, height, channels, samples })
+    }
+
+    pub fn from_image(image: &Image) -> PixelBuffer {
+        PixelBuffer {
+            width: image.width,
+            height: image.height,
+            channels: 3,
+            samples: Samples::U8(image.data.clone()),
+        }
+    }
+
+    pub fn has_alpha(&self) -> bool {
+        self.channels == 2 || self.channels == 4
+    }
+
+    // Alpha as one byte per pixel, as blit_with_alpha wants it
+    pub fn alpha(&self) -> Option<Vec<u8>> {
+        if !self.has_alpha() {
+            return None;
+        }
+
+        let values = self.samples.to_u8();
+        Some(values.chunks(self.channels).map(|p| p[self.channels - 1]).collect())
+    }
+
+    // Converts between gray and color, adding opaque alpha or dropping it
+    // as needed. Color becomes gray by Rec. 709 luminance.
+    pub fn with_channels(&self, channels: usize) -> PixelBuffer {
+        if channels == self.channels {
+            return self.clone();
+        }
+
+        let values = self.samples.to_f32();
+        let opaque = 1.0;
+
+        let mut out = Vec::with_capacity(self.width * self.height * channels);
+        for p in values.chunks(self.channels) {
+            let (rgb, alpha) = match self.channels {
+                1 => ([p[0]; 3], opaque),
+                2 => ([p[0]; 3], p[1]),
+                3 => ([p[0], p[1], p[2]], opaque),
+                _ => ([p[0], p[1], p[2]], p[3]),
+            };
+            let gray = if self.channels <= 2 { rgb[0] } else { 0.2126 * rgb[0] + 0.7152 * rgb[1] + 0.0722 * rgb[2] };
+
+            match channels {
+                1 => out.push(gray),
+                2 => out.extend_from_slice(&[gray, alpha]),
+                3 => out.extend_from_slice(&rgb),
+                _ => out.extend_from_slice(&[rgb[0], rgb[1], rgb[2], alpha]),
+            }
+        }
+
+        PixelBuffer {
+            width: self.width,
+            height: self.height,
+            channels,
+            samples: self.samples.like(&out),
+        }
+    }
+
+}
+
+// Picks the format from the file's contents, falling back on its extension
+pub fn read_file(path: &str) -> Result<PixelBuffer, ImageError> {
+    let mut bytes = Vec::new();
+    File::open(path)?.read_to_end(&mut bytes)?;
+
+    match ImageFormat::detect(&bytes).or_else(|| ImageFormat::from_path(path)) {
+        Some(format) => decode(&bytes, format),
+        None => Err(ImageError::UnknownFormat(path.to_string())),
+    }
+}
+
+// The format comes from the extension
+pub fn write_file(path: &str, pixels: &PixelBuffer) -> Result<(), ImageError> {
+    let format = match ImageFormat::from_path(path) {
+        Some(format) => format,
+        None => return Err(ImageError::UnknownFormat(path.to_string())),
+    };
+
+    let bytes = encode(pixels, format)?;
+    File::create(path)?.write_all(&bytes)?;
+    Ok(())
+}
+
+pub fn decode(bytes: &[u8], format: ImageFormat) -> Result<PixelBuffer, ImageError> {
+    match format {
+        ImageFormat::Png => decode_png(bytes),
+        ImageFormat::Tga | ImageFormat::Bmp | ImageFormat::Jpeg => decode_imagefmt(bytes),
+        ImageFormat::Ppm => decode_ppm(bytes),
+        ImageFormat::Pfm => decode_pfm(bytes),
+        ImageFormat::Hdr => decode_hdr(bytes),
+        ImageFormat::Exr => decode_exr(bytes),
+    }
+}
+
+// Formats that can't hold what's in `pixels` get the closest thing they
+// can: alpha is dropped where unsupported, and floats are quantized for
+// integer formats (and vice versa)
+pub fn encode(pixels: &PixelBuffer, format: ImageFormat) -> Result<Vec<u8>, ImageError> {
+    match format {
+        ImageFormat::Png => encode_png(pixels),
+        ImageFormat::Tga => encode_imagefmt(pixels, format),
+        ImageFormat::Bmp => {
+            let channels = if pixels.has_alpha() { 4 } else { 3 };
+            encode_imagefmt(&pixels.with_channels(channels), format)
+        }
+        ImageFormat::Jpeg => Err(ImageError::Unsupported("writing JPEG".to_string())),
+        ImageFormat::Ppm => encode_ppm(pixels),
+        ImageFormat::Pfm => encode_pfm(pixels),
+        ImageFormat::Hdr => encode_hdr(pixels),
+        ImageFormat::Exr => encode_exr(pixels),
+    }
+}
+
+fn decode_png(bytes: &[u8]) -> Result<PixelBuffer, ImageError> {
+    let mut decoder = png::Decoder::new(Cursor::new(bytes));
+    // Palettes, low bit depths and tRNS all become plain 8-bit channels
+    decoder.set_transformations(png::Transformations::EXPAND);
+
+    let mut reader = decoder.read_info()?;
+    let mut buf = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buf)?;
+    buf.truncate(info.buffer_size());
+
+    let channels = match info.color_type {
+        png::ColorType::Grayscale => 1,
+        png::ColorType::GrayscaleAlpha => 2,
+        png::ColorType::Rgb | png::ColorType::Indexed => 3,
+        png::ColorType::Rgba => 4,
+    };
+
+    let samples = if info.bit_depth == png::BitDepth::Sixteen {
+        Samples::U16(buf.chunks(2).map(|b| u16::from_be_bytes([b[0], b[1]])).collect())
+    } else {
+        Samples::U8(buf)
+    };
+
+    PixelBuffer::new(info.width as usize, info.height as usize, channels, samples)
+}
+
+fn encode_png(pixels: &PixelBuffer) -> Result<Vec<u8>, ImageError> {
+    let mut out = Vec::new();
+    {
+        let mut encoder = png::Encoder::new(&mut out, pixels.width as u32, pixels.height as u32);
+        encoder.set_color(match pixels.channels {
+            1 => png::ColorType::Grayscale,
+            2 => png::ColorType::GrayscaleAlpha,
+            3 => png::ColorType::Rgb,
+            _ => png::ColorType::Rgba,
+        });
+
+        let data = match pixels.samples {
+            Samples::U8(ref v) => {
+                encoder.set_depth(png::BitDepth::Eight);
+                v.clone()
+            }
+            ref samples => {
+                encoder.set_depth(png::BitDepth::Sixteen);
+                samples.to_u16().iter().flat_map(|s| s.to_be_bytes().to_vec()).collect()
+            }
+        };
+
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&data)?;
+    }
+    Ok(out)
+}
+
+fn col_fmt(channels: usize) -> ColFmt {
+    match channels {
+        1 => ColFmt::Y,
+        2 => ColFmt::YA,
+        3 => ColFmt::RGB,
+        _ => ColFmt::RGBA,
+    }
+}
+
+fn decode_imagefmt(bytes: &[u8]) -> Result<PixelBuffer, ImageError> {
+    // Auto keeps whatever channels the file has, as Y, YA, RGB or RGBA
+    let img = imagefmt::read_from(&mut Cursor::new(bytes), ColFmt::Auto)?;
+    let channels = match img.fmt {
+        ColFmt::Y => 1,
+        ColFmt::YA => 2,
+        ColFmt::RGB => 3,
+        _ => 4,
+    };
+
+    PixelBuffer::new(img.w, img.h, channels, Samples::U8(img.buf))
+}
+
+fn encode_imagefmt(pixels: &PixelBuffer, format: ImageFormat) -> Result<Vec<u8>, ImageError> {
+    let data = pixels.samples.to_u8();
+    let fmt = col_fmt(pixels.channels);
+    let mut out = Vec::new();
+
+    if format == ImageFormat::Bmp {
+        imagefmt::bmp::write(&mut out, pixels.width, pixels.height, fmt, &data, ColType::Auto, None)?;
+    } else {
+        imagefmt::tga::write(&mut out, pixels.width, pixels.height, fmt, &data, ColType::Auto, None)?;
+    }
+    Ok(out)
+}
+
+// Splits a Netpbm-style header into whitespace separated tokens, skipping
+// comments, and returns them with the offset just past the last one
+fn header_tokens(bytes: &[u8], count: usize) -> Result<(Vec<String>, usize), ImageError> {
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while tokens.len() < count {
+        while i < bytes.len() && (bytes[i] as char).is_whitespace() {
+            i += 1;
+        }
+        if i < bytes.len() && bytes[i] == b'#' {
+            while i < bytes.len() && bytes[i] != b'\n' {
+                i += 1;
+            }
+            continue;
+        }
+        if i >= bytes.len() {
+            return invalid("truncated header");
+        }
+
+        let start = i;
+        while i < bytes.len() && !(bytes[i] as char).is_whitespace() {
+            i += 1;
+        }
+        tokens.push(String::from_utf8_lossy(&bytes[start..i]).into_owned());
+    }
+
+    // Exactly one whitespace byte separates the header from binary data
+    Ok((tokens, i + 1))
+}
+
+fn parse<T: std::str::FromStr>(token: &str) -> Result<T, ImageError> {
+    token.parse().map_err(|_| ImageError::Invalid(format!("bad number '{}'", token)))
+}
+
+fn decode_ppm(bytes: &[u8]) -> Result<PixelBuffer, ImageError> {
+    let (header, offset) = header_tokens(bytes, 4)?;
+    let (ascii, channels) = match &header[0][..] {
+        "P2" => (true, 1),
+        "P3" => (true, 3),
+        "P5" => (false, 1),
+        "P6" => (false, 3),
+        other => return Err(ImageError::Unsupported(format!("Netpbm type {}", other))),
+    };
+
+    let width: usize = parse(&header[1])?;
+    let height: usize = parse(&header[2])?;
+    let max: u32 = parse(&header[3])?;
+    if max == 0 || max > 65535 {
+        return invalid("bad maximum value");
+    }
+
+    let count = checked_size(&[width, height, channels])?;
+    let raw: Vec<u32> = if ascii {
+        let text = String::from_utf8_lossy(&bytes[offset.min(bytes.len())..]).into_owned();
+        let values: Result<Vec<u32>, _> = text.split_whitespace().take(count).map(parse).collect();
+        values?
+    } else if max < 256 {
+        pixel_data(bytes, offset, count)?.iter().map(|&b| b as u32).collect()
+    } else {
+        pixel_data(bytes, offset, checked_size(&[count, 2])?)?
+            .chunks(2).map(|b| u16::from_be_bytes([b[0], b[1]]) as u32).collect()
+    };
+
+    if raw.len() != count {
+        return invalid("truncated pixel data");
+    }
+
+    // Rescale odd maximums to the full range of the sample type
+    let samples = if max < 256 {
+        Samples::U8(raw.iter().map(|&v| ((v.min(max) * 255 + max / 2) / max) as u8).collect())
+    } else {
+        Samples::U16(raw.iter().map(|&v| ((v.min(max) * 65535 + max / 2) / max) as u16).collect())
+    };
+
+    PixelBuffer::new(width, height, channels, samples)
+}
+
+fn encode_ppm(pixels: &PixelBuffer) -> Result<Vec<u8>, ImageError> {
+    let channels = if pixels.channels <= 2 { 1 } else { 3 };
+    let pixels = pixels.with_channels(channels);
+    let magic = if channels == 1 { "P5" } else { "P6" };
+
+    let mut out = Vec::new();
+    match pixels.samples {
+        Samples::U8(ref v) => {
+            write!(out, "{}\n{} {}\n255\n", magic, pixels.width, pixels.height)?;
+            out.extend_from_slice(v);
+        }
+        ref samples => {
+            write!(out, "{}\n{} {}\n65535\n", magic, pixels.width, pixels.height)?;
+            for s in samples.to_u16() {
+                out.extend_from_slice(&s.to_be_bytes());
+            }
+        }
+    }
+    Ok(out)
+}
+
+fn decode_pfm(bytes: &[u8]) -> Result<PixelBuffer, ImageError> {
+    let (header, offset) = header_tokens(bytes, 4)?;
+    let channels = match &header[0][..] {
+        "Pf" => 1,
+        "PF" => 3,
+        other => return Err(ImageError::Unsupported(format!("PFM type {}", other))),
+    };
+
+    let width: usize = parse(&header[1])?;
+    let height: usize = parse(&header[2])?;
+    // The sign of the scale gives the byte order
+    let little_endian = parse::<f32>(&header[3])? < 0.0;
+
+    let row = checked_size(&[width, channels])?;
+    let data = pixel_data(bytes, offset, checked_size(&[row, height, 4])?)?;
+
+    let values: Vec<f32> = data.chunks(4).map(|b| {
+        let b = [b[0], b[1], b[2], b[3]];
+        if little_endian { f32::from_le_bytes(b) } else { f32::from_be_bytes(b) }
+    }).collect();
+
+    // Rows are stored bottom to top
+    let flipped = values.chunks(row.max(1)).rev().flat_map(|r| r.to_vec()).collect();
+
+    PixelBuffer::new(width, height, channels, Samples::F32(flipped))
+}
+
+fn encode_pfm(pixels: &PixelBuffer) -> Result<Vec<u8>, ImageError> {
+    let channels = if pixels.channels <= 2 { 1 } else { 3 };
+    let pixels = pixels.with_channels(channels);
+    let values = pixels.samples.to_f32();
+
+    let mut out = Vec::new();
+    write!(out, "{}\n{} {}\n-1.0\n", if channels == 1 { "Pf" } else { "PF" }, pixels.width, pixels.height)?;
+    for row in values.chunks((pixels.width * channels).max(1)).rev() {
+        for v in row {
+            out.extend_from_slice(&v.to_le_bytes());
+        }
+    }
+    Ok(out)
+}
+
+fn rgbe_to_rgb(rgbe: &[u8]) -> [f32; 3] {
+    if rgbe[3] == 0 {
+        return [0.0; 3];
+    }
+
+    let scale = 2f32.powi(rgbe[3] as i32 - (128 + 8));
+    [rgbe[0] as f32 * scale, rgbe[1] as f32 * scale, rgbe[2] as f32 * scale]
+}
+
+fn rgb_to_rgbe(rgb: &[f32]) -> [u8; 4] {
+    let max = rgb[0].max(rgb[1]).max(rgb[2]);
+    if max < 1e-32 {
+        return [0; 4];
+    }
+
+    // max = mantissa * 2^exponent with mantissa in [0.5, 1). The exponent
+    // byte only reaches 2^127, so anything brighter saturates.
+    let exponent = (max.log2().floor() as i32).clamp(-128, 126) + 1;
+    let scale = 256.0 / 2f32.powi(exponent);
+    let byte = |c: f32| (c.max(0.0) * scale).min(255.0) as u8;
+
+    [byte(rgb[0]), byte(rgb[1]), byte(rgb[2]), (exponent + 128) as u8]
+}
+
+fn decode_hdr(bytes: &[u8]) -> Result<PixelBuffer, ImageError> {
+    let mut lines = Vec::new();
+    let mut i = 0;
+
+    // Header lines up to a blank one, then the resolution line
+    loop {
+        let end = match bytes[i..].iter().position(|&b| b == b'\n') {
+            Some(end) => i + end,
+            None => return invalid("truncated header"),
+        };
+        let line = String::from_utf8_lossy(&bytes[i..end]).into_owned();
+        i = end + 1;
+
+        if line.is_empty() && !lines.is_empty() {
+            let end = match bytes[i..].iter().position(|&b| b == b'\n') {
+                Some(end) => i + end,
+                None => return invalid("missing resolution"),
+            };
+            lines.push(String::from_utf8_lossy(&bytes[i..end]).into_owned());
+            i = end + 1;
+            break;
+        }
+        lines.push(line);
+    }
+
+    for line in &lines {
+        if line.starts_with("FORMAT=") && line != "FORMAT=32-bit_rle_rgbe" {
+            return Err(ImageError::Unsupported(line.to_string()));
+        }
+    }
+
+    let resolution: Vec<&str> = lines.last().unwrap().split_whitespace().collect();
+    if resolution.len() != 4 || resolution[0] != "-Y" || resolution[2] != "+X" {
+        return Err(ImageError::Unsupported("only -Y +X scanline order is supported".to_string()));
+    }
+    let height: usize = parse(resolution[1])?;
+    let width: usize = parse(resolution[3])?;
+
+    // Only short scanlines can be run-length encoded, so longer ones must
+    // be in the file in full
+    let line_size = checked_size(&[width, 4])?;
+    checked_size(&[line_size, height])?;
+    if width >= 32768 && line_size > bytes.len() - i {
+        return invalid("truncated pixel data");
+    }
+    // Empty scanlines take no bytes, so there's nothing to bound the height
+    if width == 0 && height > 0 {
+        return invalid("image has no width");
+    }
+
+    let mut values = Vec::new();
+    let mut scanline = vec![0u8; line_size];
+    let truncated = || ImageError::Invalid("truncated pixel data".to_string());
+
+    for _ in 0..height {
+        let rle = (8..32768).contains(&width) && bytes.get(i..i + 4).is_some_and(|b|
+            b[0] == 2 && b[1] == 2 && ((b[2] as usize) << 8 | b[3] as usize) == width);
+
+        if rle {
+            // Each component is run-length encoded separately
+            i += 4;
+            for c in 0..4 {
+                let mut x = 0;
+                while x < width {
+                    let count = *bytes.get(i).ok_or_else(truncated)? as usize;
+                    i += 1;
+
+                    if count > 128 {
+                        let run = count - 128;
+                        let value = *bytes.get(i).ok_or_else(truncated)?;
+                        i += 1;
+                        if x + run > width {
+                            return invalid("bad run length");
+                        }
+                        for _ in 0..run {
+                            scanline[x * 4 + c] = value;
+                            x += 1;
+                        }
+                    } else {
+                        if count == 0 || x + count > width {
+                            return invalid("bad run length");
+                        }
+                        let data = bytes.get(i..i + count).ok_or_else(truncated)?;
+                        i += count;
+                        for &value in data {
+                            scanline[x * 4 + c] = value;
+                            x += 1;
+                        }
+                    }
+                }
+            }
+        } else {
+            scanline.copy_from_slice(pixel_data(bytes, i, line_size)?);
+            i += line_size;
+        }
+
+        for rgbe in scanline.chunks(4) {
+            values.extend_from_slice(&rgbe_to_rgb(rgbe));
+        }
+    }
+
+    PixelBuffer::new(width, height, 3, Samples::F32(values))
+}
+
+// Scanlines are written flat, without run-length encoding
+fn encode_hdr(pixels: &PixelBuffer) -> Result<Vec<u8>, ImageError> {
+    let values = pixels.with_channels(3).samples.to_f32();
+
+    let mut out = Vec::new();
+    write!(out, "#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y {} +X {}\n", pixels.height, pixels.width)?;
+    for rgb in values.chunks(3) {
+        out.extend_from_slice(&rgb_to_rgbe(rgb));
+    }
+    Ok(out)
+}
+
+fn decode_exr(bytes: &[u8]) -> Result<PixelBuffer, ImageError> {
+    let image = exr::prelude::read()
+        .no_deep_data()
+        .largest_resolution_level()
+        .all_channels()
+        .first_valid_layer()
+        .all_attributes()
+        .from_buffered(Cursor::new(bytes))?;
+
+    let layer = image.layer_data;
+    let (width, height) = (layer.size.0, layer.size.1);
+
+    // Channels can be prefixed with a layer name, like "diffuse.R"
+    let find = |name: &str| layer.channel_data.list.iter().find(|c| {
+        let full = c.name.to_string();
+        full.rsplit('.').next() == Some(name)
+    });
+
+    let color = [find("R"), find("G"), find("B")];
+    let planes: Vec<&AnyChannel<FlatSamples>> = if color.iter().all(|c| c.is_some()) {
+        color.iter().map(|c| c.unwrap()).chain(find("A")).collect()
+    } else if let Some(y) = find("Y") {
+        Some(y).into_iter().chain(find("A")).collect()
+    } else if layer.channel_data.list.len() == 1 {
+        vec![&layer.channel_data.list[0]]
+    } else {
+        return Err(ImageError::Unsupported("EXR without RGB or Y channels".to_string()));
+    };
+
+    let planes: Vec<Vec<f32>> = planes.iter().map(|c| c.sample_data.values_as_f32().collect()).collect();
+    let channels = planes.len();
+    let mut values = vec![0.0; width * height * channels];
+    for (c, plane) in planes.iter().enumerate() {
+        for (i, &v) in plane.iter().enumerate().take(width * height) {
+            values[i * channels + c] = v;
+        }
+    }
+
+    PixelBuffer::new(width, height, channels, Samples::F32(values))
+}
+
+fn encode_exr(pixels: &PixelBuffer) -> Result<Vec<u8>, ImageError> {
+    let names: &[&str] = match pixels.channels {
+        1 => &["Y"],
+        2 => &["Y", "A"],
+        3 => &["R", "G", "B"],
+        _ => &["R", "G", "B", "A"],
+    };
+
+    let values = pixels.samples.to_f32();
+    let list: SmallVec<[AnyChannel<FlatSamples>; 4]> = names.iter().enumerate().map(|(c, &name)| {
+        let plane = values.iter().skip(c).step_by(pixels.channels).cloned().collect();
+        AnyChannel::new(name, FlatSamples::F32(plane))
+    }).collect();
+
+    let image = exr::image::Image::from_channels((pixels.width, pixels.height), AnyChannels::sort(list));
+
+    let mut out = Cursor::new(Vec::new());
+    image.write().to_buffered(&mut out)?;
+    Ok(out.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_invalid(result: Result<PixelBuffer, ImageError>) -> bool {
+        matches!(result, Err(ImageError::Invalid(_)))
+    }
+
+    #[test]
+    fn ppm_round_trips() {
+        let rgb = PixelBuffer::new(2, 1, 3, Samples::U8(vec![0, 64, 128, 192, 255, 1])).unwrap();
+        let decoded = decode(&encode(&rgb, ImageFormat::Ppm).unwrap(), ImageFormat::Ppm).unwrap();
+        assert_eq!((decoded.width, decoded.height, decoded.channels), (2, 1, 3));
+        assert_eq!(decoded.samples.to_u8(), rgb.samples.to_u8());
+
+        let gray = PixelBuffer::new(1, 2, 1, Samples::U16(vec![1, 65535])).unwrap();
+        let decoded = decode(&encode(&gray, ImageFormat::Ppm).unwrap(), ImageFormat::Ppm).unwrap();
+        assert_eq!((decoded.width, decoded.height, decoded.channels), (1, 2, 1));
+        assert_eq!(decoded.samples.to_u16(), vec![1, 65535]);
+    }
+
+    #[test]
+    fn reads_ascii_ppm() {
+        let decoded = decode(b"P3\n# comment\n2 1\n15\n0 15 5  15 0 0\n", ImageFormat::Ppm).unwrap();
+        assert_eq!(decoded.samples.to_u8(), vec![0, 255, 85, 255, 0, 0]);
+    }
+
+    #[test]
+    fn rejects_malformed_ppm() {
+        assert!(is_invalid(decode(b"P6\n99999999999 99999999999\n255\n", ImageFormat::Ppm)));
+        assert!(is_invalid(decode(b"P5\n4294967296 4294967296\n65535\n", ImageFormat::Ppm)));
+        assert!(is_invalid(decode(b"P6\n2 2\n255\n\x01\x02\x03", ImageFormat::Ppm)));
+        assert!(is_invalid(decode(b"P6\n2 2\n255", ImageFormat::Ppm)));
+        assert!(is_invalid(decode(b"P2\n2 2\n255\n1 2 3", ImageFormat::Ppm)));
+        assert!(is_invalid(decode(b"P5\n1 1\n0\n\x00", ImageFormat::Ppm)));
+        assert!(is_invalid(decode(b"P5\n-1 1\n255\n\x00", ImageFormat::Ppm)));
+    }
+
+    #[test]
+    fn pfm_round_trips() {
+        let pixels = PixelBuffer::new(1, 2, 3, Samples::F32(vec![0.5, -1.0, 100.0, 0.0, 2.5, 1e-3])).unwrap();
+        let decoded = decode(&encode(&pixels, ImageFormat::Pfm).unwrap(), ImageFormat::Pfm).unwrap();
+        assert_eq!((decoded.width, decoded.height, decoded.channels), (1, 2, 3));
+        assert_eq!(decoded.samples.to_f32(), pixels.samples.to_f32());
+    }
+
+    #[test]
+    fn rejects_malformed_pfm() {
+        assert!(is_invalid(decode(b"PF\n9999999999999 9999999999999\n-1.0\n", ImageFormat::Pfm)));
+        assert!(is_invalid(decode(b"Pf\n2 1\n-1.0\n\x00\x00\x00\x00", ImageFormat::Pfm)));
+    }
+
+    #[test]
+    fn hdr_round_trips() {
+        let values = vec![0.0, 0.5, 1.0, 3.0, 1000.0, 0.25];
+        let pixels = PixelBuffer::new(2, 1, 3, Samples::F32(values.clone())).unwrap();
+        let decoded = decode(&encode(&pixels, ImageFormat::Hdr).unwrap(), ImageFormat::Hdr).unwrap();
+        assert_eq!((decoded.width, decoded.height, decoded.channels), (2, 1, 3));
+
+        // RGBE keeps 8 bits of mantissa relative to the brightest channel
+        let decoded = decoded.samples.to_f32();
+        for (pixel, original) in decoded.chunks(3).zip(values.chunks(3)) {
+            let max = original.iter().cloned().fold(0.0, f32::max);
+            for (a, b) in pixel.iter().zip(original) {
+                assert!((a - b).abs() <= max / 128.0);
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_hdr() {
+        let header = b"#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n".to_vec();
+        let with = |resolution: &[u8], data: &[u8]| [&header[..], resolution, data].concat();
+
+        assert!(is_invalid(decode(&with(b"-Y 2 +X 99999999999999999\n", b""), ImageFormat::Hdr)));
+        assert!(is_invalid(decode(&with(b"-Y 99999999999999999 +X 0\n", b""), ImageFormat::Hdr)));
+        assert!(is_invalid(decode(&with(b"-Y 1 +X 40000\n", b"\x00\x00"), ImageFormat::Hdr)));
+        assert!(is_invalid(decode(&with(b"-Y 1 +X 2\n", b"\x00\x00\x00\x00"), ImageFormat::Hdr)));
+        assert!(is_invalid(decode(&with(b"-Y 1 +X 8\n", b"\x02\x02\x00\x08\x88"), ImageFormat::Hdr)));
+    }
+
+    #[test]
+    fn rgbe_saturates_out_of_range_values() {
+        assert_eq!(rgb_to_rgbe(&[f32::MAX, 0.0, 0.0]), [255, 0, 0, 255]);
+        assert_eq!(rgb_to_rgbe(&[f32::INFINITY, 1.0, 0.0]), [255, 0, 0, 255]);
+        assert_eq!(rgb_to_rgbe(&[1e-35, 0.0, 0.0]), [0; 4]);
+        assert_eq!(rgb_to_rgbe(&[1.0, 0.5, 0.0]), [128, 64, 0, 129]);
+    }
+}
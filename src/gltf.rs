@@ -0,0 +1,928 @@
+use std;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use serde_json::{self, Value as Json, Map};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use percent_encoding::percent_decode_str;
+use vec::{Vec2, Vec3, Vec4, Quaternion};
+use matrix::{Matrix4x4, Transform};
+use obj::{Obj, Face, FacePoint};
+use image::Image;
+
+#[derive(Debug)]
+pub enum GltfError {
+    Io(std::io::Error),
+    Json(String),
+    Invalid(String),
+    Unsupported(String),
+    Image(String),
+}
+
+impl fmt::Display for GltfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            GltfError::Io(ref e) => write!(f, "{}", e),
+            GltfError::Json(ref msg) => write!(f, "bad JSON: {}", msg),
+            GltfError::Invalid(ref msg) => write!(f, "invalid glTF: {}", msg),
+            GltfError::Unsupported(ref msg) => write!(f, "unsupported glTF feature: {}", msg),
+            GltfError::Image(ref msg) => write!(f, "couldn't load texture: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for GltfError {}
+
+impl From<std::io::Error> for GltfError {
+    fn from(e: std::io::Error) -> GltfError {
+        GltfError::Io(e)
+    }
+}
+
+type Object = Map<String, Json>;
+
+fn invalid<T>(msg: &str) -> Result<T, GltfError> {
+    Err(GltfError::Invalid(msg.to_string()))
+}
+
+pub struct TextureRef {
+    pub texture: usize,
+    pub tex_coord: usize,
+}
+
+// The metallic-roughness PBR model from the core spec
+pub struct Material {
+    pub name: String,
+    pub base_color_factor: Vec4<f32>,
+    pub base_color_texture: Option<TextureRef>,
+    pub metallic_factor: f32,
+    pub roughness_factor: f32,
+    pub metallic_roughness_texture: Option<TextureRef>,
+    pub normal_texture: Option<TextureRef>,
+    pub occlusion_texture: Option<TextureRef>,
+    pub emissive_texture: Option<TextureRef>,
+    pub emissive_factor: Vec3<f32>,
+    pub double_sided: bool,
+}
+
+pub struct Primitive {
+    pub obj: Obj,
+    pub material: Option<usize>,
+}
+
+pub struct Mesh {
+    pub name: String,
+    pub primitives: Vec<Primitive>,
+}
+
+pub struct Node {
+    pub name: String,
+    pub parent: Option<usize>,
+    pub children: Vec<usize>,
+    pub transform: Transform,
+    pub mesh: Option<usize>,
+}
+
+pub struct Gltf {
+    pub meshes: Vec<Mesh>,
+    pub materials: Vec<Material>,
+    // Indexed by glTF texture index, so `TextureRef::texture` points here
+    pub textures: Vec<Image>,
+    pub nodes: Vec<Node>,
+    // Root nodes of the default scene
+    pub roots: Vec<usize>,
+}
+
+const GLB_MAGIC: u32 = 0x4654_6c67;
+const GLB_JSON_CHUNK: u32 = 0x4e4f_534a;
+const GLB_BIN_CHUNK: u32 = 0x004e_4942;
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    bytes[offset] as u32 | (bytes[offset + 1] as u32) << 8 |
+        (bytes[offset + 2] as u32) << 16 | (bytes[offset + 3] as u32) << 24
+}
+
+// Whether `count` elements of `size` bytes, `stride` apart and starting at
+// `offset`, fit in `len` bytes. Counts and offsets come straight from the
+// file, so this mustn't overflow.
+// The most floats an accessor without a buffer view may expand to
+const MAX_ZERO_FLOATS: usize = 1 << 26;
+
+fn fits(count: usize, stride: usize, offset: usize, size: usize, len: usize) -> bool {
+    if count == 0 {
+        return true;
+    }
+
+    (count - 1).checked_mul(stride)
+        .and_then(|end| end.checked_add(offset))
+        .and_then(|end| end.checked_add(size))
+        .is_some_and(|end| end <= len)
+}
+
+// Returns the JSON text and the embedded binary chunk, if any
+fn split_glb(bytes: &[u8]) -> Result<(String, Option<Vec<u8>>), GltfError> {
+    if bytes.len() < 20 || read_u32(bytes, 0) != GLB_MAGIC {
+        return invalid("not a binary glTF file");
+    }
+    if read_u32(bytes, 4) != 2 {
+        return Err(GltfError::Unsupported("only glTF 2.0 is supported".to_string()));
+    }
+
+    let mut json = None;
+    let mut bin = None;
+    let mut offset = 12;
+
+    while offset + 8 <= bytes.len() {
+        let length = read_u32(bytes, offset) as usize;
+        let kind = read_u32(bytes, offset + 4);
+        let start = offset + 8;
+
+        if start + length > bytes.len() {
+            return invalid("GLB chunk runs past the end of the file");
+        }
+
+        let chunk = &bytes[start..start + length];
+        match kind {
+            GLB_JSON_CHUNK => json = Some(String::from_utf8_lossy(chunk).into_owned()),
+            GLB_BIN_CHUNK => bin = Some(chunk.to_vec()),
+            _ => {}
+        }
+
+        offset = start + length;
+    }
+
+    match json {
+        Some(json) => Ok((json, bin)),
+        None => invalid("GLB file has no JSON chunk"),
+    }
+}
+
+// Small helpers for pulling typed values out of JSON objects
+
+fn get_array<'a>(obj: &'a Object, key: &str) -> &'a [Json] {
+    obj.get(key).and_then(|j| j.as_array()).map_or(&[], |a| &a[..])
+}
+
+fn get_usize(obj: &Object, key: &str) -> Option<usize> {
+    obj.get(key).and_then(|j| j.as_u64()).map(|n| n as usize)
+}
+
+fn get_f32(obj: &Object, key: &str, default: f32) -> f32 {
+    obj.get(key).and_then(|j| j.as_f64()).map_or(default, |n| n as f32)
+}
+
+fn get_str(obj: &Object, key: &str) -> String {
+    obj.get(key).and_then(|j| j.as_str()).unwrap_or("").to_string()
+}
+
+// `len` floats, if the key is there
+fn get_floats(obj: &Object, key: &str, len: usize) -> Result<Option<Vec<f32>>, GltfError> {
+    let floats: Vec<f32> = match obj.get(key).and_then(|j| j.as_array()) {
+        Some(a) => a.iter().map(|x| x.as_f64().unwrap_or(0.0) as f32).collect(),
+        None => return Ok(None),
+    };
+
+    if floats.len() != len {
+        return Err(GltfError::Invalid(format!("{} should have {} elements", key, len)));
+    }
+
+    Ok(Some(floats))
+}
+
+fn as_object(json: &Json) -> Result<&Object, GltfError> {
+    match json.as_object() {
+        Some(obj) => Ok(obj),
+        None => invalid("expected a JSON object"),
+    }
+}
+
+fn load_uri(uri: &str, base: &Path) -> Result<Vec<u8>, GltfError> {
+    if uri.starts_with("data:") {
+        let comma = match uri.find(',') {
+            Some(i) => i,
+            None => return invalid("malformed data URI"),
+        };
+
+        if !uri[..comma].ends_with(";base64") {
+            return Err(GltfError::Unsupported("non-base64 data URI".to_string()));
+        }
+
+        return BASE64.decode(&uri[comma + 1..]).map_err(|e| GltfError::Invalid(e.to_string()));
+    }
+
+    // Relative URIs are percent-encoded, so spaces and the like come in as
+    // %20 etc.
+    let path = match percent_decode_str(uri).decode_utf8() {
+        Ok(path) => path,
+        Err(_) => return invalid("URI isn't valid UTF-8 once decoded"),
+    };
+
+    let mut bytes = Vec::new();
+    File::open(base.join(&*path))?.read_to_end(&mut bytes)?;
+
+    Ok(bytes)
+}
+
+struct Loader<'a> {
+    root: &'a Object,
+    buffers: Vec<Vec<u8>>,
+}
+
+impl<'a> Loader<'a> {
+    fn section(&self, key: &str, index: usize) -> Result<&'a Object, GltfError> {
+        match get_array(self.root, key).get(index) {
+            Some(json) => as_object(json),
+            None => Err(GltfError::Invalid(format!("{} {} doesn't exist", key, index))),
+        }
+    }
+
+    fn buffer_view(&self, index: usize) -> Result<(&[u8], Option<usize>), GltfError> {
+        let view = self.section("bufferViews", index)?;
+        let buffer = match get_usize(view, "buffer").and_then(|b| self.buffers.get(b)) {
+            Some(b) => b,
+            None => return invalid("buffer view references a missing buffer"),
+        };
+
+        let offset = get_usize(view, "byteOffset").unwrap_or(0);
+        let length = get_usize(view, "byteLength").unwrap_or(0);
+
+        if offset.checked_add(length).is_none_or(|end| end > buffer.len()) {
+            return invalid("buffer view runs past the end of its buffer");
+        }
+
+        Ok((&buffer[offset..offset + length], get_usize(view, "byteStride")))
+    }
+
+    // Reads any accessor as floats, `components` per element. Normalized
+    // integer accessors are mapped into [0, 1] or [-1, 1].
+    fn read_accessor(&self, index: usize) -> Result<(Vec<f32>, usize), GltfError> {
+        let accessor = self.section("accessors", index)?;
+
+        let count = get_usize(accessor, "count").unwrap_or(0);
+        let components = match &get_str(accessor, "type")[..] {
+            "SCALAR" => 1,
+            "VEC2" => 2,
+            "VEC3" => 3,
+            "VEC4" => 4,
+            "MAT4" => 16,
+            other => return Err(GltfError::Unsupported(format!("accessor type {}", other))),
+        };
+        let component_type = get_usize(accessor, "componentType").unwrap_or(0);
+        let size = match component_type {
+            5120 | 5121 => 1,
+            5122 | 5123 => 2,
+            5125 | 5126 => 4,
+            _ => return invalid("unknown accessor component type"),
+        };
+        let normalized = accessor.get("normalized").and_then(|j| j.as_bool()).unwrap_or(false);
+
+        let total = match count.checked_mul(components) {
+            Some(total) => total,
+            None => return invalid("accessor is too large"),
+        };
+
+        let view = match get_usize(accessor, "bufferView") {
+            Some(v) => v,
+            // Accessors without a view are all zeros. No data bounds their
+            // count, so it's capped instead.
+            None if total > MAX_ZERO_FLOATS => return invalid("accessor without a buffer view is too large"),
+            None => return Ok((vec![0.0; total], components)),
+        };
+
+        if accessor.contains_key("sparse") {
+            return Err(GltfError::Unsupported("sparse accessors".to_string()));
+        }
+
+        let (data, stride) = self.buffer_view(view)?;
+        let offset = get_usize(accessor, "byteOffset").unwrap_or(0);
+        let stride = stride.unwrap_or(size * components);
+        // Overlapping elements would let `count` exceed the data
+        if stride < size * components {
+            return invalid("buffer view stride is smaller than its elements");
+        }
+
+        if !fits(count, stride, offset, size * components, data.len()) {
+            return invalid("accessor runs past the end of its buffer view");
+        }
+
+        let mut out = Vec::with_capacity(total);
+        for i in 0..count {
+            for c in 0..components {
+                let at = offset + i * stride + c * size;
+                let b = &data[at..at + size];
+                let value = match component_type {
+                    5120 => {
+                        let v = b[0] as i8 as f32;
+                        if normalized { (v / 127.0).max(-1.0) } else { v }
+                    }
+                    5121 => {
+                        let v = b[0] as f32;
+                        if normalized { v / 255.0 } else { v }
+                    }
+                    5122 => {
+                        let v = (b[0] as u16 | (b[1] as u16) << 8) as i16 as f32;
+                        if normalized { (v / 32767.0).max(-1.0) } else { v }
+                    }
+                    5123 => {
+                        let v = (b[0] as u16 | (b[1] as u16) << 8) as f32;
+                        if normalized { v / 65535.0 } else { v }
+                    }
+                    5125 => read_u32(b, 0) as f32,
+                    _ => f32::from_bits(read_u32(b, 0)),
+                };
+                out.push(value);
+            }
+        }
+
+        Ok((out, components))
+    }
+
+    // Indices are read separately so large u32 values don't lose precision
+    // through f32
+    fn read_indices(&self, index: usize) -> Result<Vec<usize>, GltfError> {
+        let accessor = self.section("accessors", index)?;
+        let count = get_usize(accessor, "count").unwrap_or(0);
+        let size = match get_usize(accessor, "componentType") {
+            Some(5121) => 1,
+            Some(5123) => 2,
+            Some(5125) => 4,
+            _ => return invalid("index accessors must be unsigned integers"),
+        };
+
+        let view = match get_usize(accessor, "bufferView") {
+            Some(v) => v,
+            None => return invalid("index accessor has no buffer view"),
+        };
+        let (data, stride) = self.buffer_view(view)?;
+        let offset = get_usize(accessor, "byteOffset").unwrap_or(0);
+        let stride = stride.unwrap_or(size);
+
+        if !fits(count, stride, offset, size, data.len()) {
+            return invalid("index accessor runs past the end of its buffer view");
+        }
+
+        let mut out = Vec::with_capacity(count);
+        for i in 0..count {
+            let at = offset + i * stride;
+            out.push(match size {
+                1 => data[at] as usize,
+                2 => (data[at] as usize) | (data[at + 1] as usize) << 8,
+                _ => read_u32(data, at) as usize,
+            });
+        }
+
+        Ok(out)
+    }
+
+    // Reads a vertex attribute if the primitive has it, checking its type is
+    // one of `components` and that it has `count` elements
+    fn attribute(&self, attributes: &Object, name: &str, components: &[usize], count: Option<usize>) -> Result<Option<(Vec<f32>, usize)>, GltfError> {
+        let index = match get_usize(attributes, name) {
+            Some(i) => i,
+            None => return Ok(None),
+        };
+
+        let (data, n) = self.read_accessor(index)?;
+        if !components.contains(&n) {
+            return Err(GltfError::Invalid(format!("{} accessor has the wrong type", name)));
+        }
+        if count.is_some_and(|count| data.len() / n != count) {
+            return Err(GltfError::Invalid(format!("{} accessor doesn't have one element per vertex", name)));
+        }
+
+        Ok(Some((data, n)))
+    }
+
+    fn primitive(&self, json: &Object) -> Result<Primitive, GltfError> {
+        let attributes = match json.get("attributes").and_then(|j| j.as_object()) {
+            Some(a) => a,
+            None => return invalid("primitive has no attributes"),
+        };
+
+        let position = match self.attribute(attributes, "POSITION", &[3], None)? {
+            Some((p, _)) => p,
+            None => return invalid("primitive has no POSITION attribute"),
+        };
+        let vertex_count = position.len() / 3;
+
+        let normal = self.attribute(attributes, "NORMAL", &[3], Some(vertex_count))?.map(|n| n.0);
+        let texcoord = self.attribute(attributes, "TEXCOORD_0", &[2], Some(vertex_count))?.map(|t| t.0);
+        let color = self.attribute(attributes, "COLOR_0", &[3, 4], Some(vertex_count))?;
+
+        let mut obj = Obj::new();
+
+        for i in 0..vertex_count {
+            let pos = Vec3 { x: position[i * 3], y: position[i * 3 + 1], z: position[i * 3 + 2] };
+
+            match color {
+                // Colors may be RGB or RGBA; alpha is dropped
+                Some((ref c, n)) => {
+                    obj.add_colored_vert(pos, Vec3 { x: c[i * n], y: c[i * n + 1], z: c[i * n + 2] });
+                }
+                None => {
+                    obj.add_vert(pos);
+                }
+            }
+        }
+        if let Some(ref n) = normal {
+            for i in 0..vertex_count {
+                obj.add_norm_vert(Vec3 { x: n[i * 3], y: n[i * 3 + 1], z: n[i * 3 + 2] });
+            }
+        }
+        if let Some(ref t) = texcoord {
+            // glTF puts the texture origin in the top left, OBJ in the bottom left
+            for i in 0..vertex_count {
+                obj.add_tex_vert(Vec2 { x: t[i * 2], y: 1.0 - t[i * 2 + 1] });
+            }
+        }
+
+        let indices = match get_usize(json, "indices") {
+            Some(i) => self.read_indices(i)?,
+            None => (0..vertex_count).collect(),
+        };
+
+        if let Some(&i) = indices.iter().find(|&&i| i >= vertex_count) {
+            return Err(GltfError::Invalid(format!("index {} is out of range", i)));
+        }
+
+        // Missing attributes get index 0, which no OBJ file can reference
+        let point = |i: usize| FacePoint {
+            vindex: i + 1,
+            tindex: if texcoord.is_some() { i + 1 } else { 0 },
+            nindex: if normal.is_some() { i + 1 } else { 0 },
+        };
+
+        let triangles: Vec<(usize, usize, usize)> = match get_usize(json, "mode").unwrap_or(4) {
+            4 => indices.chunks(3).filter(|c| c.len() == 3).map(|c| (c[0], c[1], c[2])).collect(),
+            // Strips alternate winding so every triangle faces the same way
+            5 => (2..indices.len()).map(|i| if i % 2 == 0 {
+                (indices[i - 2], indices[i - 1], indices[i])
+            } else {
+                (indices[i - 1], indices[i - 2], indices[i])
+            }).collect(),
+            6 => (2..indices.len()).map(|i| (indices[0], indices[i - 1], indices[i])).collect(),
+            mode => return Err(GltfError::Unsupported(format!("primitive mode {}", mode))),
+        };
+
+        for (a, b, c) in triangles {
+            obj.add_face(Face(point(a), point(b), point(c)));
+        }
+
+        Ok(Primitive {
+            obj,
+            material: get_usize(json, "material"),
+        })
+    }
+
+    fn texture(&self, index: usize, base: &Path) -> Result<Image, GltfError> {
+        let texture = self.section("textures", index)?;
+        let source = match get_usize(texture, "source") {
+            Some(s) => self.section("images", s)?,
+            None => return invalid("texture has no source image"),
+        };
+
+        let bytes = if let Some(view) = get_usize(source, "bufferView") {
+            self.buffer_view(view)?.0.to_vec()
+        } else {
+            load_uri(&get_str(source, "uri"), base)?
+        };
+
+        Image::from_bytes(&bytes).map_err(|e| GltfError::Image(e.to_string()))
+    }
+}
+
+fn texture_ref(obj: &Object, key: &str) -> Option<TextureRef> {
+    obj.get(key).and_then(|j| j.as_object()).and_then(|t| {
+        get_usize(t, "index").map(|texture| TextureRef {
+            texture,
+            tex_coord: get_usize(t, "texCoord").unwrap_or(0),
+        })
+    })
+}
+
+fn material(json: &Object) -> Result<Material, GltfError> {
+    let empty = Object::new();
+    let pbr = json.get("pbrMetallicRoughness").and_then(|j| j.as_object()).unwrap_or(&empty);
+
+    let color = get_floats(pbr, "baseColorFactor", 4)?.unwrap_or_else(|| vec![1.0; 4]);
+    let emissive = get_floats(json, "emissiveFactor", 3)?.unwrap_or_else(|| vec![0.0; 3]);
+
+    Ok(Material {
+        name: get_str(json, "name"),
+        base_color_factor: Vec4 { x: color[0], y: color[1], z: color[2], w: color[3] },
+        base_color_texture: texture_ref(pbr, "baseColorTexture"),
+        metallic_factor: get_f32(pbr, "metallicFactor", 1.0),
+        roughness_factor: get_f32(pbr, "roughnessFactor", 1.0),
+        metallic_roughness_texture: texture_ref(pbr, "metallicRoughnessTexture"),
+        normal_texture: texture_ref(json, "normalTexture"),
+        occlusion_texture: texture_ref(json, "occlusionTexture"),
+        emissive_texture: texture_ref(json, "emissiveTexture"),
+        emissive_factor: Vec3 { x: emissive[0], y: emissive[1], z: emissive[2] },
+        double_sided: json.get("doubleSided").and_then(|j| j.as_bool()).unwrap_or(false),
+    })
+}
+
+fn node_transform(json: &Object) -> Result<Transform, GltfError> {
+    if let Some(m) = get_floats(json, "matrix", 16)? {
+        return Ok(Matrix4x4::from_column_major(&m).decompose());
+    }
+
+    let mut transform = Transform::identity();
+    if let Some(t) = get_floats(json, "translation", 3)? {
+        transform.translation = Vec3 { x: t[0], y: t[1], z: t[2] };
+    }
+    if let Some(r) = get_floats(json, "rotation", 4)? {
+        transform.rotation = Quaternion { x: r[0], y: r[1], z: r[2], w: r[3] };
+    }
+    if let Some(s) = get_floats(json, "scale", 3)? {
+        transform.scale = Vec3 { x: s[0], y: s[1], z: s[2] };
+    }
+
+    Ok(transform)
+}
+
+impl Gltf {
+    // Handles both .gltf (JSON with external or data URI buffers) and .glb
+    pub fn from_file(filename: &str) -> Result<Gltf, GltfError> {
+        let mut bytes = Vec::new();
+        File::open(filename)?.read_to_end(&mut bytes)?;
+
+        let base = Path::new(filename).parent().map_or(PathBuf::new(), |p| p.to_path_buf());
+
+        Gltf::from_bytes(&bytes, &base)
+    }
+
+    // The contents of a .gltf or .glb file. External buffers and images are
+    // looked up relative to `base`.
+    pub fn from_bytes(bytes: &[u8], base: &Path) -> Result<Gltf, GltfError> {
+        let (text, glb_bin) = if bytes.starts_with(b"glTF") {
+            split_glb(bytes)?
+        } else {
+            (String::from_utf8_lossy(bytes).into_owned(), None)
+        };
+
+        let json: Json = serde_json::from_str(&text).map_err(|e| GltfError::Json(e.to_string()))?;
+        let root = as_object(&json)?;
+
+        let mut buffers = Vec::new();
+        for (i, buffer) in get_array(root, "buffers").iter().enumerate() {
+            let buffer = as_object(buffer)?;
+
+            buffers.push(match buffer.get("uri").and_then(|u| u.as_str()) {
+                Some(uri) => load_uri(uri, base)?,
+                // Only the first buffer of a GLB may omit its URI
+                None if i == 0 && glb_bin.is_some() => glb_bin.clone().unwrap(),
+                None => return invalid("buffer has no data"),
+            });
+        }
+
+        let loader = Loader { root, buffers };
+
+        let mut meshes = Vec::new();
+        for mesh in get_array(root, "meshes") {
+            let mesh = as_object(mesh)?;
+            let mut primitives = Vec::new();
+
+            for primitive in get_array(mesh, "primitives") {
+                primitives.push(loader.primitive(as_object(primitive)?)?);
+            }
+
+            meshes.push(Mesh { name: get_str(mesh, "name"), primitives });
+        }
+
+        let mut materials = Vec::new();
+        for m in get_array(root, "materials") {
+            materials.push(material(as_object(m)?)?);
+        }
+
+        let mut textures = Vec::new();
+        for i in 0..get_array(root, "textures").len() {
+            textures.push(loader.texture(i, base)?);
+        }
+
+        let mut nodes = Vec::new();
+        for node in get_array(root, "nodes") {
+            let node = as_object(node)?;
+
+            nodes.push(Node {
+                name: get_str(node, "name"),
+                parent: None,
+                children: get_array(node, "children").iter()
+                    .filter_map(|c| c.as_u64().map(|c| c as usize))
+                    .collect(),
+                transform: node_transform(node)?,
+                mesh: get_usize(node, "mesh"),
+            });
+        }
+
+        for i in 0..nodes.len() {
+            for c in nodes[i].children.clone() {
+                if c >= nodes.len() || nodes[c].parent.is_some() {
+                    return invalid("node hierarchy isn't a forest");
+                }
+                nodes[c].parent = Some(i);
+            }
+        }
+
+        // Every node has at most one parent now, but a node can still be
+        // its own ancestor, which would send instances() round forever
+        let mut acyclic = vec![false; nodes.len()];
+        for start in 0..nodes.len() {
+            let mut visited = HashSet::new();
+            let mut current = Some(start);
+
+            while let Some(i) = current {
+                if acyclic[i] {
+                    break;
+                }
+                if !visited.insert(i) {
+                    return invalid("node hierarchy has a cycle");
+                }
+                current = nodes[i].parent;
+            }
+
+            for i in visited {
+                acyclic[i] = true;
+            }
+        }
+
+        let scenes = get_array(root, "scenes");
+        let roots: Vec<usize> = match scenes.get(get_usize(root, "scene").unwrap_or(0)) {
+            Some(scene) => get_array(as_object(scene)?, "nodes").iter()
+                .filter_map(|n| n.as_u64().map(|n| n as usize))
+                .collect(),
+            None => (0..nodes.len()).filter(|&i| nodes[i].parent.is_none()).collect(),
+        };
+
+        if roots.iter().any(|&r| r >= nodes.len()) {
+            return invalid("scene references a node that doesn't exist");
+        }
+
+        Ok(Gltf {
+            meshes,
+            materials,
+            textures,
+            nodes,
+            roots,
+        })
+    }
+
+    pub fn world_transform(&self, node: usize) -> Matrix4x4<f32> {
+        let local = self.nodes[node].transform.to_matrix();
+
+        match self.nodes[node].parent {
+            Some(p) => self.world_transform(p) * local,
+            None => local,
+        }
+    }
+
+    // Every mesh placed in the default scene, with its model matrix
+    pub fn instances(&self) -> Vec<(&Mesh, Matrix4x4<f32>)> {
+        let mut out = Vec::new();
+        let mut stack: Vec<(usize, Matrix4x4<f32>)> =
+            self.roots.iter().map(|&r| (r, Matrix4x4::identity())).collect();
+
+        while let Some((index, parent)) = stack.pop() {
+            let node = &self.nodes[index];
+            let world = parent * node.transform.to_matrix();
+
+            if let Some(mesh) = node.mesh.and_then(|m| self.meshes.get(m)) {
+                out.push((mesh, world.clone()));
+            }
+            for &child in node.children.iter() {
+                stack.push((child, world.clone()));
+            }
+        }
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    fn floats_to_bytes(floats: &[f32]) -> Vec<u8> {
+        floats.iter().flat_map(|f| f.to_le_bytes().to_vec()).collect()
+    }
+
+    // One triangle with positions and normals, placed by a single node
+    fn triangle() -> Json {
+        let buffer = floats_to_bytes(&[
+            0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0,
+            0.0, 0.0, 1.0, 0.0, 0.0, 1.0, 0.0, 0.0, 1.0,
+        ]);
+
+        let text = r#"{
+            "asset": {"version": "2.0"},
+            "buffers": [{"byteLength": 72, "uri": "URI"}],
+            "bufferViews": [
+                {"buffer": 0, "byteOffset": 0, "byteLength": 36},
+                {"buffer": 0, "byteOffset": 36, "byteLength": 36}
+            ],
+            "accessors": [
+                {"bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3"},
+                {"bufferView": 1, "componentType": 5126, "count": 3, "type": "VEC3"}
+            ],
+            "materials": [{"pbrMetallicRoughness": {"baseColorFactor": [1, 0, 0, 1]}}],
+            "meshes": [{"primitives": [{"attributes": {"POSITION": 0, "NORMAL": 1}, "material": 0}]}],
+            "nodes": [{"mesh": 0, "translation": [1, 2, 3]}],
+            "scenes": [{"nodes": [0]}]
+        }"#;
+        let uri = format!("data:application/octet-stream;base64,{}", BASE64.encode(&buffer));
+
+        serde_json::from_str(&text.replace("URI", &uri)).unwrap()
+    }
+
+    fn load(json: &Json) -> Result<Gltf, GltfError> {
+        Gltf::from_bytes(json.to_string().as_bytes(), Path::new("."))
+    }
+
+    // Sets the object member at a JSON pointer, adding it if need be
+    fn set(json: &mut Json, pointer: &str, value: &str) {
+        let split = pointer.rfind('/').unwrap();
+        let parent = json.pointer_mut(&pointer[..split]).unwrap().as_object_mut().unwrap();
+
+        parent.insert(pointer[split + 1..].to_string(), serde_json::from_str(value).unwrap());
+    }
+
+    fn assert_invalid(result: Result<Gltf, GltfError>) {
+        match result {
+            Err(GltfError::Invalid(_)) => {}
+            Err(e) => panic!("expected an invalid glTF error, got {}", e),
+            Ok(_) => panic!("expected an invalid glTF error"),
+        }
+    }
+
+    #[test]
+    fn loads_a_triangle() {
+        let gltf = load(&triangle()).unwrap();
+        let obj = &gltf.meshes[0].primitives[0].obj;
+
+        assert_eq!(obj.faces().len(), 1);
+        assert_eq!(obj.vert(2).x, 1.0);
+        assert_eq!(obj.norm_vert(1).z, 1.0);
+        assert_eq!(gltf.materials[0].base_color_factor.y, 0.0);
+
+        let instances = gltf.instances();
+        assert_eq!(instances.len(), 1);
+        assert_eq!(instances[0].1.transform_point(Vec3 { x: 0.0, y: 0.0, z: 0.0 }).y, 2.0);
+    }
+
+    #[test]
+    fn loads_glb() {
+        let mut json = triangle();
+        let data = json["buffers"][0]["uri"].as_str().unwrap().split(',').nth(1).unwrap().to_string();
+        let mut bin = BASE64.decode(data).unwrap();
+        json["buffers"][0].as_object_mut().unwrap().remove("uri");
+
+        // Chunks are padded to four bytes, JSON with spaces
+        let mut text = json.to_string().into_bytes();
+        while !text.len().is_multiple_of(4) {
+            text.push(b' ');
+        }
+        while !bin.len().is_multiple_of(4) {
+            bin.push(0);
+        }
+
+        let mut glb = Vec::new();
+        glb.extend_from_slice(&GLB_MAGIC.to_le_bytes());
+        glb.extend_from_slice(&2u32.to_le_bytes());
+        glb.extend_from_slice(&((12 + 8 + text.len() + 8 + bin.len()) as u32).to_le_bytes());
+        glb.extend_from_slice(&(text.len() as u32).to_le_bytes());
+        glb.extend_from_slice(&GLB_JSON_CHUNK.to_le_bytes());
+        glb.extend_from_slice(&text);
+        glb.extend_from_slice(&(bin.len() as u32).to_le_bytes());
+        glb.extend_from_slice(&GLB_BIN_CHUNK.to_le_bytes());
+        glb.extend_from_slice(&bin);
+
+        let gltf = Gltf::from_bytes(&glb, Path::new(".")).unwrap();
+        assert_eq!(gltf.meshes[0].primitives[0].obj.faces().len(), 1);
+    }
+
+    #[test]
+    fn decodes_external_uris() {
+        let mut json = triangle();
+        let data = json["buffers"][0]["uri"].as_str().unwrap().split(',').nth(1).unwrap().to_string();
+
+        let dir = env::temp_dir().join("rust-sdr-gltf-test");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("tri buffer#1.bin"), BASE64.decode(data).unwrap()).unwrap();
+
+        set(&mut json, "/buffers/0/uri", r#""tri%20buffer%231.bin""#);
+        let gltf = Gltf::from_bytes(json.to_string().as_bytes(), &dir).unwrap();
+
+        assert_eq!(gltf.meshes[0].primitives[0].obj.faces().len(), 1);
+    }
+
+    #[test]
+    fn rejects_node_cycles() {
+        let mut json = triangle();
+        set(&mut json, "/nodes/0/children", "[0]");
+        assert_invalid(load(&json));
+
+        let mut json = triangle();
+        set(&mut json, "/nodes", r#"[{"mesh": 0, "children": [1]}, {"children": [0]}]"#);
+        assert_invalid(load(&json));
+    }
+
+    #[test]
+    fn rejects_missing_nodes() {
+        let mut json = triangle();
+        set(&mut json, "/scenes/0/nodes", "[0, 5]");
+        assert_invalid(load(&json));
+
+        let mut json = triangle();
+        set(&mut json, "/nodes/0/children", "[3]");
+        assert_invalid(load(&json));
+    }
+
+    #[test]
+    fn rejects_short_arrays() {
+        for &(pointer, value) in &[
+            ("/nodes/0/translation", "[1, 2]"),
+            ("/nodes/0/translation", "[]"),
+            ("/nodes/0/scale", "[1]"),
+            ("/nodes/0/rotation", "[0, 0, 0]"),
+            ("/nodes/0/matrix", "[1, 0, 0]"),
+            ("/materials/0/pbrMetallicRoughness/baseColorFactor", "[1, 1, 1]"),
+            ("/materials/0/emissiveFactor", "[1, 1]"),
+        ] {
+            let mut json = triangle();
+            set(&mut json, pointer, value);
+            assert_invalid(load(&json));
+        }
+    }
+
+    #[test]
+    fn rejects_mismatched_accessors() {
+        // Fewer normals than positions
+        let mut json = triangle();
+        set(&mut json, "/accessors/1/count", "2");
+        assert_invalid(load(&json));
+
+        // Positions need three components
+        let mut json = triangle();
+        set(&mut json, "/accessors/0/type", r#""VEC2""#);
+        assert_invalid(load(&json));
+
+        let mut json = triangle();
+        set(&mut json, "/meshes/0/primitives/0/attributes/NORMAL", "0");
+        set(&mut json, "/accessors/0/type", r#""VEC4""#);
+        assert_invalid(load(&json));
+    }
+
+    #[test]
+    fn rejects_out_of_range_data() {
+        let mut json = triangle();
+        set(&mut json, "/accessors/0/count", "4");
+        assert_invalid(load(&json));
+
+        let mut json = triangle();
+        set(&mut json, "/accessors/0/count", "18446744073709551615");
+        assert_invalid(load(&json));
+
+        let mut json = triangle();
+        set(&mut json, "/accessors/0/byteOffset", "18446744073709551615");
+        assert_invalid(load(&json));
+
+        let mut json = triangle();
+        set(&mut json, "/bufferViews/0/byteOffset", "18446744073709551615");
+        assert_invalid(load(&json));
+
+        let mut json = triangle();
+        set(&mut json, "/bufferViews/0/byteStride", "0");
+        set(&mut json, "/accessors/0/count", "1000000000000000000");
+        assert_invalid(load(&json));
+    }
+
+    #[test]
+    fn accessors_without_views_are_zeros() {
+        let mut json = triangle();
+        json["accessors"][1].as_object_mut().unwrap().remove("bufferView");
+        let gltf = load(&json).unwrap();
+        assert_eq!(gltf.meshes[0].primitives[0].obj.norm_vert(1).z, 0.0);
+
+        for count in ["1000000000000000000", "1000000000"] {
+            let mut json = triangle();
+            json["accessors"][0].as_object_mut().unwrap().remove("bufferView");
+            set(&mut json, "/accessors/0/count", count);
+            assert_invalid(load(&json));
+        }
+    }
+
+    #[test]
+    fn rejects_deeply_nested_json() {
+        let text = "[".repeat(100_000);
+
+        match Gltf::from_bytes(text.as_bytes(), Path::new(".")) {
+            Err(GltfError::Json(_)) => {}
+            _ => panic!("expected a JSON error"),
+        }
+    }
+}
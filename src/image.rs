@@ -1,5 +1,6 @@
-use imagefmt;
-use imagefmt::{ColFmt, ColType};
+use vec::Vec3;
+use formats;
+use formats::{ImageError, PixelBuffer};
 
 #[derive(Clone, Copy)]
 pub struct Color(pub u8, pub u8, pub u8);
@@ -10,6 +11,21 @@ pub const RED: Color = Color(255, 0, 0);
 pub const GREEN: Color = Color(0, 255, 0);
 
 impl Color {
+    // Components in [0, 1], clamped
+    pub fn from_vec(v: Vec3<f32>) -> Color {
+        let to_byte = |c: f32| (c.clamp(0.0, 1.0) * 255.0).round() as u8;
+
+        Color(to_byte(v.x), to_byte(v.y), to_byte(v.z))
+    }
+
+    pub fn to_vec(self) -> Vec3<f32> {
+        Vec3 {
+            x: self.0 as f32 / 255.0,
+            y: self.1 as f32 / 255.0,
+            z: self.2 as f32 / 255.0,
+        }
+    }
+
     pub fn multiply(self, other: &Color) -> Color {
         Color(
             (self.0 as usize * other.0 as usize / 255) as u8,
@@ -52,43 +68,74 @@ impl Image {
         }
     }
 
+    // Mixes `color` over what's already there; `alpha` is in [0, 1]
+    pub fn blend_pixel(&mut self, x: usize, y: usize, color: &Color, alpha: f32) {
+        if x < self.width && y < self.height {
+            let index = ((self.height - y - 1) * self.width + x) * 3;
+            let alpha = alpha.clamp(0.0, 1.0);
+            let mix = |dst: u8, src: u8| (dst as f32 + (src as f32 - dst as f32) * alpha).round() as u8;
+
+            self.data[index] = mix(self.data[index], color.0);
+            self.data[index+1] = mix(self.data[index+1], color.1);
+            self.data[index+2] = mix(self.data[index+2], color.2);
+        }
+    }
+
+    // Images loaded from files have no depth buffer; everything is at the
+    // far plane
+    pub fn depth(&self, x: usize, y: usize) -> isize {
+        if x < self.width && y < self.height && !self.zbuffer.is_empty() {
+            self.zbuffer[(self.height - y - 1) * self.width + x]
+        } else {
+            isize::MIN
+        }
+    }
+
     pub fn get_pixel(&self, x: usize, y: usize) -> Color {
         let index = ((self.height - y - 1) * self.width + x) * 3;
         Color(self.data[index], self.data[index + 1], self.data[index + 2])
     }
 
-    pub fn write(&self, filename: &str) -> imagefmt::Result<()> {
-        imagefmt::write(
-            filename,
-            self.width,
-            self.height,
-            ColFmt::RGB,
-            &self.data,
-            ColType::Color)
+    // The format comes from the extension
+    pub fn write(&self, filename: &str) -> Result<(), ImageError> {
+        formats::write_file(filename, &PixelBuffer::from_image(self))
     }
 
     pub fn new(width: usize, height: usize) -> Image {
-        let mut data = Vec::with_capacity(width * height * 3);
-        data.resize(width * height * 3, 0);
-        let mut zbuffer = Vec::with_capacity(width * height);
-        zbuffer.resize(width * height, isize::min_value());
+        let data = vec![0; width * height * 3];
+        let zbuffer = vec![isize::MIN; width * height];
 
         Image {
-            data: data,
-            zbuffer: zbuffer,
-            width: width,
-            height: height,
+            data,
+            zbuffer,
+            width,
+            height,
         }
     }
 
-    pub fn from(filename: &str) -> Image {
-        let img = imagefmt::read(filename, ColFmt::RGB).unwrap();
+    pub fn from(filename: &str) -> Result<Image, ImageError> {
+        Ok(Image::from_pixels(&formats::read_file(filename)?))
+    }
+
+    // For images embedded in other files, e.g. glTF buffers
+    pub fn from_bytes(bytes: &[u8]) -> Result<Image, ImageError> {
+        let format = match formats::ImageFormat::detect(bytes) {
+            Some(format) => format,
+            // TGA is the only supported format without a signature
+            None => formats::ImageFormat::Tga,
+        };
+
+        Ok(Image::from_pixels(&formats::decode(bytes, format)?))
+    }
 
+    // Gray becomes RGB and alpha is dropped. High dynamic range pixels are
+    // clamped to [0, 1], without any tone mapping.
+    pub fn from_pixels(pixels: &PixelBuffer) -> Image {
         Image {
-            data: img.buf,
+            data: pixels.with_channels(3).samples.to_u8(),
             zbuffer: Vec::new(),
-            width: img.w,
-            height: img.h,
+            width: pixels.width,
+            height: pixels.height,
         }
     }
 }
@@ -0,0 +1,448 @@
+use std::cmp;
+use std::mem;
+use vec::{Vec2, Vec3};
+use image::{Image, Color};
+
+// The rasterizers here don't touch an image themselves. They call
+// `plot(x, y, coverage, t)` for every pixel they cover, where coverage is
+// in [0, 1] and t is how far along the line the pixel is, so callers can
+// interpolate depth or varyings however they like. Only pixels inside a
+// target of `size` (width, height) are visited, so end points can be
+// anywhere, e.g. projected from just in front of the eye.
+
+#[derive(Clone, Copy)]
+pub struct LineStyle {
+    // In pixels
+    pub width: f32,
+    pub antialiased: bool,
+    pub depth_test: bool,
+    // Added to the line's depth before testing, so lines drawn over the
+    // surface they came from don't fight with it
+    pub depth_bias: f32,
+}
+
+impl LineStyle {
+    pub fn new() -> LineStyle {
+        LineStyle {
+            width: 1.0,
+            antialiased: false,
+            depth_test: true,
+            depth_bias: 2.0,
+        }
+    }
+
+    pub fn biased_depth(&self, depth: f32) -> Option<f32> {
+        if self.depth_test { Some(depth + self.depth_bias) } else { None }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum PointShape {
+    Square,
+    Circle,
+}
+
+#[derive(Clone, Copy)]
+pub struct PointStyle {
+    // Diameter, in pixels
+    pub size: f32,
+    pub shape: PointShape,
+    pub antialiased: bool,
+    pub depth_test: bool,
+    pub depth_bias: f32,
+}
+
+impl PointStyle {
+    pub fn new() -> PointStyle {
+        PointStyle {
+            size: 3.0,
+            shape: PointShape::Square,
+            antialiased: false,
+            depth_test: true,
+            depth_bias: 2.0,
+        }
+    }
+
+    pub fn biased_depth(&self, depth: f32) -> Option<f32> {
+        if self.depth_test { Some(depth + self.depth_bias) } else { None }
+    }
+}
+
+// The part of the segment within `margin` pixels of the target, as the
+// clipped end points and their t along the whole segment (Liang-Barsky)
+fn clip_segment(from: Vec2<f32>, to: Vec2<f32>, size: (usize, usize), margin: f32) -> Option<(Vec2<f32>, Vec2<f32>, f32, f32)> {
+    if !(from.x.is_finite() && from.y.is_finite() && to.x.is_finite() && to.y.is_finite()) {
+        return None;
+    }
+
+    let (min_x, min_y) = (-margin, -margin);
+    let (max_x, max_y) = (size.0 as f32 - 1.0 + margin, size.1 as f32 - 1.0 + margin);
+    let (dx, dy) = (to.x - from.x, to.y - from.y);
+    let (mut t0, mut t1) = (0.0f32, 1.0f32);
+
+    for &(p, q) in [(-dx, from.x - min_x), (dx, max_x - from.x), (-dy, from.y - min_y), (dy, max_y - from.y)].iter() {
+        if p == 0.0 {
+            if q < 0.0 {
+                return None;
+            }
+        } else if p < 0.0 {
+            t0 = t0.max(q / p);
+        } else {
+            t1 = t1.min(q / p);
+        }
+    }
+
+    // Segments that are already inside keep their exact end points
+    let at = |t: f32| if t == 0.0 { from } else if t == 1.0 { to } else { Vec2 { x: from.x + dx * t, y: from.y + dy * t } };
+    if t0 <= t1 { Some((at(t0), at(t1), t0, t1)) } else { None }
+}
+
+// Drops pixels outside the target and maps t on a clipped segment back to
+// the whole one
+fn clipped<F: FnMut(isize, isize, f32, f32)>(size: (usize, usize), t0: f32, t1: f32, mut plot: F) -> impl FnMut(isize, isize, f32, f32) {
+    move |x, y, coverage, t| {
+        if x >= 0 && y >= 0 && (x as usize) < size.0 && (y as usize) < size.1 {
+            plot(x, y, coverage, t0 + (t1 - t0) * t);
+        }
+    }
+}
+
+pub fn bresenham<F: FnMut(isize, isize, f32, f32)>(from: Vec2<f32>, to: Vec2<f32>, size: (usize, usize), plot: F) {
+    let (from, to, t0, t1) = match clip_segment(from, to, size, 1.0) {
+        Some(clip) => clip,
+        None => return,
+    };
+    let mut plot = clipped(size, t0, t1, plot);
+
+    let (mut x0, mut y0) = (from.x.round() as isize, from.y.round() as isize);
+    let (mut x1, mut y1) = (to.x.round() as isize, to.y.round() as isize);
+
+    // Walk along the longer axis so there are no gaps
+    let steep = (y1 - y0).abs() > (x1 - x0).abs();
+    if steep {
+        mem::swap(&mut x0, &mut y0);
+        mem::swap(&mut x1, &mut y1);
+    }
+
+    let reversed = x0 > x1;
+    if reversed {
+        mem::swap(&mut x0, &mut x1);
+        mem::swap(&mut y0, &mut y1);
+    }
+
+    let dx = x1 - x0;
+    let dy = (y1 - y0).abs();
+    let ystep = if y1 > y0 { 1 } else { -1 };
+    let mut error = dx / 2;
+    let mut y = y0;
+
+    for x in x0..x1 + 1 {
+        let t = if dx == 0 { 0.0 } else { (x - x0) as f32 / dx as f32 };
+        let t = if reversed { 1.0 - t } else { t };
+
+        if steep { plot(y, x, 1.0, t) } else { plot(x, y, 1.0, t) }
+
+        error -= dy;
+        if error < 0 {
+            y += ystep;
+            error += dx;
+        }
+    }
+}
+
+// Anti-aliased one pixel wide lines, splitting each step's coverage between
+// the two pixels straddling the ideal line
+pub fn xiaolin_wu<F: FnMut(isize, isize, f32, f32)>(from: Vec2<f32>, to: Vec2<f32>, size: (usize, usize), plot: F) {
+    let (from, to, t0, t1) = match clip_segment(from, to, size, 2.0) {
+        Some(clip) => clip,
+        None => return,
+    };
+    let mut plot = clipped(size, t0, t1, plot);
+
+    let (mut x0, mut y0, mut x1, mut y1) = (from.x, from.y, to.x, to.y);
+
+    let steep = (y1 - y0).abs() > (x1 - x0).abs();
+    if steep {
+        mem::swap(&mut x0, &mut y0);
+        mem::swap(&mut x1, &mut y1);
+    }
+
+    let reversed = x0 > x1;
+    if reversed {
+        mem::swap(&mut x0, &mut x1);
+        mem::swap(&mut y0, &mut y1);
+    }
+
+    let dx = x1 - x0;
+    let gradient = if dx == 0.0 { 1.0 } else { (y1 - y0) / dx };
+    let mut emit = |major: f32, minor: f32, coverage: f32| {
+        let t = if dx == 0.0 { 0.0 } else { ((major - x0) / dx).clamp(0.0, 1.0) };
+        let t = if reversed { 1.0 - t } else { t };
+        let (major, minor) = (major as isize, minor as isize);
+
+        if coverage > 0.0 {
+            if steep { plot(minor, major, coverage, t) } else { plot(major, minor, coverage, t) }
+        }
+    };
+
+    // The end points only get partial coverage along the major axis
+    let mut endpoint = |x: f32, y: f32, start: bool| -> (f32, f32) {
+        let xend = x.round();
+        let yend = y + gradient * (xend - x);
+        let xgap = if start { 1.0 - (x + 0.5).fract() } else { (x + 0.5).fract() };
+
+        emit(xend, yend.floor(), (1.0 - yend.fract()) * xgap);
+        emit(xend, yend.floor() + 1.0, yend.fract() * xgap);
+        (xend, yend)
+    };
+
+    let (xstart, ystart) = endpoint(x0, y0, true);
+    let (xend, _) = endpoint(x1, y1, false);
+
+    let mut intery = ystart + gradient;
+    let mut x = xstart + 1.0;
+    while x < xend {
+        emit(x, intery.floor(), 1.0 - intery.fract());
+        emit(x, intery.floor() + 1.0, intery.fract());
+        intery += gradient;
+        x += 1.0;
+    }
+}
+
+// Lines wider than a pixel are drawn as capsules: every pixel within half
+// the width of the segment is covered, with a one pixel ramp at the edge
+// when anti-aliased
+pub fn thick_line<F: FnMut(isize, isize, f32, f32)>(from: Vec2<f32>, to: Vec2<f32>, width: f32, antialiased: bool,
+                                                     size: (usize, usize), plot: F) {
+    let radius = width / 2.0;
+    let pad = radius + 1.0;
+
+    let (from, to, t0, t1) = match clip_segment(from, to, size, pad) {
+        Some(clip) => clip,
+        None => return,
+    };
+    let mut plot = clipped(size, t0, t1, plot);
+
+    let (min_x, max_x) = clip_range(from.x.min(to.x) - pad, from.x.max(to.x) + pad, size.0);
+    let (min_y, max_y) = clip_range(from.y.min(to.y) - pad, from.y.max(to.y) + pad, size.1);
+
+    let (dx, dy) = (to.x - from.x, to.y - from.y);
+    let len2 = dx * dx + dy * dy;
+
+    for y in min_y..max_y + 1 {
+        for x in min_x..max_x + 1 {
+            let (px, py) = (x as f32 - from.x, y as f32 - from.y);
+            let t = if len2 == 0.0 { 0.0 } else { ((px * dx + py * dy) / len2).clamp(0.0, 1.0) };
+            let (ex, ey) = (px - dx * t, py - dy * t);
+            let distance = (ex * ex + ey * ey).sqrt();
+
+            let coverage = if antialiased {
+                (radius + 0.5 - distance).clamp(0.0, 1.0)
+            } else if distance <= radius {
+                1.0
+            } else {
+                0.0
+            };
+
+            if coverage > 0.0 {
+                plot(x, y, coverage, t);
+            }
+        }
+    }
+}
+
+// The whole pixels from `min` to `max` that are inside [0, size), as an
+// inclusive range that's empty (max < min) if there are none
+fn clip_range(min: f32, max: f32, size: usize) -> (isize, isize) {
+    let min = cmp::max(min.floor() as isize, 0);
+    let max = cmp::min(max.ceil() as isize, size as isize - 1);
+    (min, max)
+}
+
+pub fn rasterize_line<F: FnMut(isize, isize, f32, f32)>(from: Vec2<f32>, to: Vec2<f32>, style: &LineStyle,
+                                                         size: (usize, usize), plot: F) {
+    if style.width > 1.0 {
+        thick_line(from, to, style.width, style.antialiased, size, plot);
+    } else if style.antialiased {
+        xiaolin_wu(from, to, size, plot);
+    } else {
+        bresenham(from, to, size, plot);
+    }
+}
+
+// Calls `plot(x, y, coverage, sprite_coord)` for each pixel of the point,
+// where sprite_coord goes from (0, 0) at the bottom left to (1, 1) at the
+// top right, like gl_PointCoord
+pub fn rasterize_point<F: FnMut(isize, isize, f32, Vec2<f32>)>(center: Vec2<f32>, style: &PointStyle,
+                                                                size: (usize, usize), mut plot: F) {
+    if !(center.x.is_finite() && center.y.is_finite()) {
+        return;
+    }
+
+    let radius = style.size / 2.0;
+    let (min_x, max_x) = clip_range(center.x - radius - 1.0, center.x + radius + 1.0, size.0);
+    let (min_y, max_y) = clip_range(center.y - radius - 1.0, center.y + radius + 1.0, size.1);
+
+    for y in min_y..max_y + 1 {
+        for x in min_x..max_x + 1 {
+            let (dx, dy) = (x as f32 - center.x, y as f32 - center.y);
+
+            // Distance outside the shape's edge, negative inside
+            let outside = match style.shape {
+                PointShape::Square => dx.abs().max(dy.abs()) - radius,
+                PointShape::Circle => (dx * dx + dy * dy).sqrt() - radius,
+            };
+
+            let coverage = if style.antialiased {
+                (0.5 - outside).clamp(0.0, 1.0)
+            } else if outside <= 0.0 && -dx < radius && -dy < radius {
+                1.0
+            } else {
+                0.0
+            };
+
+            if coverage > 0.0 {
+                let coord = Vec2 {
+                    x: ((dx / style.size) + 0.5).clamp(0.0, 1.0),
+                    y: ((dy / style.size) + 0.5).clamp(0.0, 1.0),
+                };
+                plot(x, y, coverage, coord);
+            }
+        }
+    }
+}
+
+// Blends a fragment in if it passes the depth test. Lines and points test
+// against depth but don't write it, so drawing them after a filled pass
+// hides whatever is behind the surface without breaking later passes.
+// `depth` is None to skip the test.
+pub fn plot_fragment(image: &mut Image, x: isize, y: isize, color: &Color, coverage: f32, depth: Option<f32>) {
+    if x < 0 || y < 0 || x as usize >= image.width || y as usize >= image.height {
+        return;
+    }
+
+    let (x, y) = (x as usize, y as usize);
+    if let Some(depth) = depth {
+        if (depth as isize) < image.depth(x, y) {
+            return;
+        }
+    }
+
+    image.blend_pixel(x, y, color, coverage);
+}
+
+// `from` and `to` are in screen space (as after the viewport transform),
+// with depth in z
+pub fn draw_line(image: &mut Image, from: Vec3<f32>, to: Vec3<f32>, color: &Color, style: &LineStyle) {
+    rasterize_line(from.xy(), to.xy(), style, (image.width, image.height), |x, y, coverage, t| {
+        let depth = from.z + (to.z - from.z) * t;
+        plot_fragment(image, x, y, color, coverage, style.biased_depth(depth));
+    });
+}
+
+pub fn draw_point(image: &mut Image, center: Vec3<f32>, color: &Color, style: &PointStyle) {
+    rasterize_point(center.xy(), style, (image.width, image.height), |x, y, coverage, _| {
+        plot_fragment(image, x, y, color, coverage, style.biased_depth(center.z));
+    });
+}
+
+// A point textured with `sprite`, tinted by `color`
+pub fn draw_sprite(image: &mut Image, center: Vec3<f32>, sprite: &Image, color: &Color, style: &PointStyle) {
+    rasterize_point(center.xy(), style, (image.width, image.height), |x, y, coverage, coord| {
+        let sx = cmp::min((coord.x * sprite.width as f32) as usize, sprite.width - 1);
+        let sy = cmp::min((coord.y * sprite.height as f32) as usize, sprite.height - 1);
+        let texel = sprite.get_pixel(sx, sy).multiply(color);
+
+        plot_fragment(image, x, y, &texel, coverage, style.biased_depth(center.z));
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pt(x: f32, y: f32) -> Vec2<f32> {
+        Vec2 { x, y }
+    }
+
+    // Every (x, y, t) a rasterizer plots, checking they're on the target
+    fn plotted<R: FnOnce(&mut dyn FnMut(isize, isize, f32, f32))>(size: (usize, usize), raster: R) -> Vec<(isize, isize, f32)> {
+        let mut pixels = Vec::new();
+        raster(&mut |x, y, coverage, t| {
+            assert!(x >= 0 && y >= 0 && (x as usize) < size.0 && (y as usize) < size.1, "{} {}", x, y);
+            assert!(coverage > 0.0 && coverage <= 1.0);
+            pixels.push((x, y, t));
+        });
+        pixels
+    }
+
+    #[test]
+    fn bresenham_covers_the_line() {
+        let pixels = plotted((8, 8), |plot| bresenham(pt(4.0, 2.0), pt(0.0, 2.0), (8, 8), plot));
+
+        let xs: Vec<isize> = pixels.iter().map(|p| p.0).collect();
+        assert_eq!(xs, vec![0, 1, 2, 3, 4]);
+        assert!(pixels.iter().all(|p| p.1 == 2));
+        assert_eq!((pixels[0].2, pixels[4].2), (1.0, 0.0));
+
+        let pixels = plotted((8, 8), |plot| bresenham(pt(1.0, 1.0), pt(3.0, 6.0), (8, 8), plot));
+        assert_eq!(pixels.len(), 6);
+    }
+
+    #[test]
+    fn clipped_lines_keep_their_t() {
+        let pixels = plotted((5, 1), |plot| bresenham(pt(-10.0, 0.0), pt(10.0, 0.0), (5, 1), plot));
+
+        assert_eq!(pixels.len(), 5);
+        assert!((pixels[0].2 - 0.5).abs() < 1e-6);
+        assert!((pixels[4].2 - 0.7).abs() < 1e-6);
+    }
+
+    #[test]
+    fn huge_end_points_are_clipped() {
+        let size = (8, 8);
+        let far = [pt(1e30, 3e29), pt(-1e20, 4.0), pt(4.0, f32::MAX), pt(f32::INFINITY, 0.0)];
+
+        for &to in far.iter() {
+            let lines = [
+                plotted(size, |plot| bresenham(pt(2.0, 2.0), to, size, plot)),
+                plotted(size, |plot| xiaolin_wu(pt(2.0, 2.0), to, size, plot)),
+                plotted(size, |plot| thick_line(pt(2.0, 2.0), to, 3.0, true, size, plot)),
+            ];
+            for pixels in lines.iter() {
+                assert!(pixels.len() <= 64);
+            }
+        }
+
+        assert!(plotted(size, |plot| bresenham(pt(1e30, 1e30), pt(2e30, 1e30), size, plot)).is_empty());
+        assert!(plotted(size, |plot| thick_line(pt(f32::NAN, 0.0), pt(2.0, 2.0), 4.0, false, size, plot)).is_empty());
+        assert!(!plotted(size, |plot| bresenham(pt(2.0, 2.0), pt(1e30, 3e29), size, plot)).is_empty());
+    }
+
+    #[test]
+    fn thick_lines_cover_their_width() {
+        let pixels = plotted((10, 10), |plot| thick_line(pt(2.0, 5.0), pt(7.0, 5.0), 3.0, false, (10, 10), plot));
+
+        assert!(pixels.iter().all(|p| (p.1 - 5).abs() <= 1));
+        assert!(pixels.contains(&(4, 4, 0.4)) && pixels.contains(&(4, 6, 0.4)));
+    }
+
+    #[test]
+    fn points_are_clipped() {
+        let style = PointStyle { size: 4.0, ..PointStyle::new() };
+        let points = |center: Vec2<f32>| {
+            let mut pixels = Vec::new();
+            rasterize_point(center, &style, (8, 8), |x, y, _, coord| {
+                assert!((0..8).contains(&x) && (0..8).contains(&y));
+                assert!((0.0..=1.0).contains(&coord.x) && (0.0..=1.0).contains(&coord.y));
+                pixels.push((x, y));
+            });
+            pixels
+        };
+
+        assert_eq!(points(pt(4.0, 4.0)).len(), 16);
+        assert_eq!(points(pt(0.0, 0.0)).len(), 9);
+        assert!(points(pt(1e30, -1e30)).is_empty());
+        assert!(points(pt(f32::NAN, 2.0)).is_empty());
+    }
+}
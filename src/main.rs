@@ -2,110 +2,248 @@
 extern crate imagefmt;
 extern crate num;
 extern crate rand;
+extern crate serde_json;
+extern crate base64;
+extern crate percent_encoding;
+extern crate png;
+extern crate exr;
+#[macro_use]
+extern crate vary_derive;
 
 mod vec;
 mod image;
 mod obj;
 mod shader;
 mod matrix;
-
-use vec::{Vec2, Vec3, Vec4};
+mod animation;
+mod skin;
+mod morph;
+mod gltf;
+mod ply;
+mod stl;
+mod shaders;
+mod mesh;
+mod normals;
+mod bounds;
+mod camera;
+mod culling;
+mod bvh;
+mod simplify;
+mod subdivide;
+mod lines;
+mod canvas;
+mod formats;
+mod target;
+mod deferred;
+mod ssao;
+mod post;
+mod aa;
+mod dof;
+mod motion;
+mod shadow;
+mod accum;
+mod npr;
+
+use vec::Vec3;
 use image::*;
-use shader::{Vary, Shader, draw_triangle};
+use shader::draw_indexed;
+use shaders::{TexturedVars, TexturedShader};
 use matrix::*;
 use obj::*;
+use normals::SmoothOptions;
+use bounds::Sphere;
+use camera::Camera;
+use target::GBuffer;
+use deferred::{GeometryVars, GeometryShader};
+use ssao::{Ssao, SsaoOptions};
+use post::{PostChain, to_float, to_image};
+use dof::Lens;
+use shadow::{ShadowMap, ShadowOptions};
+use accum::{Accumulator, Jitter};
+use npr::{NprVars, ToonShader, GoochShader, HatchShader, OutlineShader, OutlineOptions, Style, OutlineMode, outline_edges};
+
+use std::env;
+use std::process;
 
 //use std::f32;
 
-#[derive(Clone, Copy)]
-struct Vars {
-    normal: Vec3<f32>,
-    tex: Vec2<f32>,
+fn arg_value(name: &str) -> Option<String> {
+    let args: Vec<String> = env::args().collect();
+
+    args.iter().position(|a| a == name).and_then(|i| args.get(i + 1).cloned())
 }
 
-impl Vary for Vars {
-    fn vary(v1: &Vars, v2: &Vars, v3: &Vars, bary: Vec3<f32>) -> Vars {
-        Vars {
-            normal: (v1.normal * bary.x + v2.normal * bary.y + v3.normal * bary.z).norm(),
-            tex: v1.tex * bary.x + v2.tex * bary.y + v3.tex * bary.z,
+// Post-processing passes from e.g. `--post bloom:0.7,vignette:0.4`, or the
+// same list in a file named with `--post-file`
+fn post_chain() -> Option<PostChain> {
+    let chain = match (arg_value("--post"), arg_value("--post-file")) {
+        (Some(spec), _) => PostChain::parse(&spec),
+        (None, Some(path)) => PostChain::from_file(&path),
+        (None, None) => return None,
+    };
+
+    match chain {
+        Ok(chain) => Some(chain),
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
         }
     }
 }
 
-struct MyShader<'a> {
-    mat: &'a Matrix4x4<f32>,
-    tex: &'a Image,
-    light_dir: Vec3<f32>,
+// `--shader toon` (or gooch, hatch) draws the model in that style instead
+// of textured, and `--outline edges` (or hull) outlines it. A toon ramp
+// image can be given with `--ramp`.
+fn named_arg<T, F: Fn(&str) -> Option<T>>(name: &str, from_name: F) -> Option<T> {
+    let value = arg_value(name)?;
+
+    match from_name(&value) {
+        Some(v) => Some(v),
+        None => {
+            eprintln!("unknown {} '{}'", name, value);
+            process::exit(1);
+        }
+    }
 }
 
-impl<'a> Shader<Vars> for MyShader<'a> {
-    fn vertex(&self, pt: Vec3<f32>, vars: &Vars) -> (Vec4<f32>, Vars) {
-        let pt4 = Vec4 { x: pt.x, y: pt.y, z: pt.z, w: 1.0 };
-
-        (
-            self.mat * &pt4,
-            *vars
-        )
+// `--samples 16` renders the frame 16 times and averages them, for
+// antialiasing and (with --shadows) soft shadows
+fn samples() -> usize {
+    match arg_value("--samples").map(|s| s.parse::<usize>()) {
+        None => 1,
+        Some(Ok(n)) if n > 0 => n,
+        Some(_) => {
+            eprintln!("--samples needs a positive whole number");
+            process::exit(1);
+        }
     }
+}
 
-    fn fragment(&self, _: Vec2<isize>, vars: Vars) -> Option<Color> {
-        let comp = vars.normal.dot(self.light_dir) * 255.0;
-
-        if comp > 0.0 {
-            let tex_x = (self.tex.width as f32 * vars.tex.x) as usize;
-            let tex_y = (self.tex.height as f32 * vars.tex.y) as usize;
-            let tex = self.tex.get_pixel(tex_x, tex_y);
-            let shading = Color(comp as u8, comp as u8, comp as u8);
-
-            Some(tex.multiply(&shading))
-        } else {
-            None
+// `--dof 0.05` blurs what's nearer or farther than the model's center
+// with an aperture that wide, in model units; `--dof 0.05:2.5` focuses at
+// 2.5 units from the eye instead
+fn lens(camera: &Camera) -> Option<Lens> {
+    let spec = arg_value("--dof")?;
+    let mut parts = spec.split(':').map(|p| p.trim().parse::<f32>());
+
+    let lens = match (parts.next(), parts.next()) {
+        (Some(Ok(aperture)), None) => Lens::new(camera, aperture),
+        (Some(Ok(aperture)), Some(Ok(focus))) => Lens { focus_distance: focus, ..Lens::new(camera, aperture) },
+        _ => {
+            eprintln!("bad --dof value '{}', expected aperture[:focus distance]", spec);
+            process::exit(1);
         }
-    }
+    };
+
+    Some(lens)
 }
 
 fn main() {
-    let mut image = Image::new(800, 800);
+    let post = post_chain();
+
+    let samples = samples();
+    let shadows = env::args().any(|a| a == "--shadows");
+    let style = named_arg("--shader", Style::from_name);
+    let outline = named_arg("--outline", OutlineMode::from_name);
 
     let viewport = Matrix4x4::viewport(0.0, 0.0, 800.0, 800.0, 255.0);
-    let view = Matrix4x4::scale(Vec3 { x: 0.8, y: 0.8, z: 0.8 });
 
-    let lookat = Matrix4x4::lookat(
+    let mut obj = Obj::from_file("head.obj").unwrap();
+    if !obj.has_normals() {
+        obj.generate_smooth_normals(&SmoothOptions::new());
+    }
+
+    // Frame the model from the same direction whatever its size or units,
+    // leaving a little room around the edges
+    let bounds = obj.bounding_sphere();
+    let camera = Camera::framing(
+        &Sphere { radius: bounds.radius * 1.1, ..bounds },
+        0.6,
         Vec3 { x: 1.0, y: 1.0, z: 3.0 },
-        Vec3 { x: 0.0, y: 0.0, z: 0.0 },
         Vec3 { x: 0.0, y: 1.0, z: 0.0 },
     );
-    let perspective = Matrix4x4::perspective(3.0);
 
-    let obj = Obj::from_file("head.obj").unwrap();
-    let tex = Image::from("head_tex.tga");
+    let tex = Image::from("head_tex.tga").unwrap();
+    let ramp = arg_value("--ramp").map(|path| Image::from(&path).unwrap_or_else(|e| {
+        eprintln!("{}: {}", path, e);
+        process::exit(1);
+    }));
+
+    let mat = viewport * camera.projection() * camera.view();
+    let light_dir = Vec3 { x: 0.0, y: 0.0, z: 1.0 };
+
+    let mesh = obj.to_indexed(TexturedVars::from_obj);
+    let geometry_mesh = obj.to_indexed(GeometryVars::from_obj);
+    let npr_mesh = obj.to_indexed(NprVars::from_obj);
+
+    let ssao = Ssao::new(SsaoOptions { radius: bounds.radius * 0.1, ..SsaoOptions::new() });
+    let shadow_options = ShadowOptions { bias: bounds.radius * 0.01, ..ShadowOptions::new() };
+
+    // With --samples, the frame is drawn several times with the camera and
+    // light slightly moved each time, and the results averaged
+    let mut accumulator = Accumulator::new(800, 800);
+    let mut gbuffer = GBuffer::new(800, 800);
+
+    for jitter in Jitter::sequence(samples).iter() {
+        let mat = jitter.screen(&mat);
+        // Wide enough to give shadows a visible penumbra
+        let light_dir = jitter.light_direction(light_dir, 0.05);
+
+        let mut image = Image::new(800, 800);
+        let model = Matrix4x4::identity();
+
+        match style {
+            Some(Style::Toon) => {
+                let mut shader = ToonShader::new(&model, &mat, light_dir);
+                shader.texture = Some(&tex);
+                shader.ramp = ramp.as_ref();
+                draw_indexed(&npr_mesh, &shader, &mut image);
+            }
+            Some(Style::Gooch) => {
+                let mut shader = GoochShader::new(&model, &mat, light_dir);
+                shader.texture = Some(&tex);
+                draw_indexed(&npr_mesh, &shader, &mut image);
+            }
+            Some(Style::Hatch) => draw_indexed(&npr_mesh, &HatchShader::new(&model, &mat, light_dir), &mut image),
+            None => draw_indexed(&mesh, &TexturedShader::new(&model, &mat, &tex, light_dir), &mut image),
+        }
 
-    let mat = viewport * perspective * lookat * view;
-    let shader = MyShader {
-        mat: &mat,
-        light_dir: Vec3 { x: 0.0, y: 0.0, z: 1.0 },
-        tex: &tex,
-    };
+        if outline == Some(OutlineMode::Hull) {
+            let shader = OutlineShader::new(&model, &mat, camera.eye, bounds.radius * 0.01);
+            draw_indexed(&npr_mesh, &shader, &mut image);
+        }
+
+        // Ambient occlusion and shadows need positions, depth and normals
+        // for every pixel, which the forward pass doesn't keep
+        gbuffer = GBuffer::new(800, 800);
+        let geometry = GeometryShader::new(&Matrix4x4::identity(), &mat, 1);
+        draw_indexed(&geometry_mesh, &geometry, &mut gbuffer);
+
+        let ao = ssao.occlusion(&gbuffer, &mat);
+        ssao.composite(&mut image, &ao);
+
+        if shadows {
+            let mut shadow_map = ShadowMap::directional(light_dir, &bounds, shadow_options);
+            shadow_map.draw(&geometry_mesh, &Matrix4x4::identity());
+            shadow_map.composite(&mut image, &gbuffer);
+        }
+
+        if outline == Some(OutlineMode::Edges) {
+            let options = OutlineOptions { depth_threshold: bounds.radius * 0.02, ..OutlineOptions::new() };
+            outline_edges(&mut image, &gbuffer, &mat, &options);
+        }
+
+        accumulator.add(&image);
+    }
+
+    let mut image = accumulator.image();
+
+    if let Some(lens) = lens(&camera) {
+        image = to_image(&lens.apply(&to_float(&image), &gbuffer.depth, &camera, &mat));
+    }
 
-    for face in obj.faces.iter() {
-        let fp0 = obj.vert(face.0.vindex);
-        let fp1 = obj.vert(face.1.vindex);
-        let fp2 = obj.vert(face.2.vindex);
-
-        let vars0 = Vars {
-            normal: obj.norm_vert(face.0.nindex),
-            tex: obj.tex_vert(face.0.tindex),
-        };
-        let vars1 = Vars {
-            normal: obj.norm_vert(face.1.nindex),
-            tex: obj.tex_vert(face.1.tindex),
-        };
-        let vars2 = Vars {
-            normal: obj.norm_vert(face.2.nindex),
-            tex: obj.tex_vert(face.2.tindex),
-        };
-
-        draw_triangle(&vec![(fp0, vars0), (fp1, vars1), (fp2, vars2)], &shader, &mut image);
+    if let Some(post) = post {
+        image = post.run(&image);
     }
 
     image.write("out.tga").unwrap();
@@ -1,9 +1,10 @@
-use vec::{Vec3, Vec4};
+use vec::{Vec3, Vec4, Quaternion};
 
-use num::traits::{Num, Zero};
+use num::traits::Num;
 use std::ops::{Sub, Add, Mul};
 use std::fmt::Display;
 
+#[derive(Clone)]
 pub struct Matrix4x4<T> {
     data: Vec<T>,
 }
@@ -17,6 +18,15 @@ impl<T: Copy + Display> Matrix4x4<T> {
 }
 
 impl Matrix4x4<f32> {
+    // Matches the storage order used here, as well as glTF's and OpenGL's
+    pub fn from_column_major(values: &[f32]) -> Matrix4x4<f32> {
+        assert!(values.len() == 16, "A 4x4 matrix needs 16 values");
+
+        Matrix4x4 {
+            data: values.to_vec(),
+        }
+    }
+
     pub fn translation(by: Vec3<f32>) -> Matrix4x4<f32> {
         Matrix4x4 {
             data: vec![
@@ -112,6 +122,196 @@ impl Matrix4x4<f32> {
     }
 }
 
+// A translation/rotation/scale triple, applied as T * R * S
+#[derive(Clone, Copy)]
+pub struct Transform {
+    pub translation: Vec3<f32>,
+    pub rotation: Quaternion,
+    pub scale: Vec3<f32>,
+}
+
+impl Transform {
+    pub fn identity() -> Transform {
+        Transform {
+            translation: Vec3 { x: 0.0, y: 0.0, z: 0.0 },
+            rotation: Quaternion::identity(),
+            scale: Vec3 { x: 1.0, y: 1.0, z: 1.0 },
+        }
+    }
+
+    pub fn to_matrix(self) -> Matrix4x4<f32> {
+        Matrix4x4::translation(self.translation) * self.rotation.to_matrix() * Matrix4x4::scale(self.scale)
+    }
+}
+
+impl Quaternion {
+    pub fn to_matrix(self) -> Matrix4x4<f32> {
+        let q = self.norm();
+
+        let (x, y, z, w) = (q.x, q.y, q.z, q.w);
+
+        Matrix4x4 {
+            data: vec![
+                1.0 - 2.0 * (y * y + z * z), 2.0 * (x * y + z * w), 2.0 * (x * z - y * w), 0.0,
+                2.0 * (x * y - z * w), 1.0 - 2.0 * (x * x + z * z), 2.0 * (y * z + x * w), 0.0,
+                2.0 * (x * z + y * w), 2.0 * (y * z - x * w), 1.0 - 2.0 * (x * x + y * y), 0.0,
+                0.0, 0.0, 0.0, 1.0,
+            ],
+        }
+    }
+
+    // Expects the upper 3x3 of `mat` to be a pure rotation
+    pub fn from_matrix(mat: &Matrix4x4<f32>) -> Quaternion {
+        let m = |row, col| mat.get(row, col);
+        let trace = m(0, 0) + m(1, 1) + m(2, 2);
+
+        let q = if trace > 0.0 {
+            let s = (trace + 1.0).sqrt() * 2.0;
+            Quaternion {
+                x: (m(2, 1) - m(1, 2)) / s,
+                y: (m(0, 2) - m(2, 0)) / s,
+                z: (m(1, 0) - m(0, 1)) / s,
+                w: 0.25 * s,
+            }
+        } else if m(0, 0) > m(1, 1) && m(0, 0) > m(2, 2) {
+            let s = (1.0 + m(0, 0) - m(1, 1) - m(2, 2)).sqrt() * 2.0;
+            Quaternion {
+                x: 0.25 * s,
+                y: (m(0, 1) + m(1, 0)) / s,
+                z: (m(0, 2) + m(2, 0)) / s,
+                w: (m(2, 1) - m(1, 2)) / s,
+            }
+        } else if m(1, 1) > m(2, 2) {
+            let s = (1.0 + m(1, 1) - m(0, 0) - m(2, 2)).sqrt() * 2.0;
+            Quaternion {
+                x: (m(0, 1) + m(1, 0)) / s,
+                y: 0.25 * s,
+                z: (m(1, 2) + m(2, 1)) / s,
+                w: (m(0, 2) - m(2, 0)) / s,
+            }
+        } else {
+            let s = (1.0 + m(2, 2) - m(0, 0) - m(1, 1)).sqrt() * 2.0;
+            Quaternion {
+                x: (m(0, 2) + m(2, 0)) / s,
+                y: (m(1, 2) + m(2, 1)) / s,
+                z: 0.25 * s,
+                w: (m(1, 0) - m(0, 1)) / s,
+            }
+        };
+
+        q.norm()
+    }
+}
+
+impl Matrix4x4<f32> {
+    // Splits an affine matrix built as T * R * S back into its parts. Shear
+    // can't be represented, so matrices containing it won't round-trip.
+    pub fn decompose(&self) -> Transform {
+        let column = |col| Vec3 { x: self.get(0, col), y: self.get(1, col), z: self.get(2, col) };
+
+        let translation = column(3);
+        let (c0, c1, c2) = (column(0), column(1), column(2));
+
+        let mut scale = Vec3 { x: c0.length(), y: c1.length(), z: c2.length() };
+
+        // A negative determinant means the basis is mirrored, so fold that
+        // into one of the scale axes
+        if c0.cross(c1).dot(c2) < 0.0 {
+            scale.x = -scale.x;
+        }
+
+        let mut rotation = Matrix4x4::identity();
+        for row in 0..3 {
+            rotation.set(row, 0, self.get(row, 0) / scale.x);
+            rotation.set(row, 1, self.get(row, 1) / scale.y);
+            rotation.set(row, 2, self.get(row, 2) / scale.z);
+        }
+
+        Transform {
+            translation,
+            rotation: Quaternion::from_matrix(&rotation),
+            scale,
+        }
+    }
+}
+
+impl Matrix4x4<f32> {
+    pub fn row(&self, row: usize) -> Vec4<f32> {
+        Vec4 { x: self.get(row, 0), y: self.get(row, 1), z: self.get(row, 2), w: self.get(row, 3) }
+    }
+
+    pub fn transform_point(&self, pt: Vec3<f32>) -> Vec3<f32> {
+        let out = self * &Vec4 { x: pt.x, y: pt.y, z: pt.z, w: 1.0 };
+
+        out.xyz() * (1.0 / out.w)
+    }
+
+    pub fn transform_vector(&self, v: Vec3<f32>) -> Vec3<f32> {
+        (self * &Vec4 { x: v.x, y: v.y, z: v.z, w: 0.0 }).xyz()
+    }
+
+    pub fn transpose(&self) -> Matrix4x4<f32> {
+        let mut result = Matrix4x4::new();
+
+        for i in 0..4 {
+            for j in 0..4 {
+                result.set(i, j, self.get(j, i));
+            }
+        }
+
+        result
+    }
+
+    // Cofactor expansion; returns None for singular matrices
+    pub fn inverse(&self) -> Option<Matrix4x4<f32>> {
+        let m = &self.data;
+        let mut inv = [0.0; 16];
+
+        inv[0] = m[5] * m[10] * m[15] - m[5] * m[11] * m[14] - m[9] * m[6] * m[15]
+            + m[9] * m[7] * m[14] + m[13] * m[6] * m[11] - m[13] * m[7] * m[10];
+        inv[4] = -m[4] * m[10] * m[15] + m[4] * m[11] * m[14] + m[8] * m[6] * m[15]
+            - m[8] * m[7] * m[14] - m[12] * m[6] * m[11] + m[12] * m[7] * m[10];
+        inv[8] = m[4] * m[9] * m[15] - m[4] * m[11] * m[13] - m[8] * m[5] * m[15]
+            + m[8] * m[7] * m[13] + m[12] * m[5] * m[11] - m[12] * m[7] * m[9];
+        inv[12] = -m[4] * m[9] * m[14] + m[4] * m[10] * m[13] + m[8] * m[5] * m[14]
+            - m[8] * m[6] * m[13] - m[12] * m[5] * m[10] + m[12] * m[6] * m[9];
+        inv[1] = -m[1] * m[10] * m[15] + m[1] * m[11] * m[14] + m[9] * m[2] * m[15]
+            - m[9] * m[3] * m[14] - m[13] * m[2] * m[11] + m[13] * m[3] * m[10];
+        inv[5] = m[0] * m[10] * m[15] - m[0] * m[11] * m[14] - m[8] * m[2] * m[15]
+            + m[8] * m[3] * m[14] + m[12] * m[2] * m[11] - m[12] * m[3] * m[10];
+        inv[9] = -m[0] * m[9] * m[15] + m[0] * m[11] * m[13] + m[8] * m[1] * m[15]
+            - m[8] * m[3] * m[13] - m[12] * m[1] * m[11] + m[12] * m[3] * m[9];
+        inv[13] = m[0] * m[9] * m[14] - m[0] * m[10] * m[13] - m[8] * m[1] * m[14]
+            + m[8] * m[2] * m[13] + m[12] * m[1] * m[10] - m[12] * m[2] * m[9];
+        inv[2] = m[1] * m[6] * m[15] - m[1] * m[7] * m[14] - m[5] * m[2] * m[15]
+            + m[5] * m[3] * m[14] + m[13] * m[2] * m[7] - m[13] * m[3] * m[6];
+        inv[6] = -m[0] * m[6] * m[15] + m[0] * m[7] * m[14] + m[4] * m[2] * m[15]
+            - m[4] * m[3] * m[14] - m[12] * m[2] * m[7] + m[12] * m[3] * m[6];
+        inv[10] = m[0] * m[5] * m[15] - m[0] * m[7] * m[13] - m[4] * m[1] * m[15]
+            + m[4] * m[3] * m[13] + m[12] * m[1] * m[7] - m[12] * m[3] * m[5];
+        inv[14] = -m[0] * m[5] * m[14] + m[0] * m[6] * m[13] + m[4] * m[1] * m[14]
+            - m[4] * m[2] * m[13] - m[12] * m[1] * m[6] + m[12] * m[2] * m[5];
+        inv[3] = -m[1] * m[6] * m[11] + m[1] * m[7] * m[10] + m[5] * m[2] * m[11]
+            - m[5] * m[3] * m[10] - m[9] * m[2] * m[7] + m[9] * m[3] * m[6];
+        inv[7] = m[0] * m[6] * m[11] - m[0] * m[7] * m[10] - m[4] * m[2] * m[11]
+            + m[4] * m[3] * m[10] + m[8] * m[2] * m[7] - m[8] * m[3] * m[6];
+        inv[11] = -m[0] * m[5] * m[11] + m[0] * m[7] * m[9] + m[4] * m[1] * m[11]
+            - m[4] * m[3] * m[9] - m[8] * m[1] * m[7] + m[8] * m[3] * m[5];
+        inv[15] = m[0] * m[5] * m[10] - m[0] * m[6] * m[9] - m[4] * m[1] * m[10]
+            + m[4] * m[2] * m[9] + m[8] * m[1] * m[6] - m[8] * m[2] * m[5];
+
+        let det = m[0] * inv[0] + m[1] * inv[4] + m[2] * inv[8] + m[3] * inv[12];
+
+        if det == 0.0 {
+            return None;
+        }
+
+        Some(Matrix4x4 {
+            data: inv.iter().map(|x| x / det).collect(),
+        })
+    }
+}
+
 impl<T: Copy> Matrix4x4<T> {
     fn index(&self, row: usize, col: usize) -> usize {
         col * 4 + row
@@ -129,11 +329,8 @@ impl<T: Copy> Matrix4x4<T> {
 
 impl<T: Copy + Num> Matrix4x4<T> {
     pub fn new() -> Matrix4x4<T> {
-        let mut data: Vec<T> = Vec::with_capacity(4 * 4);
-        data.resize(4 * 4, T::zero());
-
         Matrix4x4::<T> {
-            data: data,
+            data: vec![T::zero(); 4 * 4],
         }
     }
 
@@ -185,7 +382,7 @@ impl<T> Mul<Vec4<T>> for Matrix4x4<T>
     }
 }
 
-impl<'a, 'b, T> Mul<&'a Vec4<T>> for &'b Matrix4x4<T>
+impl<T> Mul<&Vec4<T>> for &Matrix4x4<T>
         where T: Mul<T, Output=T> + Add<T, Output=T> + Copy + Num {
     type Output = Vec4<T>;
 
@@ -199,6 +396,38 @@ impl<'a, 'b, T> Mul<&'a Vec4<T>> for &'b Matrix4x4<T>
     }
 }
 
+impl<T> Mul<&Matrix4x4<T>> for &Matrix4x4<T>
+        where T: Mul<T, Output=T> + Add<T, Output=T> + Copy + Num {
+    type Output = Matrix4x4<T>;
+
+    fn mul(self, rhs: &Matrix4x4<T>) -> Matrix4x4<T> {
+        let mut result = Matrix4x4::new();
+
+        for i in 0..4 {
+            for j in 0..4 {
+                let mut val: T = T::zero();
+                for k in 0..4 {
+                    val = val + self.get(i, k) * rhs.get(k, j);
+                }
+                result.set(i, j, val);
+            }
+        }
+
+        result
+    }
+}
+
+impl<T> Mul<T> for &Matrix4x4<T>
+        where T: Mul<T, Output=T> + Copy + Num {
+    type Output = Matrix4x4<T>;
+
+    fn mul(self, rhs: T) -> Matrix4x4<T> {
+        Matrix4x4 {
+            data: self.data.iter().map(|&x| x * rhs).collect(),
+        }
+    }
+}
+
 impl<T> Add<Matrix4x4<T>> for Matrix4x4<T>
         where T: Add<T, Output=T> + Copy + Num {
     type Output = Matrix4x4<T>;
@@ -0,0 +1,52 @@
+use std::collections::HashMap;
+use vec::Vec3;
+use obj::{Obj, FacePoint};
+
+// A single interleaved vertex buffer plus triangle indices into it. Each
+// vertex carries its position and whatever varyings the shader wants.
+pub struct IndexedMesh<V> {
+    pub vertices: Vec<(Vec3<f32>, V)>,
+    pub indices: Vec<[usize; 3]>,
+}
+
+impl<V> IndexedMesh<V> {
+    pub fn triangle(&self, i: usize) -> [&(Vec3<f32>, V); 3] {
+        let [a, b, c] = self.indices[i];
+
+        [&self.vertices[a], &self.vertices[b], &self.vertices[c]]
+    }
+}
+
+impl Obj {
+    // Welds face corners with identical position/texcoord/normal indices
+    // into one vertex, calling `attrs` once per unique corner
+    pub fn to_indexed<V, F>(&self, attrs: F) -> IndexedMesh<V>
+            where F: Fn(&Obj, &FacePoint) -> V {
+        let mut unique: HashMap<(usize, usize, usize), usize> = HashMap::new();
+        let mut mesh = IndexedMesh {
+            vertices: Vec::new(),
+            indices: Vec::with_capacity(self.faces().len()),
+        };
+
+        for face in self.faces().iter() {
+            let mut tri = [0; 3];
+
+            for (i, fp) in [face.0, face.1, face.2].iter().enumerate() {
+                let key = (fp.vindex, fp.tindex, fp.nindex);
+
+                tri[i] = match unique.get(&key) {
+                    Some(&index) => index,
+                    None => {
+                        mesh.vertices.push((self.vert(fp.vindex), attrs(self, fp)));
+                        unique.insert(key, mesh.vertices.len() - 1);
+                        mesh.vertices.len() - 1
+                    }
+                };
+            }
+
+            mesh.indices.push(tri);
+        }
+
+        mesh
+    }
+}
@@ -0,0 +1,359 @@
+use std;
+use std::fmt;
+use vec::{Vec2, Vec3, Vec4};
+use image::{Image, Color};
+use matrix::Matrix4x4;
+use obj::{Obj, FacePoint};
+use animation::{Track, Interpolation};
+use shader::Shader;
+use shaders::{Transforms, sample, diffuse};
+
+#[derive(Debug)]
+pub enum MorphError {
+    Io(std::io::Error),
+    VertexCountMismatch { expected: usize, found: usize },
+    NormalCountMismatch { expected: usize, found: usize },
+    FaceMismatch { face: usize },
+}
+
+impl fmt::Display for MorphError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MorphError::Io(ref e) => write!(f, "{}", e),
+            MorphError::VertexCountMismatch { expected, found } =>
+                write!(f, "target has {} vertices, base has {}", found, expected),
+            MorphError::NormalCountMismatch { expected, found } =>
+                write!(f, "target has {} normals, base has {}", found, expected),
+            MorphError::FaceMismatch { face } =>
+                write!(f, "face {} doesn't match the base mesh", face),
+        }
+    }
+}
+
+impl std::error::Error for MorphError {}
+
+impl From<std::io::Error> for MorphError {
+    fn from(e: std::io::Error) -> MorphError {
+        MorphError::Io(e)
+    }
+}
+
+// Offsets from the base mesh, indexed the same way as the base's
+// verts/norm_verts
+pub struct MorphTarget {
+    pub name: String,
+    pub position_deltas: Vec<Vec3<f32>>,
+    pub normal_deltas: Vec<Vec3<f32>>,
+}
+
+pub struct BlendShapes {
+    pub targets: Vec<MorphTarget>,
+}
+
+fn check_topology(base: &Obj, target: &Obj) -> Result<(), MorphError> {
+    if base.vert_count() != target.vert_count() {
+        return Err(MorphError::VertexCountMismatch {
+            expected: base.vert_count(),
+            found: target.vert_count(),
+        });
+    }
+
+    // Targets are allowed to leave normals out entirely
+    if target.norm_vert_count() != 0 && base.norm_vert_count() != target.norm_vert_count() {
+        return Err(MorphError::NormalCountMismatch {
+            expected: base.norm_vert_count(),
+            found: target.norm_vert_count(),
+        });
+    }
+
+    if base.faces().len() != target.faces().len() {
+        return Err(MorphError::FaceMismatch { face: base.faces().len().min(target.faces().len()) });
+    }
+
+    for (i, (a, b)) in base.faces().iter().zip(target.faces().iter()).enumerate() {
+        let pairs = [(a.0, b.0), (a.1, b.1), (a.2, b.2)];
+
+        if pairs.iter().any(|&(p, q)| p.vindex != q.vindex ||
+                            (target.norm_vert_count() != 0 && p.nindex != q.nindex)) {
+            return Err(MorphError::FaceMismatch { face: i });
+        }
+    }
+
+    Ok(())
+}
+
+impl BlendShapes {
+    pub fn new() -> BlendShapes {
+        BlendShapes { targets: Vec::new() }
+    }
+
+    pub fn add_target(&mut self, base: &Obj, name: &str, target: &Obj) -> Result<usize, MorphError> {
+        check_topology(base, target)?;
+
+        let position_deltas = (1..base.vert_count() + 1)
+            .map(|i| target.vert(i) - base.vert(i))
+            .collect();
+
+        let normal_deltas = if target.norm_vert_count() == 0 {
+            Vec::new()
+        } else {
+            (1..base.norm_vert_count() + 1)
+                .map(|i| target.norm_vert(i) - base.norm_vert(i))
+                .collect()
+        };
+
+        self.targets.push(MorphTarget {
+            name: name.to_string(),
+            position_deltas,
+            normal_deltas,
+        });
+
+        Ok(self.targets.len() - 1)
+    }
+
+    pub fn load_target(&mut self, base: &Obj, name: &str, filename: &str) -> Result<usize, MorphError> {
+        let target = Obj::from_file(filename)?;
+
+        self.add_target(base, name, &target)
+    }
+
+    pub fn find(&self, name: &str) -> Option<usize> {
+        self.targets.iter().position(|t| t.name == name)
+    }
+
+    // `weights` has one entry per target; missing entries count as zero.
+    // Index 0 (no vertex or normal) leaves the value as it is.
+    pub fn morph_point(&self, pt: Vec3<f32>, vindex: usize, weights: &[f32]) -> Vec3<f32> {
+        if vindex == 0 {
+            return pt;
+        }
+
+        self.targets.iter().zip(weights.iter())
+            .filter(|&(_, &w)| w != 0.0)
+            .fold(pt, |pt, (target, &w)| pt + target.position_deltas[vindex - 1] * w)
+    }
+
+    pub fn morph_normal(&self, normal: Vec3<f32>, nindex: usize, weights: &[f32]) -> Vec3<f32> {
+        if nindex == 0 {
+            return normal;
+        }
+
+        self.targets.iter().zip(weights.iter())
+            .filter(|&(target, &w)| w != 0.0 && !target.normal_deltas.is_empty())
+            .fold(normal, |n, (target, &w)| n + target.normal_deltas[nindex - 1] * w)
+            .norm()
+    }
+}
+
+// Lets the vertex stage find the deltas for the corner it's shading. The
+// indices refer to the source mesh, so they aren't interpolated.
+#[derive(Clone, Copy, Vary)]
+pub struct MorphIndex {
+    #[vary(flat)]
+    pub vindex: usize,
+    #[vary(flat)]
+    pub nindex: usize,
+}
+
+#[derive(Clone, Copy, Vary)]
+pub struct MorphVars {
+    pub index: MorphIndex,
+    pub normal: Vec3<f32>,
+    pub tex: Vec2<f32>,
+}
+
+impl MorphVars {
+    pub fn from_obj(obj: &Obj, fp: &FacePoint) -> MorphVars {
+        MorphVars {
+            index: MorphIndex { vindex: fp.vindex, nindex: fp.nindex },
+            normal: if fp.nindex == 0 { Vec3 { x: 0.0, y: 0.0, z: 0.0 } } else { obj.norm_vert(fp.nindex) },
+            tex: if fp.tindex == 0 { Vec2 { x: 0.0, y: 0.0 } } else { obj.tex_vert(fp.tindex) },
+        }
+    }
+}
+
+// Blends the targets into each vertex with `weights` (one per target, as
+// from MorphAnimation::weights), then shades like TexturedShader, or with
+// a plain color if there's no texture. The mesh must come from the base
+// Obj the targets were added against:
+//
+//     let mesh = base.to_indexed(MorphVars::from_obj);
+//     let shader = MorphShader::new(&shapes, anim.weights(time), &model, &screen, light_dir);
+//     draw_indexed(&mesh, &shader, &mut image);
+pub struct MorphShader<'a> {
+    shapes: &'a BlendShapes,
+    weights: Vec<f32>,
+    transforms: Transforms,
+    pub texture: Option<&'a Image>,
+    pub color: Vec3<f32>,
+    pub light_dir: Vec3<f32>,
+    pub ambient: f32,
+}
+
+impl<'a> MorphShader<'a> {
+    pub fn new(shapes: &'a BlendShapes, weights: Vec<f32>, model: &Matrix4x4<f32>, screen: &Matrix4x4<f32>,
+               light_dir: Vec3<f32>) -> MorphShader<'a> {
+        MorphShader {
+            shapes,
+            weights,
+            transforms: Transforms::new(model, screen),
+            texture: None,
+            color: Vec3 { x: 1.0, y: 1.0, z: 1.0 },
+            light_dir,
+            ambient: 0.1,
+        }
+    }
+}
+
+impl<'a> Shader<MorphVars> for MorphShader<'a> {
+    fn vertex(&self, pt: Vec3<f32>, vars: &MorphVars) -> (Vec4<f32>, MorphVars) {
+        let pt = self.shapes.morph_point(pt, vars.index.vindex, &self.weights);
+        let normal = self.shapes.morph_normal(vars.normal, vars.index.nindex, &self.weights);
+        let (clip, _) = self.transforms.position(pt);
+
+        (clip, MorphVars { normal: self.transforms.normal(normal), ..*vars })
+    }
+
+    fn fragment(&self, _: Vec2<isize>, vars: MorphVars) -> Option<Color> {
+        let albedo = match self.texture {
+            Some(tex) => sample(tex, vars.tex).to_vec(),
+            None => self.color,
+        };
+        let shading = self.ambient + (1.0 - self.ambient) * diffuse(vars.normal, self.light_dir);
+
+        Some(Color::from_vec(albedo * shading))
+    }
+}
+
+// One weight track per blend shape target
+pub struct MorphAnimation {
+    pub tracks: Vec<Track<f32>>,
+}
+
+impl MorphAnimation {
+    pub fn new(target_count: usize, interpolation: Interpolation) -> MorphAnimation {
+        MorphAnimation {
+            tracks: (0..target_count).map(|_| Track::new(interpolation)).collect(),
+        }
+    }
+
+    pub fn duration(&self) -> f32 {
+        self.tracks.iter().fold(0.0, |d, t| d.max(t.duration()))
+    }
+
+    pub fn weights(&self, time: f32) -> Vec<f32> {
+        self.tracks.iter().map(|t| t.sample(time).unwrap_or(0.0)).collect()
+    }
+
+    pub fn weights_at_frame(&self, frame: usize, fps: f32) -> Vec<f32> {
+        self.weights(frame as f32 / fps)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use shader::draw_triangle;
+
+    fn obj(text: &str) -> Obj {
+        Obj::from_reader(Cursor::new(text)).unwrap()
+    }
+
+    const BASE: &str = "v 0 0 0\nv 1 0 0\nv 0 1 0\nvn 0 0 1\nf 1//1 2//1 3//1\n";
+
+    #[test]
+    fn rejects_mismatched_targets() {
+        let base = obj(BASE);
+        let mut shapes = BlendShapes::new();
+
+        let result = shapes.add_target(&base, "t", &obj("v 0 0 0\nv 1 0 0\nf 1 2 2\n"));
+        assert!(matches!(result, Err(MorphError::VertexCountMismatch { expected: 3, found: 2 })));
+
+        let result = shapes.add_target(&base, "t", &obj("v 0 0 0\nv 1 0 0\nv 0 1 0\nvn 0 0 1\nvn 0 1 0\nf 1//1 2//1 3//1\n"));
+        assert!(matches!(result, Err(MorphError::NormalCountMismatch { expected: 1, found: 2 })));
+
+        let result = shapes.add_target(&base, "t", &obj("v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 3 2\n"));
+        assert!(matches!(result, Err(MorphError::FaceMismatch { face: 0 })));
+
+        let result = shapes.add_target(&base, "t", &obj("v 0 0 0\nv 1 0 0\nv 0 1 0\n"));
+        assert!(matches!(result, Err(MorphError::FaceMismatch { face: 0 })));
+
+        assert!(shapes.targets.is_empty());
+    }
+
+    #[test]
+    fn blends_target_deltas() {
+        let base = obj(BASE);
+        let mut shapes = BlendShapes::new();
+        let index = shapes.add_target(&base, "raise", &obj("v 0 0 2\nv 1 0 0\nv 0 1 0\nvn 1 0 0\nf 1//1 2//1 3//1\n")).unwrap();
+        shapes.add_target(&base, "positions only", &obj("v 0 0 0\nv 3 0 0\nv 0 1 0\nf 1 2 3\n")).unwrap();
+
+        assert_eq!(shapes.find("raise"), Some(index));
+        assert_eq!(shapes.targets[0].position_deltas[0].z, 2.0);
+        assert!(shapes.targets[1].normal_deltas.is_empty());
+
+        let pt = shapes.morph_point(base.vert(1), 1, &[0.5]);
+        assert_eq!((pt.x, pt.y, pt.z), (0.0, 0.0, 1.0));
+        let pt = shapes.morph_point(base.vert(2), 2, &[1.0, 0.5]);
+        assert_eq!((pt.x, pt.y, pt.z), (2.0, 0.0, 0.0));
+
+        let n = shapes.morph_normal(base.norm_vert(1), 1, &[0.5, 1.0]);
+        let half = std::f32::consts::FRAC_1_SQRT_2;
+        assert!((n.x - half).abs() < 1e-6 && (n.z - half).abs() < 1e-6);
+    }
+
+    #[test]
+    fn corners_without_normals_are_left_alone() {
+        let base = obj("v 0 0 0\nv 1 0 0\nv 0 1 0\nvn 0 0 1\nf 1//1 2//1 3//1\nf 1 2 3\n");
+        let mut shapes = BlendShapes::new();
+        shapes.add_target(&base, "t", &obj("v 0 0 0\nv 1 0 0\nv 0 1 0\nvn 1 0 0\nf 1//1 2//1 3//1\nf 1 2 3\n")).unwrap();
+
+        let zero = Vec3 { x: 0.0, y: 0.0, z: 0.0 };
+        let n = shapes.morph_normal(zero, 0, &[1.0]);
+        assert_eq!((n.x, n.y, n.z), (0.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn animates_weights() {
+        let mut anim = MorphAnimation::new(2, Interpolation::Linear);
+        anim.tracks[0].add_key(0.0, 0.0);
+        anim.tracks[0].add_key(1.0, 1.0);
+
+        assert_eq!(anim.duration(), 1.0);
+        assert_eq!(anim.weights(0.25), vec![0.25, 0.0]);
+        assert_eq!(anim.weights_at_frame(3, 4.0), vec![0.75, 0.0]);
+    }
+
+    #[test]
+    fn shader_morphs_in_the_vertex_stage() {
+        let base = obj(BASE);
+        let mut shapes = BlendShapes::new();
+        shapes.add_target(&base, "t", &obj("v 4 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 3\n")).unwrap();
+
+        let id = Matrix4x4::identity();
+        let light = Vec3 { x: 0.0, y: 0.0, z: 1.0 };
+        let vars = MorphVars::from_obj(&base, &base.faces()[0].0);
+
+        let shader = MorphShader::new(&shapes, vec![0.5], &id, &id, light);
+        let (clip, out) = shader.vertex(base.vert(1), &vars);
+        assert_eq!((clip.x, clip.y, clip.z), (2.0, 0.0, 0.0));
+        assert_eq!(out.normal.z, 1.0);
+
+        // Scaled up to pixels, the morphed triangle covers what the base
+        // one doesn't
+        let screen = Matrix4x4::scale(Vec3 { x: 4.0, y: 4.0, z: 1.0 });
+        let corners: Vec<_> = [1, 2, 3].iter().map(|&i| {
+            let fp = FacePoint { vindex: i, tindex: 0, nindex: 1 };
+            (base.vert(i), MorphVars::from_obj(&base, &fp))
+        }).collect();
+
+        let mut image = Image::new(16, 16);
+        draw_triangle(&corners, &MorphShader::new(&shapes, vec![0.0], &id, &screen, light), &mut image);
+        assert_eq!(image.get_pixel(5, 0).0, 0);
+
+        let mut image = Image::new(16, 16);
+        draw_triangle(&corners, &MorphShader::new(&shapes, vec![1.0], &id, &screen, light), &mut image);
+        assert_eq!(image.get_pixel(5, 0).0, 255);
+    }
+}
@@ -0,0 +1,304 @@
+use std::cmp;
+use std::f32;
+use vec::{Vec2, Vec3, Vec4};
+use image::Image;
+use matrix::{Matrix4x4, Transform};
+use animation::Keyable;
+use shader::Shader;
+use target::{Buffer, DepthTarget};
+use post::{FloatImage, sample_clamped};
+use accum::Accumulator;
+
+// Two ways to blur things that move during the frame:
+//
+// - accumulate_subframes renders the scene several times across the
+//   shutter interval and averages the results. Exact, but costs a render
+//   per subframe.
+// - Draw every object with a VelocityShader into a DepthTarget, giving
+//   each pixel's screen motion since the previous frame, then blur the
+//   finished image along it with motion_blur. One extra pass, whatever
+//   the amount of motion.
+
+#[derive(Clone, Copy, Vary)]
+pub struct VelocityVars {
+    // Screen positions this frame and last, in pixels
+    pub current: Vec2<f32>,
+    pub previous: Vec2<f32>,
+}
+
+impl VelocityVars {
+    // Meshes drawn with VelocityShader only need positions; use this for
+    // every vertex
+    pub fn new() -> VelocityVars {
+        VelocityVars {
+            current: Vec2 { x: 0.0, y: 0.0 },
+            previous: Vec2 { x: 0.0, y: 0.0 },
+        }
+    }
+}
+
+// Outputs how far each fragment moved on screen since the previous frame,
+// in pixels. Both the object and the camera may have moved.
+pub struct VelocityShader {
+    current: Matrix4x4<f32>,
+    previous: Matrix4x4<f32>,
+}
+
+impl VelocityShader {
+    // `screen` is viewport * projection * view, `model` the object's
+    // transform; the `previous_` ones are last frame's
+    pub fn new(screen: &Matrix4x4<f32>, model: &Matrix4x4<f32>, previous_screen: &Matrix4x4<f32>, previous_model: &Matrix4x4<f32>) -> VelocityShader {
+        VelocityShader {
+            current: screen * model,
+            previous: previous_screen * previous_model,
+        }
+    }
+}
+
+impl Shader<VelocityVars, Vec2<f32>> for VelocityShader {
+    fn vertex(&self, pt: Vec3<f32>, _: &VelocityVars) -> (Vec4<f32>, VelocityVars) {
+        let pt4 = Vec4 { x: pt.x, y: pt.y, z: pt.z, w: 1.0 };
+        let current = &self.current * &pt4;
+        let previous = &self.previous * &pt4;
+
+        (
+            current,
+            VelocityVars {
+                current: current.xy() / current.w,
+                previous: previous.xy() / previous.w,
+            },
+        )
+    }
+
+    fn fragment(&self, _: Vec2<isize>, vars: VelocityVars) -> Option<Vec2<f32>> {
+        Some(Vec2 { x: vars.current.x - vars.previous.x, y: vars.current.y - vars.previous.y })
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct MotionBlurOptions {
+    pub samples: usize,
+    // Fraction of the frame the shutter is open for. 1 smears each pixel
+    // over its whole motion since the last frame.
+    pub shutter: f32,
+    // In pixels; longer motion is clamped. Also the size of the tiles the
+    // dominant motion is found in, so larger values cost more.
+    pub max_length: usize,
+    // In depth buffer units. Closer than this, surfaces are treated as
+    // neither in front of nor behind each other.
+    pub depth_tolerance: f32,
+}
+
+impl MotionBlurOptions {
+    pub fn new() -> MotionBlurOptions {
+        MotionBlurOptions {
+            samples: 12,
+            shutter: 1.0,
+            max_length: 24,
+            depth_tolerance: 1.0,
+        }
+    }
+}
+
+// Blurs each pixel along the motion of whatever passes over it while the
+// shutter is open (McGuire et al., "A reconstruction filter for plausible
+// motion blur"). Moving objects smear over the static background behind
+// them, not just within their own silhouettes. `velocity` and `depth` are
+// a DepthTarget filled by VelocityShader.
+pub fn motion_blur(image: &FloatImage, velocity: &Buffer<Vec2<f32>>, depth: &Buffer<f32>, options: &MotionBlurOptions) -> FloatImage {
+    let max_length = options.max_length.max(1) as f32;
+    let scaled = |v: Vec2<f32>| {
+        let v = v * options.shutter;
+        if length(v) > max_length { v * (max_length / length(v)) } else { v }
+    };
+
+    let tile = options.max_length.max(1);
+    let neighbours = neighbour_max(velocity, tile, &scaled);
+    let mut out = image.clone();
+
+    // Uncovered pixels are infinitely far, which the comparisons below
+    // can't subtract
+    let z = |x: usize, y: usize| depth.get(x, y).max(f32::MIN);
+    let soft_compare = |a: f32, b: f32| (1.0 - (a - b) / options.depth_tolerance.max(1e-6)).clamp(0.0, 1.0);
+
+    for y in 0..image.height {
+        for x in 0..image.width {
+            let dominant = *neighbours.get(x / tile, y / tile);
+            if length(dominant) < 0.5 {
+                continue;
+            }
+
+            let v_center = scaled(*velocity.get(x, y));
+            let z_center = z(x, y);
+
+            let mut weight = 1.0 / length(v_center).max(1.0);
+            let mut sum = *image.get(x, y) * weight;
+
+            // Offsets the samples per pixel, trading banding for noise
+            let jitter = hash(x, y) - 0.5;
+
+            for i in 0..options.samples {
+                let t = -1.0 + 2.0 * (i as f32 + jitter + 1.0) / (options.samples as f32 + 1.0);
+                let sx = (x as f32 + dominant.x * 0.5 * t).round() as isize;
+                let sy = (y as f32 + dominant.y * 0.5 * t).round() as isize;
+
+                if sx < 0 || sy < 0 || sx >= image.width as isize || sy >= image.height as isize {
+                    continue;
+                }
+                let (sx, sy) = (sx as usize, sy as usize);
+
+                let v_sample = scaled(*velocity.get(sx, sy));
+                let z_sample = z(sx, sy);
+                let distance = ((sx as f32 - x as f32).powi(2) + (sy as f32 - y as f32).powi(2)).sqrt();
+
+                // The sample is in front and moving over this pixel, this
+                // pixel is in front and moving over the sample, or both are
+                // moving together
+                let front = soft_compare(z_center, z_sample);
+                let back = soft_compare(z_sample, z_center);
+                let alpha = front * cone(distance, v_sample) +
+                    back * cone(distance, v_center) +
+                    cylinder(distance, v_sample) * cylinder(distance, v_center) * 2.0;
+
+                weight += alpha;
+                sum = sum + sample_clamped(image, sx as isize, sy as isize) * alpha;
+            }
+
+            out.set(x, y, sum * (1.0 / weight));
+        }
+    }
+
+    out
+}
+
+fn length(v: Vec2<f32>) -> f32 {
+    (v.x * v.x + v.y * v.y).sqrt()
+}
+
+fn cone(distance: f32, v: Vec2<f32>) -> f32 {
+    (1.0 - distance / length(v).max(1e-6)).clamp(0.0, 1.0)
+}
+
+fn cylinder(distance: f32, v: Vec2<f32>) -> f32 {
+    let length = length(v);
+    let (edge0, edge1) = (0.95 * length, 1.05 * length + 1e-6);
+    let t = ((distance - edge0) / (edge1 - edge0)).clamp(0.0, 1.0);
+
+    1.0 - t * t * (3.0 - 2.0 * t)
+}
+
+fn hash(x: usize, y: usize) -> f32 {
+    let h = (x as u32).wrapping_mul(73856093) ^ (y as u32).wrapping_mul(19349663);
+    let h = h.wrapping_mul(2654435761);
+    (h >> 8) as f32 / (1 << 24) as f32
+}
+
+// The longest velocity in each tile and the eight around it. Any motion
+// that can reach a pixel is at most a tile away.
+fn neighbour_max<F: Fn(Vec2<f32>) -> Vec2<f32>>(velocity: &Buffer<Vec2<f32>>, tile: usize, scaled: &F) -> Buffer<Vec2<f32>> {
+    let (tiles_x, tiles_y) = (velocity.width.div_ceil(tile), velocity.height.div_ceil(tile));
+    let zero = Vec2 { x: 0.0, y: 0.0 };
+    let longest = |a: Vec2<f32>, b: Vec2<f32>| if length(b) > length(a) { b } else { a };
+
+    let mut tile_max = Buffer::new(tiles_x, tiles_y, zero);
+    for y in 0..velocity.height {
+        for x in 0..velocity.width {
+            let current = *tile_max.get(x / tile, y / tile);
+            tile_max.set(x / tile, y / tile, longest(current, scaled(*velocity.get(x, y))));
+        }
+    }
+
+    let mut neighbours = Buffer::new(tiles_x, tiles_y, zero);
+    for ty in 0..tiles_y {
+        for tx in 0..tiles_x {
+            let mut max = zero;
+
+            for ny in ty.saturating_sub(1)..cmp::min(tiles_y, ty + 2) {
+                for nx in tx.saturating_sub(1)..cmp::min(tiles_x, tx + 2) {
+                    max = longest(max, *tile_max.get(nx, ny));
+                }
+            }
+
+            neighbours.set(tx, ty, max);
+        }
+    }
+
+    neighbours
+}
+
+// Convenience for the velocity pass: an empty target to draw into
+pub fn velocity_target(width: usize, height: usize) -> DepthTarget<Vec2<f32>> {
+    DepthTarget::new(width, height, Vec2 { x: 0.0, y: 0.0 })
+}
+
+// Renders at `subframes` times evenly spread across the shutter interval
+// [0, 1] and averages them. `render` gets the time, e.g. to place objects
+// with interpolate_matrix.
+pub fn accumulate_subframes<F: FnMut(f32) -> Image>(subframes: usize, mut render: F) -> Image {
+    let subframes = subframes.max(1);
+    let mut accumulator: Option<Accumulator> = None;
+
+    for i in 0..subframes {
+        let frame = render((i as f32 + 0.5) / subframes as f32);
+        accumulator.get_or_insert_with(|| Accumulator::new(frame.width, frame.height)).add(&frame);
+    }
+
+    accumulator.unwrap().image()
+}
+
+// Blends two affine transforms by their translation, rotation and scale,
+// so rotating objects sweep through an arc instead of shrinking through
+// the middle like a plain matrix lerp would
+pub fn interpolate_matrix(from: &Matrix4x4<f32>, to: &Matrix4x4<f32>, t: f32) -> Matrix4x4<f32> {
+    let (a, b) = (from.decompose(), to.decompose());
+
+    Transform {
+        translation: a.translation.lerp(b.translation, t),
+        rotation: a.rotation.lerp(b.rotation, t),
+        scale: a.scale.lerp(b.scale, t),
+    }.to_matrix()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nothing_moving_means_no_blur() {
+        let mut image = FloatImage::new(8, 8, Vec3 { x: 0.0, y: 0.0, z: 0.0 });
+        let mut depth = Buffer::new(8, 8, f32::NEG_INFINITY);
+        for y in 0..8 {
+            for x in 0..8 {
+                image.set(x, y, Vec3 { x: x as f32 / 7.0, y: y as f32 / 7.0, z: ((x + y) % 2) as f32 });
+                if x < 5 {
+                    depth.set(x, y, y as f32);
+                }
+            }
+        }
+        let velocity = Buffer::new(8, 8, Vec2 { x: 0.0, y: 0.0 });
+
+        let out = motion_blur(&image, &velocity, &depth, &MotionBlurOptions::new());
+        for (a, b) in out.data.iter().zip(image.data.iter()) {
+            assert!(a.x == b.x && a.y == b.y && a.z == b.z);
+        }
+    }
+
+    #[test]
+    fn interpolated_matrices_start_and_end_at_the_endpoints() {
+        let axis = Vec3 { x: 1.0, y: 2.0, z: -1.0 }.norm();
+        let from = Matrix4x4::translation(Vec3 { x: 1.0, y: -2.0, z: 3.0 }) * Matrix4x4::rotation(0.4, axis) *
+            Matrix4x4::scale(Vec3 { x: 2.0, y: 2.0, z: 2.0 });
+        let to = Matrix4x4::translation(Vec3 { x: -4.0, y: 0.5, z: 1.0 }) * Matrix4x4::rotation(2.1, axis) *
+            Matrix4x4::scale(Vec3 { x: 0.5, y: 1.0, z: 3.0 });
+
+        for &(t, expected) in [(0.0, &from), (1.0, &to)].iter() {
+            let m = interpolate_matrix(&from, &to, t);
+
+            for row in 0..4 {
+                let (a, b) = (m.row(row), expected.row(row));
+                let difference = (a.x - b.x).abs() + (a.y - b.y).abs() + (a.z - b.z).abs() + (a.w - b.w).abs();
+                assert!(difference < 1e-4, "t = {} row {}", t, row);
+            }
+        }
+    }
+}
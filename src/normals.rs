@@ -0,0 +1,202 @@
+use std::collections::HashMap;
+use std::f32::consts::PI;
+use vec::Vec3;
+use obj::Obj;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum NormalWeighting {
+    Uniform,
+    // Big faces pull harder than small ones
+    Area,
+    // Each face contributes by the angle of its corner at the vertex, which
+    // keeps the result independent of how a surface was triangulated
+    Angle,
+}
+
+#[derive(Clone, Copy)]
+pub struct SmoothOptions {
+    pub weighting: NormalWeighting,
+    // Faces meeting at a sharper angle than this (in radians) don't share
+    // normals, leaving a hard edge
+    pub crease_angle: f32,
+    // Only smooth faces in the same OBJ smoothing group (`s`), and keep
+    // faces with smoothing off flat
+    pub smoothing_groups: bool,
+}
+
+impl SmoothOptions {
+    pub fn new() -> SmoothOptions {
+        SmoothOptions {
+            weighting: NormalWeighting::Angle,
+            crease_angle: PI,
+            smoothing_groups: true,
+        }
+    }
+}
+
+fn corner_angle(a: Vec3<f32>, b: Vec3<f32>, c: Vec3<f32>) -> f32 {
+    let e1 = b - a;
+    let e2 = c - a;
+    let denom = e1.length() * e2.length();
+
+    if denom == 0.0 {
+        0.0
+    } else {
+        (e1.dot(e2) / denom).clamp(-1.0, 1.0).acos()
+    }
+}
+
+// Degenerate faces get a zero normal rather than NaNs
+fn face_normals(obj: &Obj) -> Vec<Vec3<f32>> {
+    obj.faces().iter().map(|f| {
+        let area = f.area_vector(obj);
+        if area.length() > 0.0 { f.normal(obj) } else { area }
+    }).collect()
+}
+
+// Adds the normal unless an identical one already exists, so vertices that
+// end up with the same normal keep sharing it
+fn intern(obj: &mut Obj, seen: &mut HashMap<(usize, u32, u32, u32), usize>, vindex: usize, n: Vec3<f32>) -> usize {
+    let key = (vindex, n.x.to_bits(), n.y.to_bits(), n.z.to_bits());
+
+    *seen.entry(key).or_insert_with(|| obj.add_norm_vert(n))
+}
+
+impl Obj {
+    // One normal per face, so every triangle is shaded flat
+    pub fn generate_flat_normals(&mut self) {
+        let normals = face_normals(self);
+
+        self.clear_norm_verts();
+
+        for (i, n) in normals.into_iter().enumerate() {
+            let nindex = self.add_norm_vert(n);
+            self.set_normal_indices(i, [nindex; 3]);
+        }
+    }
+
+    pub fn generate_smooth_normals(&mut self, options: &SmoothOptions) {
+        let face_normals = face_normals(self);
+
+        // Smoothing groups only mean something when the faces still line up
+        // with the polygons they were loaded from
+        let mut smoothing: Vec<Option<u32>> = vec![None; self.faces().len()];
+        if options.smoothing_groups {
+            for polygon in self.all_polygons() {
+                for s in smoothing.iter_mut().skip(polygon.first_face).take(polygon.face_count) {
+                    *s = polygon.attrs.smoothing;
+                }
+            }
+        }
+
+        // How much each face contributes at each of its corners
+        let weights: Vec<[f32; 3]> = self.faces().iter().map(|f| {
+            let (v0, v1, v2) = (self.vert(f.0.vindex), self.vert(f.1.vindex), self.vert(f.2.vindex));
+
+            match options.weighting {
+                NormalWeighting::Uniform => [1.0; 3],
+                NormalWeighting::Area => [f.area_vector(self).length() / 2.0; 3],
+                NormalWeighting::Angle => [
+                    corner_angle(v0, v1, v2),
+                    corner_angle(v1, v2, v0),
+                    corner_angle(v2, v0, v1),
+                ],
+            }
+        }).collect();
+
+        let mut incident: Vec<Vec<(usize, usize)>> = vec![Vec::new(); self.vert_count()];
+        for (i, f) in self.faces().iter().enumerate() {
+            incident[f.0.vindex - 1].push((i, 0));
+            incident[f.1.vindex - 1].push((i, 1));
+            incident[f.2.vindex - 1].push((i, 2));
+        }
+
+        let cos_crease = options.crease_angle.cos();
+        let mut corner_normals: Vec<[Vec3<f32>; 3]> = face_normals.iter().map(|&n| [n; 3]).collect();
+
+        for corners in incident.iter() {
+            for &(face, corner) in corners.iter() {
+                let group = smoothing[face];
+
+                // Faces with smoothing turned off keep their own normal.
+                // Faces the file didn't give a group (None) smooth with
+                // each other.
+                if group == Some(0) {
+                    continue;
+                }
+
+                let sum = corners.iter()
+                    .filter(|&&(other, _)| smoothing[other] == group)
+                    .filter(|&&(other, _)| other == face ||
+                            face_normals[other].dot(face_normals[face]) >= cos_crease)
+                    .fold(Vec3 { x: 0.0, y: 0.0, z: 0.0 }, |sum, &(other, other_corner)| {
+                        sum + face_normals[other] * weights[other][other_corner]
+                    });
+
+                if sum.length() > 0.0 {
+                    corner_normals[face][corner] = sum.norm();
+                }
+            }
+        }
+
+        self.clear_norm_verts();
+
+        let mut seen = HashMap::new();
+        for (i, normals) in corner_normals.iter().enumerate() {
+            let (v0, v1, v2) = (self.faces()[i].0.vindex, self.faces()[i].1.vindex, self.faces()[i].2.vindex);
+
+            let n0 = intern(self, &mut seen, v0, normals[0]);
+            let n1 = intern(self, &mut seen, v1, normals[1]);
+            let n2 = intern(self, &mut seen, v2, normals[2]);
+
+            self.set_normal_indices(i, [n0, n1, n2]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Two triangles folded along the x axis, sharing vertices 1 and 2
+    fn fold(smoothing: &str) -> Obj {
+        let text = format!("v 0 0 0\nv 1 0 0\nv 0 1 0\nv 0 0 1\n{}f 1 2 3\nf 2 1 4\n", smoothing);
+        Obj::from_reader(text.as_bytes()).unwrap()
+    }
+
+    fn shares_normals(obj: &Obj) -> bool {
+        let (a, b) = (obj.faces()[0], obj.faces()[1]);
+        a.0.nindex == b.1.nindex && a.1.nindex == b.0.nindex
+    }
+
+    #[test]
+    fn smooths_meshes_without_smoothing_groups() {
+        let mut obj = fold("");
+        obj.generate_smooth_normals(&SmoothOptions::new());
+        assert!(shares_normals(&obj));
+
+        // Meshes from formats without smoothing groups
+        let mut built = Obj::new();
+        for i in 1..5 {
+            built.add_vert(obj.vert(i));
+        }
+        for &face in fold("").faces() {
+            built.add_face(face);
+        }
+        built.generate_smooth_normals(&SmoothOptions::new());
+        assert!(shares_normals(&built));
+    }
+
+    #[test]
+    fn keeps_faces_with_smoothing_off_flat() {
+        for &s in ["s off\n", "s 0\n"].iter() {
+            let mut obj = fold(s);
+            obj.generate_smooth_normals(&SmoothOptions::new());
+            assert!(!shares_normals(&obj));
+        }
+
+        let mut obj = fold("s 1\n");
+        obj.generate_smooth_normals(&SmoothOptions::new());
+        assert!(shares_normals(&obj));
+    }
+}
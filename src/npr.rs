@@ -0,0 +1,386 @@
+use std::cmp;
+use std::f32::consts::PI;
+use vec::{Vec2, Vec3, Vec4};
+use image::{Image, Color};
+use matrix::Matrix4x4;
+use obj::{Obj, FacePoint};
+use shader::Shader;
+use shaders::{Transforms, sample, diffuse};
+use target::{Buffer, GBuffer};
+
+// Non-photorealistic stock shaders. They all take the same NprVars, so a
+// mesh built once can be drawn in any of the styles, and outlined with
+// OutlineShader or outline_edges.
+
+#[derive(Clone, Copy, Vary)]
+pub struct NprVars {
+    // Model space going into the vertex shader, world space coming out
+    pub position: Vec3<f32>,
+    pub normal: Vec3<f32>,
+    pub tex: Vec2<f32>,
+}
+
+impl NprVars {
+    // Missing texcoords and normals are left at zero
+    pub fn from_obj(obj: &Obj, fp: &FacePoint) -> NprVars {
+        NprVars {
+            position: obj.vert(fp.vindex),
+            normal: if fp.nindex == 0 { Vec3 { x: 0.0, y: 0.0, z: 0.0 } } else { obj.norm_vert(fp.nindex) },
+            tex: if fp.tindex == 0 { Vec2 { x: 0.0, y: 0.0 } } else { obj.tex_vert(fp.tindex) },
+        }
+    }
+}
+
+fn vertex(transforms: &Transforms, pt: Vec3<f32>, vars: &NprVars) -> (Vec4<f32>, NprVars) {
+    let (clip, position) = transforms.position(pt);
+    (clip, NprVars { position, normal: transforms.normal(vars.normal), ..*vars })
+}
+
+// Cel shading: lighting snaps to a few flat bands instead of varying
+// smoothly
+pub struct ToonShader<'a> {
+    transforms: Transforms,
+    // Towards the light
+    pub light_dir: Vec3<f32>,
+    pub color: Vec3<f32>,
+    // Multiplies `color`
+    pub texture: Option<&'a Image>,
+    // Read left (unlit) to right (fully lit) along its middle row. Without
+    // one, lighting is quantized to `bands` evenly spaced levels.
+    pub ramp: Option<&'a Image>,
+    pub bands: usize,
+    // Brightness of the darkest band
+    pub ambient: f32,
+}
+
+impl<'a> ToonShader<'a> {
+    pub fn new(model: &Matrix4x4<f32>, screen: &Matrix4x4<f32>, light_dir: Vec3<f32>) -> ToonShader<'a> {
+        ToonShader {
+            transforms: Transforms::new(model, screen),
+            light_dir,
+            color: Vec3 { x: 1.0, y: 1.0, z: 1.0 },
+            texture: None,
+            ramp: None,
+            bands: 3,
+            ambient: 0.2,
+        }
+    }
+}
+
+// A ramp with one flat step per color, darkest first, for ToonShader
+pub fn toon_ramp(colors: &[Color]) -> Image {
+    let mut ramp = Image::new(cmp::max(colors.len(), 1), 1);
+
+    for (x, c) in colors.iter().enumerate() {
+        ramp.set_pixel(x, 0, c);
+    }
+
+    ramp
+}
+
+impl<'a> Shader<NprVars> for ToonShader<'a> {
+    fn vertex(&self, pt: Vec3<f32>, vars: &NprVars) -> (Vec4<f32>, NprVars) {
+        vertex(&self.transforms, pt, vars)
+    }
+
+    fn fragment(&self, _: Vec2<isize>, vars: NprVars) -> Option<Color> {
+        let d = diffuse(vars.normal, self.light_dir);
+
+        let mut color = self.color;
+        if let Some(tex) = self.texture {
+            color = mul(color, sample(tex, vars.tex).to_vec());
+        }
+
+        let shaded = match self.ramp {
+            Some(ramp) => mul(color, sample(ramp, Vec2 { x: d, y: 0.5 }).to_vec()),
+            None => {
+                let bands = cmp::max(self.bands, 2) as f32;
+                let level = (d * bands).floor().min(bands - 1.0) / (bands - 1.0);
+                color * (self.ambient + (1.0 - self.ambient) * level)
+            }
+        };
+
+        Some(Color::from_vec(shaded))
+    }
+}
+
+// Gooch et al.'s technical illustration shading: instead of darkening,
+// surfaces shift from warm facing the light to cool facing away, so shape
+// reads clearly even in shadow
+pub struct GoochShader<'a> {
+    transforms: Transforms,
+    pub light_dir: Vec3<f32>,
+    pub color: Vec3<f32>,
+    pub texture: Option<&'a Image>,
+    pub cool: Vec3<f32>,
+    pub warm: Vec3<f32>,
+    // How much of the surface color is mixed into the cool and warm tones
+    pub alpha: f32,
+    pub beta: f32,
+}
+
+impl<'a> GoochShader<'a> {
+    pub fn new(model: &Matrix4x4<f32>, screen: &Matrix4x4<f32>, light_dir: Vec3<f32>) -> GoochShader<'a> {
+        GoochShader {
+            transforms: Transforms::new(model, screen),
+            light_dir,
+            color: Vec3 { x: 0.75, y: 0.75, z: 0.75 },
+            texture: None,
+            cool: Vec3 { x: 0.0, y: 0.0, z: 0.55 },
+            warm: Vec3 { x: 0.3, y: 0.3, z: 0.0 },
+            alpha: 0.25,
+            beta: 0.5,
+        }
+    }
+}
+
+impl<'a> Shader<NprVars> for GoochShader<'a> {
+    fn vertex(&self, pt: Vec3<f32>, vars: &NprVars) -> (Vec4<f32>, NprVars) {
+        vertex(&self.transforms, pt, vars)
+    }
+
+    fn fragment(&self, _: Vec2<isize>, vars: NprVars) -> Option<Color> {
+        let mut color = self.color;
+        if let Some(tex) = self.texture {
+            color = mul(color, sample(tex, vars.tex).to_vec());
+        }
+
+        // Unlike Lambert, the full range from facing away to facing the
+        // light is used
+        let t = if vars.normal.length() > 0.0 {
+            (1.0 + vars.normal.norm().dot(self.light_dir.norm())) / 2.0
+        } else {
+            1.0
+        };
+
+        let cool = self.cool + color * self.alpha;
+        let warm = self.warm + color * self.beta;
+
+        Some(Color::from_vec(warm * t + cool * (1.0 - t)))
+    }
+}
+
+// Pen and ink: darker areas get more layers of parallel lines, each layer
+// at a different angle. Lines are laid out in screen space.
+pub struct HatchShader {
+    transforms: Transforms,
+    pub light_dir: Vec3<f32>,
+    // Distance between lines, in pixels
+    pub spacing: f32,
+    pub ink: Color,
+    pub paper: Color,
+}
+
+// Each layer is drawn where the lighting is below its threshold
+const HATCH_LAYERS: [(f32, f32); 4] = [
+    (0.8, PI / 4.0),
+    (0.6, -PI / 4.0),
+    (0.4, 0.0),
+    (0.2, PI / 2.0),
+];
+
+impl HatchShader {
+    pub fn new(model: &Matrix4x4<f32>, screen: &Matrix4x4<f32>, light_dir: Vec3<f32>) -> HatchShader {
+        HatchShader {
+            transforms: Transforms::new(model, screen),
+            light_dir,
+            spacing: 6.0,
+            ink: Color(20, 20, 30),
+            paper: Color(250, 245, 230),
+        }
+    }
+}
+
+impl Shader<NprVars> for HatchShader {
+    fn vertex(&self, pt: Vec3<f32>, vars: &NprVars) -> (Vec4<f32>, NprVars) {
+        vertex(&self.transforms, pt, vars)
+    }
+
+    fn fragment(&self, pt: Vec2<isize>, vars: NprVars) -> Option<Color> {
+        let d = diffuse(vars.normal, self.light_dir);
+        let spacing = self.spacing.max(2.0);
+
+        for &(threshold, angle) in HATCH_LAYERS.iter() {
+            if d >= threshold {
+                break;
+            }
+
+            let s = pt.x as f32 * angle.cos() + pt.y as f32 * angle.sin();
+            if s.rem_euclid(spacing) < 1.0 {
+                return Some(self.ink);
+            }
+        }
+
+        Some(self.paper)
+    }
+}
+
+// Inverted hull outlines: the mesh is drawn again pushed out along its
+// normals, keeping only the faces pointing away from the eye. Those sit
+// behind the real surface except around its silhouette, where they show
+// as a border. Needs smooth normals, or the hull splits open at hard edges.
+pub struct OutlineShader {
+    transforms: Transforms,
+    pub eye: Vec3<f32>,
+    // In world units
+    pub width: f32,
+    pub color: Color,
+}
+
+impl OutlineShader {
+    pub fn new(model: &Matrix4x4<f32>, screen: &Matrix4x4<f32>, eye: Vec3<f32>, width: f32) -> OutlineShader {
+        OutlineShader {
+            transforms: Transforms::new(model, screen),
+            eye,
+            width,
+            color: Color(0, 0, 0),
+        }
+    }
+}
+
+impl Shader<NprVars> for OutlineShader {
+    fn vertex(&self, pt: Vec3<f32>, vars: &NprVars) -> (Vec4<f32>, NprVars) {
+        let (_, out) = vertex(&self.transforms, pt, vars);
+        let normal = if out.normal.length() > 0.0 { out.normal.norm() } else { out.normal };
+        let position = out.position + normal * self.width;
+
+        (
+            self.transforms.project(position),
+            NprVars { position, normal, ..out },
+        )
+    }
+
+    fn fragment(&self, _: Vec2<isize>, vars: NprVars) -> Option<Color> {
+        if vars.normal.dot(self.eye - vars.position) > 0.0 {
+            None
+        } else {
+            Some(self.color)
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct OutlineOptions {
+    pub color: Color,
+    // How far depth may bend between neighbouring pixels before it counts
+    // as a crease or a step, in world units
+    pub depth_threshold: f32,
+    // 1 - cos of the angle between neighbouring normals that counts as an
+    // edge
+    pub normal_threshold: f32,
+    // In pixels
+    pub thickness: usize,
+}
+
+impl OutlineOptions {
+    pub fn new() -> OutlineOptions {
+        OutlineOptions {
+            color: Color(0, 0, 0),
+            depth_threshold: 0.05,
+            normal_threshold: 0.4,
+            thickness: 1,
+        }
+    }
+}
+
+// Pixels on a silhouette, a boundary between objects, a jump in depth or a
+// sharp change in normal. `screen` is the viewport * projection * view
+// matrix the G-buffer was drawn with.
+pub fn edges(gbuffer: &GBuffer, screen: &Matrix4x4<f32>, options: &OutlineOptions) -> Buffer<bool> {
+    let (width, height) = (gbuffer.depth.width, gbuffer.depth.height);
+    let mut edges = Buffer::new(width, height, false);
+
+    // Depth isn't divided by w, so it's an affine function of world
+    // position and this converts world distances to depth differences
+    let threshold = options.depth_threshold * screen.row(2).xyz().length();
+
+    let object = |x: isize, y: isize| {
+        let (x, y) = (x.clamp(0, width as isize - 1) as usize, y.clamp(0, height as isize - 1) as usize);
+        (*gbuffer.object_id.get(x, y), *gbuffer.depth.get(x, y), *gbuffer.normal.get(x, y))
+    };
+
+    for y in 0..height as isize {
+        for x in 0..width as isize {
+            let (id, depth, normal) = object(x, y);
+            let neighbours = [object(x - 1, y), object(x + 1, y), object(x, y - 1), object(x, y + 1)];
+
+            let mut edge = neighbours.iter().any(|n| n.0 != id);
+
+            if !edge && gbuffer.covered(x as usize, y as usize) {
+                // Second differences are zero across flat and evenly sloped
+                // surfaces, however steep, and large at steps and creases
+                let ddx = neighbours[0].1 + neighbours[1].1 - 2.0 * depth;
+                let ddy = neighbours[2].1 + neighbours[3].1 - 2.0 * depth;
+
+                edge = ddx.abs() > threshold || ddy.abs() > threshold ||
+                    neighbours.iter().any(|n| 1.0 - n.2.dot(normal) > options.normal_threshold);
+            }
+
+            edges.set(x as usize, y as usize, edge);
+        }
+    }
+
+    edges
+}
+
+// Draws the edges found by `edges` over `image`
+pub fn outline_edges(image: &mut Image, gbuffer: &GBuffer, screen: &Matrix4x4<f32>, options: &OutlineOptions) {
+    let edges = edges(gbuffer, screen, options);
+    let r = options.thickness.max(1) as isize - 1;
+
+    for y in 0..cmp::min(image.height, edges.height) as isize {
+        for x in 0..cmp::min(image.width, edges.width) as isize {
+            let mut hit = false;
+
+            for ny in cmp::max(0, y - r)..cmp::min(edges.height as isize, y + r + 1) {
+                for nx in cmp::max(0, x - r)..cmp::min(edges.width as isize, x + r + 1) {
+                    hit |= *edges.get(nx as usize, ny as usize);
+                }
+            }
+
+            if hit {
+                image.set_pixel(x as usize, y as usize, &options.color);
+            }
+        }
+    }
+}
+
+fn mul(a: Vec3<f32>, b: Vec3<f32>) -> Vec3<f32> {
+    Vec3 { x: a.x * b.x, y: a.y * b.y, z: a.z * b.z }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Style {
+    Toon,
+    Gooch,
+    Hatch,
+}
+
+impl Style {
+    pub fn from_name(name: &str) -> Option<Style> {
+        match name {
+            "toon" | "cel" => Some(Style::Toon),
+            "gooch" => Some(Style::Gooch),
+            "hatch" | "hatching" => Some(Style::Hatch),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum OutlineMode {
+    // Edge detection on the G-buffer (outline_edges)
+    Edges,
+    // OutlineShader
+    Hull,
+}
+
+impl OutlineMode {
+    pub fn from_name(name: &str) -> Option<OutlineMode> {
+        match name {
+            "edges" => Some(OutlineMode::Edges),
+            "hull" => Some(OutlineMode::Hull),
+            _ => None,
+        }
+    }
+}
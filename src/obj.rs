@@ -1,40 +1,123 @@
 use std;
 use std::fs::File;
 use vec::{Vec3, Vec2};
+use matrix::Matrix4x4;
 use std::io::BufReader;
 use std::io::BufRead;
+use std::io::{BufWriter, Write, Error, ErrorKind};
 
-#[derive(Clone, Copy)]
+// Indices are 1-based like in OBJ files; 0 means the attribute is missing
+#[derive(Clone, Copy, PartialEq)]
 pub struct FacePoint {
     pub vindex: usize,
     pub tindex: usize,
     pub nindex: usize,
 }
+#[derive(Clone, Copy)]
 pub struct Face(pub FacePoint, pub FacePoint, pub FacePoint);
 
+// State that OBJ files set with `g`, `usemtl` and `s` lines
+#[derive(Clone, Copy, PartialEq, Default)]
+pub struct FaceAttrs {
+    pub group: Option<usize>,
+    pub material: Option<usize>,
+    // None if the file never said; Some(0) is an explicit `s off`
+    pub smoothing: Option<u32>,
+}
+
+// An original polygon from the file. Its triangles are stored as a fan in
+// `faces[first_face..first_face + face_count]`, which is enough to rebuild
+// the polygon exactly.
+#[derive(Clone, Copy)]
+pub struct Polygon {
+    pub first_face: usize,
+    pub face_count: usize,
+    pub attrs: FaceAttrs,
+}
+
+#[derive(Clone)]
 pub struct Obj {
     verts: Vec<Vec3<f32>>,
     tex_verts: Vec<Vec3<f32>>,
     norm_verts: Vec<Vec3<f32>>,
-    pub faces: Vec<Face>,
+    // Per-position colors in [0, 1]; empty when the mesh has none
+    colors: Vec<Vec3<f32>>,
+    faces: Vec<Face>,
+    // Only valid while they cover `faces` exactly; see all_polygons
+    polygons: Vec<Polygon>,
+    pub groups: Vec<String>,
+    pub materials: Vec<String>,
+    pub material_libs: Vec<String>,
+}
+
+fn parse_floats(line: &str) -> Result<Vec<f32>, Error> {
+    line.split_whitespace().skip(1)
+        .map(|x| x.parse().map_err(|_| Error::new(ErrorKind::InvalidData, format!("bad number {}", x))))
+        .collect()
 }
 
-fn parse_point(line: &String) -> Vec3<f32> {
-    let mut iter = line.split_whitespace();
+// Needs at least `required` components; any others missing are 0, since
+// texture coordinates often leave off v and w
+fn parse_point(line: &str, required: usize) -> Result<Vec3<f32>, Error> {
+    let vec = parse_floats(line)?;
+
+    if vec.len() < required {
+        return Err(Error::new(ErrorKind::InvalidData, format!("too few numbers in \"{}\"", line)));
+    }
+
+    Ok(Vec3::<f32> { x: vec[0], y: *vec.get(1).unwrap_or(&0.0), z: *vec.get(2).unwrap_or(&0.0) })
+}
+
+// Resolves one index of a face corner. Negative indices count back from
+// the most recently defined element.
+fn parse_index(index: &str, count: usize) -> Result<usize, Error> {
+    if index.is_empty() {
+        return Ok(0);
+    }
+
+    let i: isize = index.parse().map_err(|_| Error::new(ErrorKind::InvalidData, format!("bad face index {}", index)))?;
+    let resolved = if i < 0 { count as isize + i + 1 } else { i };
+
+    if resolved < 1 || resolved as usize > count {
+        return Err(Error::new(ErrorKind::InvalidData, format!("face index {} is out of range", index)));
+    }
+
+    Ok(resolved as usize)
+}
 
-    iter.next();
-    let vec: Vec<f32> = iter.map(|x| x.parse().unwrap()).collect();
+fn find_or_add(names: &mut Vec<String>, name: &str) -> usize {
+    match names.iter().position(|n| n == name) {
+        Some(i) => i,
+        None => {
+            names.push(name.to_string());
+            names.len() - 1
+        }
+    }
+}
 
-    Vec3::<f32> { x: vec[0], y: vec[1], z: vec[2] }
+fn format_point(fp: &FacePoint) -> String {
+    match (fp.tindex, fp.nindex) {
+        (0, 0) => format!("{}", fp.vindex),
+        (t, 0) => format!("{}/{}", fp.vindex, t),
+        (0, n) => format!("{}//{}", fp.vindex, n),
+        (t, n) => format!("{}/{}/{}", fp.vindex, t, n),
+    }
 }
 
 impl Face {
+    // Counter-clockwise winding faces outwards, matching the `vn` normals
+    // exporters write
     pub fn normal(&self, obj: &Obj) -> Vec3<f32> {
+        self.area_vector(obj).norm()
+    }
+
+    // Points along the normal, with length equal to twice the face's area
+    pub fn area_vector(&self, obj: &Obj) -> Vec3<f32> {
         let v0 = obj.vert(self.0.vindex);
         let v1 = obj.vert(self.1.vindex);
         let v2 = obj.vert(self.2.vindex);
 
-        (v2 - v0).cross(v1 - v0).norm()
+        (v1 - v0).cross(v2 - v0)
     }
 }
 
@@ -51,43 +134,392 @@ impl Obj {
         self.norm_verts[i - 1]
     }
 
-    pub fn from_file(filename: &str) -> Result<Obj, std::io::Error> {
-        let f = try!(File::open(filename));
+    pub fn has_colors(&self) -> bool {
+        !self.colors.is_empty()
+    }
 
-        let mut obj = Obj {
+    pub fn vert_color(&self, i: usize) -> Vec3<f32> {
+        match self.colors.get(i - 1) {
+            Some(&color) => color,
+            None => Vec3 { x: 1.0, y: 1.0, z: 1.0 },
+        }
+    }
+
+    pub fn new() -> Obj {
+        Obj {
             verts: Vec::new(),
             tex_verts: Vec::new(),
             norm_verts: Vec::new(),
+            colors: Vec::new(),
             faces: Vec::new(),
-        };
+            polygons: Vec::new(),
+            groups: Vec::new(),
+            materials: Vec::new(),
+            material_libs: Vec::new(),
+        }
+    }
+
+    // Fans the polygon out into triangles and remembers how to put it back
+    // together
+    pub fn add_polygon(&mut self, points: &[FacePoint], attrs: FaceAttrs) {
+        let first_face = self.faces.len();
 
-        let file = BufReader::new(&f);
-        for line in file.lines() {
-            let line = try!(line);
+        for i in 2..points.len() {
+            self.faces.push(Face(points[0], points[i - 1], points[i]));
+        }
+
+        if points.len() >= 3 {
+            self.polygons.push(Polygon {
+                first_face,
+                face_count: points.len() - 2,
+                attrs,
+            });
+        }
+    }
+
+    pub fn faces(&self) -> &[Face] {
+        &self.faces
+    }
+
+    // A triangle of its own, with no group, material or smoothing group
+    pub fn add_face(&mut self, face: Face) {
+        self.add_polygon(&[face.0, face.1, face.2], FaceAttrs::default());
+    }
+
+    // For arbitrary edits. The faces may not match the polygons they were
+    // loaded from afterwards, so those are forgotten.
+    pub fn faces_mut(&mut self) -> &mut Vec<Face> {
+        self.polygons.clear();
+        &mut self.faces
+    }
+
+    // Points a face's corners at different normals. The polygons don't
+    // care about normals, so they stay.
+    pub fn set_normal_indices(&mut self, face: usize, nindex: [usize; 3]) {
+        let face = &mut self.faces[face];
+
+        face.0.nindex = nindex[0];
+        face.1.nindex = nindex[1];
+        face.2.nindex = nindex[2];
+    }
+
+    pub fn polygon_points(&self, polygon: &Polygon) -> Vec<FacePoint> {
+        let faces = &self.faces[polygon.first_face..polygon.first_face + polygon.face_count];
+        let mut points = vec![faces[0].0, faces[0].1];
+
+        points.extend(faces.iter().map(|f| f.2));
+        points
+    }
+
+    // The polygons covering `faces`. Meshes whose faces were edited through
+    // faces_mut get one polygon per triangle.
+    pub fn all_polygons(&self) -> Vec<Polygon> {
+        let covered: usize = self.polygons.iter().map(|p| p.face_count).sum();
+
+        if covered == self.faces.len() {
+            self.polygons.clone()
+        } else {
+            (0..self.faces.len()).map(|i| Polygon {
+                first_face: i,
+                face_count: 1,
+                attrs: FaceAttrs::default(),
+            }).collect()
+        }
+    }
+
+    // The add_* functions return the 1-based index used by FacePoint
+    pub fn add_vert(&mut self, v: Vec3<f32>) -> usize {
+        self.verts.push(v);
+        self.verts.len()
+    }
+
+    pub fn add_colored_vert(&mut self, v: Vec3<f32>, color: Vec3<f32>) -> usize {
+        // Positions added before the first color default to white
+        let white = Vec3 { x: 1.0, y: 1.0, z: 1.0 };
+        let uncolored = self.verts.len() - self.colors.len();
+        self.colors.extend(std::iter::repeat_n(white, uncolored));
+
+        self.colors.push(color);
+        self.add_vert(v)
+    }
+
+    pub fn add_tex_vert(&mut self, t: Vec2<f32>) -> usize {
+        self.tex_verts.push(Vec3 { x: t.x, y: t.y, z: 0.0 });
+        self.tex_verts.len()
+    }
+
+    pub fn add_norm_vert(&mut self, n: Vec3<f32>) -> usize {
+        self.norm_verts.push(n);
+        self.norm_verts.len()
+    }
+
+    pub fn clear_norm_verts(&mut self) {
+        self.norm_verts.clear();
+
+        for face in self.faces.iter_mut() {
+            face.0.nindex = 0;
+            face.1.nindex = 0;
+            face.2.nindex = 0;
+        }
+    }
+
+    // Moves every position by `mat`; normals follow by the inverse transpose
+    // so they stay perpendicular under non-uniform scaling
+    pub fn transform(&mut self, mat: &Matrix4x4<f32>) {
+        for v in self.verts.iter_mut() {
+            *v = mat.transform_point(*v);
+        }
+
+        if let Some(inverse) = mat.inverse() {
+            let normal_mat = inverse.transpose();
+
+            for n in self.norm_verts.iter_mut() {
+                *n = normal_mat.transform_vector(*n).norm();
+            }
+        }
+    }
+
+    pub fn has_normals(&self) -> bool {
+        self.faces.iter().all(|f| f.0.nindex != 0 && f.1.nindex != 0 && f.2.nindex != 0)
+    }
+
+    pub fn vert_count(&self) -> usize {
+        self.verts.len()
+    }
+
+    pub fn tex_vert_count(&self) -> usize {
+        self.tex_verts.len()
+    }
+
+    pub fn norm_vert_count(&self) -> usize {
+        self.norm_verts.len()
+    }
+
+    pub fn from_file(filename: &str) -> Result<Obj, std::io::Error> {
+        Obj::from_reader(BufReader::new(File::open(filename)?))
+    }
+
+    pub fn from_reader<R: BufRead>(reader: R) -> Result<Obj, std::io::Error> {
+        let mut obj = Obj::new();
+        let mut attrs = FaceAttrs::default();
+
+        for line in reader.lines() {
+            let line = line?;
+            let rest = line.split_whitespace().skip(1).collect::<Vec<&str>>().join(" ");
 
             if line.starts_with("v ") {
-                obj.verts.push(parse_point(&line));
+                // Some exporters append an RGB color to each position
+                let values = parse_floats(&line)?;
+                let v = parse_point(&line, 3)?;
+
+                if values.len() >= 6 {
+                    obj.add_colored_vert(v, Vec3 { x: values[3], y: values[4], z: values[5] });
+                } else {
+                    obj.add_vert(v);
+                }
             } else if line.starts_with("vt ") {
-                obj.tex_verts.push(parse_point(&line));
+                obj.tex_verts.push(parse_point(&line, 1)?);
             } else if line.starts_with("vn ") {
-                obj.norm_verts.push(parse_point(&line));
-            } else if line.starts_with("f") {
-                let mut iter = line.split_whitespace();
-
-                iter.next();
-                let vec: Vec<&str> = iter.collect();
+                obj.norm_verts.push(parse_point(&line, 3)?);
+            } else if line.starts_with("f ") {
                 let mut face_vec: Vec<FacePoint> = Vec::new();
 
-                for face in vec {
-                    let vec: Vec<usize> = face.split("/").map(|x| x.parse().unwrap()).collect();
+                for face in line.split_whitespace().skip(1) {
+                    let vec: Vec<&str> = face.split('/').collect();
 
-                    face_vec.push(FacePoint { vindex: vec[0], tindex: vec[1], nindex: vec[2] });
+                    face_vec.push(FacePoint {
+                        vindex: parse_index(vec[0], obj.verts.len())?,
+                        tindex: parse_index(vec.get(1).unwrap_or(&""), obj.tex_verts.len())?,
+                        nindex: parse_index(vec.get(2).unwrap_or(&""), obj.norm_verts.len())?,
+                    });
                 }
 
-                obj.faces.push(Face(face_vec[0], face_vec[1], face_vec[2]));
+                obj.add_polygon(&face_vec, attrs);
+            } else if line.starts_with("g ") || line == "g" {
+                attrs.group = if rest.is_empty() { None } else { Some(find_or_add(&mut obj.groups, &rest)) };
+            } else if line.starts_with("usemtl ") || line == "usemtl" {
+                // A bare usemtl goes back to no material, which write_to relies on
+                attrs.material = if rest.is_empty() { None } else { Some(find_or_add(&mut obj.materials, &rest)) };
+            } else if line.starts_with("mtllib ") {
+                obj.material_libs.push(rest);
+            } else if line.starts_with("s ") {
+                attrs.smoothing = Some(rest.parse().unwrap_or(0));
             }
         }
 
         Ok(obj)
     }
+
+    pub fn write_file(&self, filename: &str) -> Result<(), Error> {
+        let mut out = BufWriter::new(File::create(filename)?);
+
+        self.write_to(&mut out)?;
+        out.flush()
+    }
+
+    // Emits everything from_file reads, keeping indices as they are so that
+    // reading the output back gives the same mesh
+    pub fn write_to<W: Write>(&self, out: &mut W) -> Result<(), Error> {
+        for lib in self.material_libs.iter() {
+            writeln!(out, "mtllib {}", lib)?;
+        }
+
+        for (i, v) in self.verts.iter().enumerate() {
+            // Colors are all or nothing in the file; positions added
+            // without one get the default
+            if self.has_colors() {
+                let c = self.vert_color(i + 1);
+                writeln!(out, "v {} {} {} {} {} {}", v.x, v.y, v.z, c.x, c.y, c.z)?;
+            } else {
+                writeln!(out, "v {} {} {}", v.x, v.y, v.z)?;
+            }
+        }
+        for t in self.tex_verts.iter() {
+            writeln!(out, "vt {} {} {}", t.x, t.y, t.z)?;
+        }
+        for n in self.norm_verts.iter() {
+            writeln!(out, "vn {} {} {}", n.x, n.y, n.z)?;
+        }
+
+        // OBJ has no way back to "no smoothing group" once one is set, so
+        // faces without one that come after go in a group of their own
+        let polygons = self.all_polygons();
+        let ungrouped = polygons.iter().filter_map(|p| p.attrs.smoothing).max().unwrap_or(0) + 1;
+
+        let mut attrs = FaceAttrs::default();
+        for polygon in polygons {
+            if polygon.attrs.group != attrs.group {
+                match polygon.attrs.group {
+                    Some(g) => writeln!(out, "g {}", self.groups[g])?,
+                    None => writeln!(out, "g")?,
+                }
+            }
+            if polygon.attrs.material != attrs.material {
+                match polygon.attrs.material {
+                    Some(m) => writeln!(out, "usemtl {}", self.materials[m])?,
+                    None => writeln!(out, "usemtl")?,
+                }
+            }
+            if polygon.attrs.smoothing != attrs.smoothing {
+                match polygon.attrs.smoothing {
+                    Some(0) => writeln!(out, "s off")?,
+                    Some(s) => writeln!(out, "s {}", s)?,
+                    None => writeln!(out, "s {}", ungrouped)?,
+                }
+            }
+            attrs = polygon.attrs;
+
+            let points: Vec<String> = self.polygon_points(&polygon).iter().map(format_point).collect();
+            writeln!(out, "f {}", points.join(" "))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Result<Obj, Error> {
+        Obj::from_reader(text.as_bytes())
+    }
+
+    #[test]
+    fn reads_short_texture_coordinates() {
+        let obj = parse("v 0 0 0\nv 1 0 0\nv 0 1 0\nvt 0.5\nvt 0.25 0.75\nf 1/1 2/2 3/2\n").unwrap();
+
+        assert_eq!(obj.tex_vert(1).x, 0.5);
+        assert_eq!(obj.tex_vert(1).y, 0.0);
+        assert_eq!(obj.tex_vert(2).y, 0.75);
+        assert_eq!(obj.faces.len(), 1);
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        for text in ["v 1 2\n", "v 1 x 3\n", "v \n", "vt \n", "vt a b\n", "vn 0 1\n", "v 0 0 0\nf 1 2 3\n", "f 1/x 1 1\n"].iter() {
+            match parse(text) {
+                Err(ref e) if e.kind() == ErrorKind::InvalidData => {}
+                _ => panic!("{:?} should be rejected", text),
+            }
+        }
+    }
+
+    fn write(obj: &Obj) -> String {
+        let mut out = Vec::new();
+        obj.write_to(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    const MESH: &str = "mtllib a.mtl\n\
+        v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nv 0 0 1\n\
+        vt 0 0\nvn 0 0 1\n\
+        g body\nusemtl red\ns 1\nf 1/1/1 2/1/1 3/1/1 4/1/1\n\
+        usemtl\ns off\nf 1 2 5\n\
+        g\nusemtl blue\nf 2 3 5\n";
+
+    #[test]
+    fn write_round_trips() {
+        let obj = parse(MESH).unwrap();
+        let text = write(&obj);
+        let reread = parse(&text).unwrap();
+
+        assert_eq!(write(&reread), text);
+        assert_eq!(reread.faces().len(), 4);
+        assert_eq!(reread.material_libs, vec!["a.mtl".to_string()]);
+
+        let polygons = reread.all_polygons();
+        assert_eq!(polygons.len(), 3);
+        assert_eq!(reread.polygon_points(&polygons[0]).len(), 4);
+
+        let attrs: Vec<FaceAttrs> = polygons.iter().map(|p| p.attrs).collect();
+        assert_eq!(attrs[0].group.map(|g| &reread.groups[g][..]), Some("body"));
+        assert_eq!(attrs[0].material.map(|m| &reread.materials[m][..]), Some("red"));
+        assert_eq!(attrs[0].smoothing, Some(1));
+        // The material was cleared for the second polygon and must stay so
+        assert_eq!(attrs[1].material, None);
+        assert_eq!(attrs[1].group, attrs[0].group);
+        assert_eq!(attrs[1].smoothing, Some(0));
+        assert_eq!(attrs[2].group, None);
+        assert_eq!(attrs[2].material.map(|m| &reread.materials[m][..]), Some("blue"));
+    }
+
+    #[test]
+    fn write_pads_missing_colors() {
+        let mut obj = Obj::new();
+        obj.add_vert(Vec3 { x: 0.0, y: 0.0, z: 0.0 });
+        obj.add_colored_vert(Vec3 { x: 1.0, y: 0.0, z: 0.0 }, Vec3 { x: 1.0, y: 0.0, z: 0.0 });
+        obj.add_vert(Vec3 { x: 0.0, y: 1.0, z: 0.0 });
+
+        let reread = parse(&write(&obj)).unwrap();
+
+        assert_eq!(reread.vert_count(), 3);
+        assert_eq!(reread.vert_color(1).y, 1.0);
+        assert_eq!(reread.vert_color(2).y, 0.0);
+        assert_eq!(reread.vert_color(3).y, 1.0);
+    }
+
+    #[test]
+    fn editing_faces_forgets_polygons() {
+        let mut obj = parse(MESH).unwrap();
+        let (a, b) = (obj.faces()[0], obj.faces()[3]);
+        obj.faces_mut().swap(0, 3);
+
+        assert_eq!(obj.faces()[0].0.vindex, b.0.vindex);
+        assert_eq!(obj.faces()[3].0.vindex, a.0.vindex);
+        assert!(obj.all_polygons().iter().all(|p| p.face_count == 1 && p.attrs == FaceAttrs::default()));
+    }
+
+    #[test]
+    fn write_keeps_ungrouped_faces_apart() {
+        let obj = parse("v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 3\ns 2\nf 1 2 3\n").unwrap();
+        let mut faces = obj.clone();
+        faces.faces_mut().clear();
+        faces.add_polygon(&[obj.faces()[0].0, obj.faces()[0].1, obj.faces()[0].2], FaceAttrs { smoothing: Some(2), ..FaceAttrs::default() });
+        faces.add_face(obj.faces()[0]);
+
+        let smoothing = |obj: &Obj| obj.all_polygons().iter().map(|p| p.attrs.smoothing).collect::<Vec<_>>();
+        assert_eq!(smoothing(&parse(&write(&obj)).unwrap()), vec![None, Some(2)]);
+        assert_eq!(smoothing(&parse(&write(&faces)).unwrap()), vec![Some(2), Some(3)]);
+    }
 }
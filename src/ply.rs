@@ -0,0 +1,452 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write, Error, ErrorKind};
+use vec::{Vec2, Vec3};
+use obj::{Obj, FaceAttrs, FacePoint};
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum PlyFormat {
+    Ascii,
+    BinaryLittleEndian,
+    BinaryBigEndian,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Scalar {
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    F32,
+    F64,
+}
+
+enum Property {
+    Scalar(String, Scalar),
+    // Count type, item type
+    List(String, Scalar, Scalar),
+}
+
+struct Element {
+    name: String,
+    count: usize,
+    properties: Vec<Property>,
+}
+
+fn bad_data(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg.to_string())
+}
+
+fn parse_scalar(name: &str) -> Result<Scalar, Error> {
+    Ok(match name {
+        "char" | "int8" => Scalar::I8,
+        "uchar" | "uint8" => Scalar::U8,
+        "short" | "int16" => Scalar::I16,
+        "ushort" | "uint16" => Scalar::U16,
+        "int" | "int32" => Scalar::I32,
+        "uint" | "uint32" => Scalar::U32,
+        "float" | "float32" => Scalar::F32,
+        "double" | "float64" => Scalar::F64,
+        _ => return Err(bad_data(&format!("unknown PLY type {}", name))),
+    })
+}
+
+fn scalar_size(scalar: Scalar) -> usize {
+    match scalar {
+        Scalar::I8 | Scalar::U8 => 1,
+        Scalar::I16 | Scalar::U16 => 2,
+        Scalar::I32 | Scalar::U32 | Scalar::F32 => 4,
+        Scalar::F64 => 8,
+    }
+}
+
+// Pulls values out of the body one at a time, whichever encoding it uses
+struct ValueReader<R: BufRead> {
+    reader: R,
+    format: PlyFormat,
+    tokens: Vec<String>,
+}
+
+impl<R: BufRead> ValueReader<R> {
+    fn next(&mut self, scalar: Scalar) -> Result<f64, Error> {
+        if self.format == PlyFormat::Ascii {
+            while self.tokens.is_empty() {
+                let mut line = String::new();
+                if self.reader.read_line(&mut line)? == 0 {
+                    return Err(bad_data("PLY file ended early"));
+                }
+                self.tokens = line.split_whitespace().rev().map(|t| t.to_string()).collect();
+            }
+
+            let token = self.tokens.pop().unwrap();
+            return token.parse().map_err(|_| bad_data(&format!("bad PLY value {}", token)));
+        }
+
+        let size = scalar_size(scalar);
+        let mut buf = [0u8; 8];
+        self.reader.read_exact(&mut buf[..size])?;
+        if self.format == PlyFormat::BinaryBigEndian {
+            buf[..size].reverse();
+        }
+
+        let mut raw = [0u8; 8];
+        raw[..size].copy_from_slice(&buf[..size]);
+        let bits = u64::from_le_bytes(raw);
+
+        Ok(match scalar {
+            Scalar::I8 => bits as u8 as i8 as f64,
+            Scalar::U8 => bits as u8 as f64,
+            Scalar::I16 => bits as u16 as i16 as f64,
+            Scalar::U16 => bits as u16 as f64,
+            Scalar::I32 => bits as u32 as i32 as f64,
+            Scalar::U32 => bits as u32 as f64,
+            Scalar::F32 => f32::from_bits(bits as u32) as f64,
+            Scalar::F64 => f64::from_bits(bits),
+        })
+    }
+}
+
+fn read_header<R: BufRead>(reader: &mut R) -> Result<(PlyFormat, Vec<Element>), Error> {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    if line.trim() != "ply" {
+        return Err(bad_data("not a PLY file"));
+    }
+
+    let mut format = None;
+    let mut elements: Vec<Element> = Vec::new();
+
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(bad_data("PLY header has no end_header"));
+        }
+
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.first() {
+            Some(&"format") if words.len() >= 2 => {
+                format = Some(match words[1] {
+                    "ascii" => PlyFormat::Ascii,
+                    "binary_little_endian" => PlyFormat::BinaryLittleEndian,
+                    "binary_big_endian" => PlyFormat::BinaryBigEndian,
+                    _ => return Err(bad_data("unknown PLY format")),
+                });
+            }
+            Some(&"element") if words.len() >= 3 => {
+                elements.push(Element {
+                    name: words[1].to_string(),
+                    count: words[2].parse().map_err(|_| bad_data("bad PLY element count"))?,
+                    properties: Vec::new(),
+                });
+            }
+            Some(&"property") => {
+                let element = match elements.last_mut() {
+                    Some(e) => e,
+                    None => return Err(bad_data("PLY property outside of an element")),
+                };
+
+                if words.len() >= 5 && words[1] == "list" {
+                    element.properties.push(Property::List(
+                        words[4].to_string(), parse_scalar(words[2])?, parse_scalar(words[3])?));
+                } else if words.len() >= 3 {
+                    element.properties.push(Property::Scalar(words[2].to_string(), parse_scalar(words[1])?));
+                }
+            }
+            Some(&"end_header") => break,
+            _ => {}
+        }
+    }
+
+    match format {
+        Some(format) => Ok((format, elements)),
+        None => Err(bad_data("PLY header has no format line")),
+    }
+}
+
+// Integer colors are 0-255, float colors are already 0-1
+fn color_scale(scalar: Scalar) -> f64 {
+    match scalar {
+        Scalar::F32 | Scalar::F64 => 1.0,
+        Scalar::U16 => 65535.0,
+        _ => 255.0,
+    }
+}
+
+impl Obj {
+    pub fn from_ply(filename: &str) -> Result<Obj, Error> {
+        let mut reader = BufReader::new(File::open(filename)?);
+        let (format, elements) = read_header(&mut reader)?;
+
+        let mut values = ValueReader { reader, format, tokens: Vec::new() };
+        let mut obj = Obj::new();
+        let mut vertex_count = 0;
+        let mut has_normals = false;
+        let mut has_tex = false;
+
+        for element in elements.iter() {
+            for _ in 0..element.count {
+                let mut scalars: HashMap<&str, (f64, Scalar)> = HashMap::new();
+                let mut indices: Vec<usize> = Vec::new();
+
+                for property in element.properties.iter() {
+                    match *property {
+                        Property::Scalar(ref name, scalar) => {
+                            scalars.insert(name, (values.next(scalar)?, scalar));
+                        }
+                        Property::List(ref name, count_type, item_type) => {
+                            let count = values.next(count_type)? as usize;
+                            let is_indices = name == "vertex_indices" || name == "vertex_index";
+
+                            for _ in 0..count {
+                                let value = values.next(item_type)?;
+                                if is_indices {
+                                    indices.push(value as usize);
+                                }
+                            }
+                        }
+                    }
+                }
+
+                let get = |names: &[&str]| names.iter().filter_map(|n| scalars.get(n)).next().cloned();
+
+                if element.name == "vertex" {
+                    let (x, y, z) = match (get(&["x"]), get(&["y"]), get(&["z"])) {
+                        (Some(x), Some(y), Some(z)) => (x.0 as f32, y.0 as f32, z.0 as f32),
+                        _ => return Err(bad_data("PLY vertex without a position")),
+                    };
+                    let pos = Vec3 { x, y, z };
+
+                    match (get(&["red", "r"]), get(&["green", "g"]), get(&["blue", "b"])) {
+                        (Some(r), Some(g), Some(b)) => {
+                            let scale = color_scale(r.1);
+                            obj.add_colored_vert(pos, Vec3 {
+                                x: (r.0 / scale) as f32,
+                                y: (g.0 / scale) as f32,
+                                z: (b.0 / scale) as f32,
+                            });
+                        }
+                        _ => {
+                            obj.add_vert(pos);
+                        }
+                    }
+
+                    if let (Some(nx), Some(ny), Some(nz)) = (get(&["nx"]), get(&["ny"]), get(&["nz"])) {
+                        obj.add_norm_vert(Vec3 { x: nx.0 as f32, y: ny.0 as f32, z: nz.0 as f32 });
+                        has_normals = true;
+                    }
+
+                    if let (Some(u), Some(v)) = (get(&["u", "s", "texture_u"]), get(&["v", "t", "texture_v"])) {
+                        obj.add_tex_vert(Vec2 { x: u.0 as f32, y: v.0 as f32 });
+                        has_tex = true;
+                    }
+
+                    vertex_count += 1;
+                } else if element.name == "face" {
+                    if let Some(&i) = indices.iter().find(|&&i| i >= vertex_count) {
+                        return Err(bad_data(&format!("PLY face index {} is out of range", i)));
+                    }
+
+                    let point = |i: usize| FacePoint {
+                        vindex: i + 1,
+                        tindex: if has_tex { i + 1 } else { 0 },
+                        nindex: if has_normals { i + 1 } else { 0 },
+                    };
+
+                    let points: Vec<FacePoint> = indices.iter().map(|&i| point(i)).collect();
+                    obj.add_polygon(&points, FaceAttrs::default());
+                }
+            }
+        }
+
+        Ok(obj)
+    }
+
+    pub fn write_ply(&self, filename: &str, format: PlyFormat) -> Result<(), Error> {
+        // PLY attributes are per vertex, so every distinct combination of
+        // position/texcoord/normal used by a face becomes its own vertex
+        let mut unique: HashMap<(usize, usize, usize), usize> = HashMap::new();
+        let mut corners: Vec<FacePoint> = Vec::new();
+        let mut triangles: Vec<[usize; 3]> = Vec::new();
+
+        for face in self.faces().iter() {
+            let mut tri = [0; 3];
+
+            for (i, fp) in [face.0, face.1, face.2].iter().enumerate() {
+                let key = (fp.vindex, fp.tindex, fp.nindex);
+                let next = corners.len();
+                let index = *unique.entry(key).or_insert(next);
+
+                if index == next {
+                    corners.push(*fp);
+                }
+                tri[i] = index;
+            }
+
+            triangles.push(tri);
+        }
+
+        let has_tex = corners.iter().all(|fp| fp.tindex != 0) && self.tex_vert_count() > 0;
+        let has_normals = corners.iter().all(|fp| fp.nindex != 0) && self.norm_vert_count() > 0;
+
+        let mut out = BufWriter::new(File::create(filename)?);
+
+        writeln!(out, "ply")?;
+        writeln!(out, "format {} 1.0", match format {
+            PlyFormat::Ascii => "ascii",
+            PlyFormat::BinaryLittleEndian => "binary_little_endian",
+            PlyFormat::BinaryBigEndian => "binary_big_endian",
+        })?;
+        writeln!(out, "element vertex {}", corners.len())?;
+        writeln!(out, "property float x\nproperty float y\nproperty float z")?;
+        if has_normals {
+            writeln!(out, "property float nx\nproperty float ny\nproperty float nz")?;
+        }
+        if has_tex {
+            writeln!(out, "property float u\nproperty float v")?;
+        }
+        if self.has_colors() {
+            writeln!(out, "property uchar red\nproperty uchar green\nproperty uchar blue")?;
+        }
+        writeln!(out, "element face {}", triangles.len())?;
+        writeln!(out, "property list uchar int vertex_indices")?;
+        writeln!(out, "end_header")?;
+
+        let put_f32 = |out: &mut BufWriter<File>, v: f32| -> Result<(), Error> {
+            match format {
+                PlyFormat::Ascii => write!(out, "{} ", v),
+                PlyFormat::BinaryLittleEndian => out.write_all(&v.to_le_bytes()),
+                PlyFormat::BinaryBigEndian => out.write_all(&v.to_be_bytes()),
+            }
+        };
+        let put_u8 = |out: &mut BufWriter<File>, v: u8| -> Result<(), Error> {
+            match format {
+                PlyFormat::Ascii => write!(out, "{} ", v),
+                _ => out.write_all(&[v]),
+            }
+        };
+        let put_i32 = |out: &mut BufWriter<File>, v: i32| -> Result<(), Error> {
+            match format {
+                PlyFormat::Ascii => write!(out, "{} ", v),
+                PlyFormat::BinaryLittleEndian => out.write_all(&v.to_le_bytes()),
+                PlyFormat::BinaryBigEndian => out.write_all(&v.to_be_bytes()),
+            }
+        };
+        let end_line = |out: &mut BufWriter<File>| -> Result<(), Error> {
+            if format == PlyFormat::Ascii {
+                writeln!(out)?;
+            }
+            Ok(())
+        };
+
+        for fp in corners.iter() {
+            let v = self.vert(fp.vindex);
+            put_f32(&mut out, v.x)?;
+            put_f32(&mut out, v.y)?;
+            put_f32(&mut out, v.z)?;
+
+            if has_normals {
+                let n = self.norm_vert(fp.nindex);
+                put_f32(&mut out, n.x)?;
+                put_f32(&mut out, n.y)?;
+                put_f32(&mut out, n.z)?;
+            }
+            if has_tex {
+                let t = self.tex_vert(fp.tindex);
+                put_f32(&mut out, t.x)?;
+                put_f32(&mut out, t.y)?;
+            }
+            if self.has_colors() {
+                let c = self.vert_color(fp.vindex);
+                let to_byte = |c: f32| (c.clamp(0.0, 1.0) * 255.0).round() as u8;
+                put_u8(&mut out, to_byte(c.x))?;
+                put_u8(&mut out, to_byte(c.y))?;
+                put_u8(&mut out, to_byte(c.z))?;
+            }
+            end_line(&mut out)?;
+        }
+
+        for tri in triangles.iter() {
+            put_u8(&mut out, 3)?;
+            for &i in tri.iter() {
+                put_i32(&mut out, i as i32)?;
+            }
+            end_line(&mut out)?;
+        }
+
+        out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    fn temp_path(name: &str) -> String {
+        env::temp_dir().join(format!("rust-sdr-{}", name)).to_str().unwrap().to_string()
+    }
+
+    fn colored_triangle() -> Obj {
+        let mut obj = Obj::new();
+        obj.add_colored_vert(Vec3 { x: 0.0, y: 0.0, z: 0.0 }, Vec3 { x: 1.0, y: 0.0, z: 0.0 });
+        obj.add_colored_vert(Vec3 { x: 1.0, y: 0.0, z: 0.0 }, Vec3 { x: 0.0, y: 1.0, z: 0.0 });
+        obj.add_colored_vert(Vec3 { x: 0.0, y: 1.0, z: 0.0 }, Vec3 { x: 0.0, y: 0.0, z: 1.0 });
+        obj.add_norm_vert(Vec3 { x: 0.0, y: 0.0, z: 1.0 });
+
+        let point = |v| FacePoint { vindex: v, tindex: 0, nindex: 1 };
+        obj.add_polygon(&[point(1), point(2), point(3)], FaceAttrs::default());
+        obj
+    }
+
+    #[test]
+    fn round_trips_every_format() {
+        let formats = [
+            ("ascii", PlyFormat::Ascii),
+            ("le", PlyFormat::BinaryLittleEndian),
+            ("be", PlyFormat::BinaryBigEndian),
+        ];
+
+        for &(name, format) in formats.iter() {
+            let path = temp_path(&format!("round-trip-{}.ply", name));
+            colored_triangle().write_ply(&path, format).unwrap();
+            let obj = Obj::from_ply(&path).unwrap();
+
+            assert_eq!(obj.vert_count(), 3);
+            assert_eq!(obj.faces().len(), 1);
+            assert_eq!(obj.vert(2).x, 1.0);
+            assert_eq!(obj.vert_color(3).z, 1.0);
+            assert_eq!(obj.norm_vert(obj.faces()[0].0.nindex).z, 1.0);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_files() {
+        let cases: [(&str, &[u8]); 7] = [
+            ("not-ply", b"solid\n"),
+            ("no-format", b"ply\nelement vertex 1\nproperty float x\nend_header\n0\n"),
+            ("no-end", b"ply\nformat ascii 1.0\nelement vertex 1\n"),
+            ("bad-type", b"ply\nformat ascii 1.0\nelement vertex 1\nproperty quad x\nend_header\n"),
+            ("truncated", b"ply\nformat ascii 1.0\nelement vertex 2\nproperty float x\nproperty float y\nproperty float z\nend_header\n0 0 0\n"),
+            ("bad-value", b"ply\nformat ascii 1.0\nelement vertex 1\nproperty float x\nproperty float y\nproperty float z\nend_header\n0 zero 0\n"),
+            ("bad-index", b"ply\nformat ascii 1.0\nelement vertex 1\nproperty float x\nproperty float y\nproperty float z\n\
+                element face 1\nproperty list uchar int vertex_indices\nend_header\n0 0 0\n3 0 0 7\n"),
+        ];
+
+        for &(name, bytes) in cases.iter() {
+            let path = temp_path(&format!("{}.ply", name));
+            fs::write(&path, bytes).unwrap();
+
+            assert!(Obj::from_ply(&path).is_err(), "{} should be rejected", name);
+        }
+
+        // A binary body that stops partway through a vertex
+        let path = temp_path("short-binary.ply");
+        let mut bytes = b"ply\nformat binary_little_endian 1.0\nelement vertex 1\nproperty float x\nproperty float y\nproperty float z\nend_header\n".to_vec();
+        bytes.extend_from_slice(&1.0f32.to_le_bytes());
+        fs::write(&path, bytes).unwrap();
+        assert!(Obj::from_ply(&path).is_err());
+    }
+}
@@ -0,0 +1,488 @@
+use std::cmp;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use vec::Vec3;
+use image::{Image, Color};
+use target::Buffer;
+use aa::{FxaaOptions, SmaaOptions, fxaa_float, smaa_float};
+
+// RGB not limited to [0, 1], so passes like bloom can work with values
+// brighter than white before the final conversion back to bytes
+pub type FloatImage = Buffer<Vec3<f32>>;
+
+pub fn to_float(image: &Image) -> FloatImage {
+    Buffer {
+        data: image.data.chunks(3).map(|c| Color(c[0], c[1], c[2]).to_vec()).collect(),
+        width: image.width,
+        height: image.height,
+    }
+}
+
+pub fn to_image(image: &FloatImage) -> Image {
+    image.to_image(|&c| Color::from_vec(c))
+}
+
+#[derive(Debug)]
+pub enum PostError {
+    Io(io::Error),
+    UnknownPass(String),
+    BadArgument(String),
+    BadLut(String),
+}
+
+impl fmt::Display for PostError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PostError::Io(ref e) => write!(f, "{}", e),
+            PostError::UnknownPass(ref name) => write!(f, "unknown post-processing pass '{}'", name),
+            PostError::BadArgument(ref msg) => write!(f, "bad argument: {}", msg),
+            PostError::BadLut(ref msg) => write!(f, "bad LUT: {}", msg),
+        }
+    }
+}
+
+impl Error for PostError {}
+
+impl From<io::Error> for PostError {
+    fn from(e: io::Error) -> PostError {
+        PostError::Io(e)
+    }
+}
+
+fn read_to_string(path: &str) -> Result<String, PostError> {
+    let mut text = String::new();
+    File::open(path)?.read_to_string(&mut text)?;
+    Ok(text)
+}
+
+// Rec. 709 weights
+pub fn luma(c: Vec3<f32>) -> f32 {
+    0.2126 * c.x + 0.7152 * c.y + 0.0722 * c.z
+}
+
+fn lerp(a: Vec3<f32>, b: Vec3<f32>, t: f32) -> Vec3<f32> {
+    a + (b - a) * t
+}
+
+// Reads past the edges repeat the border pixels
+pub fn sample_clamped(image: &FloatImage, x: isize, y: isize) -> Vec3<f32> {
+    let x = x.clamp(0, image.width as isize - 1) as usize;
+    let y = y.clamp(0, image.height as isize - 1) as usize;
+
+    *image.get(x, y)
+}
+
+// Pixel centers are at whole coordinates
+pub fn sample_bilinear(image: &FloatImage, x: f32, y: f32) -> Vec3<f32> {
+    let (x0, y0) = (x.floor(), y.floor());
+    let (tx, ty) = (x - x0, y - y0);
+    let (x0, y0) = (x0 as isize, y0 as isize);
+
+    let bottom = lerp(sample_clamped(image, x0, y0), sample_clamped(image, x0 + 1, y0), tx);
+    let top = lerp(sample_clamped(image, x0, y0 + 1), sample_clamped(image, x0 + 1, y0 + 1), tx);
+    lerp(bottom, top, ty)
+}
+
+fn map<F: Fn(usize, usize, Vec3<f32>) -> Vec3<f32>>(image: &FloatImage, f: F) -> FloatImage {
+    let mut out = image.clone();
+
+    for y in 0..image.height {
+        for x in 0..image.width {
+            out.set(x, y, f(x, y, *image.get(x, y)));
+        }
+    }
+
+    out
+}
+
+// Convolves rows, then columns, with the same 1D kernel (centered)
+fn separable(image: &FloatImage, kernel: &[f32]) -> FloatImage {
+    let r = (kernel.len() / 2) as isize;
+
+    let horizontal = map(image, |x, y, _| {
+        kernel.iter().enumerate().fold(Vec3 { x: 0.0, y: 0.0, z: 0.0 }, |sum, (i, &w)| {
+            sum + sample_clamped(image, x as isize + i as isize - r, y as isize) * w
+        })
+    });
+
+    map(&horizontal, |x, y, _| {
+        kernel.iter().enumerate().fold(Vec3 { x: 0.0, y: 0.0, z: 0.0 }, |sum, (i, &w)| {
+            sum + sample_clamped(&horizontal, x as isize, y as isize + i as isize - r) * w
+        })
+    })
+}
+
+pub fn gaussian_blur(image: &FloatImage, sigma: f32) -> FloatImage {
+    if sigma <= 0.0 {
+        return image.clone();
+    }
+
+    let r = (sigma * 3.0).ceil() as isize;
+    let weights: Vec<f32> = (-r..r + 1).map(|i| (-(i * i) as f32 / (2.0 * sigma * sigma)).exp()).collect();
+    let total: f32 = weights.iter().sum();
+
+    separable(image, &weights.iter().map(|w| w / total).collect::<Vec<f32>>())
+}
+
+pub fn box_blur(image: &FloatImage, radius: usize) -> FloatImage {
+    let size = radius * 2 + 1;
+
+    separable(image, &vec![1.0 / size as f32; size])
+}
+
+// Bright parts of the image bleed light into their surroundings
+pub fn bloom(image: &FloatImage, threshold: f32, intensity: f32, sigma: f32) -> FloatImage {
+    let bright = map(image, |_, _, c| {
+        let l = luma(c);
+        if l > threshold { c * ((l - threshold) / l) } else { Vec3 { x: 0.0, y: 0.0, z: 0.0 } }
+    });
+    let glow = gaussian_blur(&bright, sigma);
+
+    map(image, |x, y, c| c + *glow.get(x, y) * intensity)
+}
+
+// Unsharp mask: pushes each pixel away from its blurred neighborhood
+pub fn sharpen(image: &FloatImage, amount: f32) -> FloatImage {
+    let blurred = gaussian_blur(image, 1.0);
+
+    map(image, |x, y, c| c + (c - *blurred.get(x, y)) * amount)
+}
+
+// Darkens towards the corners. `softness` is how far in from the corners
+// the falloff starts, as a fraction of the distance to the center.
+pub fn vignette(image: &FloatImage, strength: f32, softness: f32) -> FloatImage {
+    let (cx, cy) = ((image.width as f32 - 1.0) / 2.0, (image.height as f32 - 1.0) / 2.0);
+    let corner = (cx * cx + cy * cy).sqrt().max(1e-6);
+    let inner = (1.0 - softness).clamp(0.0, 1.0);
+
+    map(image, |x, y, c| {
+        let (dx, dy) = (x as f32 - cx, y as f32 - cy);
+        let d = (dx * dx + dy * dy).sqrt() / corner;
+        let t = ((d - inner) / (1.0 - inner).max(1e-6)).clamp(0.0, 1.0);
+
+        c * (1.0 - strength * t * t * (3.0 - 2.0 * t))
+    })
+}
+
+// Raises each channel to 1 / gamma, e.g. 2.2 to encode linear values for
+// display
+pub fn gamma(image: &FloatImage, gamma: f32) -> FloatImage {
+    let inv = 1.0 / gamma;
+
+    map(image, |_, _, c| Vec3 { x: c.x.max(0.0).powf(inv), y: c.y.max(0.0).powf(inv), z: c.z.max(0.0).powf(inv) })
+}
+
+// Red and blue are scaled away from and towards the center, like a lens
+// that doesn't focus all wavelengths at once. `amount` is the shift at the
+// corners, in pixels.
+pub fn chromatic_aberration(image: &FloatImage, amount: f32) -> FloatImage {
+    let (cx, cy) = ((image.width as f32 - 1.0) / 2.0, (image.height as f32 - 1.0) / 2.0);
+    let scale = amount / (cx * cx + cy * cy).sqrt().max(1e-6);
+
+    map(image, |x, y, c| {
+        let (dx, dy) = (x as f32 - cx, y as f32 - cy);
+        let red = sample_bilinear(image, cx + dx * (1.0 + scale), cy + dy * (1.0 + scale));
+        let blue = sample_bilinear(image, cx + dx * (1.0 - scale), cy + dy * (1.0 - scale));
+
+        Vec3 { x: red.x, y: c.y, z: blue.z }
+    })
+}
+
+// The largest LUT_3D_SIZE the .cube spec allows
+const MAX_LUT_SIZE: usize = 256;
+
+// A 3D color lookup table, indexed by input red, green and blue
+#[derive(Clone)]
+pub struct Lut3d {
+    pub size: usize,
+    // Red varies fastest, then green, then blue
+    pub data: Vec<Vec3<f32>>,
+    pub domain_min: Vec3<f32>,
+    pub domain_max: Vec3<f32>,
+}
+
+impl Lut3d {
+    // Maps every color to itself
+    pub fn identity(size: usize) -> Lut3d {
+        let step = 1.0 / (size - 1) as f32;
+        let mut data = Vec::with_capacity(size * size * size);
+
+        for b in 0..size {
+            for g in 0..size {
+                for r in 0..size {
+                    data.push(Vec3 { x: r as f32 * step, y: g as f32 * step, z: b as f32 * step });
+                }
+            }
+        }
+
+        Lut3d {
+            size,
+            data,
+            domain_min: Vec3 { x: 0.0, y: 0.0, z: 0.0 },
+            domain_max: Vec3 { x: 1.0, y: 1.0, z: 1.0 },
+        }
+    }
+
+    // Adobe/Resolve .cube files
+    pub fn from_cube(path: &str) -> Result<Lut3d, PostError> {
+        Lut3d::parse_cube(&read_to_string(path)?)
+    }
+
+    pub fn parse_cube(text: &str) -> Result<Lut3d, PostError> {
+        let bad = |msg: &str| PostError::BadLut(msg.to_string());
+        let floats = |parts: &[&str]| -> Result<Vec3<f32>, PostError> {
+            let v: Result<Vec<f32>, _> = parts.iter().map(|p| p.parse::<f32>()).collect();
+            match v {
+                Ok(ref v) if v.len() == 3 => Ok(Vec3 { x: v[0], y: v[1], z: v[2] }),
+                _ => Err(PostError::BadLut(format!("expected three numbers, got '{}'", parts.join(" ")))),
+            }
+        };
+
+        let mut lut = Lut3d::identity(2);
+        lut.size = 0;
+        lut.data.clear();
+
+        for line in text.lines() {
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            if parts.is_empty() || parts[0].starts_with('#') {
+                continue;
+            }
+
+            match parts[0] {
+                "LUT_3D_SIZE" => {
+                    lut.size = parts.get(1).and_then(|s| s.parse().ok()).ok_or_else(|| bad("bad LUT_3D_SIZE"))?;
+                }
+                "DOMAIN_MIN" => lut.domain_min = floats(&parts[1..])?,
+                "DOMAIN_MAX" => lut.domain_max = floats(&parts[1..])?,
+                "LUT_1D_SIZE" => return Err(bad("1D LUTs aren't supported")),
+                "TITLE" | "LUT_1D_INPUT_RANGE" | "LUT_3D_INPUT_RANGE" => (),
+                _ => lut.data.push(floats(&parts)?),
+            }
+        }
+
+        if lut.size < 2 {
+            return Err(bad("missing LUT_3D_SIZE"));
+        }
+        if lut.size > MAX_LUT_SIZE {
+            return Err(PostError::BadLut(format!("LUT_3D_SIZE {} is over {}", lut.size, MAX_LUT_SIZE)));
+        }
+        if lut.data.len() != lut.size * lut.size * lut.size {
+            return Err(PostError::BadLut(format!("expected {} entries, found {}", lut.size.pow(3), lut.data.len())));
+        }
+
+        Ok(lut)
+    }
+
+    fn entry(&self, r: usize, g: usize, b: usize) -> Vec3<f32> {
+        self.data[(b * self.size + g) * self.size + r]
+    }
+
+    // Trilinear interpolation between the nearest entries
+    pub fn lookup(&self, c: Vec3<f32>) -> Vec3<f32> {
+        let max = (self.size - 1) as f32;
+        let coord = |v: f32, min: f32, dmax: f32| ((v - min) / (dmax - min).max(1e-6)).clamp(0.0, 1.0) * max;
+
+        let (r, g, b) = (
+            coord(c.x, self.domain_min.x, self.domain_max.x),
+            coord(c.y, self.domain_min.y, self.domain_max.y),
+            coord(c.z, self.domain_min.z, self.domain_max.z),
+        );
+        let (r0, g0, b0) = (r.floor() as usize, g.floor() as usize, b.floor() as usize);
+        let (r1, g1, b1) = (cmp::min(r0 + 1, self.size - 1), cmp::min(g0 + 1, self.size - 1), cmp::min(b0 + 1, self.size - 1));
+        let (tr, tg, tb) = (r - r0 as f32, g - g0 as f32, b - b0 as f32);
+
+        let plane = |b: usize| {
+            let bottom = lerp(self.entry(r0, g0, b), self.entry(r1, g0, b), tr);
+            let top = lerp(self.entry(r0, g1, b), self.entry(r1, g1, b), tr);
+            lerp(bottom, top, tg)
+        };
+
+        lerp(plane(b0), plane(b1), tb)
+    }
+}
+
+// `strength` blends between the original and graded colors
+pub fn color_grade(image: &FloatImage, lut: &Lut3d, strength: f32) -> FloatImage {
+    map(image, |_, _, c| lerp(c, lut.lookup(c), strength))
+}
+
+// In pixels. Wider kernels would take forever to run, if they fit in
+// memory at all.
+const MAX_BLUR_RADIUS: f32 = 1024.0;
+
+pub enum Pass {
+    GaussianBlur { sigma: f32 },
+    BoxBlur { radius: usize },
+    Bloom { threshold: f32, intensity: f32, sigma: f32 },
+    Sharpen { amount: f32 },
+    Vignette { strength: f32, softness: f32 },
+    ColorGrade { lut: Lut3d, strength: f32 },
+    Fxaa(FxaaOptions),
+    Smaa(SmaaOptions),
+    Gamma { gamma: f32 },
+    ChromaticAberration { amount: f32 },
+    // Multiplies every channel; handy before bloom or after a LUT
+    Exposure { scale: f32 },
+}
+
+impl Pass {
+    pub fn apply(&self, image: &FloatImage) -> FloatImage {
+        match *self {
+            Pass::GaussianBlur { sigma } => gaussian_blur(image, sigma),
+            Pass::BoxBlur { radius } => box_blur(image, radius),
+            Pass::Bloom { threshold, intensity, sigma } => bloom(image, threshold, intensity, sigma),
+            Pass::Sharpen { amount } => sharpen(image, amount),
+            Pass::Vignette { strength, softness } => vignette(image, strength, softness),
+            Pass::ColorGrade { ref lut, strength } => color_grade(image, lut, strength),
+            Pass::Fxaa(ref options) => fxaa_float(image, options),
+            Pass::Smaa(ref options) => smaa_float(image, options),
+            Pass::Gamma { gamma: g } => gamma(image, g),
+            Pass::ChromaticAberration { amount } => chromatic_aberration(image, amount),
+            Pass::Exposure { scale } => map(image, |_, _, c| c * scale),
+        }
+    }
+
+    // `name[:arg[:arg...]]`, with defaults for missing arguments, e.g.
+    // `bloom:0.7:0.5` or `lut:film.cube`
+    pub fn parse(spec: &str) -> Result<Pass, PostError> {
+        let parts: Vec<&str> = spec.trim().split(':').collect();
+        let args = &parts[1..];
+
+        let number = |i: usize, default: f32| -> Result<f32, PostError> {
+            match args.get(i) {
+                Some(s) => s.parse().map_err(|_| PostError::BadArgument(format!("'{}' in '{}'", s, spec))),
+                None => Ok(default),
+            }
+        };
+        // Gaussian kernels reach three sigmas out
+        let blur = |i: usize, default: f32, reach: f32| -> Result<f32, PostError> {
+            let value = number(i, default)?;
+            if value * reach > MAX_BLUR_RADIUS {
+                return Err(PostError::BadArgument(format!("'{}' in '{}' blurs too far", value, spec)));
+            }
+            Ok(value)
+        };
+
+        Ok(match parts[0] {
+            "blur" | "gaussian-blur" => Pass::GaussianBlur { sigma: blur(0, 1.5, 3.0)? },
+            "box-blur" => Pass::BoxBlur { radius: blur(0, 1.0, 1.0)?.max(0.0) as usize },
+            "bloom" => Pass::Bloom { threshold: number(0, 0.8)?, intensity: number(1, 0.6)?, sigma: blur(2, 4.0, 3.0)? },
+            "sharpen" => Pass::Sharpen { amount: number(0, 0.5)? },
+            "vignette" => Pass::Vignette { strength: number(0, 0.5)?, softness: number(1, 0.6)? },
+            "lut" => {
+                let path = args.first().ok_or_else(|| PostError::BadArgument("lut needs a .cube file".to_string()))?;
+                Pass::ColorGrade { lut: Lut3d::from_cube(path)?, strength: number(1, 1.0)? }
+            }
+            "fxaa" => {
+                let defaults = FxaaOptions::new();
+                Pass::Fxaa(FxaaOptions {
+                    subpixel: number(0, defaults.subpixel)?,
+                    edge_threshold: number(1, defaults.edge_threshold)?,
+                    edge_threshold_min: number(2, defaults.edge_threshold_min)?,
+                })
+            }
+            "smaa" => {
+                let defaults = SmaaOptions::new();
+                Pass::Smaa(SmaaOptions {
+                    threshold: number(0, defaults.threshold)?,
+                    contrast_adaptation: number(1, defaults.contrast_adaptation)?,
+                })
+            }
+            "gamma" => Pass::Gamma { gamma: number(0, 2.2)? },
+            "chromatic-aberration" | "ca" => Pass::ChromaticAberration { amount: number(0, 2.0)? },
+            "exposure" => Pass::Exposure { scale: number(0, 1.0)? },
+            name => return Err(PostError::UnknownPass(name.to_string())),
+        })
+    }
+}
+
+// Passes run in order, each on the previous one's output
+pub struct PostChain {
+    pub passes: Vec<Pass>,
+}
+
+impl PostChain {
+    // Passes separated by commas or newlines; `#` starts a comment
+    pub fn parse(spec: &str) -> Result<PostChain, PostError> {
+        let passes: Result<Vec<Pass>, PostError> = spec.lines()
+            .map(|line| line.split('#').next().unwrap())
+            .flat_map(|line| line.split(','))
+            .filter(|s| !s.trim().is_empty())
+            .map(Pass::parse)
+            .collect();
+
+        Ok(PostChain { passes: passes? })
+    }
+
+    pub fn from_file(path: &str) -> Result<PostChain, PostError> {
+        PostChain::parse(&read_to_string(path)?)
+    }
+
+    pub fn run_float(&self, image: &FloatImage) -> FloatImage {
+        self.passes.iter().fold(image.clone(), |image, pass| pass.apply(&image))
+    }
+
+    pub fn run(&self, image: &Image) -> Image {
+        to_image(&self.run_float(&to_float(image)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cube(size: &str, entries: usize) -> String {
+        let mut text = format!("# comment\nTITLE \"test\"\nLUT_3D_SIZE {}\n", size);
+        for i in 0..entries {
+            text += &format!("{} {} {}\n", i & 1, (i >> 1) & 1, (i >> 2) & 1);
+        }
+        text
+    }
+
+    fn is_bad_lut(result: Result<Lut3d, PostError>) -> bool {
+        matches!(result, Err(PostError::BadLut(_)))
+    }
+
+    #[test]
+    fn parses_cube() {
+        let lut = Lut3d::parse_cube(&cube("2", 8)).unwrap();
+        assert_eq!(lut.size, 2);
+
+        let c = Vec3 { x: 0.25, y: 0.5, z: 1.0 };
+        assert!((lut.lookup(c) - c).length() < 1e-6);
+        for (a, b) in lut.data.iter().zip(&Lut3d::identity(2).data) {
+            assert!((*a - *b).length() == 0.0);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_cubes() {
+        assert!(is_bad_lut(Lut3d::parse_cube(&cube("2", 7))));
+        assert!(is_bad_lut(Lut3d::parse_cube(&cube("1", 1))));
+        assert!(is_bad_lut(Lut3d::parse_cube(&cube("-2", 8))));
+        assert!(is_bad_lut(Lut3d::parse_cube(&cube("257", 8))));
+        assert!(is_bad_lut(Lut3d::parse_cube(&cube("18446744073709551615", 8))));
+        assert!(is_bad_lut(Lut3d::parse_cube("LUT_1D_SIZE 2\n0 0 0\n1 1 1\n")));
+        assert!(is_bad_lut(Lut3d::parse_cube(&(cube("2", 7) + "0 0\n"))));
+        assert!(is_bad_lut(Lut3d::parse_cube(&(cube("2", 8) + "DOMAIN_MAX 1 x 1\n"))));
+        assert!(is_bad_lut(Lut3d::parse_cube("")));
+    }
+
+    #[test]
+    fn parses_passes() {
+        assert!(matches!(Pass::parse("box-blur:3"), Ok(Pass::BoxBlur { radius: 3 })));
+        assert!(matches!(Pass::parse(" blur "), Ok(Pass::GaussianBlur { .. })));
+        assert!(matches!(Pass::parse("glow"), Err(PostError::UnknownPass(_))));
+        assert!(matches!(Pass::parse("gamma:two"), Err(PostError::BadArgument(_))));
+        assert!(matches!(Pass::parse("lut"), Err(PostError::BadArgument(_))));
+    }
+
+    #[test]
+    fn rejects_huge_blurs() {
+        assert!(matches!(Pass::parse("box-blur:1e30"), Err(PostError::BadArgument(_))));
+        assert!(matches!(Pass::parse("blur:1000"), Err(PostError::BadArgument(_))));
+        assert!(matches!(Pass::parse("bloom:0.8:0.6:1e9"), Err(PostError::BadArgument(_))));
+    }
+}
@@ -1,11 +1,68 @@
 use vec::{Vec2, Vec3, Vec4};
 use image::{Image, Color};
+use mesh::IndexedMesh;
+use lines::{LineStyle, PointStyle, rasterize_line, rasterize_point, plot_fragment};
+use target::RenderTarget;
 use std::cmp;
+use std::collections::HashSet;
 
 pub trait Vary {
-    fn vary(&Self, &Self, &Self, Vec3<f32>) -> Self;
+    fn vary(v1: &Self, v2: &Self, v3: &Self, bary: Vec3<f32>) -> Self;
 }
 
+// The field types #[derive(Vary)] knows how to interpolate, besides other
+// Vary structs
+impl Vary for f32 {
+    fn vary(v1: &f32, v2: &f32, v3: &f32, bary: Vec3<f32>) -> f32 {
+        v1 * bary.x + v2 * bary.y + v3 * bary.z
+    }
+}
+
+impl Vary for Vec2<f32> {
+    fn vary(v1: &Vec2<f32>, v2: &Vec2<f32>, v3: &Vec2<f32>, bary: Vec3<f32>) -> Vec2<f32> {
+        *v1 * bary.x + *v2 * bary.y + *v3 * bary.z
+    }
+}
+
+impl Vary for Vec3<f32> {
+    fn vary(v1: &Vec3<f32>, v2: &Vec3<f32>, v3: &Vec3<f32>, bary: Vec3<f32>) -> Vec3<f32> {
+        *v1 * bary.x + *v2 * bary.y + *v3 * bary.z
+    }
+}
+
+impl Vary for Vec4<f32> {
+    fn vary(v1: &Vec4<f32>, v2: &Vec4<f32>, v3: &Vec4<f32>, bary: Vec3<f32>) -> Vec4<f32> {
+        *v1 * bary.x + *v2 * bary.y + *v3 * bary.z
+    }
+}
+
+// For #[vary(normalize)] fields. Zero vectors are left alone, since
+// shaders use them to mean "no normal".
+pub trait Normalize {
+    fn normalized(self) -> Self;
+}
+
+impl Normalize for Vec2<f32> {
+    fn normalized(self) -> Vec2<f32> {
+        let length = (self.x * self.x + self.y * self.y).sqrt();
+        if length > 0.0 { self / length } else { self }
+    }
+}
+
+impl Normalize for Vec3<f32> {
+    fn normalized(self) -> Vec3<f32> {
+        if self.length() > 0.0 { self.norm() } else { self }
+    }
+}
+
+impl Normalize for Vec4<f32> {
+    fn normalized(self) -> Vec4<f32> {
+        let length = (self.x * self.x + self.y * self.y + self.z * self.z + self.w * self.w).sqrt();
+        if length > 0.0 { self * (1.0 / length) } else { self }
+    }
+}
+
+#[derive(Clone, Copy)]
 pub struct NoVary;
 
 impl Vary for NoVary {
@@ -14,12 +71,14 @@ impl Vary for NoVary {
     }
 }
 
-pub trait Shader<V: Vary> {
-    fn vertex(&self, Vec3<f32>, &V) -> (Vec4<f32>, V);
-    fn fragment(&self, Vec2<isize>, V) -> Option<Color>;
+// `F` is the fragment output, which the render target stores. Shaders
+// drawing into an Image output a Color.
+pub trait Shader<V: Vary, F = Color> {
+    fn vertex(&self, pt: Vec3<f32>, vars: &V) -> (Vec4<f32>, V);
+    fn fragment(&self, pt: Vec2<isize>, vars: V) -> Option<F>;
 }
 
-fn barycentric(point: Vec2<isize>, verts: &Vec<Vec2<isize>>) -> Vec3<f32> {
+fn barycentric(point: Vec2<isize>, verts: &[Vec2<isize>]) -> Vec3<f32> {
     let c = Vec3::cross(
         Vec3 { x: (verts[2].x - verts[0].x) as f32, y: (verts[1].x - verts[0].x) as f32, z: (verts[0].x - point.x) as f32 },
         Vec3 { x: (verts[2].y - verts[0].y) as f32, y: (verts[1].y - verts[0].y) as f32, z: (verts[0].y - point.y) as f32 },
@@ -32,7 +91,7 @@ fn barycentric(point: Vec2<isize>, verts: &Vec<Vec2<isize>>) -> Vec3<f32> {
     }
 }
 
-fn bounding_box<T: cmp::Ord + Copy>(pts: &Vec<Vec2<T>>) -> (Vec2<T>, Vec2<T>) {
+fn bounding_box<T: cmp::Ord + Copy>(pts: &[Vec2<T>]) -> (Vec2<T>, Vec2<T>) {
     let mut min: Vec2<T> = pts[0];
     let mut max: Vec2<T> = pts[0];
 
@@ -47,21 +106,130 @@ fn bounding_box<T: cmp::Ord + Copy>(pts: &Vec<Vec2<T>>) -> (Vec2<T>, Vec2<T>) {
     (min, max)
 }
 
-pub fn draw_triangle<V: Vary, S: Shader<V>>(verts: &Vec<(Vec3<f32>, V)>, shader: &S, image: &mut Image) {
-    let vertex_outs: Vec<(Vec4<f32>, V)> = verts.iter().map(|&(pt, ref vary)| shader.vertex(pt, vary)).collect();
-    let depths: Vec<f32> = vertex_outs.iter().map(|&(v, _)| v.z).collect();
+pub fn draw_triangle<V, F, S, T>(verts: &[(Vec3<f32>, V)], shader: &S, target: &mut T)
+        where V: Vary, S: Shader<V, F>, T: RenderTarget<F> {
+    let vertex_outs: Vec<(Vec4<f32>, V)> = verts.iter().map(|(pt, vary)| shader.vertex(*pt, vary)).collect();
+
+    rasterize([&vertex_outs[0], &vertex_outs[1], &vertex_outs[2]], shader, target);
+}
+
+// Runs the vertex shader at most once per vertex of the mesh, no matter how
+// many triangles share it
+pub fn draw_indexed<V, F, S, T>(mesh: &IndexedMesh<V>, shader: &S, target: &mut T)
+        where V: Vary + Clone, S: Shader<V, F>, T: RenderTarget<F> {
+    draw_indexed_triangles(mesh, 0..mesh.indices.len(), shader, target);
+}
+
+// Like draw_indexed, but only for the given triangles (e.g. those that
+// survived culling)
+pub fn draw_indexed_triangles<V, F, S, T, I>(mesh: &IndexedMesh<V>, triangles: I, shader: &S, target: &mut T)
+        where V: Vary + Clone, S: Shader<V, F>, T: RenderTarget<F>, I: IntoIterator<Item = usize> {
+    let mut cache: Vec<Option<(Vec4<f32>, V)>> = vec![None; mesh.vertices.len()];
+
+    for t in triangles {
+        let tri = mesh.indices[t];
+
+        for &i in tri.iter() {
+            if cache[i].is_none() {
+                let (pt, ref vary) = mesh.vertices[i];
+                cache[i] = Some(shader.vertex(pt, vary));
+            }
+        }
+
+        let out = |i: usize| cache[i].as_ref().unwrap();
+        rasterize([out(tri[0]), out(tri[1]), out(tri[2])], shader, target);
+    }
+}
+
+// How draw_indexed_mode turns triangles into pixels, like glPolygonMode.
+// Lines and points get their color from the fragment shader, with varyings
+// interpolated along each edge.
+#[derive(Clone, Copy)]
+pub enum PolygonMode {
+    Fill,
+    Line(LineStyle),
+    Point(PointStyle),
+}
+
+pub fn draw_indexed_mode<V, S>(mesh: &IndexedMesh<V>, shader: &S, image: &mut Image, mode: &PolygonMode)
+        where V: Vary + Clone, S: Shader<V> {
+    if let PolygonMode::Fill = *mode {
+        return draw_indexed(mesh, shader, image);
+    }
+
+    let outs: Vec<(Vec4<f32>, V)> = mesh.vertices.iter().map(|(pt, vary)| shader.vertex(*pt, vary)).collect();
+
+    // Lines and points behind the eye would project to nonsense
+    let visible = |i: usize| outs[i].0.w > 0.0;
+    let screen = |i: usize| {
+        let v = outs[i].0;
+        Vec3 { x: v.x / v.w, y: v.y / v.w, z: v.z }
+    };
+
+    match *mode {
+        PolygonMode::Line(ref line) => {
+            // Shared edges are only drawn once, so anti-aliased edges don't
+            // get blended twice
+            let mut drawn = HashSet::new();
+
+            for tri in mesh.indices.iter() {
+                for k in 0..3 {
+                    let (a, b, c) = (tri[k], tri[(k + 1) % 3], tri[(k + 2) % 3]);
+
+                    if !visible(a) || !visible(b) || !drawn.insert((cmp::min(a, b), cmp::max(a, b))) {
+                        continue;
+                    }
+
+                    let (from, to) = (screen(a), screen(b));
+                    rasterize_line(from.xy(), to.xy(), line, (image.width, image.height), |x, y, coverage, t| {
+                        let bary = Vec3 { x: 1.0 - t, y: t, z: 0.0 };
+                        let varied = V::vary(&outs[a].1, &outs[b].1, &outs[c].1, bary);
+
+                        if let Some(color) = shader.fragment(Vec2 { x, y }, varied) {
+                            let depth = from.z + (to.z - from.z) * t;
+                            plot_fragment(image, x, y, &color, coverage, line.biased_depth(depth));
+                        }
+                    });
+                }
+            }
+        }
+        PolygonMode::Point(ref point) => {
+            let mut used = vec![false; outs.len()];
+            for tri in mesh.indices.iter() {
+                for &i in tri.iter() {
+                    used[i] = true;
+                }
+            }
+
+            for i in (0..outs.len()).filter(|&i| used[i] && visible(i)) {
+                let center = screen(i);
+                rasterize_point(center.xy(), point, (image.width, image.height), |x, y, coverage, _| {
+                    if let Some(color) = shader.fragment(Vec2 { x, y }, outs[i].1.clone()) {
+                        plot_fragment(image, x, y, &color, coverage, point.biased_depth(center.z));
+                    }
+                });
+            }
+        }
+        PolygonMode::Fill => (),
+    }
+}
+
+// Everything after the vertex stage
+fn rasterize<V, F, S, T>(vertex_outs: [&(Vec4<f32>, V); 3], shader: &S, target: &mut T)
+        where V: Vary, S: Shader<V, F>, T: RenderTarget<F> {
+    let depths: Vec<f32> = vertex_outs.iter().map(|&&(v, _)| v.z).collect();
     let xy_verts: Vec<Vec2<isize>> =
         vertex_outs.iter()
-        .map(|&(v, _)| v.xy() / v.w)
+        .map(|&&(v, _)| v.xy() / v.w)
         .map(|v| Vec2 { x: v.x as isize, y: v.y as isize })
         .collect();
-    let varies: Vec<&V> = vertex_outs.iter().map(|&(_, ref v)| v).collect();
+    let varies: Vec<&V> = vertex_outs.iter().map(|&(_, v)| v).collect();
 
     let (min_bb, max_bb) = bounding_box(&xy_verts);
 
-    for x in cmp::max(0, min_bb.x)..cmp::min(image.width as isize, max_bb.x) {
-        for y in cmp::max(0, min_bb.y)..cmp::min(image.height as isize, max_bb.y) {
-            let pt = Vec2 { x: x, y: y };
+    for x in cmp::max(0, min_bb.x)..cmp::min(target.width() as isize, max_bb.x) {
+        for y in cmp::max(0, min_bb.y)..cmp::min(target.height() as isize, max_bb.y) {
+            let pt = Vec2 { x, y };
 
             let bary = barycentric(pt, &xy_verts);
 
@@ -70,16 +238,101 @@ pub fn draw_triangle<V: Vary, S: Shader<V>>(verts: &Vec<(Vec3<f32>, V)>, shader:
             }
 
             let varied = V::vary(varies[0], varies[1], varies[2], bary);
-            let maybe_out_color = shader.fragment(pt, varied);
+            let maybe_out = shader.fragment(pt, varied);
 
-            match maybe_out_color {
-                Some(out_color) => {
-                    let depth = depths[0] * bary.x + depths[1] * bary.y + depths[2] * bary.z;
-                    image.set_pixel_with_depth(x as usize, y as usize, &out_color, depth as isize);
-                }
-                None => {}
+            if let Some(out) = maybe_out {
+                let depth = depths[0] * bary.x + depths[1] * bary.y + depths[2] * bary.z;
+                target.write(x as usize, y as usize, depth, out);
             }
 
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Takes w from the position's z, to place vertices anywhere in clip
+    // space
+    struct ClipShader;
+
+    impl Shader<NoVary> for ClipShader {
+        fn vertex(&self, pt: Vec3<f32>, _: &NoVary) -> (Vec4<f32>, NoVary) {
+            (Vec4 { x: pt.x, y: pt.y, z: 0.0, w: pt.z }, NoVary)
+        }
+
+        fn fragment(&self, _: Vec2<isize>, _: NoVary) -> Option<Color> {
+            Some(Color(255, 255, 255))
+        }
+    }
+
+    #[derive(Clone, Copy, Vary)]
+    struct Vars {
+        weight: f32,
+        #[vary(normalize)]
+        normal: Vec3<f32>,
+        #[vary(flat)]
+        id: u32,
+        nested: Nested<Vec2<f32>>,
+    }
+
+    #[derive(Clone, Copy, Vary)]
+    struct Nested<T>(T);
+
+    fn vars(weight: f32, normal: Vec3<f32>, id: u32, tex: Vec2<f32>) -> Vars {
+        Vars { weight, normal, id, nested: Nested(tex) }
+    }
+
+    #[test]
+    fn derived_vary_interpolates_fields() {
+        let x = Vec3 { x: 1.0, y: 0.0, z: 0.0 };
+        let y = Vec3 { x: 0.0, y: 2.0, z: 0.0 };
+        let zero = Vec3 { x: 0.0, y: 0.0, z: 0.0 };
+        let (v1, v2, v3) = (
+            vars(1.0, x, 7, Vec2 { x: 0.0, y: 0.0 }),
+            vars(2.0, y, 8, Vec2 { x: 1.0, y: 0.0 }),
+            vars(4.0, zero, 9, Vec2 { x: 0.0, y: 1.0 }),
+        );
+
+        let v = Vary::vary(&v1, &v2, &v3, Vec3 { x: 0.5, y: 0.25, z: 0.25 });
+        assert_eq!(v.weight, 2.0);
+        assert_eq!(v.id, 7);
+        assert_eq!((v.nested.0.x, v.nested.0.y), (0.25, 0.25));
+
+        // (0.5, 0.5, 0) before normalizing
+        let s = 0.5f32.sqrt();
+        assert!((v.normal.x - s).abs() < 1e-6 && (v.normal.y - s).abs() < 1e-6 && v.normal.z == 0.0);
+
+        // Zero normals stay zero rather than becoming NaN
+        let v = Vary::vary(&v3, &v3, &v3, Vec3 { x: 0.2, y: 0.3, z: 0.5 });
+        assert_eq!((v.normal.x, v.normal.y, v.normal.z), (0.0, 0.0, 0.0));
+        assert_eq!(v.id, 9);
+    }
+
+    #[test]
+    fn lines_and_points_near_the_eye_are_clipped() {
+        let mesh = IndexedMesh {
+            vertices: vec![
+                (Vec3 { x: 1.0, y: 1.0, z: 1.0 }, NoVary),
+                (Vec3 { x: 6.0, y: 1.0, z: 1.0 }, NoVary),
+                // Just in front of the eye, so it projects miles away
+                (Vec3 { x: 3.0, y: 5.0, z: 1e-30 }, NoVary),
+            ],
+            indices: vec![[0, 1, 2]],
+        };
+
+        let modes = [
+            PolygonMode::Line(LineStyle::new()),
+            PolygonMode::Line(LineStyle { antialiased: true, ..LineStyle::new() }),
+            PolygonMode::Line(LineStyle { width: 3.0, ..LineStyle::new() }),
+            PolygonMode::Point(PointStyle::new()),
+        ];
+        for mode in modes.iter() {
+            let mut image = Image::new(8, 8);
+            draw_indexed_mode(&mesh, &ClipShader, &mut image, mode);
+
+            assert!(image.get_pixel(1, 1).0 > 0);
+        }
+    }
+}
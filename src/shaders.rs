@@ -0,0 +1,585 @@
+use std::cmp;
+use vec::{Vec2, Vec3, Vec4};
+use image::{Image, Color};
+use matrix::Matrix4x4;
+use obj::{Obj, FacePoint};
+use shader::{Vary, Shader};
+use target::{GBufferFragment, Material};
+
+// Ready-made shaders for common renders. Each comes with its own varyings
+// struct, which has a from_obj to pass to Obj::to_indexed:
+//
+//     let mesh = obj.to_indexed(PhongVars::from_obj);
+//     draw_indexed(&mesh, &PhongShader::new(&model, &screen, eye, light_dir), &mut image);
+//
+// Unless noted otherwise, `model` places the mesh in the world, `screen` is
+// viewport * projection * view, and light directions point towards the
+// light in world space.
+
+// The vertex stage most shaders share
+pub struct Transforms {
+    pub model: Matrix4x4<f32>,
+    normal_mat: Matrix4x4<f32>,
+    pub screen: Matrix4x4<f32>,
+}
+
+impl Transforms {
+    pub fn new(model: &Matrix4x4<f32>, screen: &Matrix4x4<f32>) -> Transforms {
+        Transforms {
+            model: model.clone(),
+            normal_mat: model.inverse().unwrap_or_else(Matrix4x4::identity).transpose(),
+            screen: screen.clone(),
+        }
+    }
+
+    // Clip space and world space positions of a model space point
+    pub fn position(&self, pt: Vec3<f32>) -> (Vec4<f32>, Vec3<f32>) {
+        let world = self.model.transform_point(pt);
+        (self.project(world), world)
+    }
+
+    pub fn project(&self, world: Vec3<f32>) -> Vec4<f32> {
+        &self.screen * &Vec4 { x: world.x, y: world.y, z: world.z, w: 1.0 }
+    }
+
+    // Not renormalized, so zero normals (meshes without any) stay zero
+    pub fn normal(&self, normal: Vec3<f32>) -> Vec3<f32> {
+        self.normal_mat.transform_vector(normal)
+    }
+}
+
+// Nearest texel, with texcoords outside [0, 1] clamped to the edges. Empty
+// textures are black.
+pub fn sample(tex: &Image, uv: Vec2<f32>) -> Color {
+    if tex.width == 0 || tex.height == 0 {
+        return Color(0, 0, 0);
+    }
+
+    let x = cmp::min((tex.width as f32 * uv.x.max(0.0)) as usize, tex.width - 1);
+    let y = cmp::min((tex.height as f32 * uv.y.max(0.0)) as usize, tex.height - 1);
+
+    tex.get_pixel(x, y)
+}
+
+// Lambert term for an interpolated normal. Surfaces without normals are
+// lit as if they face the light.
+pub fn diffuse(normal: Vec3<f32>, light_dir: Vec3<f32>) -> f32 {
+    if normal.length() > 0.0 {
+        normal.norm().dot(light_dir.norm()).max(0.0)
+    } else {
+        1.0
+    }
+}
+
+fn obj_normal(obj: &Obj, fp: &FacePoint) -> Vec3<f32> {
+    if fp.nindex == 0 { Vec3 { x: 0.0, y: 0.0, z: 0.0 } } else { obj.norm_vert(fp.nindex) }
+}
+
+fn obj_tex(obj: &Obj, fp: &FacePoint) -> Vec2<f32> {
+    if fp.tindex == 0 { Vec2 { x: 0.0, y: 0.0 } } else { obj.tex_vert(fp.tindex) }
+}
+
+fn mul(a: Vec3<f32>, b: Vec3<f32>) -> Vec3<f32> {
+    Vec3 { x: a.x * b.x, y: a.y * b.y, z: a.z * b.z }
+}
+
+#[derive(Clone, Copy, Vary)]
+pub struct ColorVars {
+    // World space
+    pub position: Vec3<f32>,
+    pub color: Vec3<f32>,
+    pub normal: Vec3<f32>,
+}
+
+impl ColorVars {
+    // Meshes without normals are lit as if they face the light
+    pub fn from_obj(obj: &Obj, fp: &FacePoint) -> ColorVars {
+        ColorVars {
+            position: obj.vert(fp.vindex),
+            color: obj.vert_color(fp.vindex),
+            normal: obj_normal(obj, fp),
+        }
+    }
+}
+
+// Renders per-vertex colors (from PLY, glTF or colored OBJ positions) with
+// simple diffuse lighting
+pub struct VertexColorShader {
+    transforms: Transforms,
+    pub light_dir: Vec3<f32>,
+    pub ambient: f32,
+}
+
+impl VertexColorShader {
+    pub fn new(model: &Matrix4x4<f32>, screen: &Matrix4x4<f32>, light_dir: Vec3<f32>) -> VertexColorShader {
+        VertexColorShader {
+            transforms: Transforms::new(model, screen),
+            light_dir,
+            ambient: 0.1,
+        }
+    }
+}
+
+impl Shader<ColorVars> for VertexColorShader {
+    fn vertex(&self, pt: Vec3<f32>, vars: &ColorVars) -> (Vec4<f32>, ColorVars) {
+        let (clip, position) = self.transforms.position(pt);
+        (clip, ColorVars { position, normal: self.transforms.normal(vars.normal), ..*vars })
+    }
+
+    fn fragment(&self, _: Vec2<isize>, vars: ColorVars) -> Option<Color> {
+        let shading = self.ambient + (1.0 - self.ambient) * diffuse(vars.normal, self.light_dir);
+        Some(Color::from_vec(vars.color * shading))
+    }
+}
+
+// Fills a G-buffer with per-vertex colors as albedo, tagging every pixel
+// with `object_id` so later passes can tell meshes apart. For textured
+// meshes, use deferred::GeometryShader.
+pub struct VertexColorGBufferShader {
+    transforms: Transforms,
+    pub material: Material,
+    pub object_id: u32,
+}
+
+impl VertexColorGBufferShader {
+    pub fn new(model: &Matrix4x4<f32>, screen: &Matrix4x4<f32>, object_id: u32) -> VertexColorGBufferShader {
+        VertexColorGBufferShader {
+            transforms: Transforms::new(model, screen),
+            material: Material::new(),
+            object_id,
+        }
+    }
+}
+
+impl Shader<ColorVars, GBufferFragment> for VertexColorGBufferShader {
+    fn vertex(&self, pt: Vec3<f32>, vars: &ColorVars) -> (Vec4<f32>, ColorVars) {
+        let (clip, position) = self.transforms.position(pt);
+        (clip, ColorVars { position, normal: self.transforms.normal(vars.normal), ..*vars })
+    }
+
+    fn fragment(&self, _: Vec2<isize>, vars: ColorVars) -> Option<GBufferFragment> {
+        Some(GBufferFragment {
+            albedo: Color::from_vec(vars.color),
+            position: vars.position,
+            normal: if vars.normal.length() > 0.0 { vars.normal.norm() } else { vars.normal },
+            material: self.material,
+            object_id: self.object_id,
+        })
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct FlatVars {
+    // World space
+    pub position: Vec3<f32>,
+    // Of the whole triangle, worked out from its corners when varying, so
+    // even meshes with smooth or no normals come out faceted
+    pub normal: Vec3<f32>,
+}
+
+impl FlatVars {
+    pub fn from_obj(obj: &Obj, fp: &FacePoint) -> FlatVars {
+        FlatVars {
+            position: obj.vert(fp.vindex),
+            normal: Vec3 { x: 0.0, y: 0.0, z: 0.0 },
+        }
+    }
+}
+
+impl Vary for FlatVars {
+    fn vary(v1: &FlatVars, v2: &FlatVars, v3: &FlatVars, bary: Vec3<f32>) -> FlatVars {
+        FlatVars {
+            position: v1.position * bary.x + v2.position * bary.y + v3.position * bary.z,
+            // Counter-clockwise faces outwards, as for Face::normal
+            normal: (v2.position - v1.position).cross(v3.position - v1.position),
+        }
+    }
+}
+
+// One shade per triangle
+pub struct FlatShader {
+    transforms: Transforms,
+    pub light_dir: Vec3<f32>,
+    pub color: Vec3<f32>,
+    // Brightness of faces turned away from the light
+    pub ambient: f32,
+}
+
+impl FlatShader {
+    pub fn new(model: &Matrix4x4<f32>, screen: &Matrix4x4<f32>, light_dir: Vec3<f32>) -> FlatShader {
+        FlatShader {
+            transforms: Transforms::new(model, screen),
+            light_dir,
+            color: Vec3 { x: 1.0, y: 1.0, z: 1.0 },
+            ambient: 0.1,
+        }
+    }
+}
+
+impl Shader<FlatVars> for FlatShader {
+    fn vertex(&self, pt: Vec3<f32>, vars: &FlatVars) -> (Vec4<f32>, FlatVars) {
+        let (clip, position) = self.transforms.position(pt);
+        (clip, FlatVars { position, ..*vars })
+    }
+
+    fn fragment(&self, _: Vec2<isize>, vars: FlatVars) -> Option<Color> {
+        let shading = self.ambient + (1.0 - self.ambient) * diffuse(vars.normal, self.light_dir);
+        Some(Color::from_vec(self.color * shading))
+    }
+}
+
+#[derive(Clone, Copy, Vary)]
+pub struct GouraudVars {
+    pub normal: Vec3<f32>,
+    // Lighting worked out by the vertex shader
+    pub intensity: f32,
+}
+
+impl GouraudVars {
+    pub fn from_obj(obj: &Obj, fp: &FacePoint) -> GouraudVars {
+        GouraudVars {
+            normal: obj_normal(obj, fp),
+            intensity: 0.0,
+        }
+    }
+}
+
+// Lit once per vertex, with the results blended across each triangle.
+// Cheap, but highlights smaller than a triangle get lost.
+pub struct GouraudShader {
+    transforms: Transforms,
+    pub light_dir: Vec3<f32>,
+    pub color: Vec3<f32>,
+    pub ambient: f32,
+}
+
+impl GouraudShader {
+    pub fn new(model: &Matrix4x4<f32>, screen: &Matrix4x4<f32>, light_dir: Vec3<f32>) -> GouraudShader {
+        GouraudShader {
+            transforms: Transforms::new(model, screen),
+            light_dir,
+            color: Vec3 { x: 1.0, y: 1.0, z: 1.0 },
+            ambient: 0.1,
+        }
+    }
+}
+
+impl Shader<GouraudVars> for GouraudShader {
+    fn vertex(&self, pt: Vec3<f32>, vars: &GouraudVars) -> (Vec4<f32>, GouraudVars) {
+        let (clip, _) = self.transforms.position(pt);
+        let normal = self.transforms.normal(vars.normal);
+
+        (
+            clip,
+            GouraudVars {
+                normal,
+                intensity: self.ambient + (1.0 - self.ambient) * diffuse(normal, self.light_dir),
+            },
+        )
+    }
+
+    fn fragment(&self, _: Vec2<isize>, vars: GouraudVars) -> Option<Color> {
+        Some(Color::from_vec(self.color * vars.intensity))
+    }
+}
+
+#[derive(Clone, Copy, Vary)]
+pub struct PhongVars {
+    // World space
+    pub position: Vec3<f32>,
+    pub normal: Vec3<f32>,
+}
+
+impl PhongVars {
+    pub fn from_obj(obj: &Obj, fp: &FacePoint) -> PhongVars {
+        PhongVars {
+            position: obj.vert(fp.vindex),
+            normal: obj_normal(obj, fp),
+        }
+    }
+}
+
+// Lit per pixel with the Phong reflection model: ambient, diffuse and a
+// specular highlight around the light's mirror direction
+pub struct PhongShader {
+    transforms: Transforms,
+    pub eye: Vec3<f32>,
+    pub light_dir: Vec3<f32>,
+    pub color: Vec3<f32>,
+    pub ambient: f32,
+    pub specular: f32,
+    pub shininess: f32,
+}
+
+impl PhongShader {
+    pub fn new(model: &Matrix4x4<f32>, screen: &Matrix4x4<f32>, eye: Vec3<f32>, light_dir: Vec3<f32>) -> PhongShader {
+        PhongShader {
+            transforms: Transforms::new(model, screen),
+            eye,
+            light_dir,
+            color: Vec3 { x: 1.0, y: 1.0, z: 1.0 },
+            ambient: 0.1,
+            specular: 0.5,
+            shininess: 32.0,
+        }
+    }
+}
+
+impl Shader<PhongVars> for PhongShader {
+    fn vertex(&self, pt: Vec3<f32>, vars: &PhongVars) -> (Vec4<f32>, PhongVars) {
+        let (clip, position) = self.transforms.position(pt);
+        (clip, PhongVars { position, normal: self.transforms.normal(vars.normal) })
+    }
+
+    fn fragment(&self, _: Vec2<isize>, vars: PhongVars) -> Option<Color> {
+        let d = diffuse(vars.normal, self.light_dir);
+        let mut color = self.color * (self.ambient + (1.0 - self.ambient) * d);
+
+        if vars.normal.length() > 0.0 && d > 0.0 {
+            let n = vars.normal.norm();
+            let l = self.light_dir.norm();
+            let reflected = n * (2.0 * n.dot(l)) - l;
+            let view = (self.eye - vars.position).norm();
+            let highlight = self.specular * reflected.dot(view).max(0.0).powf(self.shininess);
+
+            color = color + Vec3 { x: highlight, y: highlight, z: highlight };
+        }
+
+        Some(Color::from_vec(color))
+    }
+}
+
+#[derive(Clone, Copy, Vary)]
+pub struct TexturedVars {
+    pub normal: Vec3<f32>,
+    pub tex: Vec2<f32>,
+}
+
+impl TexturedVars {
+    pub fn from_obj(obj: &Obj, fp: &FacePoint) -> TexturedVars {
+        TexturedVars {
+            normal: obj_normal(obj, fp),
+            tex: obj_tex(obj, fp),
+        }
+    }
+}
+
+// A texture with diffuse lighting
+pub struct TexturedShader<'a> {
+    transforms: Transforms,
+    pub texture: &'a Image,
+    pub light_dir: Vec3<f32>,
+    pub ambient: f32,
+}
+
+impl<'a> TexturedShader<'a> {
+    pub fn new(model: &Matrix4x4<f32>, screen: &Matrix4x4<f32>, texture: &'a Image, light_dir: Vec3<f32>) -> TexturedShader<'a> {
+        TexturedShader {
+            transforms: Transforms::new(model, screen),
+            texture,
+            light_dir,
+            ambient: 0.0,
+        }
+    }
+}
+
+impl<'a> Shader<TexturedVars> for TexturedShader<'a> {
+    fn vertex(&self, pt: Vec3<f32>, vars: &TexturedVars) -> (Vec4<f32>, TexturedVars) {
+        let (clip, _) = self.transforms.position(pt);
+        (clip, TexturedVars { normal: self.transforms.normal(vars.normal), ..*vars })
+    }
+
+    fn fragment(&self, _: Vec2<isize>, vars: TexturedVars) -> Option<Color> {
+        let shading = self.ambient + (1.0 - self.ambient) * diffuse(vars.normal, self.light_dir);
+
+        // Without ambient light, faces turned away from the light aren't
+        // drawn at all
+        if shading <= 0.0 {
+            return None;
+        }
+
+        Some(Color::from_vec(sample(self.texture, vars.tex).to_vec() * shading))
+    }
+}
+
+#[derive(Clone, Copy, Vary)]
+pub struct NormalVars {
+    pub normal: Vec3<f32>,
+}
+
+impl NormalVars {
+    pub fn from_obj(obj: &Obj, fp: &FacePoint) -> NormalVars {
+        NormalVars { normal: obj_normal(obj, fp) }
+    }
+}
+
+// World space normals as colors: x to red, y to green, z to blue, with -1
+// at 0 and 1 at full brightness. Missing normals come out mid grey.
+pub struct NormalVisShader {
+    transforms: Transforms,
+}
+
+impl NormalVisShader {
+    pub fn new(model: &Matrix4x4<f32>, screen: &Matrix4x4<f32>) -> NormalVisShader {
+        NormalVisShader { transforms: Transforms::new(model, screen) }
+    }
+}
+
+impl Shader<NormalVars> for NormalVisShader {
+    fn vertex(&self, pt: Vec3<f32>, vars: &NormalVars) -> (Vec4<f32>, NormalVars) {
+        let (clip, _) = self.transforms.position(pt);
+        (clip, NormalVars { normal: self.transforms.normal(vars.normal) })
+    }
+
+    fn fragment(&self, _: Vec2<isize>, vars: NormalVars) -> Option<Color> {
+        let n = if vars.normal.length() > 0.0 { vars.normal.norm() } else { vars.normal };
+        Some(Color::from_vec(n * 0.5 + Vec3 { x: 0.5, y: 0.5, z: 0.5 }))
+    }
+}
+
+#[derive(Clone, Copy, Vary)]
+pub struct DepthVars {
+    // From the eye, in world units
+    pub distance: f32,
+}
+
+impl DepthVars {
+    pub fn from_obj(_: &Obj, _: &FacePoint) -> DepthVars {
+        DepthVars { distance: 0.0 }
+    }
+}
+
+// Distance from the eye as grey: white at `near`, black at `far`
+pub struct DepthVisShader {
+    transforms: Transforms,
+    pub eye: Vec3<f32>,
+    pub near: f32,
+    pub far: f32,
+}
+
+impl DepthVisShader {
+    pub fn new(model: &Matrix4x4<f32>, screen: &Matrix4x4<f32>, eye: Vec3<f32>, near: f32, far: f32) -> DepthVisShader {
+        DepthVisShader {
+            transforms: Transforms::new(model, screen),
+            eye,
+            near,
+            far,
+        }
+    }
+}
+
+impl Shader<DepthVars> for DepthVisShader {
+    fn vertex(&self, pt: Vec3<f32>, _: &DepthVars) -> (Vec4<f32>, DepthVars) {
+        let (clip, position) = self.transforms.position(pt);
+        (clip, DepthVars { distance: (position - self.eye).length() })
+    }
+
+    fn fragment(&self, _: Vec2<isize>, vars: DepthVars) -> Option<Color> {
+        let t = 1.0 - (vars.distance - self.near) / (self.far - self.near).max(1e-6);
+        let t = t.clamp(0.0, 1.0);
+
+        Some(Color::from_vec(Vec3 { x: t, y: t, z: t }))
+    }
+}
+
+#[derive(Clone, Copy, Vary)]
+pub struct UvVars {
+    pub tex: Vec2<f32>,
+}
+
+impl UvVars {
+    pub fn from_obj(obj: &Obj, fp: &FacePoint) -> UvVars {
+        UvVars { tex: obj_tex(obj, fp) }
+    }
+}
+
+// A checkerboard laid out in texture space, tinted red along u and green
+// along v, for spotting stretched, flipped or missing texcoords
+pub struct UvCheckerShader {
+    transforms: Transforms,
+    // Squares across the whole [0, 1] range
+    pub squares: f32,
+}
+
+impl UvCheckerShader {
+    pub fn new(model: &Matrix4x4<f32>, screen: &Matrix4x4<f32>) -> UvCheckerShader {
+        UvCheckerShader {
+            transforms: Transforms::new(model, screen),
+            squares: 8.0,
+        }
+    }
+}
+
+impl Shader<UvVars> for UvCheckerShader {
+    fn vertex(&self, pt: Vec3<f32>, vars: &UvVars) -> (Vec4<f32>, UvVars) {
+        let (clip, _) = self.transforms.position(pt);
+        (clip, *vars)
+    }
+
+    fn fragment(&self, _: Vec2<isize>, vars: UvVars) -> Option<Color> {
+        let (u, v) = (vars.tex.x, vars.tex.y);
+        let odd = ((u * self.squares).floor() + (v * self.squares).floor()) as i64 % 2 != 0;
+
+        let tint = Vec3 { x: u.clamp(0.0, 1.0), y: v.clamp(0.0, 1.0), z: 0.5 };
+        let brightness = if odd { 0.35 } else { 1.0 };
+
+        Some(Color::from_vec(tint * brightness))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shader::draw_triangle;
+    use target::{GBuffer, NO_OBJECT};
+
+    #[test]
+    fn vertex_colors_fill_a_gbuffer() {
+        let up = Vec3 { x: 0.0, y: 0.0, z: 1.0 };
+        let vars = |x, y| (Vec3 { x, y, z: 0.0 }, ColorVars {
+            position: Vec3 { x, y, z: 0.0 },
+            color: Vec3 { x: 1.0, y: 0.0, z: 0.0 },
+            normal: up * 2.0,
+        });
+
+        // Model space is moved back along z, while the screen transform
+        // leaves x and y as pixels
+        let model = Matrix4x4::translation(Vec3 { x: 0.0, y: 0.0, z: -5.0 });
+        let shader = VertexColorGBufferShader::new(&model, &Matrix4x4::identity(), 7);
+        let mut gbuffer = GBuffer::new(8, 8);
+        draw_triangle(&[vars(0.0, 0.0), vars(8.0, 0.0), vars(0.0, 8.0)], &shader, &mut gbuffer);
+
+        assert_eq!(*gbuffer.object_id.get(1, 1), 7);
+        assert_eq!(*gbuffer.object_id.get(7, 7), NO_OBJECT);
+        assert_eq!(gbuffer.albedo.get(1, 1).0, 255);
+        assert!((gbuffer.position.get(1, 1).z + 5.0).abs() < 1e-5);
+        assert!((gbuffer.normal.get(1, 1).z - 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn samples_clamp_to_the_texture() {
+        let mut tex = Image::new(2, 2);
+        tex.set_pixel(0, 0, &Color(10, 0, 0));
+        tex.set_pixel(1, 1, &Color(20, 0, 0));
+
+        assert_eq!(sample(&tex, Vec2 { x: -3.0, y: 0.2 }).0, 10);
+        assert_eq!(sample(&tex, Vec2 { x: 7.0, y: 1.0 }).0, 20);
+        assert_eq!(sample(&Image::new(0, 0), Vec2 { x: 0.5, y: 0.5 }).0, 0);
+        assert_eq!(sample(&Image::new(4, 0), Vec2 { x: 0.5, y: 0.5 }).0, 0);
+    }
+
+    #[test]
+    fn unlit_textured_fragments_are_discarded() {
+        let tex = Image::new(1, 1);
+        let light = Vec3 { x: 0.0, y: 0.0, z: 1.0 };
+        let vars = |z| TexturedVars { normal: Vec3 { x: 0.0, y: 0.0, z }, tex: Vec2 { x: 0.5, y: 0.5 } };
+        let mut shader = TexturedShader::new(&Matrix4x4::identity(), &Matrix4x4::identity(), &tex, light);
+        let pixel = Vec2 { x: 0, y: 0 };
+
+        assert!(shader.fragment(pixel, vars(1.0)).is_some());
+        assert!(shader.fragment(pixel, vars(-1.0)).is_none());
+
+        shader.ambient = 0.2;
+        assert!(shader.fragment(pixel, vars(-1.0)).is_some());
+    }
+}
@@ -0,0 +1,199 @@
+use vec::{Vec2, Vec3, Vec4};
+use image::{Image, Color};
+use matrix::Matrix4x4;
+use mesh::IndexedMesh;
+use bounds::Sphere;
+use camera::Camera;
+use shader::{Vary, Shader, draw_indexed};
+use target::{DepthTarget, GBuffer};
+
+#[derive(Clone, Copy)]
+pub struct ShadowOptions {
+    // Width and height of the depth map, in pixels
+    pub size: usize,
+    // In world units; keeps surfaces from shadowing themselves
+    pub bias: f32,
+    // 0 leaves the image alone, 1 turns shadowed pixels black
+    pub strength: f32,
+}
+
+impl ShadowOptions {
+    pub fn new() -> ShadowOptions {
+        ShadowOptions {
+            size: 1024,
+            bias: 0.01,
+            strength: 0.6,
+        }
+    }
+}
+
+// Depth of the scene as seen from a light. Applied to a finished image
+// from the G-buffer's world positions, like Ssao, so shaders don't need to
+// know about it.
+pub struct ShadowMap {
+    pub options: ShadowOptions,
+    // viewport * projection * view for the light
+    screen: Matrix4x4<f32>,
+    depth: DepthTarget<()>,
+    // World space size of a texel at the middle of the scene
+    texel: f32,
+}
+
+impl ShadowMap {
+    // A light at `position` shining on everything in `scene`, which it must
+    // be outside of
+    pub fn point(position: Vec3<f32>, scene: &Sphere, options: ShadowOptions) -> ShadowMap {
+        let distance = (scene.center - position).length().max(scene.radius * 1.001);
+        let camera = Camera {
+            eye: position,
+            center: scene.center,
+            up: up_for(scene.center - position),
+            fov: 2.0 * (scene.radius / distance).min(1.0).asin(),
+        };
+
+        ShadowMap::from_camera(&camera, scene, options)
+    }
+
+    // Light arriving from `direction` (towards the light). Seen from a
+    // light this far away, the perspective is close enough to parallel.
+    pub fn directional(direction: Vec3<f32>, scene: &Sphere, options: ShadowOptions) -> ShadowMap {
+        let camera = Camera::framing(scene, 0.05, direction, up_for(direction));
+
+        ShadowMap::from_camera(&camera, scene, options)
+    }
+
+    fn from_camera(camera: &Camera, scene: &Sphere, options: ShadowOptions) -> ShadowMap {
+        let size = options.size as f32;
+        let viewport = Matrix4x4::viewport(0.0, 0.0, size, size, 255.0);
+
+        ShadowMap {
+            options,
+            screen: viewport * camera.projection() * camera.view(),
+            depth: DepthTarget::new(options.size, options.size, ()),
+            texel: 2.0 * scene.radius / size,
+        }
+    }
+
+    // Adds a mesh's triangles as shadow casters
+    pub fn draw<V: Vary + Clone>(&mut self, mesh: &IndexedMesh<V>, model: &Matrix4x4<f32>) {
+        let shader = DepthShader { screen: &self.screen * model };
+        draw_indexed(mesh, &shader, &mut self.depth);
+    }
+
+    // 1 if the light reaches the world space point `p`, 0 if something is
+    // in the way
+    pub fn visibility(&self, p: Vec3<f32>) -> f32 {
+        let clip = &self.screen * &Vec4 { x: p.x, y: p.y, z: p.z, w: 1.0 };
+        if clip.w <= 0.0 {
+            return 1.0;
+        }
+
+        let (x, y) = ((clip.x / clip.w).floor(), (clip.y / clip.w).floor());
+        if x < 0.0 || y < 0.0 || x >= self.options.size as f32 || y >= self.options.size as f32 {
+            return 1.0;
+        }
+
+        // Depth is an affine function of world position, see Ssao
+        let bias = self.options.bias * self.screen.row(2).xyz().length();
+
+        if clip.z + bias >= *self.depth.depth.get(x as usize, y as usize) { 1.0 } else { 0.0 }
+    }
+
+    // Darkens the pixels of `image` the light doesn't reach. `gbuffer` must
+    // have been drawn with the same camera as the image.
+    pub fn composite(&self, image: &mut Image, gbuffer: &GBuffer) {
+        for y in 0..image.height.min(gbuffer.depth.height) {
+            for x in 0..image.width.min(gbuffer.depth.width) {
+                if !gbuffer.covered(x, y) {
+                    continue;
+                }
+
+                // Looking up a texel or so out along the normal keeps
+                // surfaces at grazing angles to the light from shadowing
+                // themselves
+                let p = *gbuffer.position.get(x, y) + *gbuffer.normal.get(x, y) * (self.texel * 1.5);
+
+                let shade = 1.0 - self.options.strength * (1.0 - self.visibility(p));
+                if shade < 1.0 {
+                    let color = image.get_pixel(x, y).to_vec() * shade;
+                    image.set_pixel(x, y, &Color::from_vec(color));
+                }
+            }
+        }
+    }
+
+    // Nearest surfaces white, nothing black
+    pub fn depth_image(&self) -> Image {
+        let depth = &self.depth.depth;
+        let covered = depth.data.iter().cloned().filter(|d| d.is_finite());
+        let (near, far) = covered.fold((f32::NEG_INFINITY, f32::INFINITY), |(near, far), d| (near.max(d), far.min(d)));
+        let range = (near - far).max(1e-6);
+
+        depth.to_image(|&d| {
+            let v = if d.is_finite() { ((d - far) / range * 255.0) as u8 } else { 0 };
+            Color(v, v, v)
+        })
+    }
+}
+
+// Any up vector works for a light as long as it isn't parallel to where
+// the light is looking
+fn up_for(direction: Vec3<f32>) -> Vec3<f32> {
+    let direction = direction.norm();
+
+    if direction.y.abs() < 0.99 {
+        Vec3 { x: 0.0, y: 1.0, z: 0.0 }
+    } else {
+        Vec3 { x: 1.0, y: 0.0, z: 0.0 }
+    }
+}
+
+// Only positions matter for the shadow map; whatever the mesh's vertex
+// data is gets passed through untouched
+struct DepthShader {
+    screen: Matrix4x4<f32>,
+}
+
+impl<V: Vary + Clone> Shader<V, ()> for DepthShader {
+    fn vertex(&self, pt: Vec3<f32>, vars: &V) -> (Vec4<f32>, V) {
+        (&self.screen * &Vec4 { x: pt.x, y: pt.y, z: pt.z, w: 1.0 }, vars.clone())
+    }
+
+    fn fragment(&self, _: Vec2<isize>, _: V) -> Option<()> {
+        Some(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shader::NoVary;
+
+    // Lit from straight above, with a 2x2 square at y = 1 in the way
+    fn shadow_map(options: ShadowOptions) -> ShadowMap {
+        let scene = Sphere { center: Vec3 { x: 0.0, y: 0.5, z: 0.0 }, radius: 4.0 };
+        let mut map = ShadowMap::directional(Vec3 { x: 0.0, y: 1.0, z: 0.0 }, &scene, options);
+
+        let corner = |x, z| (Vec3 { x, y: 1.0, z }, NoVary);
+        let square = IndexedMesh {
+            vertices: vec![corner(-1.0, -1.0), corner(1.0, -1.0), corner(1.0, 1.0), corner(-1.0, 1.0)],
+            indices: vec![[0, 1, 2], [0, 2, 3]],
+        };
+        map.draw(&square, &Matrix4x4::identity());
+
+        map
+    }
+
+    #[test]
+    fn occluders_block_the_light() {
+        let map = shadow_map(ShadowOptions { size: 128, ..ShadowOptions::new() });
+        let at = |x, y, z| map.visibility(Vec3 { x, y, z });
+
+        assert_eq!(at(0.0, 0.0, 0.0), 0.0);
+        assert_eq!(at(0.5, -1.0, -0.5), 0.0);
+        // Above the square, beside it and on it
+        assert_eq!(at(0.0, 2.0, 0.0), 1.0);
+        assert_eq!(at(2.5, 0.0, 0.0), 1.0);
+        assert_eq!(at(0.0, 1.0, 0.0), 1.0);
+    }
+}
@@ -0,0 +1,715 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use vec::{Vec2, Vec3};
+use matrix::Matrix4x4;
+use obj::{Obj, FaceAttrs, FacePoint};
+use camera::Camera;
+
+// Symmetric 4x4 error quadric, stored as its upper triangle
+#[derive(Clone, Copy)]
+struct Quadric([f64; 10]);
+
+impl Quadric {
+    fn zero() -> Quadric {
+        Quadric([0.0; 10])
+    }
+
+    // Squared distance to the plane n.p + d = 0, scaled by `weight`
+    fn plane(n: Vec3<f32>, d: f32, weight: f32) -> Quadric {
+        let (a, b, c, d, w) = (n.x as f64, n.y as f64, n.z as f64, d as f64, weight as f64);
+
+        Quadric([
+            a * a * w, a * b * w, a * c * w, a * d * w,
+            b * b * w, b * c * w, b * d * w,
+            c * c * w, c * d * w,
+            d * d * w,
+        ])
+    }
+
+    fn add(&self, other: &Quadric) -> Quadric {
+        let mut q = *self;
+        for (a, b) in q.0.iter_mut().zip(other.0.iter()) {
+            *a += *b;
+        }
+        q
+    }
+
+    fn error(&self, p: Vec3<f32>) -> f64 {
+        let q = &self.0;
+        let (x, y, z) = (p.x as f64, p.y as f64, p.z as f64);
+
+        q[0] * x * x + 2.0 * q[1] * x * y + 2.0 * q[2] * x * z + 2.0 * q[3] * x
+            + q[4] * y * y + 2.0 * q[5] * y * z + 2.0 * q[6] * y
+            + q[7] * z * z + 2.0 * q[8] * z
+            + q[9]
+    }
+
+    // The point minimizing the error, if the quadric isn't degenerate
+    fn optimum(&self) -> Option<Vec3<f32>> {
+        let q = &self.0;
+        let (a, b, c) = ([q[0], q[1], q[2]], [q[1], q[4], q[5]], [q[2], q[5], q[7]]);
+        let r = [-q[3], -q[6], -q[8]];
+
+        let det3 = |a: [f64; 3], b: [f64; 3], c: [f64; 3]| {
+            a[0] * (b[1] * c[2] - b[2] * c[1]) -
+                a[1] * (b[0] * c[2] - b[2] * c[0]) +
+                a[2] * (b[0] * c[1] - b[1] * c[0])
+        };
+
+        let det = det3(a, b, c);
+        if det.abs() < 1e-12 {
+            return None;
+        }
+
+        // Cramer's rule
+        let x = det3([r[0], a[1], a[2]], [r[1], b[1], b[2]], [r[2], c[1], c[2]]) / det;
+        let y = det3([a[0], r[0], a[2]], [b[0], r[1], b[2]], [c[0], r[2], c[2]]) / det;
+        let z = det3([a[0], a[1], r[0]], [b[0], b[1], r[1]], [c[0], c[1], r[2]]) / det;
+
+        Some(Vec3 { x: x as f32, y: y as f32, z: z as f32 })
+    }
+}
+
+// Boundary edges get a plane through them, perpendicular to their face,
+// weighted heavily so the outline of open meshes stays put
+const BOUNDARY_WEIGHT: f32 = 1000.0;
+
+// A collapse is rejected if it turns any remaining face by more than this
+// (as the cosine of the angle)
+const MAX_NORMAL_CHANGE: f32 = 0.2;
+
+struct Collapse {
+    cost: f64,
+    keep: usize,
+    remove: usize,
+    target: Vec3<f32>,
+    versions: (u32, u32),
+}
+
+impl PartialEq for Collapse {
+    fn eq(&self, other: &Collapse) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Collapse {}
+
+impl PartialOrd for Collapse {
+    fn partial_cmp(&self, other: &Collapse) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// Reversed, so the BinaryHeap pops the cheapest collapse first. NaN costs
+// sort above everything, so they come out last.
+impl Ord for Collapse {
+    fn cmp(&self, other: &Collapse) -> Ordering {
+        other.cost.total_cmp(&self.cost)
+    }
+}
+
+// Working copy of the mesh. Positions, texcoords and normals keep the
+// 1-based indexing of FacePoint, with an unused entry at 0.
+struct Decimator {
+    pos: Vec<Vec3<f32>>,
+    tex: Vec<Vec2<f32>>,
+    norms: Vec<Vec3<f32>>,
+    tris: Vec<[FacePoint; 3]>,
+    attrs: Vec<FaceAttrs>,
+    alive: Vec<bool>,
+    live_count: usize,
+    vert_tris: Vec<Vec<usize>>,
+    quadrics: Vec<Quadric>,
+    removed: Vec<bool>,
+    versions: Vec<u32>,
+    // Positions whose corners don't all share the same texcoord and normal.
+    // They never move, so seams and hard edges keep their shape.
+    seam: Vec<bool>,
+}
+
+fn face_normal(a: Vec3<f32>, b: Vec3<f32>, c: Vec3<f32>) -> Vec3<f32> {
+    (b - a).cross(c - a)
+}
+
+impl Decimator {
+    fn new(obj: &Obj) -> Decimator {
+        let zero2 = Vec2 { x: 0.0, y: 0.0 };
+        let zero3 = Vec3 { x: 0.0, y: 0.0, z: 0.0 };
+
+        let mut pos = vec![zero3];
+        pos.extend(obj.positions());
+        let mut tex = vec![zero2];
+        tex.extend((1..obj.tex_vert_count() + 1).map(|i| obj.tex_vert(i)));
+        let mut norms = vec![zero3];
+        norms.extend((1..obj.norm_vert_count() + 1).map(|i| obj.norm_vert(i)));
+
+        let mut tris = Vec::with_capacity(obj.faces().len());
+        let mut attrs = vec![FaceAttrs::default(); obj.faces().len()];
+        for f in obj.faces() {
+            tris.push([f.0, f.1, f.2]);
+        }
+        for polygon in obj.all_polygons() {
+            for a in &mut attrs[polygon.first_face..polygon.first_face + polygon.face_count] {
+                *a = polygon.attrs;
+            }
+        }
+
+        let n = pos.len();
+        let mut vert_tris = vec![Vec::new(); n];
+        let mut corner_attrs: Vec<Option<(usize, usize)>> = vec![None; n];
+        let mut seam = vec![false; n];
+
+        for (i, tri) in tris.iter().enumerate() {
+            for p in tri {
+                vert_tris[p.vindex].push(i);
+
+                match corner_attrs[p.vindex] {
+                    None => corner_attrs[p.vindex] = Some((p.tindex, p.nindex)),
+                    Some(ta) if ta != (p.tindex, p.nindex) => seam[p.vindex] = true,
+                    _ => (),
+                }
+            }
+        }
+
+        let mut d = Decimator {
+            pos,
+            tex,
+            norms,
+            live_count: tris.len(),
+            alive: vec![true; tris.len()],
+            tris,
+            attrs,
+            vert_tris,
+            quadrics: vec![Quadric::zero(); n],
+            removed: vec![false; n],
+            versions: vec![0; n],
+            seam,
+        };
+        d.init_quadrics();
+        d
+    }
+
+    fn init_quadrics(&mut self) {
+        for tri in &self.tris {
+            let (a, b, c) = (self.pos[tri[0].vindex], self.pos[tri[1].vindex], self.pos[tri[2].vindex]);
+            let area_vector = face_normal(a, b, c);
+            let area = area_vector.length();
+            if area == 0.0 {
+                continue;
+            }
+
+            let n = area_vector * (1.0 / area);
+            let q = Quadric::plane(n, -n.dot(a), area * 0.5);
+            for p in tri {
+                self.quadrics[p.vindex] = self.quadrics[p.vindex].add(&q);
+            }
+
+            // An edge only one face uses is on the boundary
+            for k in 0..3 {
+                let (u, v) = (tri[k].vindex, tri[(k + 1) % 3].vindex);
+                if self.shared_tris(u, v).len() == 1 {
+                    let edge = self.pos[v] - self.pos[u];
+                    let side = edge.cross(n).norm();
+                    let weight = BOUNDARY_WEIGHT * edge.dot(edge);
+                    let q = Quadric::plane(side, -side.dot(self.pos[u]), weight);
+
+                    self.quadrics[u] = self.quadrics[u].add(&q);
+                    self.quadrics[v] = self.quadrics[v].add(&q);
+                }
+            }
+        }
+    }
+
+    fn shared_tris(&self, u: usize, v: usize) -> Vec<usize> {
+        self.vert_tris[u].iter()
+            .cloned()
+            .filter(|&t| self.alive[t] && self.tris[t].iter().any(|p| p.vindex == v))
+            .collect()
+    }
+
+    fn neighbours(&self, v: usize) -> Vec<usize> {
+        let mut result: Vec<usize> = self.vert_tris[v].iter()
+            .filter(|&&t| self.alive[t])
+            .flat_map(|&t| self.tris[t].iter().map(|p| p.vindex))
+            .filter(|&u| u != v)
+            .collect();
+
+        result.sort();
+        result.dedup();
+        result
+    }
+
+    fn plan(&self, a: usize, b: usize) -> Option<Collapse> {
+        let q = self.quadrics[a].add(&self.quadrics[b]);
+
+        let (keep, remove, target) = match (self.seam[a], self.seam[b]) {
+            (true, true) => return None,
+            (true, false) => (a, b, self.pos[a]),
+            (false, true) => (b, a, self.pos[b]),
+            (false, false) => {
+                let (pa, pb) = (self.pos[a], self.pos[b]);
+                let mid = (pa + pb) * 0.5;
+
+                // Nearly flat regions give nearly singular quadrics whose
+                // solution can land far away, so the optimum only competes
+                // with the endpoints and midpoint when it stays near the edge
+                let reach = (pb - pa).length();
+                let mut candidates = vec![pa, pb, mid];
+                if let Some(p) = q.optimum() {
+                    if (p - mid).length() <= reach {
+                        candidates.push(p);
+                    }
+                }
+
+                let mut target = mid;
+                let mut best = f64::INFINITY;
+                for p in candidates {
+                    let e = q.error(p);
+                    if e < best {
+                        best = e;
+                        target = p;
+                    }
+                }
+
+                (a, b, target)
+            }
+        };
+
+        Some(Collapse {
+            cost: q.error(target).max(0.0),
+            keep,
+            remove,
+            target,
+            versions: (self.versions[keep], self.versions[remove]),
+        })
+    }
+
+    fn push_edges(&self, v: usize, heap: &mut BinaryHeap<Collapse>) {
+        for u in self.neighbours(v) {
+            if let Some(c) = self.plan(v, u) {
+                heap.push(c);
+            }
+        }
+    }
+
+    // Only collapses that keep the mesh manifold and don't fold any face
+    // over are allowed
+    fn is_valid(&self, c: &Collapse, shared: &[usize]) -> bool {
+        if shared.is_empty() || shared.len() > 2 {
+            return false;
+        }
+
+        let keep_ring = self.neighbours(c.keep);
+        let common = self.neighbours(c.remove).iter()
+            .filter(|u| keep_ring.binary_search(u).is_ok())
+            .count();
+        if common != shared.len() {
+            return false;
+        }
+
+        for &v in &[c.keep, c.remove] {
+            for &t in &self.vert_tris[v] {
+                if !self.alive[t] || shared.contains(&t) {
+                    continue;
+                }
+
+                let tri = &self.tris[t];
+                let before = face_normal(self.pos[tri[0].vindex], self.pos[tri[1].vindex], self.pos[tri[2].vindex]);
+                let moved = |p: &FacePoint| {
+                    if p.vindex == c.keep || p.vindex == c.remove { c.target } else { self.pos[p.vindex] }
+                };
+                let after = face_normal(moved(&tri[0]), moved(&tri[1]), moved(&tri[2]));
+
+                if after.length() == 0.0 || before.norm().dot(after.norm()) < MAX_NORMAL_CHANGE {
+                    return false;
+                }
+            }
+        }
+
+        true
+    }
+
+    fn corner(&self, t: usize, v: usize) -> FacePoint {
+        *self.tris[t].iter().find(|p| p.vindex == v).unwrap()
+    }
+
+    fn collapse(&mut self, c: &Collapse, shared: &[usize]) -> bool {
+        let keep_corner = self.corner(shared[0], c.keep);
+        let remove_corner = self.corner(shared[0], c.remove);
+
+        let (tindex, nindex) = if self.seam[c.keep] {
+            // The removed vertex's faces all sit on one side of the seam,
+            // so they take the keep vertex's attributes from that side
+            if shared.iter().any(|&t| self.corner(t, c.keep) != keep_corner) {
+                return false;
+            }
+            (keep_corner.tindex, keep_corner.nindex)
+        } else {
+            // Both are interior to a chart; interpolate along the edge
+            let edge = self.pos[c.remove] - self.pos[c.keep];
+            let len2 = edge.dot(edge);
+            let s = if len2 > 0.0 { ((c.target - self.pos[c.keep]).dot(edge) / len2).clamp(0.0, 1.0) } else { 0.5 };
+
+            let tindex = if keep_corner.tindex != 0 && remove_corner.tindex != 0 {
+                let t = self.tex[keep_corner.tindex] * (1.0 - s) + self.tex[remove_corner.tindex] * s;
+                self.tex.push(t);
+                self.tex.len() - 1
+            } else {
+                keep_corner.tindex
+            };
+
+            let nindex = if keep_corner.nindex != 0 && remove_corner.nindex != 0 {
+                let n = self.norms[keep_corner.nindex] * (1.0 - s) + self.norms[remove_corner.nindex] * s;
+                self.norms.push(if n.length() > 0.0 { n.norm() } else { self.norms[keep_corner.nindex] });
+                self.norms.len() - 1
+            } else {
+                keep_corner.nindex
+            };
+
+            (tindex, nindex)
+        };
+
+        for &t in shared {
+            self.alive[t] = false;
+            self.live_count -= 1;
+        }
+
+        let keep_seam = self.seam[c.keep];
+        let remove_tris = std::mem::take(&mut self.vert_tris[c.remove]);
+        for &t in remove_tris.iter().chain(self.vert_tris[c.keep].clone().iter()) {
+            if !self.alive[t] {
+                continue;
+            }
+
+            for p in &mut self.tris[t] {
+                if p.vindex == c.remove || (p.vindex == c.keep && !keep_seam) {
+                    *p = FacePoint { vindex: c.keep, tindex, nindex };
+                }
+            }
+        }
+
+        let mut tris: Vec<usize> = self.vert_tris[c.keep].iter()
+            .chain(remove_tris.iter())
+            .cloned()
+            .filter(|&t| self.alive[t])
+            .collect();
+        tris.sort();
+        tris.dedup();
+
+        self.vert_tris[c.keep] = tris;
+        self.pos[c.keep] = c.target;
+        self.quadrics[c.keep] = self.quadrics[c.keep].add(&self.quadrics[c.remove]);
+        self.removed[c.remove] = true;
+        self.versions[c.keep] += 1;
+        true
+    }
+
+    fn run(&mut self, target_faces: usize) {
+        let mut heap = BinaryHeap::new();
+        for v in 1..self.pos.len() {
+            for u in self.neighbours(v) {
+                if u > v {
+                    if let Some(c) = self.plan(v, u) {
+                        heap.push(c);
+                    }
+                }
+            }
+        }
+
+        while self.live_count > target_faces {
+            let c = match heap.pop() {
+                Some(c) => c,
+                None => break,
+            };
+
+            if self.removed[c.keep] || self.removed[c.remove] ||
+                c.versions != (self.versions[c.keep], self.versions[c.remove]) {
+                continue;
+            }
+
+            let shared = self.shared_tris(c.keep, c.remove);
+            if !self.is_valid(&c, &shared) || !self.collapse(&c, &shared) {
+                continue;
+            }
+
+            self.push_edges(c.keep, &mut heap);
+        }
+    }
+
+    // Builds a compact Obj from the surviving faces, dropping anything
+    // they no longer reference
+    fn to_obj(&self, source: &Obj) -> Obj {
+        let mut obj = Obj::new();
+        obj.groups = source.groups.clone();
+        obj.materials = source.materials.clone();
+        obj.material_libs = source.material_libs.clone();
+
+        let mut vmap = vec![0; self.pos.len()];
+        let mut tmap = vec![0; self.tex.len()];
+        let mut nmap = vec![0; self.norms.len()];
+
+        for (t, tri) in self.tris.iter().enumerate() {
+            if !self.alive[t] {
+                continue;
+            }
+
+            let mut points = [FacePoint { vindex: 0, tindex: 0, nindex: 0 }; 3];
+            for (p, out) in tri.iter().zip(points.iter_mut()) {
+                if vmap[p.vindex] == 0 {
+                    vmap[p.vindex] = if source.has_colors() {
+                        obj.add_colored_vert(self.pos[p.vindex], source.vert_color(p.vindex))
+                    } else {
+                        obj.add_vert(self.pos[p.vindex])
+                    };
+                }
+                if p.tindex != 0 && tmap[p.tindex] == 0 {
+                    tmap[p.tindex] = obj.add_tex_vert(self.tex[p.tindex]);
+                }
+                if p.nindex != 0 && nmap[p.nindex] == 0 {
+                    nmap[p.nindex] = obj.add_norm_vert(self.norms[p.nindex]);
+                }
+
+                *out = FacePoint { vindex: vmap[p.vindex], tindex: tmap[p.tindex], nindex: nmap[p.nindex] };
+            }
+
+            obj.add_polygon(&points, self.attrs[t]);
+        }
+
+        obj
+    }
+}
+
+impl Obj {
+    // Quadric error metric edge collapse (Garland & Heckbert), stopping once
+    // `target_faces` triangles remain or nothing else can be collapsed
+    // safely. Texcoords and normals are interpolated along collapsed edges;
+    // vertices on UV seams or hard edges stay where they are.
+    pub fn simplify(&self, target_faces: usize) -> Obj {
+        let mut decimator = Decimator::new(self);
+        decimator.run(target_faces);
+        decimator.to_obj(self)
+    }
+}
+
+pub struct LodLevel {
+    pub obj: Obj,
+    // Smallest projected diameter, in pixels, this level is used at
+    pub min_screen_size: f32,
+}
+
+pub struct Lod {
+    levels: Vec<LodLevel>,
+}
+
+impl Lod {
+    // Levels should go from most to least detailed
+    pub fn new(levels: Vec<LodLevel>) -> Lod {
+        Lod { levels }
+    }
+
+    // Builds `count` levels, each with `ratio` of the previous level's
+    // faces. The full mesh is used down to `full_detail_size` pixels, and
+    // each level after that for sizes shrinking by the same ratio.
+    pub fn generate(obj: Obj, count: usize, ratio: f32, full_detail_size: f32) -> Lod {
+        let mut levels = Vec::with_capacity(count);
+        let mut faces = obj.faces().len();
+        let mut size = full_detail_size;
+
+        levels.push(LodLevel { obj, min_screen_size: size });
+        for _ in 1..count {
+            faces = (faces as f32 * ratio) as usize;
+            size *= ratio.sqrt();
+
+            let obj = levels.last().unwrap().obj.simplify(faces);
+            levels.push(LodLevel { obj, min_screen_size: size });
+        }
+
+        // The last level covers everything smaller
+        if let Some(last) = levels.last_mut() {
+            last.min_screen_size = 0.0;
+        }
+
+        Lod { levels }
+    }
+
+    pub fn levels(&self) -> &[LodLevel] {
+        &self.levels
+    }
+
+    pub fn level(&self, i: usize) -> &Obj {
+        &self.levels[i].obj
+    }
+
+    // Approximate on-screen diameter, in pixels, of the model's bounding
+    // sphere placed with `model`
+    pub fn screen_size(&self, camera: &Camera, model: &Matrix4x4<f32>, viewport_height: f32) -> f32 {
+        let sphere = match self.levels.first() {
+            Some(level) => level.obj.bounding_sphere().transform(model),
+            None => return 0.0,
+        };
+
+        let distance = (sphere.center - camera.eye).length();
+        if distance <= sphere.radius {
+            return f32::INFINITY;
+        }
+
+        let half_height = distance * (camera.fov / 2.0).tan();
+        sphere.radius / half_height * viewport_height
+    }
+
+    pub fn select(&self, camera: &Camera, model: &Matrix4x4<f32>, viewport_height: f32) -> usize {
+        let size = self.screen_size(camera, model, viewport_height);
+
+        self.levels.iter()
+            .position(|level| size >= level.min_screen_size)
+            .unwrap_or(self.levels.len().saturating_sub(1))
+    }
+
+    pub fn select_obj(&self, camera: &Camera, model: &Matrix4x4<f32>, viewport_height: f32) -> &Obj {
+        self.level(self.select(camera, model, viewport_height))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use bounds::Aabb;
+
+    // A flat n by n grid over [0, 1]^2, with texcoords matching positions.
+    // With `seam`, the faces right of x = 0.5 get their own texcoords there,
+    // shifted 10 along u.
+    fn grid(n: usize, seam: bool) -> Obj {
+        let mut text = String::new();
+        for y in 0..n + 1 {
+            for x in 0..n + 1 {
+                let (u, v) = (x as f32 / n as f32, y as f32 / n as f32);
+                text += &format!("v {} {} 0\nvt {} {}\n", u, v, u, v);
+            }
+        }
+        for y in 0..n + 1 {
+            text += &format!("vt {} {}\n", 10.5, y as f32 / n as f32);
+        }
+
+        let index = |x: usize, y: usize| y * (n + 1) + x + 1;
+        let corner = |x: usize, y: usize, right: bool| {
+            let t = if seam && right && x == n / 2 { (n + 1) * (n + 1) + y + 1 } else { index(x, y) };
+            format!("{}/{}", index(x, y), t)
+        };
+        for y in 0..n {
+            for x in 0..n {
+                let right = x >= n / 2;
+                text += &format!("f {} {} {}\n", corner(x, y, right), corner(x + 1, y, right), corner(x + 1, y + 1, right));
+                text += &format!("f {} {} {}\n", corner(x, y, right), corner(x + 1, y + 1, right), corner(x, y + 1, right));
+            }
+        }
+
+        Obj::from_reader(Cursor::new(text)).unwrap()
+    }
+
+    fn area(obj: &Obj) -> f32 {
+        obj.faces().iter().map(|f| f.area_vector(obj).length() / 2.0).sum()
+    }
+
+    // Every index in range and every face a proper triangle
+    fn assert_valid(obj: &Obj) {
+        for face in obj.faces() {
+            let points = [face.0, face.1, face.2];
+            for p in points.iter() {
+                assert!(p.vindex >= 1 && p.vindex <= obj.vert_count());
+                assert!(p.tindex <= obj.tex_vert_count() && p.nindex <= obj.norm_vert_count());
+            }
+            assert!(points[0].vindex != points[1].vindex && points[1].vindex != points[2].vindex &&
+                    points[0].vindex != points[2].vindex);
+        }
+
+        let mut text = Vec::new();
+        obj.write_to(&mut text).unwrap();
+        let reread = Obj::from_reader(Cursor::new(text)).unwrap();
+        assert_eq!(reread.faces().len(), obj.faces().len());
+    }
+
+    #[test]
+    fn reaches_the_target_face_count() {
+        let obj = grid(10, false);
+        assert_eq!(obj.faces().len(), 200);
+
+        let simple = obj.simplify(50);
+        assert!(simple.faces().len() <= 50 && simple.faces().len() >= 49, "{}", simple.faces().len());
+        assert!(simple.vert_count() < obj.vert_count());
+        assert_valid(&simple);
+    }
+
+    #[test]
+    fn keeps_boundaries_in_place() {
+        let simple = grid(8, false).simplify(20);
+
+        // A flat grid only keeps its area if the outline doesn't move
+        assert!((area(&simple) - 1.0).abs() < 1e-3, "{}", area(&simple));
+        let bounds = Aabb::from_points(simple.positions());
+        assert!(bounds.min.x.abs() < 1e-5 && bounds.min.y.abs() < 1e-5);
+        assert!((bounds.max.x - 1.0).abs() < 1e-5 && (bounds.max.y - 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn keeps_uv_seams() {
+        let obj = grid(8, true);
+        let simple = obj.simplify(30);
+        assert_valid(&simple);
+        assert!(simple.faces().len() < obj.faces().len());
+
+        // Seam vertices never move or go away
+        for y in 0..9 {
+            let seam = Vec3 { x: 0.5, y: y as f32 / 8.0, z: 0.0 };
+            assert!(simple.positions().iter().any(|&p| (p - seam).length() < 1e-6), "lost {}", seam.y);
+        }
+
+        // No face mixes texcoords from both sides of the seam
+        for face in simple.faces() {
+            let us: Vec<f32> = [face.0, face.1, face.2].iter().map(|p| simple.tex_vert(p.tindex).x).collect();
+            assert!(us.iter().all(|&u| u <= 0.5 + 1e-5) || us.iter().all(|&u| u > 0.5 + 1e-5), "{:?}", us);
+        }
+    }
+
+    #[test]
+    fn nan_costs_come_out_last() {
+        let collapse = |cost: f64| Collapse {
+            cost,
+            keep: 1,
+            remove: 2,
+            target: Vec3 { x: 0.0, y: 0.0, z: 0.0 },
+            versions: (0, 0),
+        };
+
+        let mut heap: BinaryHeap<Collapse> = [1.0, f64::NAN, 0.5, 2.0].iter().map(|&c| collapse(c)).collect();
+        let order: Vec<f64> = std::iter::from_fn(|| heap.pop().map(|c| c.cost)).collect();
+
+        assert_eq!(&order[..3], &[0.5, 1.0, 2.0]);
+        assert!(order[3].is_nan());
+    }
+
+    #[test]
+    fn selects_lod_levels_by_screen_size() {
+        let lod = Lod::generate(grid(8, false), 3, 0.5, 200.0);
+        let faces: Vec<usize> = lod.levels().iter().map(|l| l.obj.faces().len()).collect();
+        assert!(faces[0] > faces[1] && faces[1] > faces[2], "{:?}", faces);
+        assert_eq!(lod.levels()[2].min_screen_size, 0.0);
+
+        let camera = |distance: f32| Camera {
+            eye: Vec3 { x: 0.5, y: 0.5, z: distance },
+            center: Vec3 { x: 0.5, y: 0.5, z: 0.0 },
+            up: Vec3 { x: 0.0, y: 1.0, z: 0.0 },
+            fov: 1.0,
+        };
+        let model = Matrix4x4::identity();
+
+        let sizes: Vec<f32> = [1.0, 2.0, 4.0, 100.0].iter().map(|&d| lod.screen_size(&camera(d), &model, 800.0)).collect();
+        assert!(sizes.windows(2).all(|w| w[0] > w[1]));
+
+        assert_eq!(lod.select(&camera(1.0), &model, 800.0), 0);
+        assert_eq!(lod.select(&camera(100.0), &model, 800.0), 2);
+        // Inside the bounding sphere
+        assert_eq!(lod.select(&camera(0.1), &model, 800.0), 0);
+        assert_eq!(lod.select_obj(&camera(100.0), &model, 800.0).faces().len(), faces[2]);
+    }
+}
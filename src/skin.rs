@@ -0,0 +1,248 @@
+use vec::{Vec3, Vec4};
+use matrix::{Matrix4x4, Transform};
+use animation::Clip;
+
+pub const MAX_INFLUENCES: usize = 4;
+
+pub struct Joint {
+    pub name: String,
+    pub parent: Option<usize>,
+    pub rest: Transform,
+    pub inverse_bind: Matrix4x4<f32>,
+}
+
+// Joints are stored so that every parent comes before its children, which
+// lets global transforms be computed in a single pass
+pub struct Skeleton {
+    pub joints: Vec<Joint>,
+}
+
+impl Skeleton {
+    pub fn new() -> Skeleton {
+        Skeleton { joints: Vec::new() }
+    }
+
+    pub fn add_joint(&mut self, name: &str, parent: Option<usize>, rest: Transform) -> usize {
+        if let Some(p) = parent {
+            assert!(p < self.joints.len(), "Joint parents must be added before their children");
+        }
+
+        self.joints.push(Joint {
+            name: name.to_string(),
+            parent,
+            rest,
+            inverse_bind: Matrix4x4::identity(),
+        });
+
+        self.joints.len() - 1
+    }
+
+    pub fn find(&self, name: &str) -> Option<usize> {
+        self.joints.iter().position(|j| j.name == name)
+    }
+
+    pub fn rest_pose(&self) -> Vec<Transform> {
+        self.joints.iter().map(|j| j.rest).collect()
+    }
+
+    // Model-space transform of every joint, given local transforms
+    pub fn global_transforms(&self, locals: &[Transform]) -> Vec<Matrix4x4<f32>> {
+        let mut globals: Vec<Matrix4x4<f32>> = Vec::with_capacity(self.joints.len());
+
+        for (i, joint) in self.joints.iter().enumerate() {
+            let local = locals[i].to_matrix();
+
+            let global = match joint.parent {
+                Some(p) => &globals[p] * &local,
+                None => local,
+            };
+            globals.push(global);
+        }
+
+        globals
+    }
+
+    // Treats the rest pose as the bind pose, for skeletons that weren't
+    // loaded with their own inverse bind matrices
+    pub fn bind_rest_pose(&mut self) {
+        let globals = self.global_transforms(&self.rest_pose());
+
+        for (joint, global) in self.joints.iter_mut().zip(globals) {
+            joint.inverse_bind = global.inverse().unwrap_or_else(Matrix4x4::identity);
+        }
+    }
+
+    // The matrices handed to the vertex stage: each takes a bind-pose vertex
+    // to its posed position
+    pub fn joint_matrices(&self, locals: &[Transform]) -> Vec<Matrix4x4<f32>> {
+        self.global_transforms(locals).iter()
+            .zip(self.joints.iter())
+            .map(|(global, joint)| global * &joint.inverse_bind)
+            .collect()
+    }
+
+    pub fn animate(&self, clip: &Clip, time: f32) -> Vec<Matrix4x4<f32>> {
+        self.joint_matrices(&clip.sample_looped(time, &self.rest_pose()))
+    }
+
+    pub fn animate_frame(&self, clip: &Clip, frame: usize, fps: f32) -> Vec<Matrix4x4<f32>> {
+        self.animate(clip, frame as f32 / fps)
+    }
+}
+
+// Per-vertex joint influences. Unused slots have a weight of zero. They're
+// a property of the source vertex, so interpolating them is meaningless;
+// fragments just see the first vertex's values.
+#[derive(Clone, Copy, Vary)]
+pub struct SkinWeights {
+    #[vary(flat)]
+    pub joints: [usize; MAX_INFLUENCES],
+    #[vary(flat)]
+    pub weights: [f32; MAX_INFLUENCES],
+}
+
+impl SkinWeights {
+    pub fn rigid(joint: usize) -> SkinWeights {
+        SkinWeights {
+            joints: [joint, 0, 0, 0],
+            weights: [1.0, 0.0, 0.0, 0.0],
+        }
+    }
+
+    // Keeps the strongest four influences and renormalizes them
+    pub fn from_influences(influences: &[(usize, f32)]) -> SkinWeights {
+        // A NaN weight would poison the whole sum when renormalizing
+        let mut sorted: Vec<(usize, f32)> = influences.iter().cloned().filter(|&(_, w)| w.is_finite()).collect();
+        sorted.sort_by(|a, b| b.1.total_cmp(&a.1));
+        sorted.truncate(MAX_INFLUENCES);
+
+        let mut skin = SkinWeights {
+            joints: [0; MAX_INFLUENCES],
+            weights: [0.0; MAX_INFLUENCES],
+        };
+
+        for (i, &(joint, weight)) in sorted.iter().enumerate() {
+            skin.joints[i] = joint;
+            skin.weights[i] = weight;
+        }
+
+        skin.normalized()
+    }
+
+    pub fn normalized(mut self) -> SkinWeights {
+        let total: f32 = self.weights.iter().sum();
+
+        if total > 0.0 {
+            for w in self.weights.iter_mut() {
+                *w /= total;
+            }
+        }
+
+        self
+    }
+
+    // Linear blend of the influencing joint matrices. Joints the skeleton
+    // doesn't have (from a bad asset) are skipped, and the remaining
+    // influences share their weight.
+    pub fn matrix(&self, joint_matrices: &[Matrix4x4<f32>]) -> Matrix4x4<f32> {
+        let mut result = Matrix4x4::new();
+        let mut total = 0.0;
+        let mut skipped = false;
+
+        for i in 0..MAX_INFLUENCES {
+            if self.weights[i] != 0.0 {
+                match joint_matrices.get(self.joints[i]) {
+                    Some(mat) => {
+                        result = result + mat * self.weights[i];
+                        total += self.weights[i];
+                    }
+                    None => skipped = true,
+                }
+            }
+        }
+
+        if skipped && total > 0.0 {
+            result = &result * (1.0 / total);
+        }
+        result
+    }
+}
+
+// Skin weights for every position of a mesh, indexed like `Obj::vert`
+pub struct Skin {
+    pub weights: Vec<SkinWeights>,
+}
+
+impl Skin {
+    pub fn new(vert_count: usize) -> Skin {
+        Skin {
+            weights: vec![SkinWeights::rigid(0); vert_count],
+        }
+    }
+
+    pub fn vert(&self, i: usize) -> SkinWeights {
+        self.weights[i - 1]
+    }
+
+    pub fn set_vert(&mut self, i: usize, weights: SkinWeights) {
+        self.weights[i - 1] = weights;
+    }
+}
+
+pub fn skin_point(pt: Vec3<f32>, skin: &SkinWeights, joint_matrices: &[Matrix4x4<f32>]) -> Vec3<f32> {
+    skin.matrix(joint_matrices).transform_point(pt)
+}
+
+// Normals take the inverse transpose, like Transforms::normal, so scaled or
+// sheared joints don't skew them
+pub fn skin_normal(normal: Vec3<f32>, skin: &SkinWeights, joint_matrices: &[Matrix4x4<f32>]) -> Vec3<f32> {
+    let mat = skin.matrix(joint_matrices);
+    let normal_mat = match mat.inverse() {
+        Some(inverse) => inverse.transpose(),
+        None => mat,
+    };
+
+    normal_mat.transform_vector(normal).norm()
+}
+
+// Convenience for `Shader::vertex`: skins the point and returns it ready to
+// be multiplied by the rest of the transform chain
+pub fn skin_vertex(pt: Vec3<f32>, skin: &SkinWeights, joint_matrices: &[Matrix4x4<f32>]) -> Vec4<f32> {
+    let skinned = skin_point(pt, skin, joint_matrices);
+
+    Vec4 { x: skinned.x, y: skinned.y, z: skinned.z, w: 1.0 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nan_weights_are_dropped() {
+        let skin = SkinWeights::from_influences(&[(1, 0.5), (2, f32::NAN), (3, 1.5)]);
+
+        assert_eq!(skin.joints[..2], [3, 1]);
+        assert!((skin.weights[0] - 0.75).abs() < 1e-6 && (skin.weights[1] - 0.25).abs() < 1e-6);
+    }
+
+    #[test]
+    fn normals_stay_perpendicular_under_scaling() {
+        let joints = [Matrix4x4::scale(Vec3 { x: 4.0, y: 1.0, z: 1.0 })];
+        let skin = SkinWeights::rigid(0);
+
+        // A surface along the diagonal, stretched along x
+        let tangent = skin_point(Vec3 { x: 1.0, y: -1.0, z: 0.0 }, &skin, &joints);
+        let normal = skin_normal(Vec3 { x: 1.0, y: 1.0, z: 0.0 }.norm(), &skin, &joints);
+
+        assert!(tangent.dot(normal).abs() < 1e-5);
+    }
+
+    #[test]
+    fn missing_joints_are_skipped() {
+        let joints = [Matrix4x4::translation(Vec3 { x: 1.0, y: 0.0, z: 0.0 })];
+        let skin = SkinWeights::from_influences(&[(0, 0.5), (7, 0.5)]);
+
+        let pt = skin_point(Vec3 { x: 0.0, y: 0.0, z: 0.0 }, &skin, &joints);
+        assert!((pt.x - 1.0).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,268 @@
+use std::cmp;
+use rand::{Rng, SeedableRng, XorShiftRng};
+use vec::{Vec3, Vec4};
+use image::{Image, Color};
+use matrix::Matrix4x4;
+use target::{Buffer, GBuffer};
+
+#[derive(Clone, Copy)]
+pub struct SsaoOptions {
+    pub samples: usize,
+    // How far around each point to look for occluders, in world units
+    pub radius: f32,
+    // Also in world units; keeps flat surfaces from occluding themselves
+    pub bias: f32,
+    // 0 leaves the image alone, 1 darkens fully occluded pixels to black
+    pub strength: f32,
+    // In pixels. Should cover the noise tile so its pattern averages out.
+    pub blur_radius: usize,
+}
+
+impl SsaoOptions {
+    pub fn new() -> SsaoOptions {
+        SsaoOptions {
+            samples: 16,
+            radius: 0.1,
+            bias: 0.005,
+            strength: 1.0,
+            blur_radius: 2,
+        }
+    }
+}
+
+// Random rotations of the kernel repeat across the screen in tiles this
+// many pixels wide, trading banding for noise the blur removes
+const NOISE_SIZE: usize = 4;
+
+// Screen-space ambient occlusion from a G-buffer's depth and normals. Each
+// pixel tests a hemisphere of sample points around its normal against the
+// depth buffer; the fraction that end up behind other geometry is how
+// occluded it is.
+pub struct Ssao {
+    pub options: SsaoOptions,
+    kernel: Vec<Vec3<f32>>,
+    noise: Vec<Vec3<f32>>,
+}
+
+impl Ssao {
+    // Always uses the same seed, so renders are reproducible
+    pub fn new(options: SsaoOptions) -> Ssao {
+        // Without samples every pixel's occlusion would be 0 / 0
+        let options = SsaoOptions { samples: cmp::max(options.samples, 1), ..options };
+        let mut rng = XorShiftRng::from_seed([0x193a6754, 0xa8a7d469, 0x97830e05, 0x113ba7bb]);
+
+        let kernel = (0..options.samples).map(|i| {
+            let dir = Vec3 {
+                x: rng.gen::<f32>() * 2.0 - 1.0,
+                y: rng.gen::<f32>() * 2.0 - 1.0,
+                z: rng.gen::<f32>(),
+            };
+            let dir = if dir.length() > 0.0 { dir.norm() } else { Vec3 { x: 0.0, y: 0.0, z: 1.0 } };
+
+            // More samples close to the point, where occluders matter most
+            let t = i as f32 / options.samples as f32;
+            dir * rng.gen::<f32>() * (0.1 + 0.9 * t * t)
+        }).collect();
+
+        let noise = (0..NOISE_SIZE * NOISE_SIZE).map(|_| Vec3 {
+            x: rng.gen::<f32>() * 2.0 - 1.0,
+            y: rng.gen::<f32>() * 2.0 - 1.0,
+            z: 0.0,
+        }).collect();
+
+        Ssao { options, kernel, noise }
+    }
+
+    // Ambient visibility per pixel: 1 where nothing is in the way, towards 0
+    // in creases and corners. `screen` is the viewport * projection * view
+    // matrix the G-buffer was drawn with.
+    pub fn occlusion(&self, gbuffer: &GBuffer, screen: &Matrix4x4<f32>) -> Buffer<f32> {
+        let (width, height) = (gbuffer.depth.width, gbuffer.depth.height);
+        let mut ao = Buffer::new(width, height, 1.0);
+
+        // Depth isn't divided by w, so it's an affine function of world
+        // position and this converts world distances to depth differences
+        let depth_scale = screen.row(2).xyz().length();
+        let bias = self.options.bias * depth_scale;
+        let radius = self.options.radius * depth_scale;
+
+        for y in 0..height {
+            for x in 0..width {
+                let normal = *gbuffer.normal.get(x, y);
+                if !gbuffer.covered(x, y) || normal.length() == 0.0 {
+                    continue;
+                }
+
+                let position = *gbuffer.position.get(x, y);
+                let depth = *gbuffer.depth.get(x, y);
+
+                let random = self.noise[(y % NOISE_SIZE) * NOISE_SIZE + x % NOISE_SIZE];
+                let (tangent, bitangent) = basis(normal, random);
+
+                let mut occlusion = 0.0;
+                for k in self.kernel.iter() {
+                    let offset = tangent * k.x + bitangent * k.y + normal * k.z;
+                    let sample = position + offset * self.options.radius;
+                    let clip = screen * &Vec4 { x: sample.x, y: sample.y, z: sample.z, w: 1.0 };
+
+                    if clip.w <= 0.0 {
+                        continue;
+                    }
+
+                    let (sx, sy) = ((clip.x / clip.w).floor(), (clip.y / clip.w).floor());
+                    if sx < 0.0 || sy < 0.0 || sx >= width as f32 || sy >= height as f32 {
+                        continue;
+                    }
+
+                    let scene_depth = *gbuffer.depth.get(sx as usize, sy as usize);
+                    if scene_depth >= clip.z + bias {
+                        // Geometry far in front of this point doesn't shadow it
+                        let range = (radius / (scene_depth - depth).abs().max(1e-6)).min(1.0);
+                        occlusion += range * range * (3.0 - 2.0 * range);
+                    }
+                }
+
+                ao.set(x, y, 1.0 - occlusion / self.kernel.len() as f32);
+            }
+        }
+
+        self.blur(&ao, gbuffer, radius)
+    }
+
+    // Box blur that only mixes pixels at similar depths, so occlusion
+    // doesn't bleed across silhouettes
+    fn blur(&self, ao: &Buffer<f32>, gbuffer: &GBuffer, max_depth_difference: f32) -> Buffer<f32> {
+        let r = self.options.blur_radius as isize;
+        let mut out = ao.clone();
+
+        for y in 0..ao.height {
+            for x in 0..ao.width {
+                if !gbuffer.covered(x, y) {
+                    continue;
+                }
+
+                let depth = *gbuffer.depth.get(x, y);
+                let (mut sum, mut count) = (0.0, 0.0);
+
+                for ny in cmp::max(0, y as isize - r)..cmp::min(ao.height as isize, y as isize + r + 1) {
+                    for nx in cmp::max(0, x as isize - r)..cmp::min(ao.width as isize, x as isize + r + 1) {
+                        let (nx, ny) = (nx as usize, ny as usize);
+
+                        if gbuffer.covered(nx, ny) && (*gbuffer.depth.get(nx, ny) - depth).abs() <= max_depth_difference {
+                            sum += *ao.get(nx, ny);
+                            count += 1.0;
+                        }
+                    }
+                }
+
+                out.set(x, y, sum / count);
+            }
+        }
+
+        out
+    }
+
+    // Darkens `image` by the occlusion, scaled by the strength option
+    pub fn composite(&self, image: &mut Image, ao: &Buffer<f32>) {
+        for y in 0..cmp::min(image.height, ao.height) {
+            for x in 0..cmp::min(image.width, ao.width) {
+                let shade = 1.0 - self.options.strength * (1.0 - *ao.get(x, y));
+                let color = image.get_pixel(x, y).to_vec() * shade;
+
+                image.set_pixel(x, y, &Color::from_vec(color));
+            }
+        }
+    }
+
+    pub fn occlusion_image(ao: &Buffer<f32>) -> Image {
+        ao.to_image(|&v| {
+            let v = (v.clamp(0.0, 1.0) * 255.0) as u8;
+            Color(v, v, v)
+        })
+    }
+}
+
+// Tangent and bitangent around `normal`, rotated towards `random` (Gram-
+// Schmidt)
+fn basis(normal: Vec3<f32>, random: Vec3<f32>) -> (Vec3<f32>, Vec3<f32>) {
+    let mut tangent = random - normal * normal.dot(random);
+
+    if tangent.length() < 1e-4 {
+        let axis = if normal.x.abs() < 0.9 { Vec3 { x: 1.0, y: 0.0, z: 0.0 } } else { Vec3 { x: 0.0, y: 1.0, z: 0.0 } };
+        tangent = axis - normal * normal.dot(axis);
+    }
+
+    let tangent = tangent.norm();
+    (tangent, normal.cross(tangent))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use target::{GBufferFragment, Material, RenderTarget};
+
+    // A 16x16 G-buffer seen from above through an identity screen matrix,
+    // so world x and y are pixels and world z is depth. `height` gives the
+    // surface's z at each pixel, or None to leave it uncovered.
+    fn gbuffer<H: Fn(usize, usize) -> Option<f32>>(height: H) -> GBuffer {
+        let mut gbuffer = GBuffer::new(16, 16);
+
+        for y in 0..16 {
+            for x in 0..16 {
+                if let Some(z) = height(x, y) {
+                    let fragment = GBufferFragment {
+                        albedo: Color(255, 255, 255),
+                        position: Vec3 { x: x as f32 + 0.5, y: y as f32 + 0.5, z },
+                        normal: Vec3 { x: 0.0, y: 0.0, z: 1.0 },
+                        material: Material::new(),
+                        object_id: 1,
+                    };
+                    gbuffer.write(x, y, z, fragment);
+                }
+            }
+        }
+
+        gbuffer
+    }
+
+    fn occlusion(gbuffer: &GBuffer, samples: usize) -> Buffer<f32> {
+        let ssao = Ssao::new(SsaoOptions { samples, radius: 6.0, blur_radius: 0, ..SsaoOptions::new() });
+        ssao.occlusion(gbuffer, &Matrix4x4::identity())
+    }
+
+    #[test]
+    fn flat_planes_are_unoccluded() {
+        let ao = occlusion(&gbuffer(|_, _| Some(0.0)), 16);
+
+        assert!(ao.data.iter().all(|&v| (v - 1.0).abs() < 1e-6));
+    }
+
+    #[test]
+    fn creases_are_occluded() {
+        // A step up to a ledge at x = 8
+        let ao = occlusion(&gbuffer(|x, _| Some(if x < 8 { 0.0 } else { 3.0 })), 16);
+
+        assert!(*ao.get(7, 8) < 0.9);
+        assert!((*ao.get(1, 8) - 1.0).abs() < 1e-6);
+        assert!((*ao.get(12, 8) - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn uncovered_pixels_are_left_alone() {
+        let ao = occlusion(&gbuffer(|x, y| if y < 12 { Some(if x < 8 { 0.0 } else { 3.0 }) } else { None }), 16);
+
+        for x in 0..16 {
+            assert_eq!(*ao.get(x, 14), 1.0);
+        }
+        assert!(ao.data.iter().all(|v| v.is_finite()));
+    }
+
+    #[test]
+    fn needs_at_least_one_sample() {
+        let ssao = Ssao::new(SsaoOptions { samples: 0, ..SsaoOptions::new() });
+        assert_eq!(ssao.options.samples, 1);
+
+        let ao = occlusion(&gbuffer(|x, _| Some(if x < 8 { 0.0 } else { 3.0 })), 0);
+        assert!(ao.data.iter().all(|v| v.is_finite()));
+    }
+}
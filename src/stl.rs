@@ -0,0 +1,240 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Read, Write, Error, ErrorKind};
+use vec::Vec3;
+use obj::{Obj, Face, FacePoint};
+
+fn bad_data(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg.to_string())
+}
+
+fn read_f32(bytes: &[u8], offset: usize) -> f32 {
+    let mut raw = [0u8; 4];
+    raw.copy_from_slice(&bytes[offset..offset + 4]);
+    f32::from_le_bytes(raw)
+}
+
+// STL stores every triangle separately, so positions are welded back
+// together as they're read
+struct Welder {
+    obj: Obj,
+    seen: HashMap<(u32, u32, u32), usize>,
+}
+
+impl Welder {
+    fn vert(&mut self, v: Vec3<f32>) -> usize {
+        // Adding 0.0 turns -0.0 into 0.0 so both weld together
+        let key = ((v.x + 0.0).to_bits(), (v.y + 0.0).to_bits(), (v.z + 0.0).to_bits());
+        let obj = &mut self.obj;
+
+        *self.seen.entry(key).or_insert_with(|| obj.add_vert(v))
+    }
+
+    fn facet(&mut self, normal: Vec3<f32>, verts: [Vec3<f32>; 3]) {
+        // Some exporters leave the normal zeroed, so fall back to the winding
+        let normal = if normal.length() > 0.0 {
+            normal.norm()
+        } else {
+            (verts[1] - verts[0]).cross(verts[2] - verts[0]).norm()
+        };
+        let nindex = self.obj.add_norm_vert(normal);
+
+        let mut points = [FacePoint { vindex: 0, tindex: 0, nindex }; 3];
+        for (point, &v) in points.iter_mut().zip(verts.iter()) {
+            point.vindex = self.vert(v);
+        }
+
+        self.obj.add_face(Face(points[0], points[1], points[2]));
+    }
+}
+
+fn parse_ascii(text: &str) -> Result<Obj, Error> {
+    let mut welder = Welder { obj: Obj::new(), seen: HashMap::new() };
+    let mut normal = Vec3 { x: 0.0, y: 0.0, z: 0.0 };
+    let mut verts: Vec<Vec3<f32>> = Vec::new();
+
+    let parse_vec = |words: &[&str]| -> Result<Vec3<f32>, Error> {
+        let v: Result<Vec<f32>, _> = words.iter().take(3).map(|w| w.parse()).collect();
+        match v {
+            Ok(ref v) if v.len() == 3 => Ok(Vec3 { x: v[0], y: v[1], z: v[2] }),
+            _ => Err(bad_data("bad STL vector")),
+        }
+    };
+
+    for line in text.lines() {
+        let words: Vec<&str> = line.split_whitespace().collect();
+
+        match words.first() {
+            Some(&"facet") if words.len() >= 5 => {
+                normal = parse_vec(&words[2..])?;
+                verts.clear();
+            }
+            Some(&"vertex") => verts.push(parse_vec(&words[1..])?),
+            Some(&"endfacet") => {
+                if verts.len() != 3 {
+                    return Err(bad_data("STL facet doesn't have three vertices"));
+                }
+                welder.facet(normal, [verts[0], verts[1], verts[2]]);
+            }
+            _ => {}
+        }
+    }
+
+    Ok(welder.obj)
+}
+
+fn parse_binary(bytes: &[u8]) -> Result<Obj, Error> {
+    if bytes.len() < 84 {
+        return Err(bad_data("STL file is too short"));
+    }
+
+    let count = u32::from_le_bytes([bytes[80], bytes[81], bytes[82], bytes[83]]) as usize;
+    if bytes.len() < 84 + count * 50 {
+        return Err(bad_data("STL file has fewer triangles than its header says"));
+    }
+
+    let mut welder = Welder { obj: Obj::new(), seen: HashMap::new() };
+    let vec_at = |offset: usize| Vec3 {
+        x: read_f32(bytes, offset),
+        y: read_f32(bytes, offset + 4),
+        z: read_f32(bytes, offset + 8),
+    };
+
+    for i in 0..count {
+        let at = 84 + i * 50;
+        welder.facet(vec_at(at), [vec_at(at + 12), vec_at(at + 24), vec_at(at + 36)]);
+    }
+
+    Ok(welder.obj)
+}
+
+impl Obj {
+    pub fn from_stl(filename: &str) -> Result<Obj, Error> {
+        let mut bytes = Vec::new();
+        File::open(filename)?.read_to_end(&mut bytes)?;
+
+        // Binary files are allowed to start with "solid" too, so only trust
+        // it if the size doesn't match the binary layout
+        let binary_size = if bytes.len() >= 84 {
+            84 + u32::from_le_bytes([bytes[80], bytes[81], bytes[82], bytes[83]]) as usize * 50
+        } else {
+            0
+        };
+
+        if bytes.starts_with(b"solid") && binary_size != bytes.len() {
+            parse_ascii(&String::from_utf8_lossy(&bytes))
+        } else {
+            parse_binary(&bytes)
+        }
+    }
+
+    pub fn write_stl(&self, filename: &str, binary: bool) -> Result<(), Error> {
+        let mut out = BufWriter::new(File::create(filename)?);
+
+        let facet_normal = |face: &Face| {
+            let (v0, v1, v2) = (self.vert(face.0.vindex), self.vert(face.1.vindex), self.vert(face.2.vindex));
+            let n = (v1 - v0).cross(v2 - v0);
+            if n.length() > 0.0 { n.norm() } else { n }
+        };
+
+        if binary {
+            let mut header = [0u8; 80];
+            let name = b"rust-sdr";
+            header[..name.len()].copy_from_slice(name);
+            out.write_all(&header)?;
+            out.write_all(&(self.faces().len() as u32).to_le_bytes())?;
+
+            for face in self.faces().iter() {
+                let n = facet_normal(face);
+                let verts = [n, self.vert(face.0.vindex), self.vert(face.1.vindex), self.vert(face.2.vindex)];
+
+                for v in verts.iter() {
+                    out.write_all(&v.x.to_le_bytes())?;
+                    out.write_all(&v.y.to_le_bytes())?;
+                    out.write_all(&v.z.to_le_bytes())?;
+                }
+                out.write_all(&[0, 0])?;
+            }
+        } else {
+            writeln!(out, "solid mesh")?;
+
+            for face in self.faces().iter() {
+                let n = facet_normal(face);
+                writeln!(out, "  facet normal {} {} {}", n.x, n.y, n.z)?;
+                writeln!(out, "    outer loop")?;
+                for fp in [face.0, face.1, face.2].iter() {
+                    let v = self.vert(fp.vindex);
+                    writeln!(out, "      vertex {} {} {}", v.x, v.y, v.z)?;
+                }
+                writeln!(out, "    endloop")?;
+                writeln!(out, "  endfacet")?;
+            }
+
+            writeln!(out, "endsolid mesh")?;
+        }
+
+        out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    fn temp_path(name: &str) -> String {
+        env::temp_dir().join(format!("rust-sdr-{}", name)).to_str().unwrap().to_string()
+    }
+
+    // Two triangles sharing an edge
+    fn quad() -> Obj {
+        let mut obj = Obj::new();
+        for &(x, y) in [(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0)].iter() {
+            obj.add_vert(Vec3 { x, y, z: 0.0 });
+        }
+
+        let point = |v| FacePoint { vindex: v, tindex: 0, nindex: 0 };
+        obj.add_face(Face(point(1), point(2), point(3)));
+        obj.add_face(Face(point(1), point(3), point(4)));
+        obj
+    }
+
+    #[test]
+    fn round_trips_and_welds() {
+        for &binary in [false, true].iter() {
+            let path = temp_path(&format!("round-trip-{}.stl", binary));
+            quad().write_stl(&path, binary).unwrap();
+            let obj = Obj::from_stl(&path).unwrap();
+
+            assert_eq!(obj.faces().len(), 2);
+            assert_eq!(obj.vert_count(), 4);
+            assert_eq!(obj.norm_vert(obj.faces()[1].0.nindex).z, 1.0);
+            assert_eq!(obj.vert(obj.faces()[1].2.vindex).y, 1.0);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_files() {
+        let cases: [(&str, &[u8]); 3] = [
+            ("short", b"not an stl"),
+            ("two-vertices", b"solid x\nfacet normal 0 0 1\nouter loop\nvertex 0 0 0\nvertex 1 0 0\nendloop\nendfacet\nendsolid x\n"),
+            ("bad-vector", b"solid x\nfacet normal 0 0 1\nouter loop\nvertex 0 0\nendloop\nendfacet\nendsolid x\n"),
+        ];
+
+        for &(name, bytes) in cases.iter() {
+            let path = temp_path(&format!("{}.stl", name));
+            fs::write(&path, bytes).unwrap();
+
+            assert!(Obj::from_stl(&path).is_err(), "{} should be rejected", name);
+        }
+
+        // The header promises more triangles than there are
+        let mut bytes = vec![0u8; 80];
+        bytes.extend_from_slice(&1000u32.to_le_bytes());
+        bytes.extend_from_slice(&[0u8; 50]);
+        let path = temp_path("truncated.stl");
+        fs::write(&path, bytes).unwrap();
+        assert!(Obj::from_stl(&path).is_err());
+    }
+}
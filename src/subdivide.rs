@@ -0,0 +1,405 @@
+use std::collections::HashMap;
+use std::f32::consts::PI;
+use vec::{Vec2, Vec3};
+use obj::{Obj, FaceAttrs, FacePoint};
+use normals::SmoothOptions;
+
+#[derive(Clone, Copy)]
+pub struct SubdivideOptions {
+    pub levels: usize,
+    // Edges between faces meeting at a sharper angle than this (in radians)
+    // stay sharp, like mesh boundaries do
+    pub crease_angle: f32,
+    // Edges between different OBJ smoothing groups stay sharp
+    pub smoothing_groups: bool,
+}
+
+impl SubdivideOptions {
+    pub fn new() -> SubdivideOptions {
+        SubdivideOptions {
+            levels: 1,
+            crease_angle: PI,
+            smoothing_groups: true,
+        }
+    }
+}
+
+struct Edge {
+    a: usize,
+    b: usize,
+    faces: Vec<usize>,
+    sharp: bool,
+}
+
+impl Edge {
+    fn other(&self, v: usize) -> usize {
+        if v == self.a { self.b } else { self.a }
+    }
+}
+
+// A mesh as a list of polygons, with the edges between them. Vertex
+// indices are the usual 1-based ones, so the vert_* lists have an unused
+// entry at 0.
+struct Topology {
+    polygons: Vec<Vec<FacePoint>>,
+    attrs: Vec<FaceAttrs>,
+    edges: Vec<Edge>,
+    edge_ids: HashMap<(usize, usize), usize>,
+    vert_edges: Vec<Vec<usize>>,
+    vert_faces: Vec<Vec<usize>>,
+}
+
+fn zero() -> Vec3<f32> {
+    Vec3 { x: 0.0, y: 0.0, z: 0.0 }
+}
+
+fn edge_key(a: usize, b: usize) -> (usize, usize) {
+    if a < b { (a, b) } else { (b, a) }
+}
+
+impl Topology {
+    fn new(obj: &Obj, polygons: Vec<Vec<FacePoint>>, attrs: Vec<FaceAttrs>, options: &SubdivideOptions) -> Topology {
+        let mut t = Topology {
+            polygons,
+            attrs,
+            edges: Vec::new(),
+            edge_ids: HashMap::new(),
+            vert_edges: vec![Vec::new(); obj.vert_count() + 1],
+            vert_faces: vec![Vec::new(); obj.vert_count() + 1],
+        };
+
+        for (f, points) in t.polygons.iter().enumerate() {
+            for (i, p) in points.iter().enumerate() {
+                let q = points[(i + 1) % points.len()];
+                t.vert_faces[p.vindex].push(f);
+
+                let edges = &mut t.edges;
+                let vert_edges = &mut t.vert_edges;
+                let id = *t.edge_ids.entry(edge_key(p.vindex, q.vindex)).or_insert_with(|| {
+                    edges.push(Edge { a: p.vindex, b: q.vindex, faces: Vec::new(), sharp: false });
+                    vert_edges[p.vindex].push(edges.len() - 1);
+                    vert_edges[q.vindex].push(edges.len() - 1);
+                    edges.len() - 1
+                });
+                t.edges[id].faces.push(f);
+            }
+        }
+
+        let normals: Vec<Vec3<f32>> = t.polygons.iter().map(|points| {
+            let sum = (2..points.len()).fold(zero(), |sum, i| {
+                let v0 = obj.vert(points[0].vindex);
+                sum + (obj.vert(points[i - 1].vindex) - v0).cross(obj.vert(points[i].vindex) - v0)
+            });
+            if sum.length() > 0.0 { sum.norm() } else { sum }
+        }).collect();
+
+        let cos_crease = options.crease_angle.cos();
+        for edge in t.edges.iter_mut() {
+            edge.sharp = match edge.faces[..] {
+                [f1, f2] if f1 != f2 && edge.a != edge.b => {
+                    normals[f1].dot(normals[f2]) < cos_crease ||
+                        (options.smoothing_groups && t.attrs[f1].smoothing != t.attrs[f2].smoothing)
+                }
+                // Boundaries, edges shared by more than two faces, and the
+                // edges of degenerate faces that repeat a vertex, which have
+                // nothing opposite them
+                _ => true,
+            };
+        }
+
+        t
+    }
+
+    fn edge(&self, a: usize, b: usize) -> usize {
+        self.edge_ids[&edge_key(a, b)]
+    }
+
+    fn sharp_neighbours(&self, v: usize) -> Vec<usize> {
+        self.vert_edges[v].iter()
+            .map(|&e| &self.edges[e])
+            .filter(|e| e.sharp)
+            .map(|e| e.other(v))
+            .collect()
+    }
+
+    // Vertices on two sharp edges follow the cubic B-spline along them, and
+    // vertices where more meet are corners that don't move. Anything else
+    // is left to the scheme's smooth rule.
+    fn sharp_vertex(&self, obj: &Obj, v: usize) -> Option<Vec3<f32>> {
+        let sharp = self.sharp_neighbours(v);
+
+        match sharp.len() {
+            0 | 1 => None,
+            2 => Some(obj.vert(v) * 0.75 + (obj.vert(sharp[0]) + obj.vert(sharp[1])) * 0.125),
+            _ => Some(obj.vert(v)),
+        }
+    }
+}
+
+// Builds the output mesh alongside the topology, keeping the original
+// vertices' indices and sharing new texcoords across faces that agreed on
+// them before
+struct Builder {
+    obj: Obj,
+    colors: bool,
+    tex: HashMap<(usize, usize), usize>,
+}
+
+impl Builder {
+    fn new(source: &Obj, positions: &[Vec3<f32>]) -> Builder {
+        let mut obj = Obj::new();
+        obj.groups = source.groups.clone();
+        obj.materials = source.materials.clone();
+        obj.material_libs = source.material_libs.clone();
+
+        for i in 1..source.tex_vert_count() + 1 {
+            obj.add_tex_vert(source.tex_vert(i));
+        }
+
+        let colors = source.has_colors();
+        for (i, &p) in positions.iter().enumerate() {
+            if colors {
+                obj.add_colored_vert(p, source.vert_color(i + 1));
+            } else {
+                obj.add_vert(p);
+            }
+        }
+
+        Builder { obj, colors, tex: HashMap::new() }
+    }
+
+    fn add_vert(&mut self, p: Vec3<f32>, color: Vec3<f32>) -> usize {
+        if self.colors { self.obj.add_colored_vert(p, color) } else { self.obj.add_vert(p) }
+    }
+
+    fn edge_tex(&mut self, source: &Obj, t1: usize, t2: usize) -> usize {
+        if t1 == 0 || t2 == 0 {
+            return 0;
+        }
+
+        let obj = &mut self.obj;
+        *self.tex.entry(edge_key(t1, t2)).or_insert_with(|| {
+            obj.add_tex_vert((source.tex_vert(t1) + source.tex_vert(t2)) * 0.5)
+        })
+    }
+
+    fn face_tex(&mut self, source: &Obj, points: &[FacePoint]) -> usize {
+        if points.iter().any(|p| p.tindex == 0) {
+            return 0;
+        }
+
+        let sum = points.iter().fold(Vec2 { x: 0.0, y: 0.0 }, |sum, p| sum + source.tex_vert(p.tindex));
+        self.obj.add_tex_vert(sum * (1.0 / points.len() as f32))
+    }
+
+    fn finish(mut self, source: &Obj, options: &SubdivideOptions) -> Obj {
+        if source.has_normals() {
+            let smooth = SmoothOptions {
+                crease_angle: options.crease_angle,
+                smoothing_groups: options.smoothing_groups,
+                ..SmoothOptions::new()
+            };
+            self.obj.generate_smooth_normals(&smooth);
+        }
+        self.obj
+    }
+}
+
+fn point(vindex: usize, tindex: usize) -> FacePoint {
+    FacePoint { vindex, tindex, nindex: 0 }
+}
+
+fn face_attrs(obj: &Obj) -> Vec<FaceAttrs> {
+    let mut attrs = vec![FaceAttrs::default(); obj.faces().len()];
+
+    for polygon in obj.all_polygons() {
+        for a in &mut attrs[polygon.first_face..polygon.first_face + polygon.face_count] {
+            *a = polygon.attrs;
+        }
+    }
+    attrs
+}
+
+fn loop_step(obj: &Obj, options: &SubdivideOptions) -> Obj {
+    let polygons = obj.faces().iter().map(|f| vec![f.0, f.1, f.2]).collect();
+    let t = Topology::new(obj, polygons, face_attrs(obj), options);
+
+    let positions: Vec<Vec3<f32>> = (1..obj.vert_count() + 1).map(|v| {
+        if let Some(p) = t.sharp_vertex(obj, v) {
+            return p;
+        }
+
+        let n = t.vert_edges[v].len();
+        if n == 0 {
+            return obj.vert(v);
+        }
+
+        let c = 0.375 + 0.25 * (2.0 * PI / n as f32).cos();
+        let beta = (0.625 - c * c) / n as f32;
+        let sum = t.vert_edges[v].iter().fold(zero(), |sum, &e| sum + obj.vert(t.edges[e].other(v)));
+
+        obj.vert(v) * (1.0 - n as f32 * beta) + sum * beta
+    }).collect();
+
+    let mut out = Builder::new(obj, &positions);
+
+    let edge_verts: Vec<usize> = t.edges.iter().map(|edge| {
+        let (a, b) = (obj.vert(edge.a), obj.vert(edge.b));
+
+        let p = if edge.sharp {
+            (a + b) * 0.5
+        } else {
+            let opposite = edge.faces.iter().fold(zero(), |sum, &f| {
+                let third = t.polygons[f].iter().find(|p| p.vindex != edge.a && p.vindex != edge.b).unwrap();
+                sum + obj.vert(third.vindex)
+            });
+            (a + b) * 0.375 + opposite * 0.125
+        };
+
+        let color = (obj.vert_color(edge.a) + obj.vert_color(edge.b)) * 0.5;
+        out.add_vert(p, color)
+    }).collect();
+
+    for (f, points) in t.polygons.iter().enumerate() {
+        let mids: Vec<FacePoint> = (0..3).map(|i| {
+            let (p, q) = (points[i], points[(i + 1) % 3]);
+            point(edge_verts[t.edge(p.vindex, q.vindex)], out.edge_tex(obj, p.tindex, q.tindex))
+        }).collect();
+        let corners: Vec<FacePoint> = points.iter().map(|p| point(p.vindex, p.tindex)).collect();
+
+        for i in 0..3 {
+            out.obj.add_polygon(&[corners[i], mids[i], mids[(i + 2) % 3]], t.attrs[f]);
+        }
+        out.obj.add_polygon(&mids, t.attrs[f]);
+    }
+
+    out.finish(obj, options)
+}
+
+fn catmull_clark_step(obj: &Obj, options: &SubdivideOptions) -> Obj {
+    let source_polygons = obj.all_polygons();
+    let polygons = source_polygons.iter().map(|p| obj.polygon_points(p)).collect();
+    let attrs = source_polygons.iter().map(|p| p.attrs).collect();
+    let t = Topology::new(obj, polygons, attrs, options);
+
+    let average = |points: &[FacePoint], f: &dyn Fn(usize) -> Vec3<f32>| {
+        points.iter().fold(zero(), |sum, p| sum + f(p.vindex)) * (1.0 / points.len() as f32)
+    };
+    let face_points: Vec<Vec3<f32>> = t.polygons.iter().map(|points| average(points, &|v| obj.vert(v))).collect();
+
+    let edge_points: Vec<Vec3<f32>> = t.edges.iter().map(|edge| {
+        let mid = (obj.vert(edge.a) + obj.vert(edge.b)) * 0.5;
+
+        if edge.sharp {
+            mid
+        } else {
+            (mid + (face_points[edge.faces[0]] + face_points[edge.faces[1]]) * 0.5) * 0.5
+        }
+    }).collect();
+
+    let positions: Vec<Vec3<f32>> = (1..obj.vert_count() + 1).map(|v| {
+        if let Some(p) = t.sharp_vertex(obj, v) {
+            return p;
+        }
+
+        let n = t.vert_edges[v].len() as f32;
+        let faces = &t.vert_faces[v];
+        if faces.is_empty() {
+            return obj.vert(v);
+        }
+
+        let q = faces.iter().fold(zero(), |sum, &f| sum + face_points[f]) * (1.0 / faces.len() as f32);
+        let r = t.vert_edges[v].iter()
+            .fold(zero(), |sum, &e| sum + (obj.vert(t.edges[e].a) + obj.vert(t.edges[e].b)) * 0.5) * (1.0 / n);
+
+        (q + r * 2.0 + obj.vert(v) * (n - 3.0)) * (1.0 / n)
+    }).collect();
+
+    let mut out = Builder::new(obj, &positions);
+
+    let edge_verts: Vec<usize> = t.edges.iter().enumerate().map(|(e, edge)| {
+        let color = (obj.vert_color(edge.a) + obj.vert_color(edge.b)) * 0.5;
+        out.add_vert(edge_points[e], color)
+    }).collect();
+
+    for (f, points) in t.polygons.iter().enumerate() {
+        let color = average(points, &|v| obj.vert_color(v));
+        let center = point(out.add_vert(face_points[f], color), out.face_tex(obj, points));
+        let k = points.len();
+
+        let mids: Vec<FacePoint> = (0..k).map(|i| {
+            let (p, q) = (points[i], points[(i + 1) % k]);
+            point(edge_verts[t.edge(p.vindex, q.vindex)], out.edge_tex(obj, p.tindex, q.tindex))
+        }).collect();
+
+        for i in 0..k {
+            let corner = point(points[i].vindex, points[i].tindex);
+            out.obj.add_polygon(&[corner, mids[i], center, mids[(i + k - 1) % k]], t.attrs[f]);
+        }
+    }
+
+    out.finish(obj, options)
+}
+
+impl Obj {
+    // Loop subdivision, which works on the triangles in `faces`. Every
+    // level splits each triangle into four.
+    pub fn subdivide_loop(&self, options: &SubdivideOptions) -> Obj {
+        (0..options.levels).fold(self.clone(), |obj, _| loop_step(&obj, options))
+    }
+
+    // Catmull-Clark subdivision of the original polygons. The first level
+    // turns every n-gon into n quads; later levels split each quad into
+    // four.
+    pub fn subdivide_catmull_clark(&self, options: &SubdivideOptions) -> Obj {
+        (0..options.levels).fold(self.clone(), |obj, _| catmull_clark_step(&obj, options))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn octahedron(smoothing: &str) -> Obj {
+        let mut text = String::from("v 1 0 0\nv -1 0 0\nv 0 1 0\nv 0 -1 0\nv 0 0 1\nv 0 0 -1\nvn 0 0 1\n");
+        text.push_str(smoothing);
+        for &(a, b, c) in [(1, 3, 5), (3, 2, 5), (2, 4, 5), (4, 1, 5), (3, 1, 6), (2, 3, 6), (4, 2, 6), (1, 4, 6)].iter() {
+            text.push_str(&format!("f {}//1 {}//1 {}//1\n", a, b, c));
+        }
+
+        Obj::from_reader(text.as_bytes()).unwrap()
+    }
+
+    // Whether every corner at a position uses the same normal
+    fn is_smooth(obj: &Obj) -> bool {
+        let mut normals: HashMap<usize, usize> = HashMap::new();
+
+        obj.faces().iter().flat_map(|f| vec![f.0, f.1, f.2]).all(|p| *normals.entry(p.vindex).or_insert(p.nindex) == p.nindex)
+    }
+
+    #[test]
+    fn smooths_meshes_without_smoothing_groups() {
+        let obj = octahedron("");
+
+        assert!(is_smooth(&obj.subdivide_loop(&SubdivideOptions::new())));
+        assert!(is_smooth(&obj.subdivide_catmull_clark(&SubdivideOptions::new())));
+    }
+
+    #[test]
+    fn keeps_smoothing_off_flat() {
+        let obj = octahedron("s off\n");
+
+        assert!(!is_smooth(&obj.subdivide_loop(&SubdivideOptions::new())));
+        assert!(!is_smooth(&obj.subdivide_catmull_clark(&SubdivideOptions::new())));
+    }
+
+    #[test]
+    fn tolerates_degenerate_faces() {
+        let obj = Obj::from_reader("v 0 0 0\nv 1 0 0\nv 0 1 0\nv 1 1 0\nf 1 2 3\nf 3 4 4\n".as_bytes()).unwrap();
+
+        for out in [obj.subdivide_loop(&SubdivideOptions::new()), obj.subdivide_catmull_clark(&SubdivideOptions::new())] {
+            assert!(!out.faces().is_empty());
+            assert!(out.positions().iter().all(|p| p.x.is_finite() && p.y.is_finite() && p.z.is_finite()));
+        }
+    }
+}
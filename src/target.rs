@@ -0,0 +1,299 @@
+use vec::Vec3;
+use image::{Image, Color};
+
+// Anything triangles can be rasterized into. `F` is what the fragment shader
+// outputs: a Color for a plain Image, or a struct with one field per
+// attachment when a single pass writes several targets at once.
+pub trait RenderTarget<F> {
+    fn width(&self) -> usize;
+    fn height(&self) -> usize;
+
+    // Stores the fragment if it passes the target's depth test
+    fn write(&mut self, x: usize, y: usize, depth: f32, fragment: F);
+}
+
+impl RenderTarget<Color> for Image {
+    fn width(&self) -> usize {
+        self.width
+    }
+
+    fn height(&self) -> usize {
+        self.height
+    }
+
+    fn write(&mut self, x: usize, y: usize, depth: f32, color: Color) {
+        self.set_pixel_with_depth(x, y, &color, depth as isize);
+    }
+}
+
+// A 2D array of anything, addressed like Image (y = 0 is the bottom row)
+#[derive(Clone)]
+pub struct Buffer<T> {
+    pub data: Vec<T>,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl<T: Clone> Buffer<T> {
+    pub fn new(width: usize, height: usize, value: T) -> Buffer<T> {
+        Buffer {
+            data: vec![value; width * height],
+            width,
+            height,
+        }
+    }
+
+    fn index(&self, x: usize, y: usize) -> usize {
+        (self.height - y - 1) * self.width + x
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> &T {
+        &self.data[self.index(x, y)]
+    }
+
+    pub fn set(&mut self, x: usize, y: usize, value: T) {
+        if x < self.width && y < self.height {
+            let index = self.index(x, y);
+            self.data[index] = value;
+        }
+    }
+
+    pub fn clear(&mut self, value: T) {
+        for v in self.data.iter_mut() {
+            *v = value.clone();
+        }
+    }
+
+    // Renders the buffer for viewing, e.g. to debug a G-buffer attachment
+    pub fn to_image<F: Fn(&T) -> Color>(&self, to_color: F) -> Image {
+        let mut image = Image::new(self.width, self.height);
+
+        for (i, v) in self.data.iter().enumerate() {
+            let color = to_color(v);
+            image.data[i * 3] = color.0;
+            image.data[i * 3 + 1] = color.1;
+            image.data[i * 3 + 2] = color.2;
+        }
+
+        image
+    }
+}
+
+// Object ID of pixels no triangle covered. Geometry may use it too, since
+// coverage comes from depth.
+pub const NO_OBJECT: u32 = 0;
+
+// Surface properties the lighting pass needs besides albedo, for
+// Blinn-Phong highlights
+#[derive(Clone, Copy)]
+pub struct Material {
+    // Strength of the highlight, in [0, 1]
+    pub specular: f32,
+    pub shininess: f32,
+}
+
+impl Material {
+    pub fn new() -> Material {
+        Material {
+            specular: 0.3,
+            shininess: 32.0,
+        }
+    }
+}
+
+// What a geometry pass writes for each fragment. Depth comes from the
+// rasterizer.
+#[derive(Clone, Copy)]
+pub struct GBufferFragment {
+    pub albedo: Color,
+    // World space, as are the normals
+    pub position: Vec3<f32>,
+    // Unit length
+    pub normal: Vec3<f32>,
+    pub material: Material,
+    pub object_id: u32,
+}
+
+// Multiple render targets sharing one depth test: every attachment holds
+// the nearest fragment at each pixel
+pub struct GBuffer {
+    pub albedo: Buffer<Color>,
+    pub position: Buffer<Vec3<f32>>,
+    pub normal: Buffer<Vec3<f32>>,
+    pub material: Buffer<Material>,
+    // Same units as the depth in Image's z-buffer, larger is nearer.
+    // Uncovered pixels are at negative infinity.
+    pub depth: Buffer<f32>,
+    pub object_id: Buffer<u32>,
+}
+
+impl GBuffer {
+    pub fn new(width: usize, height: usize) -> GBuffer {
+        GBuffer {
+            albedo: Buffer::new(width, height, Color(0, 0, 0)),
+            position: Buffer::new(width, height, Vec3 { x: 0.0, y: 0.0, z: 0.0 }),
+            normal: Buffer::new(width, height, Vec3 { x: 0.0, y: 0.0, z: 0.0 }),
+            material: Buffer::new(width, height, Material::new()),
+            depth: Buffer::new(width, height, f32::NEG_INFINITY),
+            object_id: Buffer::new(width, height, NO_OBJECT),
+        }
+    }
+
+    pub fn covered(&self, x: usize, y: usize) -> bool {
+        self.depth.get(x, y).is_finite()
+    }
+
+    pub fn normal_image(&self) -> Image {
+        self.normal.to_image(|&n| Color::from_vec(n * 0.5 + Vec3 { x: 0.5, y: 0.5, z: 0.5 }))
+    }
+
+    // Nearest covered pixels are white, farthest black
+    pub fn depth_image(&self) -> Image {
+        let covered = self.depth.data.iter().cloned().filter(|d| d.is_finite());
+        let (near, far) = covered.fold((f32::NEG_INFINITY, f32::INFINITY), |(near, far), d| (near.max(d), far.min(d)));
+        let range = (near - far).max(1e-6);
+
+        self.depth.to_image(|&d| {
+            if d.is_finite() {
+                let v = ((d - far) / range * 255.0) as u8;
+                Color(v, v, v)
+            } else {
+                Color(0, 0, 0)
+            }
+        })
+    }
+
+    // A distinct, stable color per object
+    pub fn object_id_image(&self) -> Image {
+        self.object_id.to_image(|&id| {
+            if id == NO_OBJECT {
+                return Color(0, 0, 0);
+            }
+
+            let hash = id.wrapping_mul(2654435761);
+            Color((hash >> 24) as u8 | 0x40, (hash >> 16) as u8 | 0x40, (hash >> 8) as u8 | 0x40)
+        })
+    }
+}
+
+impl RenderTarget<GBufferFragment> for GBuffer {
+    fn width(&self) -> usize {
+        self.albedo.width
+    }
+
+    fn height(&self) -> usize {
+        self.albedo.height
+    }
+
+    fn write(&mut self, x: usize, y: usize, depth: f32, fragment: GBufferFragment) {
+        if x >= self.width() || y >= self.height() || depth.is_nan() || depth <= *self.depth.get(x, y) {
+            return;
+        }
+
+        self.albedo.set(x, y, fragment.albedo);
+        self.position.set(x, y, fragment.position);
+        self.normal.set(x, y, fragment.normal);
+        self.material.set(x, y, fragment.material);
+        self.depth.set(x, y, depth);
+        self.object_id.set(x, y, fragment.object_id);
+    }
+}
+
+// A single attachment of any type with its own depth test, for passes that
+// only need one value per pixel (e.g. velocities)
+pub struct DepthTarget<T> {
+    pub color: Buffer<T>,
+    // Same convention as GBuffer::depth
+    pub depth: Buffer<f32>,
+}
+
+impl<T: Clone> DepthTarget<T> {
+    pub fn new(width: usize, height: usize, clear: T) -> DepthTarget<T> {
+        DepthTarget {
+            color: Buffer::new(width, height, clear),
+            depth: Buffer::new(width, height, f32::NEG_INFINITY),
+        }
+    }
+}
+
+impl<T: Clone> RenderTarget<T> for DepthTarget<T> {
+    fn width(&self) -> usize {
+        self.color.width
+    }
+
+    fn height(&self) -> usize {
+        self.color.height
+    }
+
+    fn write(&mut self, x: usize, y: usize, depth: f32, fragment: T) {
+        if x >= self.width() || y >= self.height() || depth.is_nan() || depth <= *self.depth.get(x, y) {
+            return;
+        }
+
+        self.color.set(x, y, fragment);
+        self.depth.set(x, y, depth);
+    }
+}
+
+impl Image {
+    // The z-buffer in GBuffer::depth's convention. Images that were loaded
+    // rather than drawn into have no depth, so every pixel is uncovered.
+    pub fn depth_buffer(&self) -> Buffer<f32> {
+        let mut depth = Buffer::new(self.width, self.height, f32::NEG_INFINITY);
+
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let d = self.depth(x, y);
+                if d != isize::MIN {
+                    depth.set(x, y, d as f32);
+                }
+            }
+        }
+
+        depth
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fragment(shade: u8, id: u32) -> GBufferFragment {
+        let v = shade as f32;
+        GBufferFragment {
+            albedo: Color(shade, shade, shade),
+            position: Vec3 { x: v, y: v, z: v },
+            normal: Vec3 { x: 0.0, y: 0.0, z: 1.0 },
+            material: Material { specular: v, shininess: v },
+            object_id: id,
+        }
+    }
+
+    #[test]
+    fn keeps_the_nearest_fragment_in_every_attachment() {
+        let mut gbuffer = GBuffer::new(2, 2);
+        gbuffer.write(0, 0, 1.0, fragment(10, 1));
+        gbuffer.write(0, 0, 5.0, fragment(50, 2));
+        gbuffer.write(0, 0, 3.0, fragment(30, 3));
+        gbuffer.write(0, 0, 5.0, fragment(60, 4));
+
+        assert_eq!(gbuffer.albedo.get(0, 0).0, 50);
+        assert_eq!(gbuffer.position.get(0, 0).x, 50.0);
+        assert_eq!(gbuffer.material.get(0, 0).specular, 50.0);
+        assert_eq!(*gbuffer.depth.get(0, 0), 5.0);
+        assert_eq!(*gbuffer.object_id.get(0, 0), 2);
+    }
+
+    #[test]
+    fn coverage_comes_from_depth() {
+        let mut gbuffer = GBuffer::new(2, 2);
+        gbuffer.write(1, 0, 1.0, fragment(10, NO_OBJECT));
+
+        assert!(gbuffer.covered(1, 0));
+        assert!(!gbuffer.covered(0, 0));
+
+        gbuffer.write(0, 0, f32::NAN, fragment(20, 1));
+        assert!(!gbuffer.covered(0, 0));
+        assert_eq!(*gbuffer.object_id.get(0, 0), NO_OBJECT);
+    }
+}
@@ -156,3 +156,167 @@ impl<T: Copy> Vec4<T> {
         }
     }
 }
+
+impl<T> Add<Vec4<T>> for Vec4<T>
+        where T: Add<T, Output = T> {
+    type Output = Vec4<T>;
+
+    fn add(self, other: Vec4<T>) -> Vec4<T> {
+        Vec4 {
+            x: self.x + other.x,
+            y: self.y + other.y,
+            z: self.z + other.z,
+            w: self.w + other.w,
+        }
+    }
+}
+
+impl<T> Mul<T> for Vec4<T>
+        where T: Mul<T, Output = T> + Copy {
+    type Output = Vec4<T>;
+
+    fn mul(self, rhs: T) -> Vec4<T> {
+        Vec4 {
+            x: self.x * rhs,
+            y: self.y * rhs,
+            z: self.z * rhs,
+            w: self.w * rhs,
+        }
+    }
+}
+
+impl<T: Copy> Vec4<T> {
+    pub fn xyz(&self) -> Vec3<T> {
+        Vec3 {
+            x: self.x,
+            y: self.y,
+            z: self.z,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Quaternion {
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+    pub w: f32,
+}
+
+impl Quaternion {
+    pub fn identity() -> Quaternion {
+        Quaternion { x: 0.0, y: 0.0, z: 0.0, w: 1.0 }
+    }
+
+    // Matches the rotation built by Matrix4x4::rotation(theta, about)
+    pub fn from_axis_angle(theta: f32, about: Vec3<f32>) -> Quaternion {
+        let about = about.norm();
+        let sin_half = (theta / 2.0).sin();
+
+        Quaternion {
+            x: about.x * sin_half,
+            y: about.y * sin_half,
+            z: about.z * sin_half,
+            w: (theta / 2.0).cos(),
+        }
+    }
+
+    pub fn dot(self, other: Quaternion) -> f32 {
+        self.x * other.x + self.y * other.y + self.z * other.z + self.w * other.w
+    }
+
+    pub fn length(self) -> f32 {
+        self.dot(self).sqrt()
+    }
+
+    pub fn norm(self) -> Quaternion {
+        let length = self.length();
+
+        Quaternion {
+            x: self.x / length,
+            y: self.y / length,
+            z: self.z / length,
+            w: self.w / length,
+        }
+    }
+
+    pub fn conjugate(self) -> Quaternion {
+        Quaternion { x: -self.x, y: -self.y, z: -self.z, w: self.w }
+    }
+
+    pub fn rotate(self, v: Vec3<f32>) -> Vec3<f32> {
+        let p = Quaternion { x: v.x, y: v.y, z: v.z, w: 0.0 };
+        let r = self * p * self.conjugate();
+
+        Vec3 { x: r.x, y: r.y, z: r.z }
+    }
+
+    // Normalized linear interpolation, taking the shorter way around
+    pub fn nlerp(self, other: Quaternion, t: f32) -> Quaternion {
+        let other = if self.dot(other) < 0.0 { other * -1.0 } else { other };
+
+        (self * (1.0 - t) + other * t).norm()
+    }
+
+    pub fn slerp(self, other: Quaternion, t: f32) -> Quaternion {
+        let mut cos_theta = self.dot(other);
+        let mut other = other;
+
+        if cos_theta < 0.0 {
+            other = other * -1.0;
+            cos_theta = -cos_theta;
+        }
+
+        // Nearly parallel, so the sines below would blow up
+        if cos_theta > 0.9995 {
+            return self.nlerp(other, t);
+        }
+
+        let theta = cos_theta.acos();
+        let sin_theta = theta.sin();
+
+        let a = ((1.0 - t) * theta).sin() / sin_theta;
+        let b = (t * theta).sin() / sin_theta;
+
+        self * a + other * b
+    }
+}
+
+impl Add<Quaternion> for Quaternion {
+    type Output = Quaternion;
+
+    fn add(self, other: Quaternion) -> Quaternion {
+        Quaternion {
+            x: self.x + other.x,
+            y: self.y + other.y,
+            z: self.z + other.z,
+            w: self.w + other.w,
+        }
+    }
+}
+
+impl Mul<f32> for Quaternion {
+    type Output = Quaternion;
+
+    fn mul(self, rhs: f32) -> Quaternion {
+        Quaternion {
+            x: self.x * rhs,
+            y: self.y * rhs,
+            z: self.z * rhs,
+            w: self.w * rhs,
+        }
+    }
+}
+
+impl Mul<Quaternion> for Quaternion {
+    type Output = Quaternion;
+
+    fn mul(self, rhs: Quaternion) -> Quaternion {
+        Quaternion {
+            x: self.w * rhs.x + self.x * rhs.w + self.y * rhs.z - self.z * rhs.y,
+            y: self.w * rhs.y - self.x * rhs.z + self.y * rhs.w + self.z * rhs.x,
+            z: self.w * rhs.z + self.x * rhs.y - self.y * rhs.x + self.z * rhs.w,
+            w: self.w * rhs.w - self.x * rhs.x - self.y * rhs.y - self.z * rhs.z,
+        }
+    }
+}
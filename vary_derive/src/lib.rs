@@ -0,0 +1,154 @@
+// `#[derive(Vary)]` for rust-sdr's varyings structs. Every field is
+// interpolated with its own Vary impl (f32, Vec2/3/4<f32>, or another
+// struct deriving Vary), so
+//
+//     #[derive(Clone, Copy, Vary)]
+//     struct Vars {
+//         #[vary(normalize)]
+//         normal: Vec3<f32>,
+//         tex: Vec2<f32>,
+//         #[vary(flat)]
+//         material: u32,
+//     }
+//
+// gives each fragment the weighted sum of the three vertices' normals,
+// renormalized, and their texcoords, and the first vertex's material.
+// Flat fields can be any Clone type.
+//
+// Type parameters of a generic struct must be Vary themselves.
+//
+// The generated impl names the trait as `crate::shader::Vary`, so it only works
+// inside rust-sdr itself.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{parse_macro_input, parse_quote, Data, DeriveInput, Field, Fields, Index, Member};
+
+#[proc_macro_derive(Vary, attributes(vary))]
+pub fn derive_vary(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+
+    match expand(&input) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+#[derive(PartialEq)]
+enum Mode {
+    Interpolate,
+    Flat,
+    Normalize,
+}
+
+fn expand(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let fields = match input.data {
+        Data::Struct(ref data) => &data.fields,
+        _ => return Err(syn::Error::new_spanned(&input.ident, "Vary can only be derived for structs")),
+    };
+
+    let body = match *fields {
+        Fields::Named(ref named) => {
+            let values = named.named.iter().map(|f| {
+                let name = f.ident.as_ref().unwrap();
+                let value = field_value(f, &Member::Named(name.clone()))?;
+                Ok(quote! { #name: #value })
+            }).collect::<syn::Result<Vec<_>>>()?;
+
+            quote! { Self { #(#values),* } }
+        }
+        Fields::Unnamed(ref unnamed) => {
+            let values = unnamed.unnamed.iter().enumerate().map(|(i, f)| {
+                field_value(f, &Member::Unnamed(Index::from(i)))
+            }).collect::<syn::Result<Vec<_>>>()?;
+
+            quote! { Self(#(#values),*) }
+        }
+        Fields::Unit => quote! { Self },
+    };
+
+    let mut generics = input.generics.clone();
+    let params: Vec<_> = generics.type_params().map(|p| p.ident.clone()).collect();
+    let predicates = &mut generics.make_where_clause().predicates;
+    for param in params {
+        predicates.push(parse_quote! { #param: crate::shader::Vary });
+    }
+
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+
+    Ok(quote! {
+        impl #impl_generics crate::shader::Vary for #name #ty_generics #where_clause {
+            #[allow(unused_variables)]
+            fn vary(v1: &Self, v2: &Self, v3: &Self, bary: crate::vec::Vec3<f32>) -> Self {
+                #body
+            }
+        }
+    })
+}
+
+fn field_value(field: &Field, member: &Member) -> syn::Result<TokenStream2> {
+    let interpolated = quote! {
+        crate::shader::Vary::vary(&v1.#member, &v2.#member, &v3.#member, bary)
+    };
+
+    Ok(match mode(field)? {
+        Mode::Interpolate => interpolated,
+        Mode::Flat => quote! { ::std::clone::Clone::clone(&v1.#member) },
+        Mode::Normalize => quote! { crate::shader::Normalize::normalized(#interpolated) },
+    })
+}
+
+// From the field's `#[vary(...)]` attributes, if any
+fn mode(field: &Field) -> syn::Result<Mode> {
+    let mut mode = Mode::Interpolate;
+
+    for attr in field.attrs.iter().filter(|a| a.path().is_ident("vary")) {
+        attr.parse_nested_meta(|meta| {
+            let new_mode = if meta.path.is_ident("flat") {
+                Mode::Flat
+            } else if meta.path.is_ident("normalize") {
+                Mode::Normalize
+            } else {
+                return Err(meta.error("expected `flat` or `normalize`"));
+            };
+
+            if mode != Mode::Interpolate && mode != new_mode {
+                return Err(meta.error("a field can't be both `flat` and `normalize`"));
+            }
+
+            mode = new_mode;
+            Ok(())
+        })?;
+    }
+
+    Ok(mode)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expand_str(input: &str) -> syn::Result<String> {
+        expand(&syn::parse_str(input).unwrap()).map(|tokens| tokens.to_string())
+    }
+
+    #[test]
+    fn bounds_type_parameters() {
+        let tokens = expand_str("struct Pair<T, U: Copy> { a: T, #[vary(flat)] b: U }").unwrap();
+
+        assert!(tokens.contains("where T : crate :: shader :: Vary , U : crate :: shader :: Vary"), "{}", tokens);
+    }
+
+    #[test]
+    fn rejects_unknown_attributes() {
+        let error = expand_str("struct Vars { #[vary(smooth)] a: f32 }").unwrap_err();
+        assert_eq!(error.to_string(), "expected `flat` or `normalize`");
+
+        let error = expand_str("struct Vars { #[vary(flat, normalize)] a: f32 }").unwrap_err();
+        assert_eq!(error.to_string(), "a field can't be both `flat` and `normalize`");
+
+        assert!(expand_str("enum Vars { A }").is_err());
+    }
+}